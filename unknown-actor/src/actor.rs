@@ -16,6 +16,12 @@ impl ServerActor {
     }
 }
 
+impl Default for ServerActor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg_attr(feature = "server", remote_message)]
 impl Message<Greet> for ServerActor {
     type Reply = ();
@@ -50,3 +56,11 @@ impl Message<String> for ServerActor {
         info!(msg);
     }
 }
+
+impl Message<Job> for ServerActor {
+    type Reply = ();
+
+    async fn handle(&mut self, msg: Job, _ctx: &mut Context<Self, Self::Reply>) -> Self::Reply {
+        tokio::spawn(msg.0);
+    }
+}
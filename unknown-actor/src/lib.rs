@@ -10,7 +10,7 @@ pub static NAME: &str = "unknown-actor";
 pub mod prelude {
     pub use super::NAME;
     pub use crate::actor::ServerActor;
-    pub use crate::message::{Add, Greet};
+    pub use crate::message::{Add, Greet, Job};
 
     // Server only
     #[cfg(feature = "server")]
@@ -1,6 +1,7 @@
 pub use kameo_actors::pool::{Broadcast, Dispatch};
 #[cfg(feature = "server")]
 use serde::{Deserialize, Serialize};
+use std::pin::Pin;
 
 #[cfg_attr(feature = "server", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
@@ -17,4 +18,16 @@ impl Message for Add {}
 pub struct Shutdown;
 impl Message for Shutdown {}
 
+/// Runs a future to completion as a task of its own, spawned by whichever worker receives it.
+/// Used by the web server to push background work onto the pool. Workers don't wait for the
+/// future, so long running jobs don't hold up the messages behind them.
+pub struct Job(pub Pin<Box<dyn Future<Output = ()> + Send>>);
+impl Message for Job {}
+
+impl Job {
+    pub fn new(fut: impl Future<Output = ()> + Send + 'static) -> Self {
+        Self(Box::pin(fut))
+    }
+}
+
 pub trait Message {}
@@ -0,0 +1,2 @@
+-- Drop file tables
+DROP TABLE files;
\ No newline at end of file
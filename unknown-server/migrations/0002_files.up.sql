@@ -0,0 +1,15 @@
+-- Create files
+CREATE TABLE IF NOT EXISTS files
+(
+    id          uuid PRIMARY KEY NOT NULL,
+    owner_id    uuid             NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name        text             NOT NULL,
+    storage_key text             NOT NULL,
+    size        bigint           NOT NULL,
+    mime_type   text             NOT NULL,
+    sha256      text             NOT NULL,
+    created     timestamptz      NOT NULL default now(),
+    modified    timestamptz      NOT NULL default now()
+);
+
+CREATE INDEX IF NOT EXISTS files_owner_id_idx ON files (owner_id);
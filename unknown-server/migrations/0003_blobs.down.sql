@@ -0,0 +1,13 @@
+-- Point files back at their blob objects
+ALTER TABLE files
+    DROP CONSTRAINT files_sha256_fkey,
+    ADD COLUMN storage_key text;
+
+UPDATE files
+SET storage_key = (SELECT storage_key FROM blobs WHERE blobs.sha256 = files.sha256);
+
+ALTER TABLE files
+    ALTER COLUMN storage_key SET NOT NULL;
+
+-- Drop blob tables
+DROP TABLE blobs;
@@ -0,0 +1,23 @@
+-- Create blobs. File contents are stored once per sha256 and shared between files.
+CREATE TABLE IF NOT EXISTS blobs
+(
+    sha256      text PRIMARY KEY NOT NULL,
+    storage_key text             NOT NULL,
+    size        bigint           NOT NULL,
+    ref_count   bigint           NOT NULL default 0,
+    created     timestamptz      NOT NULL default now()
+);
+
+-- Existing files keep the oldest copy of their contents.
+-- Objects of newer duplicates are no longer referenced.
+INSERT INTO blobs (sha256, storage_key, size, ref_count, created)
+SELECT DISTINCT ON (sha256) sha256, storage_key, size, 0, created
+FROM files
+ORDER BY sha256, created;
+
+UPDATE blobs
+SET ref_count = (SELECT count(*) FROM files WHERE files.sha256 = blobs.sha256);
+
+ALTER TABLE files
+    DROP COLUMN storage_key,
+    ADD CONSTRAINT files_sha256_fkey FOREIGN KEY (sha256) REFERENCES blobs (sha256);
@@ -0,0 +1,3 @@
+-- Per user quota in bytes. Null falls back to the configured default
+ALTER TABLE users
+    ADD COLUMN quota_bytes bigint;
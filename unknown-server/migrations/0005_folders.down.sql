@@ -0,0 +1,6 @@
+-- Drop folders. Files keep their names but lose their place in the tree
+ALTER TABLE files
+    DROP COLUMN folder_id;
+DROP INDEX IF EXISTS files_name_idx;
+
+DROP TABLE folders;
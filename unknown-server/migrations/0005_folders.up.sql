@@ -0,0 +1,30 @@
+-- Create folders. Folders without a parent live at the root of their owner's tree
+CREATE TABLE IF NOT EXISTS folders
+(
+    id        uuid PRIMARY KEY NOT NULL,
+    owner_id  uuid             NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    parent_id uuid REFERENCES folders (id) ON DELETE CASCADE,
+    name      text             NOT NULL,
+    created   timestamptz      NOT NULL default now(),
+    modified  timestamptz      NOT NULL default now()
+);
+
+-- Names are unique within a folder. The nil uuid stands in for the root
+CREATE UNIQUE INDEX IF NOT EXISTS folders_name_idx
+    ON folders (owner_id, coalesce(parent_id, '00000000-0000-0000-0000-000000000000'), name);
+CREATE INDEX IF NOT EXISTS folders_parent_idx ON folders (parent_id);
+
+-- Files without a folder live at the root
+ALTER TABLE files
+    ADD COLUMN folder_id uuid REFERENCES folders (id);
+
+-- Existing files all end up in the root, so their names have to be made unique first
+UPDATE files
+SET name = files.name || ' (' || files.id || ')'
+FROM (SELECT id, row_number() OVER (PARTITION BY owner_id, name ORDER BY created) AS n FROM files) AS dupes
+WHERE files.id = dupes.id
+  AND dupes.n > 1;
+
+CREATE UNIQUE INDEX IF NOT EXISTS files_name_idx
+    ON files (owner_id, coalesce(folder_id, '00000000-0000-0000-0000-000000000000'), name);
+CREATE INDEX IF NOT EXISTS files_folder_idx ON files (folder_id);
@@ -0,0 +1,21 @@
+-- Trashed items come back, names have to be unique again
+UPDATE folders
+SET name = name || ' (' || id || ')'
+WHERE deleted_at IS NOT NULL;
+UPDATE files
+SET name = name || ' (' || id || ')'
+WHERE deleted_at IS NOT NULL;
+
+DROP INDEX folders_name_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS folders_name_idx
+    ON folders (owner_id, coalesce(parent_id, '00000000-0000-0000-0000-000000000000'), name);
+DROP INDEX files_name_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS files_name_idx
+    ON files (owner_id, coalesce(folder_id, '00000000-0000-0000-0000-000000000000'), name);
+
+ALTER TABLE folders
+    DROP COLUMN deleted_at,
+    DROP COLUMN trashed_by;
+ALTER TABLE files
+    DROP COLUMN deleted_at,
+    DROP COLUMN trashed_by;
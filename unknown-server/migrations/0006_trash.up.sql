@@ -0,0 +1,22 @@
+-- Trashed files and folders have `deleted_at` set.
+-- Everything below a trashed folder is trashed along with it and points to it with `trashed_by`,
+-- so restoring the folder brings back exactly what was trashed with it
+ALTER TABLE folders
+    ADD COLUMN deleted_at timestamptz,
+    ADD COLUMN trashed_by uuid REFERENCES folders (id) ON DELETE SET NULL;
+ALTER TABLE files
+    ADD COLUMN deleted_at timestamptz,
+    ADD COLUMN trashed_by uuid REFERENCES folders (id) ON DELETE SET NULL;
+
+-- Trashed items don't take up their name
+DROP INDEX folders_name_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS folders_name_idx
+    ON folders (owner_id, coalesce(parent_id, '00000000-0000-0000-0000-000000000000'), name)
+    WHERE deleted_at IS NULL;
+DROP INDEX files_name_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS files_name_idx
+    ON files (owner_id, coalesce(folder_id, '00000000-0000-0000-0000-000000000000'), name)
+    WHERE deleted_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS folders_deleted_at_idx ON folders (deleted_at) WHERE deleted_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS files_deleted_at_idx ON files (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,13 @@
+-- Earlier versions are dropped, their blobs lose a reference each
+UPDATE blobs
+SET ref_count = ref_count - (SELECT count(*) FROM file_versions WHERE file_versions.sha256 = blobs.sha256);
+
+-- Blobs only earlier versions pointed to are gone. Their objects stay in storage, since a
+-- migration can't reach it
+DELETE FROM blobs WHERE ref_count <= 0;
+
+DROP TABLE file_versions;
+
+ALTER TABLE files
+    DROP COLUMN version,
+    DROP COLUMN uploaded;
@@ -0,0 +1,23 @@
+-- The files row always holds the current version, earlier versions are kept here
+ALTER TABLE files
+    ADD COLUMN version  integer     NOT NULL default 1,
+    -- When the current version was uploaded. `modified` also changes on renames and moves
+    ADD COLUMN uploaded timestamptz NOT NULL default now();
+UPDATE files
+SET uploaded = created;
+
+CREATE TABLE IF NOT EXISTS file_versions
+(
+    file_id   uuid        NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+    version   integer     NOT NULL,
+    size      bigint      NOT NULL,
+    mime_type text        NOT NULL,
+    sha256    text        NOT NULL REFERENCES blobs (sha256),
+    -- When this version was uploaded
+    created   timestamptz NOT NULL,
+    -- When a newer version replaced it
+    replaced  timestamptz NOT NULL default now(),
+    PRIMARY KEY (file_id, version)
+);
+
+CREATE INDEX IF NOT EXISTS file_versions_replaced_idx ON file_versions (replaced);
@@ -0,0 +1,7 @@
+DROP INDEX IF EXISTS file_versions_unscanned_idx;
+DROP INDEX IF EXISTS files_unscanned_idx;
+
+ALTER TABLE file_versions
+    DROP COLUMN status;
+ALTER TABLE files
+    DROP COLUMN status;
@@ -0,0 +1,12 @@
+-- Malware scan state of the contents of files and their earlier versions.
+-- Files stored before scanning existed are treated as clean
+ALTER TABLE files
+    ADD COLUMN status text NOT NULL default 'clean'
+        CHECK (status IN ('pending', 'clean', 'infected', 'error'));
+ALTER TABLE file_versions
+    ADD COLUMN status text NOT NULL default 'clean'
+        CHECK (status IN ('pending', 'clean', 'infected', 'error'));
+
+-- The scanner looks for work here
+CREATE INDEX IF NOT EXISTS files_unscanned_idx ON files (sha256) WHERE status IN ('pending', 'error');
+CREATE INDEX IF NOT EXISTS file_versions_unscanned_idx ON file_versions (sha256) WHERE status IN ('pending', 'error');
@@ -0,0 +1,4 @@
+ALTER TABLE file_versions
+    DROP COLUMN detected_type;
+ALTER TABLE files
+    DROP COLUMN detected_type;
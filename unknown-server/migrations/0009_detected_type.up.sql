@@ -0,0 +1,6 @@
+-- Content type sniffed from the contents, next to the one the client claimed in `mime_type`.
+-- `NULL` when it couldn't be recognized
+ALTER TABLE files
+    ADD COLUMN detected_type text;
+ALTER TABLE file_versions
+    ADD COLUMN detected_type text;
@@ -0,0 +1,2 @@
+-- Objects encrypted so far can't be read without their keys, decrypt them before going back
+DROP TABLE object_keys;
@@ -0,0 +1,15 @@
+-- Data keys of encrypted objects, each wrapped with one of the configured master keys.
+-- Objects without a row here are stored in plain text
+CREATE TABLE IF NOT EXISTS object_keys
+(
+    storage_key text PRIMARY KEY,
+    -- Id of the master key `data_key` is wrapped with
+    key_id      text        NOT NULL,
+    -- Nonce followed by the sealed data key
+    data_key    bytea       NOT NULL,
+    -- Plain text size of the object
+    size        bigint      NOT NULL,
+    created     timestamptz NOT NULL default now()
+);
+
+CREATE INDEX IF NOT EXISTS object_keys_key_id_idx ON object_keys (key_id);
@@ -0,0 +1,3 @@
+ALTER TABLE blobs
+    DROP COLUMN scan_after,
+    DROP COLUMN scan_attempts;
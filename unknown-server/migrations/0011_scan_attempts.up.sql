@@ -0,0 +1,5 @@
+-- Failed scans are retried with a growing delay, so contents clamd keeps failing on don't
+-- hold up everything else
+ALTER TABLE blobs
+    ADD COLUMN scan_attempts int NOT NULL default 0,
+    ADD COLUMN scan_after    timestamptz;
@@ -0,0 +1,13 @@
+-- Tokens for clients that can't go through the login form, such as WebDAV clients. Only the
+-- sha256 of a token is kept, the token itself is shown once when it's created
+CREATE TABLE IF NOT EXISTS api_tokens
+(
+    id         uuid PRIMARY KEY NOT NULL,
+    user_id    uuid             NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name       text             NOT NULL,
+    token_hash text             NOT NULL UNIQUE,
+    created    timestamptz      NOT NULL default now(),
+    last_used  timestamptz
+);
+
+CREATE INDEX IF NOT EXISTS api_tokens_user_idx ON api_tokens (user_id);
@@ -0,0 +1,13 @@
+-- Access keys for the S3 compatible API. SigV4 signatures can only be checked with the secret
+-- itself, so unlike API tokens the secret is kept as is
+CREATE TABLE IF NOT EXISTS s3_access_keys
+(
+    id         text PRIMARY KEY NOT NULL,
+    user_id    uuid             NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name       text             NOT NULL,
+    secret     text             NOT NULL,
+    created    timestamptz      NOT NULL default now(),
+    last_used  timestamptz
+);
+
+CREATE INDEX IF NOT EXISTS s3_access_keys_user_idx ON s3_access_keys (user_id);
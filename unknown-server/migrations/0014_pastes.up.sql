@@ -0,0 +1,15 @@
+-- Text snippets shared by link. The text is an ordinary file of the owner, a paste only adds
+-- what the paste views need
+CREATE TABLE IF NOT EXISTS pastes
+(
+    id       uuid PRIMARY KEY NOT NULL,
+    file_id  uuid             NOT NULL UNIQUE REFERENCES files (id) ON DELETE CASCADE,
+    owner_id uuid             NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    title    text,
+    -- Name of the syntax the text is highlighted with, detected when unset
+    language text,
+    expires  timestamptz,
+    created  timestamptz      NOT NULL default now()
+);
+
+CREATE INDEX IF NOT EXISTS pastes_expires_idx ON pastes (expires) WHERE expires IS NOT NULL;
@@ -0,0 +1,6 @@
+-- Uploads can be given a time after which they are deleted. Expired files are hidden right
+-- away, the `file_expiry` job deletes them
+ALTER TABLE files
+    ADD COLUMN expires timestamptz;
+
+CREATE INDEX IF NOT EXISTS files_expires_idx ON files (expires) WHERE expires IS NOT NULL;
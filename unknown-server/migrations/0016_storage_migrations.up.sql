@@ -0,0 +1,16 @@
+-- Progress of copying every blob from one storage backend to another, see `migrate-storage`.
+-- Backends are named like `StorageConfig::describe`
+CREATE TABLE IF NOT EXISTS storage_migrations
+(
+    source       text        NOT NULL,
+    target       text        NOT NULL,
+    -- sha256 of the last blob copied. Blobs are copied in sha256 order
+    checkpoint   text,
+    copied       bigint      NOT NULL default 0,
+    copied_bytes bigint      NOT NULL default 0,
+    started      timestamptz NOT NULL default now(),
+    updated      timestamptz NOT NULL default now(),
+    -- Set once everything was copied and the target became the active backend
+    finished     timestamptz,
+    PRIMARY KEY (source, target)
+);
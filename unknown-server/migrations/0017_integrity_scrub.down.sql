@@ -0,0 +1,18 @@
+-- Corrupt contents can't be told apart anymore, treat them like failed scans
+UPDATE files SET status = 'error' WHERE status = 'corrupt';
+UPDATE file_versions SET status = 'error' WHERE status = 'corrupt';
+
+ALTER TABLE file_versions
+    DROP CONSTRAINT file_versions_status_check,
+    ADD CONSTRAINT file_versions_status_check
+        CHECK (status IN ('pending', 'clean', 'infected', 'error'));
+ALTER TABLE files
+    DROP CONSTRAINT files_status_check,
+    ADD CONSTRAINT files_status_check
+        CHECK (status IN ('pending', 'clean', 'infected', 'error'));
+
+DROP INDEX IF EXISTS blobs_unverified_idx;
+
+ALTER TABLE blobs
+    DROP COLUMN corrupt,
+    DROP COLUMN verified;
@@ -0,0 +1,17 @@
+-- Integrity scrubbing. Objects are re-read and hashed now and then, contents that no longer
+-- match their sha256 are marked corrupt and no longer served
+ALTER TABLE blobs
+    ADD COLUMN verified timestamptz,
+    ADD COLUMN corrupt  timestamptz;
+
+-- The scrub job looks for work here, blobs never verified first
+CREATE INDEX IF NOT EXISTS blobs_unverified_idx ON blobs (verified NULLS FIRST) WHERE corrupt IS NULL;
+
+ALTER TABLE files
+    DROP CONSTRAINT files_status_check,
+    ADD CONSTRAINT files_status_check
+        CHECK (status IN ('pending', 'clean', 'infected', 'error', 'corrupt'));
+ALTER TABLE file_versions
+    DROP CONSTRAINT file_versions_status_check,
+    ADD CONSTRAINT file_versions_status_check
+        CHECK (status IN ('pending', 'clean', 'infected', 'error', 'corrupt'));
@@ -0,0 +1,3 @@
+-- Compressed objects would be served as they are stored, decompress them before going back
+ALTER TABLE blobs
+    DROP COLUMN encoding;
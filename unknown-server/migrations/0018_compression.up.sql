@@ -0,0 +1,3 @@
+-- How the object of a blob is encoded. Contents are stored as they are when null
+ALTER TABLE blobs
+    ADD COLUMN encoding text CHECK (encoding IN ('zstd'));
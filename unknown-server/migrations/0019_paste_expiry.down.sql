@@ -0,0 +1,9 @@
+ALTER TABLE pastes
+    ADD COLUMN expires timestamptz;
+
+UPDATE pastes
+SET expires = files.expires
+FROM files
+WHERE files.id = pastes.file_id;
+
+CREATE INDEX IF NOT EXISTS pastes_expires_idx ON pastes (expires) WHERE expires IS NOT NULL;
@@ -0,0 +1,12 @@
+-- Pastes expire like any other file, through `files.expires`. Whichever of the two expiries
+-- comes first is kept
+UPDATE files
+SET expires = pastes.expires
+FROM pastes
+WHERE pastes.file_id = files.id
+  AND pastes.expires IS NOT NULL
+  AND (files.expires IS NULL OR pastes.expires < files.expires);
+
+DROP INDEX IF EXISTS pastes_expires_idx;
+ALTER TABLE pastes
+    DROP COLUMN expires;
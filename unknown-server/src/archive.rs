@@ -0,0 +1,728 @@
+//! Streams files and folders as a ZIP archive, straight from storage.
+//!
+//! Entries are stored without compression, so the archive and its length are known before the
+//! first byte is read. Only the checksums have to wait, they follow every entry in a data
+//! descriptor and are repeated in the central directory at the end. Entries and offsets past
+//! 4 GiB, and archives with more than 65535 entries, use the zip64 extensions.
+use crate::compress;
+use crate::models::blob::{Blob, Encoding};
+use crate::models::file::{File, FileStatus};
+use crate::models::folder::Folder;
+use crate::prelude::*;
+use crate::serve::{self, Disposition};
+use crate::storage::{ByteStream, StorageRef};
+use axum::body::Body;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, header};
+use axum::response::{IntoResponse, Response};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use uuid::Uuid;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const END: u32 = 0x0605_4b50;
+
+const ZIP64_EXTRA: u16 = 0x0001;
+const TIMESTAMP_EXTRA: u16 = 0x5455;
+
+/// Sizes, offsets and counts at or above these go into zip64 fields.
+const MAX_U32: u64 = u32::MAX as u64;
+const MAX_U16: u64 = u16::MAX as u64;
+
+/// Checksum and sizes follow the data
+const FLAG_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// Unix, so the external attributes carry permissions
+const MADE_BY_UNIX: u16 = 3 << 8;
+
+/// How many selected files were left out because they can't be served yet, or at all.
+const ARCHIVE_SKIPPED: HeaderName = HeaderName::from_static("x-archive-skipped");
+
+/// Chunks buffered between reading storage and sending the response.
+const BUFFERED_CHUNKS: usize = 4;
+
+#[derive(Debug, Clone)]
+pub(crate) enum EntryKind {
+    Directory,
+    File {
+        storage_key: String,
+        /// Of the contents as they were uploaded
+        size: u64,
+        encoding: Option<Encoding>,
+    },
+}
+
+/// A file or directory in an archive.
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    /// Path within the archive, directories without the trailing slash
+    pub(crate) path: String,
+    pub(crate) modified: time::OffsetDateTime,
+    pub(crate) kind: EntryKind,
+}
+
+impl Entry {
+    fn name(&self) -> String {
+        match self.kind {
+            EntryKind::Directory => format!("{}/", self.path),
+            EntryKind::File { .. } => self.path.clone(),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self.kind {
+            EntryKind::Directory => 0,
+            EntryKind::File { size, .. } => size,
+        }
+    }
+
+    /// Whether the sizes need zip64 fields.
+    fn zip64(&self) -> bool {
+        self.size() >= MAX_U32
+    }
+
+    fn flags(&self) -> u16 {
+        match self.kind {
+            EntryKind::Directory => FLAG_UTF8,
+            EntryKind::File { .. } => FLAG_UTF8 | FLAG_DESCRIPTOR,
+        }
+    }
+
+    fn descriptor_len(&self) -> u64 {
+        match (&self.kind, self.zip64()) {
+            (EntryKind::Directory, _) => 0,
+            (EntryKind::File { .. }, false) => 16,
+            (EntryKind::File { .. }, true) => 24,
+        }
+    }
+
+    fn external_attributes(&self) -> u32 {
+        match self.kind {
+            // Unix mode in the upper half, the MS-DOS directory bit in the lower
+            EntryKind::Directory => (0o040755 << 16) | 0x10,
+            EntryKind::File { .. } => 0o100644 << 16,
+        }
+    }
+}
+
+/// Date and time in MS-DOS format. Times before 1980 can't be represented and are clamped.
+fn dos_datetime(time: time::OffsetDateTime) -> (u16, u16) {
+    let time = time.to_offset(time::UtcOffset::UTC);
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let dos_time =
+        ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() as u16 / 2);
+    let dos_date = (((time.year() - 1980).min(127) as u16) << 9)
+        | ((time.month() as u16) << 5)
+        | time.day() as u16;
+    (dos_time, dos_date)
+}
+
+/// Extended timestamp with the modification time, in seconds since the epoch.
+fn put_timestamp(buf: &mut BytesMut, modified: time::OffsetDateTime) {
+    buf.put_u16_le(TIMESTAMP_EXTRA);
+    buf.put_u16_le(5);
+    buf.put_u8(1);
+    buf.put_i32_le(
+        modified
+            .unix_timestamp()
+            .clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+    );
+}
+
+const TIMESTAMP_LEN: u16 = 9;
+
+fn local_header(entry: &Entry) -> Bytes {
+    let name = entry.name();
+    let zip64 = entry.zip64();
+    let (dos_time, dos_date) = dos_datetime(entry.modified);
+    let extra_len = TIMESTAMP_LEN + if zip64 { 20 } else { 0 };
+
+    let mut buf = BytesMut::with_capacity(30 + name.len() + extra_len as usize);
+    buf.put_u32_le(LOCAL_HEADER);
+    buf.put_u16_le(if zip64 {
+        VERSION_ZIP64
+    } else {
+        VERSION_DEFAULT
+    });
+    buf.put_u16_le(entry.flags());
+    // Stored
+    buf.put_u16_le(0);
+    buf.put_u16_le(dos_time);
+    buf.put_u16_le(dos_date);
+    // The checksum is only known once the data went out, it's in the descriptor. The sizes
+    // are known, which helps readers that go through the archive front to back
+    buf.put_u32_le(0);
+    let size = if zip64 { MAX_U32 } else { entry.size() } as u32;
+    buf.put_u32_le(size);
+    buf.put_u32_le(size);
+    buf.put_u16_le(name.len() as u16);
+    buf.put_u16_le(extra_len);
+    buf.put_slice(name.as_bytes());
+    if zip64 {
+        buf.put_u16_le(ZIP64_EXTRA);
+        buf.put_u16_le(16);
+        buf.put_u64_le(entry.size());
+        buf.put_u64_le(entry.size());
+    }
+    put_timestamp(&mut buf, entry.modified);
+    buf.freeze()
+}
+
+fn data_descriptor(entry: &Entry, crc: u32) -> Bytes {
+    let mut buf = BytesMut::with_capacity(entry.descriptor_len() as usize);
+    buf.put_u32_le(DATA_DESCRIPTOR);
+    buf.put_u32_le(crc);
+    if entry.zip64() {
+        buf.put_u64_le(entry.size());
+        buf.put_u64_le(entry.size());
+    } else {
+        buf.put_u32_le(entry.size() as u32);
+        buf.put_u32_le(entry.size() as u32);
+    }
+    buf.freeze()
+}
+
+fn central_header(buf: &mut BytesMut, entry: &Entry, offset: u64, crc: u32) {
+    let name = entry.name();
+    let (dos_time, dos_date) = dos_datetime(entry.modified);
+    let mut zip64 = vec![];
+    if entry.zip64() {
+        zip64.extend([entry.size(), entry.size()]);
+    }
+    if offset >= MAX_U32 {
+        zip64.push(offset);
+    }
+    let version = if zip64.is_empty() {
+        VERSION_DEFAULT
+    } else {
+        VERSION_ZIP64
+    };
+    let zip64_len = if zip64.is_empty() {
+        0
+    } else {
+        4 + 8 * zip64.len() as u16
+    };
+
+    buf.put_u32_le(CENTRAL_HEADER);
+    buf.put_u16_le(MADE_BY_UNIX | VERSION_ZIP64);
+    buf.put_u16_le(version);
+    buf.put_u16_le(entry.flags());
+    buf.put_u16_le(0);
+    buf.put_u16_le(dos_time);
+    buf.put_u16_le(dos_date);
+    buf.put_u32_le(crc);
+    let size = entry.size().min(MAX_U32) as u32;
+    buf.put_u32_le(size);
+    buf.put_u32_le(size);
+    buf.put_u16_le(name.len() as u16);
+    buf.put_u16_le(zip64_len + TIMESTAMP_LEN);
+    // Comment, disk, internal attributes
+    buf.put_u16_le(0);
+    buf.put_u16_le(0);
+    buf.put_u16_le(0);
+    buf.put_u32_le(entry.external_attributes());
+    buf.put_u32_le(offset.min(MAX_U32) as u32);
+    buf.put_slice(name.as_bytes());
+    if !zip64.is_empty() {
+        buf.put_u16_le(ZIP64_EXTRA);
+        buf.put_u16_le(8 * zip64.len() as u16);
+        for value in zip64 {
+            buf.put_u64_le(value);
+        }
+    }
+    put_timestamp(buf, entry.modified);
+}
+
+fn central_header_len(entry: &Entry, offset: u64) -> u64 {
+    let zip64_fields = if entry.zip64() { 2 } else { 0 } + u64::from(offset >= MAX_U32);
+    let zip64_len = if zip64_fields > 0 {
+        4 + 8 * zip64_fields
+    } else {
+        0
+    };
+    46 + entry.name().len() as u64 + zip64_len + TIMESTAMP_LEN as u64
+}
+
+/// Everything after the last entry: the central directory and the end records.
+fn directory(entries: &[Entry], offsets: &[u64], crcs: &[u32], start: u64) -> Bytes {
+    let mut buf = BytesMut::new();
+    for ((entry, offset), crc) in entries.iter().zip(offsets).zip(crcs) {
+        central_header(&mut buf, entry, *offset, *crc);
+    }
+    let size = buf.len() as u64;
+    let count = entries.len() as u64;
+
+    if needs_zip64_end(count, size, start) {
+        let zip64_end = start + size;
+        buf.put_u32_le(ZIP64_END);
+        // Size of the rest of the record
+        buf.put_u64_le(44);
+        buf.put_u16_le(MADE_BY_UNIX | VERSION_ZIP64);
+        buf.put_u16_le(VERSION_ZIP64);
+        buf.put_u32_le(0);
+        buf.put_u32_le(0);
+        buf.put_u64_le(count);
+        buf.put_u64_le(count);
+        buf.put_u64_le(size);
+        buf.put_u64_le(start);
+
+        buf.put_u32_le(ZIP64_LOCATOR);
+        buf.put_u32_le(0);
+        buf.put_u64_le(zip64_end);
+        buf.put_u32_le(1);
+    }
+
+    buf.put_u32_le(END);
+    buf.put_u16_le(0);
+    buf.put_u16_le(0);
+    buf.put_u16_le(count.min(MAX_U16) as u16);
+    buf.put_u16_le(count.min(MAX_U16) as u16);
+    buf.put_u32_le(size.min(MAX_U32) as u32);
+    buf.put_u32_le(start.min(MAX_U32) as u32);
+    buf.put_u16_le(0);
+    buf.freeze()
+}
+
+fn needs_zip64_end(count: u64, size: u64, start: u64) -> bool {
+    count >= MAX_U16 || size >= MAX_U32 || start >= MAX_U32
+}
+
+/// A ZIP archive of objects in storage, laid out up front.
+#[derive(Debug)]
+pub(crate) struct Archive {
+    entries: Vec<Entry>,
+    /// Offset of every entry's local header
+    offsets: Vec<u64>,
+    /// Where the central directory starts
+    directory_start: u64,
+    len: u64,
+}
+
+impl Archive {
+    pub(crate) fn new(entries: Vec<Entry>) -> Self {
+        let mut offsets = Vec::with_capacity(entries.len());
+        let mut offset = 0;
+        for entry in &entries {
+            offsets.push(offset);
+            offset += local_header(entry).len() as u64 + entry.size() + entry.descriptor_len();
+        }
+        let directory_start = offset;
+
+        let directory_size: u64 = entries
+            .iter()
+            .zip(&offsets)
+            .map(|(entry, offset)| central_header_len(entry, *offset))
+            .sum();
+        let zip64_end = if needs_zip64_end(entries.len() as u64, directory_size, directory_start) {
+            56 + 20
+        } else {
+            0
+        };
+        let len = directory_start + directory_size + zip64_end + 22;
+
+        Self {
+            entries,
+            offsets,
+            directory_start,
+            len,
+        }
+    }
+
+    /// Length of the whole archive in bytes.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Stream the archive, reading the entries from `storage` one after the other. Objects
+    /// that don't match the size they were recorded with fail the stream, since the archive
+    /// would be broken from there on.
+    pub(crate) fn stream(self, storage: StorageRef) -> ByteStream<'static> {
+        let (mut sender, receiver) = mpsc::channel::<io::Result<Bytes>>(BUFFERED_CHUNKS);
+        tokio::spawn(async move {
+            if let Err(err) = self.write(&storage, &mut sender).await {
+                // Fails too if the client went away, nobody is left to tell then
+                let _ = sender.send(Err(err)).await;
+            }
+        });
+        receiver.boxed()
+    }
+
+    async fn write(
+        &self,
+        storage: &StorageRef,
+        sender: &mut mpsc::Sender<io::Result<Bytes>>,
+    ) -> io::Result<()> {
+        let gone = |_| io::Error::new(io::ErrorKind::BrokenPipe, "archive receiver is gone");
+        let mut crcs = Vec::with_capacity(self.entries.len());
+
+        for entry in &self.entries {
+            sender.send(Ok(local_header(entry))).await.map_err(gone)?;
+            let EntryKind::File {
+                storage_key,
+                size,
+                encoding,
+            } = &entry.kind
+            else {
+                crcs.push(0);
+                continue;
+            };
+
+            let mut body = storage
+                .get(storage_key, None)
+                .await
+                .map_err(io::Error::other)?
+                .body;
+            if let Some(encoding) = *encoding {
+                body = compress::decode(body, encoding);
+            }
+            let mut hasher = crc32fast::Hasher::new();
+            let mut written = 0u64;
+            while let Some(chunk) = body.try_next().await? {
+                written += chunk.len() as u64;
+                if written > *size {
+                    break;
+                }
+                hasher.update(&chunk);
+                sender.send(Ok(chunk)).await.map_err(gone)?;
+            }
+            if written != *size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "`{}` doesn't have the size it was recorded with",
+                        entry.path
+                    ),
+                ));
+            }
+
+            let crc = hasher.finalize();
+            crcs.push(crc);
+            sender
+                .send(Ok(data_descriptor(entry, crc)))
+                .await
+                .map_err(gone)?;
+        }
+
+        let directory = directory(&self.entries, &self.offsets, &crcs, self.directory_start);
+        sender.send(Ok(directory)).await.map_err(gone)?;
+        Ok(())
+    }
+}
+
+/// What to put in an archive.
+#[derive(Debug, Default)]
+pub(crate) struct Selection {
+    pub(crate) files: Vec<Uuid>,
+    pub(crate) folders: Vec<Uuid>,
+}
+
+/// An archive of the selected files and folders of `owner`, ready to be streamed.
+pub(crate) struct Collected {
+    pub(crate) archive: Archive,
+    /// Files left out because they aren't [`FileStatus::Clean`]
+    pub(crate) skipped: usize,
+}
+
+/// Make `path` unique among `used`, numbering it like `notes (2).txt`.
+fn unique_path(path: String, used: &mut HashSet<String>) -> String {
+    if used.insert(path.clone()) {
+        return path;
+    }
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (format!("{dir}/"), name),
+        None => (String::new(), path.as_str()),
+    };
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name, String::new()),
+    };
+    (2..)
+        .map(|n| format!("{dir}{stem} ({n}){extension}"))
+        .find(|candidate| used.insert(candidate.clone()))
+        .expect("some number is free")
+}
+
+/// Collect the selected files, and the selected folders with everything in them, of `owner`.
+/// Folders keep their structure below their own name. Anything that doesn't exist or belongs to
+/// someone else makes this fail with `404 Not Found`.
+pub(crate) async fn collect(
+    state: &AppState,
+    owner: Uuid,
+    selection: &Selection,
+) -> Result<Collected> {
+    let mut used = HashSet::new();
+    let mut entries = vec![];
+    // Files with the directory they are going to
+    let mut files: Vec<(Option<String>, File)> = vec![];
+
+    for id in &selection.folders {
+        let Some(folder) = Folder::get_owned(state.db(), *id, owner).await? else {
+            return Err(StatusCode::NOT_FOUND.into());
+        };
+        let root = unique_path(folder.name.clone(), &mut used);
+        let subtree = Folder::subtree_paths(state.db(), folder.id).await?;
+
+        // Folders are listed parents first, so directories come out in a sensible order
+        let mut directories = HashMap::new();
+        for folder_path in subtree {
+            // Swap the name of the top folder for the one it got in the archive
+            let path = match folder_path.path.split_once('/') {
+                Some((_, below)) => format!("{root}/{below}"),
+                None => root.clone(),
+            };
+            used.insert(path.clone());
+            directories.insert(folder_path.id, path.clone());
+            entries.push(Entry {
+                path,
+                modified: folder.modified,
+                kind: EntryKind::Directory,
+            });
+        }
+        let ids: Vec<Uuid> = directories.keys().copied().collect();
+        for file in File::in_folders(state.db(), owner, &ids).await? {
+            let directory = file.folder_id.and_then(|id| directories.get(&id)).cloned();
+            files.push((directory, file));
+        }
+    }
+
+    let selected = File::get_many_owned(state.db(), &selection.files, owner).await?;
+    let unique: HashSet<&Uuid> = selection.files.iter().collect();
+    if selected.len() != unique.len() {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    files.extend(selected.into_iter().map(|file| (None, file)));
+
+    let (servable, skipped): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .partition(|(_, file)| file.status == FileStatus::Clean);
+    let sha256s: Vec<String> = servable
+        .iter()
+        .map(|(_, file)| file.sha256.clone())
+        .collect();
+    let blobs: HashMap<String, Blob> = Blob::get_many(state.db(), &sha256s)
+        .await?
+        .into_iter()
+        .map(|blob| (blob.sha256.clone(), blob))
+        .collect();
+
+    for (directory, file) in servable {
+        let Some(blob) = blobs.get(&file.sha256) else {
+            error!(id = %file.id, sha256 = file.sha256, "file points to a missing blob");
+            return Err(StatusCode::NOT_FOUND.into());
+        };
+        let path = match directory {
+            Some(directory) => format!("{directory}/{}", file.name),
+            None => file.name.clone(),
+        };
+        entries.push(Entry {
+            path: unique_path(path, &mut used),
+            modified: file.uploaded,
+            kind: EntryKind::File {
+                storage_key: blob.storage_key.clone(),
+                size: file.size as u64,
+                encoding: blob.encoding,
+            },
+        });
+    }
+
+    Ok(Collected {
+        archive: Archive::new(entries),
+        skipped: skipped.len(),
+    })
+}
+
+/// Respond with `collected` as a download called `name`.zip.
+pub(crate) fn respond(state: &AppState, collected: Collected, name: &str) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/zip"),
+    );
+    headers.insert(header::CONTENT_LENGTH, collected.archive.len().into());
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("private"));
+    if let Ok(disposition) = HeaderValue::from_str(&serve::content_disposition(
+        Disposition::Attachment,
+        &format!("{name}.zip"),
+    )) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    headers.insert(ARCHIVE_SKIPPED, collected.skipped.into());
+
+    let body = Body::from_stream(collected.archive.stream(state.storage_ref()));
+    (StatusCode::OK, headers, body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use crate::storage::{PutOptions, Storage};
+    use futures::stream;
+    use std::io::{Cursor, Read};
+    use std::sync::Arc;
+
+    fn modified() -> time::OffsetDateTime {
+        time::OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap()
+    }
+
+    fn file(path: &str, storage_key: &str, size: u64) -> Entry {
+        Entry {
+            path: path.to_string(),
+            modified: modified(),
+            kind: EntryKind::File {
+                storage_key: storage_key.to_string(),
+                size,
+                encoding: None,
+            },
+        }
+    }
+
+    fn directory_entry(path: &str) -> Entry {
+        Entry {
+            path: path.to_string(),
+            modified: modified(),
+            kind: EntryKind::Directory,
+        }
+    }
+
+    async fn storage(objects: &[(&str, &[u8])]) -> StorageRef {
+        let root = std::env::temp_dir().join(format!("archive-test-{}", Uuid::new_v4()));
+        let storage = LocalStorage::new(root).await.unwrap();
+        for (key, data) in objects {
+            let body = stream::once(async { Ok(Bytes::copy_from_slice(data)) }).boxed();
+            storage.put(key, body, PutOptions::default()).await.unwrap();
+        }
+        Arc::new(storage)
+    }
+
+    async fn collect_bytes(body: ByteStream<'_>) -> io::Result<Vec<u8>> {
+        body.try_fold(vec![], |mut all, chunk| async move {
+            all.extend_from_slice(&chunk);
+            Ok(all)
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn archives_files_and_directories() {
+        let storage = storage(&[("blobs/a", b"hello world"), ("blobs/b", b"")]).await;
+        let archive = Archive::new(vec![
+            directory_entry("project"),
+            directory_entry("project/empty"),
+            file("project/hello.txt", "blobs/a", 11),
+            file("project/nothing", "blobs/b", 0),
+            file("grüße.txt", "blobs/a", 11),
+        ]);
+        let len = archive.len();
+
+        let bytes = collect_bytes(archive.stream(storage)).await.unwrap();
+        assert_eq!(bytes.len() as u64, len);
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let names: Vec<_> = zip.file_names().map(str::to_string).collect();
+        assert_eq!(zip.len(), 5, "{names:?}");
+
+        let dir = zip.by_name("project/empty/").unwrap();
+        assert!(dir.is_dir());
+        drop(dir);
+
+        let mut hello = zip.by_name("project/hello.txt").unwrap();
+        let mut contents = String::new();
+        hello.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello world");
+        let modified = hello.last_modified().unwrap();
+        assert_eq!(
+            (modified.year(), modified.month(), modified.day()),
+            (2023, 11, 14)
+        );
+        drop(hello);
+
+        let mut unicode = zip.by_name("grüße.txt").unwrap();
+        let mut contents = vec![];
+        unicode.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn empty_archive() {
+        let storage = storage(&[]).await;
+        let archive = Archive::new(vec![]);
+        assert_eq!(archive.len(), 22);
+
+        let bytes = collect_bytes(archive.stream(storage)).await.unwrap();
+        let zip = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(zip.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn fails_on_objects_of_the_wrong_size() {
+        let storage = storage(&[("blobs/a", b"hello world")]).await;
+        let archive = Archive::new(vec![file("hello.txt", "blobs/a", 5)]);
+        assert!(collect_bytes(archive.stream(storage)).await.is_err());
+    }
+
+    #[test]
+    fn zip64_layout() {
+        // Too large to stream in a test, but the layout must still add up
+        let large = file("large.bin", "blobs/large", 5 * 1024 * 1024 * 1024);
+        let archive = Archive::new(vec![large.clone(), file("after.txt", "blobs/a", 1)]);
+
+        let header = local_header(&large);
+        assert_eq!(header.len() as u64, 30 + 9 + 20 + TIMESTAMP_LEN as u64);
+        assert_eq!(large.descriptor_len(), 24);
+        // The second entry starts past 4 GiB, so its offset goes into zip64 fields too
+        assert!(archive.offsets[1] > MAX_U32);
+
+        let crcs = vec![0; 2];
+        let directory = directory_bytes(&archive, &crcs);
+        let end = archive.len - archive.directory_start;
+        assert_eq!(directory.len() as u64, end);
+    }
+
+    fn directory_bytes(archive: &Archive, crcs: &[u32]) -> Bytes {
+        directory(
+            &archive.entries,
+            &archive.offsets,
+            crcs,
+            archive.directory_start,
+        )
+    }
+
+    #[test]
+    fn dos_times() {
+        let time = time::OffsetDateTime::UNIX_EPOCH
+            + time::Duration::days(19_782)
+            + time::Duration::seconds(13 * 3600 + 37 * 60 + 59);
+        assert_eq!(
+            dos_datetime(time),
+            ((13 << 11) | (37 << 5) | 29, (44 << 9) | (2 << 5) | 29)
+        );
+        assert_eq!(
+            dos_datetime(time::OffsetDateTime::UNIX_EPOCH),
+            (0, (1 << 5) | 1)
+        );
+    }
+
+    #[test]
+    fn unique_paths() {
+        let mut used = HashSet::new();
+        assert_eq!(unique_path("a.txt".into(), &mut used), "a.txt");
+        assert_eq!(unique_path("a.txt".into(), &mut used), "a (2).txt");
+        assert_eq!(unique_path("a.txt".into(), &mut used), "a (3).txt");
+        assert_eq!(unique_path("dir/.env".into(), &mut used), "dir/.env");
+        assert_eq!(unique_path("dir/.env".into(), &mut used), "dir/.env (2)");
+        assert_eq!(unique_path("dir".into(), &mut used), "dir");
+        assert_eq!(unique_path("dir".into(), &mut used), "dir (2)");
+    }
+}
@@ -0,0 +1,265 @@
+//! Transparent compression of stored contents.
+//!
+//! Text like logs, CSV and JSON shrinks a lot, so uploads that look like text are compressed
+//! with zstd on their way into storage. The encoding is recorded on the blob, as contents are
+//! stored once per sha256. Everything reading contents goes through [`read`], which hands them
+//! back as they were uploaded. Only [`serve_file`](crate::serve::serve_file) passes compressed
+//! objects on as they are, to clients that accept them.
+use crate::config::CONFIG;
+use crate::models::blob::{Blob, Encoding};
+use crate::storage::{ByteStream, GetObject, ObjectMeta, Storage, StorageResult, clamp_range};
+use async_compression::Level;
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt, stream};
+use std::io;
+use std::ops::Range;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Content types compressed besides `text/*` and those ending in `+json` or `+xml`.
+const TEXT_TYPES: &[&str] = &[
+    "application/json",
+    "application/x-ndjson",
+    "application/xml",
+    "application/javascript",
+    "application/yaml",
+    "application/x-yaml",
+    "application/toml",
+    "application/sql",
+    "application/csv",
+];
+
+/// Extensions compressed whatever type the client sent, which often is
+/// `application/octet-stream` for logs.
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "log", "csv", "tsv", "json", "jsonl", "ndjson", "xml", "yaml", "yml", "toml", "md",
+    "sql", "html", "css", "js",
+];
+
+/// Whether contents uploaded as `name` with `content_type` are likely text.
+fn compressible(name: &str, content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || TEXT_TYPES.contains(&essence.as_str())
+    {
+        return true;
+    }
+    name.rsplit_once('.').is_some_and(|(_, extension)| {
+        TEXT_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+    })
+}
+
+/// How new contents are encoded on their way into storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Compression {
+    pub(crate) encoding: Encoding,
+    pub(crate) level: i32,
+}
+
+impl Compression {
+    /// How contents uploaded as `name` with `content_type` are stored, `None` for as they are.
+    pub(crate) fn for_upload(name: &str, content_type: &str) -> Option<Self> {
+        let config = &CONFIG.compression;
+        (config.enabled && compressible(name, content_type)).then_some(Self {
+            encoding: Encoding::Zstd,
+            level: config.level,
+        })
+    }
+
+    pub(crate) fn encode<'a>(self, body: ByteStream<'a>) -> ByteStream<'a> {
+        match self.encoding {
+            Encoding::Zstd => {
+                let encoder =
+                    ZstdEncoder::with_quality(StreamReader::new(body), Level::Precise(self.level));
+                ReaderStream::new(encoder).boxed()
+            }
+        }
+    }
+}
+
+/// Decode a body stored with `encoding`. Damaged bodies fail with `InvalidData`, cut off ones
+/// with `UnexpectedEof`.
+pub(crate) fn decode<'a>(body: ByteStream<'a>, encoding: Encoding) -> ByteStream<'a> {
+    match encoding {
+        Encoding::Zstd => ReaderStream::new(ZstdDecoder::new(StreamReader::new(body))).boxed(),
+    }
+}
+
+/// Read `range` of the contents of `blob` as they were uploaded. Ranges of compressed contents
+/// are cut from the decoded body, so everything before the range is decoded too.
+pub(crate) async fn read(
+    storage: &dyn Storage,
+    blob: &Blob,
+    range: Option<Range<u64>>,
+) -> StorageResult<GetObject> {
+    let Some(encoding) = blob.encoding else {
+        return storage.get(&blob.storage_key, range).await;
+    };
+    let object = storage.get(&blob.storage_key, None).await?;
+    let size = blob.size as u64;
+    let range = clamp_range(range, size);
+
+    Ok(GetObject {
+        meta: ObjectMeta {
+            key: object.meta.key,
+            size,
+        },
+        range: range.clone(),
+        body: slice(decode(object.body, encoding), range),
+    })
+}
+
+/// The bytes of `body` within `range`. Reading stops at the end of the range.
+fn slice(body: ByteStream<'_>, range: Range<u64>) -> ByteStream<'_> {
+    split(body, 0, vec![range])
+        .map_ok(|(_, chunk)| chunk)
+        .boxed()
+}
+
+/// The bytes of `body`, which starts at `offset` of the contents, within each of `ranges`,
+/// along with the index of their range. Every range is cut from the same pass over `body`, so
+/// they have to be sorted and apart, like merged ranges are. Reading stops at the end of the
+/// last range.
+pub(crate) fn split(
+    body: ByteStream<'_>,
+    offset: u64,
+    ranges: Vec<Range<u64>>,
+) -> BoxStream<'_, io::Result<(usize, Bytes)>> {
+    // The chunk read last is kept for the next range when it reaches into it
+    let pending: Option<(u64, Bytes)> = None;
+    stream::unfold(
+        (body, ranges, offset, 0, pending),
+        |(mut body, ranges, mut offset, mut index, mut pending)| async move {
+            loop {
+                let range = ranges.get(index)?.clone();
+                let (start, chunk) = match pending.take() {
+                    Some(pending) => pending,
+                    None => match body.next().await? {
+                        Ok(chunk) => {
+                            offset += chunk.len() as u64;
+                            (offset - chunk.len() as u64, chunk)
+                        }
+                        Err(err) => return Some((Err(err), (body, vec![], offset, 0, None))),
+                    },
+                };
+                let end = start + chunk.len() as u64;
+                if end <= range.start {
+                    continue;
+                }
+                if start >= range.end {
+                    index += 1;
+                    pending = Some((start, chunk));
+                    continue;
+                }
+
+                let part = chunk.slice(
+                    (range.start.max(start) - start) as usize
+                        ..(range.end.min(end) - start) as usize,
+                );
+                let current = index;
+                if end > range.end {
+                    index += 1;
+                    pending = Some((start, chunk));
+                }
+                return Some((Ok((current, part)), (body, ranges, offset, index, pending)));
+            }
+        },
+    )
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(data: &[u8], size: usize) -> ByteStream<'static> {
+        let chunks: Vec<io::Result<Bytes>> = data
+            .chunks(size)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        stream::iter(chunks).boxed()
+    }
+
+    async fn collect(body: ByteStream<'_>) -> io::Result<Vec<u8>> {
+        let chunks: Vec<Bytes> = body.try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    #[test]
+    fn compresses_text() {
+        assert!(compressible("notes", "text/plain; charset=utf-8"));
+        assert!(compressible("data", "application/json"));
+        assert!(compressible("feed", "application/atom+xml"));
+        assert!(compressible("app.LOG", "application/octet-stream"));
+        assert!(!compressible("photo.jpg", "image/jpeg"));
+        assert!(!compressible("archive.tar.gz", "application/gzip"));
+        assert!(!compressible("log", "application/octet-stream"));
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        let text = "2024-01-01 INFO request served\n".repeat(1000);
+        let compression = Compression {
+            encoding: Encoding::Zstd,
+            level: 3,
+        };
+
+        let encoded = collect(compression.encode(chunked(text.as_bytes(), 100)))
+            .await
+            .unwrap();
+        assert!(encoded.len() < text.len() / 10);
+
+        let decoded = collect(decode(chunked(&encoded, 7), Encoding::Zstd))
+            .await
+            .unwrap();
+        assert_eq!(decoded, text.as_bytes());
+
+        let damaged = collect(decode(
+            chunked(&encoded[..encoded.len() / 2], 7),
+            Encoding::Zstd,
+        ))
+        .await
+        .unwrap_err();
+        assert_eq!(damaged.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn slices_ranges_across_chunks() {
+        let data: Vec<u8> = (0..100).collect();
+        for range in [0..100, 0..1, 5..25, 9..10, 10..11, 95..100, 50..50] {
+            let sliced = collect(slice(chunked(&data, 10), range.clone()))
+                .await
+                .unwrap();
+            assert_eq!(sliced, &data[range.start as usize..range.end as usize]);
+        }
+    }
+
+    #[tokio::test]
+    async fn splits_ranges_in_one_pass() {
+        let data: Vec<u8> = (0..100).collect();
+        let ranges = vec![2..5, 9..11, 12..13, 25..40, 95..100];
+        // The body starts at the first range, like a read of the span of all of them
+        let parts: Vec<(usize, Bytes)> = split(chunked(&data[2..], 10), 2, ranges.clone())
+            .try_collect()
+            .await
+            .unwrap();
+
+        for (index, range) in ranges.iter().enumerate() {
+            let part: Vec<u8> = parts
+                .iter()
+                .filter(|(part, _)| *part == index)
+                .flat_map(|(_, chunk)| chunk.to_vec())
+                .collect();
+            assert_eq!(part, &data[range.start as usize..range.end as usize]);
+        }
+        assert!(parts.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    }
+}
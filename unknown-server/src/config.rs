@@ -8,7 +8,8 @@ use figment::{
 use figment_file_provider_adapter::FileAdapter;
 use rand::{RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
-use std::cell::LazyCell;
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct RedisConfig {
@@ -59,6 +60,447 @@ impl SignupConfig {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LocalStorageConfig {
+    /// Directory objects are written to. Created if missing.
+    #[serde(default = "LocalStorageConfig::default_path")]
+    pub(crate) path: String,
+}
+
+impl LocalStorageConfig {
+    fn default_path() -> String {
+        "data/storage".to_string()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct S3StorageConfig {
+    /// Bucket name
+    pub(crate) bucket: String,
+    /// Endpoint url. Leave unset to use AWS
+    pub(crate) endpoint: Option<String>,
+    /// Region. Defaults to `garage`
+    #[serde(default = "S3StorageConfig::default_region")]
+    pub(crate) region: String,
+    pub(crate) access_key_id: String,
+    /// Use the `STORAGE__SECRET_ACCESS_KEY_FILE` env variable to load it from a file
+    pub(crate) secret_access_key: String,
+    /// Garage needs path style requests unless `root_domain` is set up
+    #[serde(default = "S3StorageConfig::default_force_path_style")]
+    pub(crate) force_path_style: bool,
+
+    /// Bodies larger than this many bytes are sent as multipart uploads. Defaults to 16MiB
+    #[serde(default = "S3StorageConfig::default_multipart_threshold")]
+    pub(crate) multipart_threshold: usize,
+    /// Size of each multipart part in bytes. S3 requires at least 5MiB. Defaults to 8MiB
+    #[serde(default = "S3StorageConfig::default_part_size")]
+    pub(crate) part_size: usize,
+    /// Parts uploaded at once for a single upload. Defaults to 4
+    #[serde(default = "S3StorageConfig::default_multipart_concurrency")]
+    pub(crate) multipart_concurrency: usize,
+}
+
+impl S3StorageConfig {
+    fn default_region() -> String {
+        "garage".to_string()
+    }
+    fn default_force_path_style() -> bool {
+        true
+    }
+    fn default_multipart_threshold() -> usize {
+        16 * 1024 * 1024
+    }
+    fn default_part_size() -> usize {
+        8 * 1024 * 1024
+    }
+    fn default_multipart_concurrency() -> usize {
+        4
+    }
+}
+
+/// Storage backend, selected with `backend = "local"` or `backend = "s3"`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub(crate) enum StorageConfig {
+    Local(LocalStorageConfig),
+    S3(S3StorageConfig),
+}
+
+impl StorageConfig {
+    /// Names the backend, telling apart directories and buckets. Migrations are recorded under
+    /// these names
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Self::Local(config) => format!("local:{}", config.path),
+            Self::S3(config) => format!(
+                "s3:{}/{}",
+                config.endpoint.as_deref().unwrap_or("aws"),
+                config.bucket
+            ),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self::Local(LocalStorageConfig {
+            path: LocalStorageConfig::default_path(),
+        })
+    }
+}
+
+/// What may be uploaded. Content types are matched as case insensitive globs such as `image/*`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct UploadPolicyConfig {
+    /// Only these content types may be uploaded. Everything is allowed if empty
+    #[serde(default)]
+    pub(crate) allowed_types: Vec<String>,
+    /// These content types may never be uploaded, even when allowed above
+    #[serde(default)]
+    pub(crate) denied_types: Vec<String>,
+    /// File name extensions that are refused, such as `exe`
+    #[serde(default)]
+    pub(crate) blocked_extensions: Vec<String>,
+    /// Largest size in bytes per content type glob. The smallest matching limit applies
+    #[serde(default)]
+    pub(crate) max_sizes: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct UploadConfig {
+    /// Seconds an unfinished tus upload is kept around. Defaults to a day
+    #[serde(default = "UploadConfig::default_tus_expiration")]
+    pub(crate) tus_expiration: u64,
+
+    /// Largest upload accepted through tus, in bytes. Unlimited if unset
+    #[serde(default)]
+    pub(crate) tus_max_size: Option<u64>,
+
+    /// Seconds before storage level leftovers of an interrupted upload are removed,
+    /// such as incomplete S3 multipart uploads. Defaults to a day
+    #[serde(default = "UploadConfig::default_incomplete_expiration")]
+    pub(crate) incomplete_expiration: u64,
+
+    /// Seconds presigned upload and download urls stay valid. Defaults to an hour
+    #[serde(default = "UploadConfig::default_presign_expiration")]
+    pub(crate) presign_expiration: u64,
+
+    /// Longest time in seconds uploads may be kept. Uploads that don't pick an expiry get this
+    /// one, longer ones are refused. Uploads are kept until they are deleted if unset
+    #[serde(default)]
+    pub(crate) max_expiry: Option<u64>,
+
+    /// Restrictions on what may be uploaded
+    #[serde(default)]
+    pub(crate) policy: UploadPolicyConfig,
+}
+
+impl UploadConfig {
+    fn default_tus_expiration() -> u64 {
+        60 * 60 * 24
+    }
+    fn default_incomplete_expiration() -> u64 {
+        60 * 60 * 24
+    }
+    fn default_presign_expiration() -> u64 {
+        60 * 60
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            tus_expiration: Self::default_tus_expiration(),
+            tus_max_size: None,
+            incomplete_expiration: Self::default_incomplete_expiration(),
+            presign_expiration: Self::default_presign_expiration(),
+            max_expiry: None,
+            policy: UploadPolicyConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct TrashConfig {
+    /// Seconds deleted files and folders stay in the trash before they are purged.
+    /// Defaults to 30 days
+    #[serde(default = "TrashConfig::default_retention")]
+    pub(crate) retention: u64,
+}
+
+impl TrashConfig {
+    fn default_retention() -> u64 {
+        60 * 60 * 24 * 30
+    }
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            retention: Self::default_retention(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct VersionsConfig {
+    /// Earlier versions kept per file, the oldest are dropped first. 0 keeps none.
+    /// Defaults to 10
+    #[serde(default = "VersionsConfig::default_max_versions")]
+    pub(crate) max_versions: u32,
+    /// Seconds an earlier version is kept after a newer one replaced it. Forever if unset
+    #[serde(default)]
+    pub(crate) max_age: Option<u64>,
+}
+
+impl VersionsConfig {
+    fn default_max_versions() -> u32 {
+        10
+    }
+}
+
+impl Default for VersionsConfig {
+    fn default() -> Self {
+        Self {
+            max_versions: Self::default_max_versions(),
+            max_age: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ClamdScanConfig {
+    /// `host:port` of clamd, or the path of its unix socket as `unix:/run/clamav/clamd.ctl`.
+    /// Defaults to `127.0.0.1:3310`
+    #[serde(default = "ClamdScanConfig::default_address")]
+    pub(crate) address: String,
+    /// Seconds a single scan may take. Defaults to 5 minutes
+    #[serde(default = "ClamdScanConfig::default_timeout")]
+    pub(crate) timeout: u64,
+    /// Largest chunk sent to clamd at once, in bytes. Defaults to 64KiB
+    #[serde(default = "ClamdScanConfig::default_chunk_size")]
+    pub(crate) chunk_size: usize,
+}
+
+impl ClamdScanConfig {
+    fn default_address() -> String {
+        "127.0.0.1:3310".to_string()
+    }
+    fn default_timeout() -> u64 {
+        5 * 60
+    }
+    fn default_chunk_size() -> usize {
+        64 * 1024
+    }
+}
+
+/// Malware scanner, selected with `backend = "none"` or `backend = "clamd"`.
+/// Without a scanner new uploads are considered clean right away
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub(crate) enum ScanConfig {
+    #[default]
+    None,
+    Clamd(ClamdScanConfig),
+}
+
+/// Envelope encryption of everything written to storage. Disabled while no keys are configured.
+///
+/// Keys are base64 encoded 32 byte AES keys by id, best loaded from secret files with
+/// `ENCRYPTION__KEYS__<ID>_FILE`. To rotate, add a new key, make it `current` and keep the old
+/// ones around until the `key_rotation` job re-wrapped all data keys with the new one.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct EncryptionConfig {
+    #[serde(default)]
+    pub(crate) keys: BTreeMap<String, String>,
+    /// Id of the key new data keys are wrapped with. Optional with a single key
+    #[serde(default)]
+    pub(crate) current: Option<String>,
+}
+
+/// S3 compatible API on a listener of its own, for tools like rclone, restic or the aws cli.
+/// Buckets are the folders at the root of a user's files. Only path style requests are
+/// understood, such as `http://host:3910/bucket/key`
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct S3ApiConfig {
+    /// Host and port to listen on, such as `0.0.0.0:3910`. The API is off unless set
+    #[serde(default)]
+    pub(crate) host: Option<String>,
+    /// Region reported to clients. Signatures are accepted for any region. Defaults to
+    /// `us-east-1`
+    #[serde(default = "S3ApiConfig::default_region")]
+    pub(crate) region: String,
+    /// Seconds an unfinished multipart upload is kept around. Defaults to a day
+    #[serde(default = "S3ApiConfig::default_multipart_expiration")]
+    pub(crate) multipart_expiration: u64,
+}
+
+impl S3ApiConfig {
+    fn default_region() -> String {
+        "us-east-1".to_string()
+    }
+    fn default_multipart_expiration() -> u64 {
+        60 * 60 * 24
+    }
+}
+
+impl Default for S3ApiConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            region: Self::default_region(),
+            multipart_expiration: Self::default_multipart_expiration(),
+        }
+    }
+}
+
+/// Text snippets shared by link at `/paste`
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PasteConfig {
+    /// Largest paste accepted, in bytes. Defaults to 1MiB
+    #[serde(default = "PasteConfig::default_max_size")]
+    pub(crate) max_size: u64,
+    /// Folder at the root of a user's files pastes are stored in, created when needed.
+    /// Defaults to `Pastes`
+    #[serde(default = "PasteConfig::default_folder")]
+    pub(crate) folder: String,
+}
+
+impl PasteConfig {
+    fn default_max_size() -> u64 {
+        1024 * 1024
+    }
+    fn default_folder() -> String {
+        "Pastes".to_string()
+    }
+}
+
+impl Default for PasteConfig {
+    fn default() -> Self {
+        Self {
+            max_size: Self::default_max_size(),
+            folder: Self::default_folder(),
+        }
+    }
+}
+
+/// Checks that the blob records and the objects in storage match up, see `reconcile.rs`
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ReconcileConfig {
+    /// Only report what doesn't match, change nothing. Defaults to true
+    #[serde(default = "ReconcileConfig::default_dry_run")]
+    pub(crate) dry_run: bool,
+    /// Point records whose object is missing at objects without a record that have their
+    /// contents. Objects are read and hashed for this
+    #[serde(default)]
+    pub(crate) relink: bool,
+    /// Delete objects without a record, and files and versions whose contents are missing
+    #[serde(default)]
+    pub(crate) delete: bool,
+    /// Seconds between runs. Defaults to a day
+    #[serde(default = "ReconcileConfig::default_interval")]
+    pub(crate) interval: u64,
+}
+
+impl ReconcileConfig {
+    fn default_dry_run() -> bool {
+        true
+    }
+    fn default_interval() -> u64 {
+        60 * 60 * 24
+    }
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        Self {
+            dry_run: Self::default_dry_run(),
+            relink: false,
+            delete: false,
+            interval: Self::default_interval(),
+        }
+    }
+}
+
+/// Compresses uploads that look like text before storing them, see `compress.rs`
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CompressionConfig {
+    /// Defaults to true. Contents stored compressed stay readable when turned off
+    #[serde(default = "CompressionConfig::default_enabled")]
+    pub(crate) enabled: bool,
+    /// zstd level from 1 to 22. Defaults to 3
+    #[serde(default = "CompressionConfig::default_level")]
+    pub(crate) level: i32,
+}
+
+impl CompressionConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+    fn default_level() -> i32 {
+        3
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            level: Self::default_level(),
+        }
+    }
+}
+
+/// Re-reads stored contents to find the ones damaged in storage, see `scrub.rs`
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ScrubConfig {
+    /// Defaults to true
+    #[serde(default = "ScrubConfig::default_enabled")]
+    pub(crate) enabled: bool,
+    /// Bytes read per second at most. Defaults to 8 MiB
+    #[serde(default = "ScrubConfig::default_rate")]
+    pub(crate) rate: u64,
+    /// Bytes read per run at most. Defaults to 8 GiB
+    #[serde(default = "ScrubConfig::default_max_bytes")]
+    pub(crate) max_bytes: u64,
+    /// Seconds before contents are verified again. Defaults to 30 days
+    #[serde(default = "ScrubConfig::default_reverify_after")]
+    pub(crate) reverify_after: u64,
+    /// Seconds between runs. Defaults to an hour
+    #[serde(default = "ScrubConfig::default_interval")]
+    pub(crate) interval: u64,
+}
+
+impl ScrubConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+    fn default_rate() -> u64 {
+        8 * 1024 * 1024
+    }
+    fn default_max_bytes() -> u64 {
+        8 * 1024 * 1024 * 1024
+    }
+    fn default_reverify_after() -> u64 {
+        60 * 60 * 24 * 30
+    }
+    fn default_interval() -> u64 {
+        60 * 60
+    }
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            rate: Self::default_rate(),
+            max_bytes: Self::default_max_bytes(),
+            reverify_after: Self::default_reverify_after(),
+            interval: Self::default_interval(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct AppConfig {
     /// Redis Config
@@ -67,6 +509,47 @@ pub(crate) struct AppConfig {
     pub(crate) database: DatabaseConfig,
     /// Signup config
     pub(crate) signup: SignupConfig,
+    /// Object storage config. Defaults to local storage in `data/storage`
+    #[serde(default)]
+    pub(crate) storage: StorageConfig,
+    /// Storage being migrated to with `migrate-storage`. While set, new objects are written
+    /// there and reads fall back to `storage`, until the migration makes it the only backend
+    #[serde(default)]
+    pub(crate) storage_target: Option<StorageConfig>,
+    /// Upload config
+    #[serde(default)]
+    pub(crate) upload: UploadConfig,
+    /// Trash config
+    #[serde(default)]
+    pub(crate) trash: TrashConfig,
+    /// File versioning config
+    #[serde(default)]
+    pub(crate) versions: VersionsConfig,
+    /// Malware scanning config
+    #[serde(default)]
+    pub(crate) scan: ScanConfig,
+    /// Encryption at rest config
+    #[serde(default)]
+    pub(crate) encryption: EncryptionConfig,
+    /// S3 compatible API config
+    #[serde(default)]
+    pub(crate) s3_api: S3ApiConfig,
+    /// Paste config
+    #[serde(default)]
+    pub(crate) paste: PasteConfig,
+    /// Storage reconciliation config
+    #[serde(default)]
+    pub(crate) reconcile: ReconcileConfig,
+    /// Integrity scrub config
+    #[serde(default)]
+    pub(crate) scrub: ScrubConfig,
+    /// Compression of stored contents
+    #[serde(default)]
+    pub(crate) compression: CompressionConfig,
+    /// Default storage quota per user in bytes. Unlimited if unset.
+    /// Can be overridden per user with `users.quota_bytes`
+    #[serde(default)]
+    pub(crate) quota: Option<u64>,
 
     /// Host and port to listen on. Defaults to `0.0.0.0:3000`
     #[serde(default = "AppConfig::default_app_host")]
@@ -87,7 +570,7 @@ impl AppConfig {
     }
 }
 
-pub const CONFIG: LazyCell<AppConfig> = LazyCell::new(|| {
+pub static CONFIG: LazyLock<AppConfig> = LazyLock::new(|| {
     Figment::new()
         .merge(Toml::file(Env::var_or("CONFIG_TOML_FILE", "config.toml")))
         .merge(Yaml::file(Env::var_or("CONFIG_YAML_FILE", "config.yaml")))
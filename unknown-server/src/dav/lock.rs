@@ -0,0 +1,367 @@
+//! Write locks, kept in Redis so they hold across instances and expire on their own.
+//!
+//! Every lock has a key of its own, taken with `SET NX` so two clients can't both get it. The
+//! paths of a user's locks are also kept in a set, to find the locks below a collection that's
+//! about to be moved or deleted.
+use super::DavPath;
+use crate::prelude::*;
+use fred::prelude::{Expiration, KeysInterface, SetOptions, SetsInterface};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Locks last this long unless the client asks for less.
+pub(crate) const MAX_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+fn lock_key(owner: Uuid, path: &str) -> String {
+    format!("dav:lock:{owner}:{path}")
+}
+
+fn index_key(owner: Uuid) -> String {
+    format!("dav:locks:{owner}")
+}
+
+/// An exclusive write lock on a resource.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Lock {
+    pub(crate) token: String,
+    /// [`DavPath::key`] of the locked resource
+    pub(crate) path: String,
+    /// Whether everything below a locked collection is locked too
+    pub(crate) infinite: bool,
+    pub(crate) owner: Option<String>,
+    /// Unix timestamp the lock expires at
+    pub(crate) expires: i64,
+}
+
+impl Lock {
+    pub(crate) fn new(
+        path: &DavPath,
+        infinite: bool,
+        owner: Option<String>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            token: format!("opaquelocktoken:{}", Uuid::new_v4()),
+            path: path.key(),
+            infinite,
+            owner,
+            expires: now() + timeout.as_secs() as i64,
+        }
+    }
+
+    /// Seconds until the lock expires.
+    pub(crate) fn timeout(&self) -> u64 {
+        (self.expires - now()).max(0) as u64
+    }
+
+    fn locked_path(&self) -> DavPath {
+        DavPath::from_key(&self.path)
+    }
+
+    /// Whether `path` is under this lock.
+    pub(crate) fn covers(&self, path: &DavPath) -> bool {
+        let locked = self.locked_path();
+        match self.infinite {
+            true => path.is_within(&locked),
+            false => *path == locked,
+        }
+    }
+}
+
+fn now() -> i64 {
+    time::OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// How a request changes a resource, which decides the locks it has to hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Change {
+    /// Only the contents or properties change
+    Contents,
+    /// The resource is created or removed, which also changes the collection it is in and
+    /// everything below it
+    Membership,
+}
+
+/// The locks of one user that haven't expired.
+#[derive(Debug, Default)]
+pub(crate) struct Locks {
+    locks: Vec<Lock>,
+}
+
+impl Locks {
+    pub(crate) async fn load(state: &AppState, owner: Uuid) -> Result<Self> {
+        let paths: Vec<String> = state.fred().smembers(index_key(owner)).await?;
+        if paths.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let keys: Vec<String> = paths.iter().map(|path| lock_key(owner, path)).collect();
+        let values: Vec<Option<String>> = state.fred().mget(keys).await?;
+        let mut locks = vec![];
+        let mut expired = vec![];
+        for (path, value) in paths.into_iter().zip(values) {
+            match value.and_then(|value| serde_json::from_str::<Lock>(&value).ok()) {
+                Some(lock) => locks.push(lock),
+                None => expired.push(path),
+            }
+        }
+        if !expired.is_empty() {
+            let _: () = state.fred().srem(index_key(owner), expired).await?;
+        }
+
+        Ok(Self { locks })
+    }
+
+    /// The locks `path` is under, its own and those of collections locked with everything
+    /// in them.
+    pub(crate) fn covering(&self, path: &DavPath) -> Vec<Lock> {
+        self.locks
+            .iter()
+            .filter(|lock| lock.covers(path))
+            .cloned()
+            .collect()
+    }
+
+    /// The lock with `token`, if it covers `path`.
+    pub(crate) fn find(&self, path: &DavPath, token: &str) -> Option<&Lock> {
+        self.locks
+            .iter()
+            .find(|lock| lock.token == token && lock.covers(path))
+    }
+
+    /// Whether a new lock on `path` would overlap one that's held.
+    pub(crate) fn conflicts(&self, path: &DavPath, infinite: bool) -> bool {
+        self.locks
+            .iter()
+            .any(|lock| lock.covers(path) || infinite && lock.locked_path().is_within(path))
+    }
+
+    /// Fail with `423 Locked` unless the request submitted the tokens of every lock `change`
+    /// of `path` runs into.
+    pub(crate) fn check(&self, path: &DavPath, change: Change, tokens: &[String]) -> Result<()> {
+        let parent = path.parent();
+        let conflicting = self.locks.iter().find(|lock| {
+            let affected = lock.covers(path)
+                || change == Change::Membership
+                    && (parent.as_ref().is_some_and(|parent| lock.covers(parent))
+                        || lock.locked_path().is_within(path));
+            affected && !tokens.contains(&lock.token)
+        });
+
+        match conflicting {
+            Some(lock) => {
+                debug!(path = path.key(), lock = lock.path, "resource is locked");
+                Err(StatusCode::LOCKED.into())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Take a new lock. Returns whether it was free, that a conflicting lock on a collection above
+/// or below it isn't held has to be checked before.
+pub(crate) async fn acquire(state: &AppState, owner: Uuid, lock: &Lock) -> Result<bool> {
+    let taken: Option<String> = state
+        .fred()
+        .set(
+            lock_key(owner, &lock.path),
+            serde_json::to_string(lock).expect("locks serialize"),
+            Some(Expiration::EX(lock.timeout() as i64)),
+            Some(SetOptions::NX),
+            false,
+        )
+        .await?;
+    if taken.is_none() {
+        return Ok(false);
+    }
+    let _: () = state.fred().sadd(index_key(owner), &lock.path).await?;
+    // The index outlives every lock in it, but not by long
+    let _: () = state
+        .fred()
+        .expire(index_key(owner), MAX_TIMEOUT.as_secs() as i64, None)
+        .await?;
+    Ok(true)
+}
+
+/// Extend a lock that is held by `timeout` from now.
+pub(crate) async fn refresh(
+    state: &AppState,
+    owner: Uuid,
+    lock: &Lock,
+    timeout: Duration,
+) -> Result<Lock> {
+    let mut lock = lock.clone();
+    lock.expires = now() + timeout.as_secs() as i64;
+    let _: Option<String> = state
+        .fred()
+        .set(
+            lock_key(owner, &lock.path),
+            serde_json::to_string(&lock).expect("locks serialize"),
+            Some(Expiration::EX(timeout.as_secs() as i64)),
+            Some(SetOptions::XX),
+            false,
+        )
+        .await?;
+    let _: () = state
+        .fred()
+        .expire(index_key(owner), MAX_TIMEOUT.as_secs() as i64, None)
+        .await?;
+    Ok(lock)
+}
+
+pub(crate) async fn release(state: &AppState, owner: Uuid, lock: &Lock) -> Result<()> {
+    let _: () = state.fred().del(lock_key(owner, &lock.path)).await?;
+    let _: () = state.fred().srem(index_key(owner), &lock.path).await?;
+    Ok(())
+}
+
+/// Release the locks on `path` and below after it was moved or deleted. Failures are only
+/// logged, the locks expire on their own.
+pub(crate) async fn release_below(state: &AppState, owner: Uuid, locks: &Locks, path: &DavPath) {
+    for lock in &locks.locks {
+        if lock.locked_path().is_within(path)
+            && let Err(err) = release(state, owner, lock).await
+        {
+            warn!(%err, path = lock.path, "failed to release webdav lock");
+        }
+    }
+}
+
+/// The lock tokens in an `If` header. The conditions themselves aren't evaluated, holding the
+/// token is what counts.
+pub(crate) fn submitted_tokens(header: Option<&str>) -> Vec<String> {
+    let Some(header) = header else {
+        return vec![];
+    };
+    header
+        .split('<')
+        .skip(1)
+        .filter_map(|part| part.split_once('>'))
+        .map(|(token, _)| token.to_string())
+        .filter(|token| token.starts_with("opaquelocktoken:"))
+        .collect()
+}
+
+/// The timeout a client asks for in a `Timeout` header, like `Second-600` or `Infinite`,
+/// capped at [`MAX_TIMEOUT`]. Redis can't expire keys after no time at all, so that's a second.
+pub(crate) fn requested_timeout(header: Option<&str>) -> Duration {
+    let Some(header) = header else {
+        return DEFAULT_TIMEOUT;
+    };
+    header
+        .split(',')
+        .map(str::trim)
+        .find_map(|timeout| match timeout {
+            "Infinite" => Some(MAX_TIMEOUT),
+            timeout => timeout
+                .strip_prefix("Second-")?
+                .parse()
+                .ok()
+                .map(Duration::from_secs),
+        })
+        .unwrap_or(DEFAULT_TIMEOUT)
+        .clamp(Duration::from_secs(1), MAX_TIMEOUT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &str) -> DavPath {
+        DavPath::parse(path).unwrap()
+    }
+
+    fn locks(locks: &[(&str, bool)]) -> Locks {
+        Locks {
+            locks: locks
+                .iter()
+                .map(|(locked, infinite)| Lock::new(&path(locked), *infinite, None, MAX_TIMEOUT))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn checks_locks() {
+        let locks = locks(&[
+            ("/docs", true),
+            ("/notes/todo.txt", false),
+            ("/music", false),
+        ]);
+        let docs = locks.locks[0].token.clone();
+
+        // Below a collection locked with everything in it
+        let report = path("/docs/2026/report.pdf");
+        assert!(locks.check(&report, Change::Contents, &[]).is_err());
+        assert!(locks.check(&report, Change::Contents, &[docs]).is_ok());
+
+        // A resource locked on its own
+        let todo = path("/notes/todo.txt");
+        assert!(locks.check(&todo, Change::Contents, &[]).is_err());
+        assert!(
+            locks
+                .check(&path("/notes/other.txt"), Change::Membership, &[])
+                .is_ok()
+        );
+
+        // Adding to a collection locked without its members needs its lock
+        let song = path("/music/song.mp3");
+        assert!(locks.check(&song, Change::Contents, &[]).is_ok());
+        assert!(locks.check(&song, Change::Membership, &[]).is_err());
+
+        // Removing a collection needs the locks below it
+        assert!(locks.check(&path("/notes"), Change::Contents, &[]).is_ok());
+        assert!(
+            locks
+                .check(&path("/notes"), Change::Membership, &[])
+                .is_err()
+        );
+        assert!(
+            locks
+                .check(&DavPath::default(), Change::Membership, &[])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn finds_conflicting_locks() {
+        let locks = locks(&[("/docs", true), ("/music", false)]);
+        assert!(locks.conflicts(&path("/docs/2026"), false));
+        assert!(!locks.conflicts(&path("/music/song.mp3"), true));
+        assert!(locks.conflicts(&DavPath::default(), true));
+        assert!(!locks.conflicts(&DavPath::default(), false));
+    }
+
+    #[test]
+    fn parses_if_headers() {
+        assert!(submitted_tokens(None).is_empty());
+        assert_eq!(
+            submitted_tokens(Some(
+                "</dav/a> (<opaquelocktoken:1234> [\"etag\"]) (Not <DAV:no-lock>)"
+            )),
+            vec!["opaquelocktoken:1234"]
+        );
+        assert_eq!(
+            submitted_tokens(Some("(<opaquelocktoken:a>) (<opaquelocktoken:b>)")),
+            vec!["opaquelocktoken:a", "opaquelocktoken:b"]
+        );
+    }
+
+    #[test]
+    fn parses_timeouts() {
+        assert_eq!(requested_timeout(None), DEFAULT_TIMEOUT);
+        assert_eq!(
+            requested_timeout(Some("Second-60")),
+            Duration::from_secs(60)
+        );
+        assert_eq!(requested_timeout(Some("Infinite, Second-60")), MAX_TIMEOUT);
+        assert_eq!(requested_timeout(Some("Second-999999")), MAX_TIMEOUT);
+        assert_eq!(
+            requested_timeout(Some("Minute-5, Second-30")),
+            Duration::from_secs(30)
+        );
+        assert_eq!(requested_timeout(Some("garbage")), DEFAULT_TIMEOUT);
+        assert_eq!(requested_timeout(Some("Second-0")), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,236 @@
+//! WebDAV view of every user's files and folders, so they can be mounted in file managers and
+//! sync tools.
+//!
+//! Paths below [`PREFIX`] map onto the folder tree of the signed in user, `/dav/docs/a.pdf` is
+//! the file `a.pdf` in their folder `docs`. Deleting moves things to the trash like the rest of
+//! the API does, and uploading to the path of an existing file makes a new version of it.
+pub(crate) mod lock;
+pub(crate) mod xml;
+
+pub(crate) use lock::{Change, Lock, Locks};
+pub(crate) use xml::{LockInfo, Multistatus, Properties, Propfind};
+
+use crate::models::file::File;
+use crate::models::folder::Folder;
+use crate::prelude::*;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use uuid::Uuid;
+
+/// Where the WebDAV router is nested.
+pub(crate) const PREFIX: &str = "/dav";
+
+/// Everything but the unreserved characters of RFC 3986 is encoded in hrefs.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A path below [`PREFIX`] as its decoded segments. The root has none.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct DavPath(Vec<String>);
+
+impl DavPath {
+    /// Parse the percent-encoded path of a request, relative to [`PREFIX`]. `None` if it isn't
+    /// utf-8 or names something that can't exist, like `..`.
+    pub(crate) fn parse(path: &str) -> Option<Self> {
+        let segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| {
+                let segment = percent_decode_str(segment).decode_utf8().ok()?;
+                crate::files::valid_name(&segment).then(|| segment.into_owned())
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self(segments))
+    }
+
+    /// Parse a `Destination` header, an absolute url or path that has to be below
+    /// [`PREFIX`]. The host isn't checked here.
+    pub(crate) fn from_destination(destination: &str) -> Option<Self> {
+        let uri: axum::http::Uri = destination.parse().ok()?;
+        let path = uri.path().strip_prefix(PREFIX)?;
+        if !path.is_empty() && !path.starts_with('/') {
+            return None;
+        }
+        Self::parse(path)
+    }
+
+    /// The path a lock was taken on, see [`Self::key`].
+    pub(crate) fn from_key(key: &str) -> Self {
+        Self(
+            key.split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+
+    pub(crate) fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The last segment, `None` for the root.
+    pub(crate) fn name(&self) -> Option<&str> {
+        self.0.last().map(String::as_str)
+    }
+
+    pub(crate) fn segments(&self) -> &[String] {
+        &self.0
+    }
+
+    /// The path of the collection this is in, `None` for the root.
+    pub(crate) fn parent(&self) -> Option<Self> {
+        let (_, parent) = self.0.split_last()?;
+        Some(Self(parent.to_vec()))
+    }
+
+    pub(crate) fn join(&self, name: &str) -> Self {
+        let mut segments = self.0.clone();
+        segments.push(name.to_string());
+        Self(segments)
+    }
+
+    /// Whether this is `other` or somewhere below it.
+    pub(crate) fn is_within(&self, other: &Self) -> bool {
+        self.0.starts_with(&other.0)
+    }
+
+    /// The decoded segments joined with `/`, unique per path.
+    pub(crate) fn key(&self) -> String {
+        self.0.join("/")
+    }
+
+    /// The percent-encoded url path, ending in `/` for collections.
+    pub(crate) fn href(&self, collection: bool) -> String {
+        let mut href = PREFIX.to_string();
+        for segment in &self.0 {
+            href.push('/');
+            href.extend(utf8_percent_encode(segment, SEGMENT));
+        }
+        if collection || self.is_root() {
+            href.push('/');
+        }
+        href
+    }
+}
+
+/// What a path points at.
+#[derive(Debug, Clone)]
+pub(crate) enum Resource {
+    /// The top of the user's tree, which isn't a folder of its own
+    Root,
+    Folder(Folder),
+    File(File),
+}
+
+impl Resource {
+    pub(crate) fn is_collection(&self) -> bool {
+        !matches!(self, Resource::File(_))
+    }
+
+    /// The folder id files in this collection have, `None` for files.
+    pub(crate) fn as_parent(&self) -> Option<Option<Uuid>> {
+        match self {
+            Resource::Root => Some(None),
+            Resource::Folder(folder) => Some(Some(folder.id)),
+            Resource::File(_) => None,
+        }
+    }
+}
+
+/// Find what `path` points at in the tree of `owner`.
+pub(crate) async fn resolve(
+    state: &AppState,
+    owner: Uuid,
+    path: &DavPath,
+) -> Result<Option<Resource>> {
+    let Some((last, parents)) = path.segments().split_last() else {
+        return Ok(Some(Resource::Root));
+    };
+    let Some(parent_id) = Folder::walk(state.db(), owner, parents).await? else {
+        return Ok(None);
+    };
+
+    if let Some(folder) = Folder::find_child(state.db(), owner, parent_id, last).await? {
+        return Ok(Some(Resource::Folder(folder)));
+    }
+    let file = File::find_in_folder(state.db(), owner, parent_id, last).await?;
+    Ok(file.map(Resource::File))
+}
+
+/// The folder `path` is or would be in, `Some(None)` for the root. `None` if that folder
+/// doesn't exist, or if `path` is the root itself.
+pub(crate) async fn resolve_parent(
+    state: &AppState,
+    owner: Uuid,
+    path: &DavPath,
+) -> Result<Option<Option<Uuid>>> {
+    let Some(parent) = path.parent() else {
+        return Ok(None);
+    };
+    Ok(Folder::walk(state.db(), owner, parent.segments()).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_paths() {
+        assert_eq!(DavPath::parse("/"), Some(DavPath::default()));
+        assert_eq!(DavPath::parse(""), Some(DavPath::default()));
+        assert_eq!(
+            DavPath::parse("/docs/2026/"),
+            Some(DavPath(vec!["docs".into(), "2026".into()]))
+        );
+        assert_eq!(
+            DavPath::parse("/gr%C3%BC%C3%9Fe/a%20b.txt"),
+            Some(DavPath(vec!["grüße".into(), "a b.txt".into()]))
+        );
+        assert_eq!(DavPath::parse("/docs/../secret"), None);
+        assert_eq!(DavPath::parse("/a%2Fb"), None);
+        assert_eq!(DavPath::parse("/%FF"), None);
+    }
+
+    #[test]
+    fn parses_destinations() {
+        assert_eq!(
+            DavPath::from_destination("https://files.example.com/dav/docs/a%20b.txt"),
+            Some(DavPath(vec!["docs".into(), "a b.txt".into()]))
+        );
+        assert_eq!(
+            DavPath::from_destination("/dav/docs/"),
+            Some(DavPath(vec!["docs".into()]))
+        );
+        assert_eq!(
+            DavPath::from_destination("http://host/dav"),
+            Some(DavPath::default())
+        );
+        assert_eq!(DavPath::from_destination("http://host/files/a"), None);
+        assert_eq!(DavPath::from_destination("http://host/davx/a"), None);
+    }
+
+    #[test]
+    fn builds_hrefs() {
+        let path = DavPath(vec!["grüße".into(), "a b&c.txt".into()]);
+        assert_eq!(path.href(false), "/dav/gr%C3%BC%C3%9Fe/a%20b%26c.txt");
+        assert_eq!(path.parent().unwrap().href(true), "/dav/gr%C3%BC%C3%9Fe/");
+        assert_eq!(DavPath::default().href(false), "/dav/");
+        assert_eq!(
+            DavPath::parse(&path.href(false)[PREFIX.len()..]),
+            Some(path)
+        );
+    }
+
+    #[test]
+    fn relates_paths() {
+        let docs = DavPath::parse("/docs").unwrap();
+        let nested = docs.join("2026");
+        assert!(nested.is_within(&docs));
+        assert!(docs.is_within(&docs));
+        assert!(!docs.is_within(&nested));
+        assert!(!DavPath::parse("/docs2").unwrap().is_within(&docs));
+        assert_eq!(DavPath::from_key(&nested.key()), nested);
+    }
+}
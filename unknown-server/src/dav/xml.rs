@@ -0,0 +1,574 @@
+//! The XML bodies of WebDAV requests and responses.
+use super::{DavPath, Lock};
+use quick_xml::NsReader;
+use quick_xml::escape::{escape, resolve_predefined_entity};
+use quick_xml::events::Event;
+use quick_xml::name::{Namespace, ResolveResult};
+use std::fmt::Write;
+use std::time::SystemTime;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+pub(crate) const DAV: &str = "DAV:";
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid request body")]
+pub(crate) struct InvalidBody;
+
+/// The name of a property, qualified by its namespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PropName {
+    pub(crate) namespace: String,
+    pub(crate) name: String,
+}
+
+impl PropName {
+    fn dav(name: &str) -> Self {
+        Self {
+            namespace: DAV.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    /// The element, with `value` as its content.
+    fn write(&self, out: &mut String, value: &str) {
+        let name = escape(self.name.as_str());
+        let _ = match self.namespace.as_str() {
+            DAV if value.is_empty() => write!(out, "<D:{name}/>"),
+            DAV => write!(out, "<D:{name}>{value}</D:{name}>"),
+            "" if value.is_empty() => write!(out, "<{name} xmlns=\"\"/>"),
+            "" => write!(out, "<{name} xmlns=\"\">{value}</{name}>"),
+            namespace if value.is_empty() => {
+                write!(out, "<x:{name} xmlns:x=\"{}\"/>", escape(namespace))
+            }
+            namespace => write!(
+                out,
+                "<x:{name} xmlns:x=\"{}\">{value}</x:{name}>",
+                escape(namespace)
+            ),
+        };
+    }
+}
+
+/// What a `PROPFIND` asks for.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Propfind {
+    /// Every property there is, except for those that are expensive to compute
+    AllProp,
+    /// Only the names of the properties
+    PropName,
+    Prop(Vec<PropName>),
+}
+
+impl Propfind {
+    /// Parse the body of a `PROPFIND`. An empty body asks for all properties.
+    pub(crate) fn parse(body: &str) -> Result<Self, InvalidBody> {
+        if body.trim().is_empty() {
+            return Ok(Self::AllProp);
+        }
+
+        let mut request = None;
+        let mut props = vec![];
+        let mut parent = String::new();
+        let mut nodes = Nodes::new(body);
+        while let Some(node) = nodes.next()? {
+            let Node::Element { depth, name } = node else {
+                continue;
+            };
+            let dav = name.namespace == DAV;
+            match (depth, name.name.as_str()) {
+                (0, "propfind") if dav => {}
+                (0, _) => return Err(InvalidBody),
+                (1, element) => {
+                    parent = if dav {
+                        element.to_string()
+                    } else {
+                        String::new()
+                    };
+                    match parent.as_str() {
+                        "allprop" => request = Some(Self::AllProp),
+                        "propname" => request = Some(Self::PropName),
+                        "prop" => request = Some(Self::Prop(vec![])),
+                        // `include` and extensions we don't know
+                        _ => {}
+                    }
+                }
+                (2, _) if parent == "prop" => props.push(name),
+                _ => {}
+            }
+        }
+
+        match request.ok_or(InvalidBody)? {
+            Self::Prop(_) => Ok(Self::Prop(props)),
+            request => Ok(request),
+        }
+    }
+}
+
+/// What a `LOCK` asks for.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct LockInfo {
+    /// Shared locks are only told apart to refuse them
+    pub(crate) exclusive: bool,
+    /// Who the client says holds the lock, usually an url
+    pub(crate) owner: Option<String>,
+}
+
+impl LockInfo {
+    /// Parse the body of a `LOCK`. An empty body refreshes an existing lock, that's `None`.
+    pub(crate) fn parse(body: &str) -> Result<Option<Self>, InvalidBody> {
+        if body.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let mut exclusive = None;
+        let mut write = false;
+        // Only the text of the owner is kept, whatever elements it is wrapped in
+        let mut owner: Option<String> = None;
+        let mut parent = String::new();
+        let mut nodes = Nodes::new(body);
+        while let Some(node) = nodes.next()? {
+            let (depth, name) = match node {
+                Node::Text { depth, text } if depth >= 2 && parent == "owner" => {
+                    owner.get_or_insert_default().push_str(&text);
+                    continue;
+                }
+                Node::Text { .. } => continue,
+                Node::Element { depth, name } => (depth, name),
+            };
+            let dav = name.namespace == DAV;
+            match (depth, name.name.as_str()) {
+                (0, "lockinfo") if dav => {}
+                (0, _) => return Err(InvalidBody),
+                (1, element) => {
+                    parent = if dav {
+                        element.to_string()
+                    } else {
+                        String::new()
+                    };
+                }
+                (2, "exclusive") if dav && parent == "lockscope" => exclusive = Some(true),
+                (2, "shared") if dav && parent == "lockscope" => exclusive = Some(false),
+                (2, "write") if dav && parent == "locktype" => write = true,
+                _ => {}
+            }
+        }
+
+        // Write locks are the only kind there is
+        let (Some(exclusive), true) = (exclusive, write) else {
+            return Err(InvalidBody);
+        };
+        let owner = owner
+            .map(|owner| owner.trim().to_string())
+            .filter(|owner| !owner.is_empty());
+        Ok(Some(Self { exclusive, owner }))
+    }
+}
+
+/// A piece of a request body, with how deep it is nested.
+enum Node {
+    Element { depth: usize, name: PropName },
+    Text { depth: usize, text: String },
+}
+
+/// Walks the elements and text of a document in order.
+struct Nodes<'a> {
+    reader: NsReader<&'a [u8]>,
+    depth: usize,
+}
+
+impl<'a> Nodes<'a> {
+    fn new(body: &'a str) -> Self {
+        // Text isn't trimmed, references split it up and the spaces around them matter
+        let reader = NsReader::from_reader(body.as_bytes());
+        Self { reader, depth: 0 }
+    }
+
+    fn next(&mut self) -> Result<Option<Node>, InvalidBody> {
+        loop {
+            let (namespace, event) = self.reader.read_resolved_event().map_err(|_| InvalidBody)?;
+            let namespace = match namespace {
+                ResolveResult::Bound(Namespace(namespace)) => {
+                    String::from_utf8(namespace.to_vec()).map_err(|_| InvalidBody)?
+                }
+                ResolveResult::Unbound => String::new(),
+                ResolveResult::Unknown(_) => return Err(InvalidBody),
+            };
+            let (start, empty) = match event {
+                Event::Start(start) => (start, false),
+                Event::Empty(start) => (start, true),
+                Event::End(_) => {
+                    self.depth = self.depth.checked_sub(1).ok_or(InvalidBody)?;
+                    continue;
+                }
+                Event::Text(text) => {
+                    let text = text.xml_content().map_err(|_| InvalidBody)?;
+                    let depth = self.depth;
+                    return Ok(Some(Node::Text {
+                        depth,
+                        text: text.into_owned(),
+                    }));
+                }
+                Event::GeneralRef(reference) => {
+                    let text = match reference.resolve_char_ref().map_err(|_| InvalidBody)? {
+                        Some(char) => char.to_string(),
+                        None => {
+                            let name = reference.decode().map_err(|_| InvalidBody)?;
+                            resolve_predefined_entity(&name)
+                                .ok_or(InvalidBody)?
+                                .to_string()
+                        }
+                    };
+                    let depth = self.depth;
+                    return Ok(Some(Node::Text { depth, text }));
+                }
+                Event::CData(text) => {
+                    let text = text.decode().map_err(|_| InvalidBody)?;
+                    let depth = self.depth;
+                    return Ok(Some(Node::Text {
+                        depth,
+                        text: text.into_owned(),
+                    }));
+                }
+                Event::Eof if self.depth == 0 => return Ok(None),
+                Event::Eof => return Err(InvalidBody),
+                _ => continue,
+            };
+
+            let name = std::str::from_utf8(start.local_name().into_inner())
+                .map_err(|_| InvalidBody)?
+                .to_string();
+            let depth = self.depth;
+            if !empty {
+                self.depth += 1;
+            }
+            return Ok(Some(Node::Element {
+                depth,
+                name: PropName { namespace, name },
+            }));
+        }
+    }
+}
+
+/// What `PROPFIND` reports about a file or collection.
+#[derive(Debug, Clone)]
+pub(crate) struct Properties {
+    pub(crate) path: DavPath,
+    pub(crate) collection: bool,
+    pub(crate) size: Option<u64>,
+    pub(crate) content_type: Option<String>,
+    pub(crate) etag: Option<String>,
+    pub(crate) created: Option<OffsetDateTime>,
+    pub(crate) modified: Option<OffsetDateTime>,
+    /// Bytes used and available to the owner, for collections
+    pub(crate) quota: Option<(u64, Option<u64>)>,
+    /// Locks the resource is under
+    pub(crate) locks: Vec<Lock>,
+}
+
+impl Properties {
+    /// The properties this resource has. Quotas are left out of `allprop`, RFC 4331 asks for
+    /// that since they can be expensive to compute.
+    fn names(&self, all: bool) -> Vec<PropName> {
+        let mut names = vec![
+            PropName::dav("displayname"),
+            PropName::dav("resourcetype"),
+            PropName::dav("supportedlock"),
+            PropName::dav("lockdiscovery"),
+        ];
+        let optional = [
+            ("getcontentlength", self.size.is_some()),
+            ("getcontenttype", self.content_type.is_some()),
+            ("getetag", self.etag.is_some()),
+            ("creationdate", self.created.is_some()),
+            ("getlastmodified", self.modified.is_some()),
+            ("quota-used-bytes", !all && self.quota.is_some()),
+            (
+                "quota-available-bytes",
+                !all && matches!(self.quota, Some((_, Some(_)))),
+            ),
+        ];
+        names.extend(
+            optional
+                .into_iter()
+                .filter(|(_, present)| *present)
+                .map(|(name, _)| PropName::dav(name)),
+        );
+        names
+    }
+
+    /// The content of the property element, `None` if the resource doesn't have it.
+    fn value(&self, name: &PropName) -> Option<String> {
+        if name.namespace != DAV {
+            return None;
+        }
+        let value = match name.name.as_str() {
+            "displayname" => escape(self.path.name().unwrap_or_default()).into_owned(),
+            "resourcetype" if self.collection => "<D:collection/>".to_string(),
+            "resourcetype" => String::new(),
+            "supportedlock" => "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope>\
+                <D:locktype><D:write/></D:locktype></D:lockentry>"
+                .to_string(),
+            "lockdiscovery" => self.locks.iter().map(active_lock).collect(),
+            "getcontentlength" => self.size?.to_string(),
+            "getcontenttype" => escape(self.content_type.as_deref()?).into_owned(),
+            "getetag" => escape(self.etag.as_deref()?).into_owned(),
+            "creationdate" => self.created?.format(&Rfc3339).ok()?,
+            "getlastmodified" => httpdate::fmt_http_date(SystemTime::from(self.modified?)),
+            "quota-used-bytes" => self.quota?.0.to_string(),
+            "quota-available-bytes" => self.quota?.1?.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// A `207 Multi-Status` body.
+pub(crate) struct Multistatus {
+    body: String,
+}
+
+impl Default for Multistatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Multistatus {
+    pub(crate) fn new() -> Self {
+        Self {
+            body: "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                <D:multistatus xmlns:D=\"DAV:\">"
+                .to_string(),
+        }
+    }
+
+    /// Report the properties `request` asks for of a resource.
+    pub(crate) fn properties(&mut self, properties: &Properties, request: &Propfind) {
+        let mut found = String::new();
+        let mut missing = String::new();
+        match request {
+            Propfind::PropName => {
+                for name in properties.names(false) {
+                    name.write(&mut found, "");
+                }
+            }
+            Propfind::AllProp => {
+                for name in properties.names(true) {
+                    let value = properties.value(&name).unwrap_or_default();
+                    name.write(&mut found, &value);
+                }
+            }
+            Propfind::Prop(names) => {
+                for name in names {
+                    match properties.value(name) {
+                        Some(value) => name.write(&mut found, &value),
+                        None => name.write(&mut missing, ""),
+                    }
+                }
+            }
+        }
+
+        let collection = properties.collection;
+        let _ = write!(
+            self.body,
+            "<D:response><D:href>{}</D:href>",
+            properties.path.href(collection)
+        );
+        for (props, status) in [(found, "200 OK"), (missing, "404 Not Found")] {
+            if !props.is_empty() {
+                let _ = write!(
+                    self.body,
+                    "<D:propstat><D:prop>{props}</D:prop>\
+                    <D:status>HTTP/1.1 {status}</D:status></D:propstat>"
+                );
+            }
+        }
+        self.body.push_str("</D:response>");
+    }
+
+    pub(crate) fn finish(mut self) -> String {
+        self.body.push_str("</D:multistatus>");
+        self.body
+    }
+}
+
+fn active_lock(lock: &Lock) -> String {
+    let depth = if lock.infinite { "infinity" } else { "0" };
+    let owner = match &lock.owner {
+        Some(owner) => format!("<D:owner>{}</D:owner>", escape(owner.as_str())),
+        None => String::new(),
+    };
+    format!(
+        "<D:activelock><D:locktype><D:write/></D:locktype>\
+        <D:lockscope><D:exclusive/></D:lockscope><D:depth>{depth}</D:depth>{owner}\
+        <D:timeout>Second-{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken>\
+        <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+        lock.timeout(),
+        escape(lock.token.as_str()),
+        DavPath::from_key(&lock.path).href(false),
+    )
+}
+
+/// The body of a successful `LOCK`.
+pub(crate) fn lock_discovery(lock: &Lock) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>",
+        active_lock(lock)
+    )
+}
+
+/// An error body naming the precondition that failed, like `no-conflicting-lock`.
+pub(crate) fn precondition(name: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <D:error xmlns:D=\"DAV:\"><D:{name}/></D:error>"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prop(namespace: &str, name: &str) -> PropName {
+        PropName {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_propfind() {
+        assert_eq!(Propfind::parse("").unwrap(), Propfind::AllProp);
+        assert_eq!(
+            Propfind::parse(r#"<?xml version="1.0"?><propfind xmlns="DAV:"><allprop/></propfind>"#)
+                .unwrap(),
+            Propfind::AllProp
+        );
+        assert_eq!(
+            Propfind::parse(r#"<D:propfind xmlns:D="DAV:"><D:propname/></D:propfind>"#).unwrap(),
+            Propfind::PropName
+        );
+        assert_eq!(
+            Propfind::parse(
+                r#"<D:propfind xmlns:D="DAV:" xmlns:Z="urn:example">
+                    <D:prop><D:getcontentlength/><Z:color/><D:resourcetype></D:resourcetype></D:prop>
+                </D:propfind>"#
+            )
+            .unwrap(),
+            Propfind::Prop(vec![
+                prop(DAV, "getcontentlength"),
+                prop("urn:example", "color"),
+                prop(DAV, "resourcetype"),
+            ])
+        );
+    }
+
+    #[test]
+    fn refuses_bad_propfind() {
+        assert!(Propfind::parse("<propfind><allprop/></propfind>").is_err());
+        assert!(Propfind::parse(r#"<D:propfind xmlns:D="DAV:"></D:propfind>"#).is_err());
+        assert!(Propfind::parse(r#"<D:propfind xmlns:D="DAV:"><D:prop>"#).is_err());
+        assert!(Propfind::parse(r#"<X:propfind><X:allprop/></X:propfind>"#).is_err());
+    }
+
+    #[test]
+    fn parses_lockinfo() {
+        assert_eq!(LockInfo::parse("  ").unwrap(), None);
+        assert_eq!(
+            LockInfo::parse(
+                r#"<?xml version="1.0" encoding="utf-8" ?>
+                <D:lockinfo xmlns:D='DAV:'>
+                    <D:lockscope><D:exclusive/></D:lockscope>
+                    <D:locktype><D:write/></D:locktype>
+                    <D:owner><D:href>mailto:a&amp;b@example.com</D:href></D:owner>
+                </D:lockinfo>"#
+            )
+            .unwrap(),
+            Some(LockInfo {
+                exclusive: true,
+                owner: Some("mailto:a&b@example.com".to_string()),
+            })
+        );
+        assert_eq!(
+            LockInfo::parse(
+                r#"<lockinfo xmlns="DAV:"><lockscope><shared/></lockscope>
+                <locktype><write/></locktype></lockinfo>"#
+            )
+            .unwrap(),
+            Some(LockInfo {
+                exclusive: false,
+                owner: None,
+            })
+        );
+        // Write locks are the only kind there is
+        assert!(
+            LockInfo::parse(
+                r#"<lockinfo xmlns="DAV:"><lockscope><exclusive/></lockscope></lockinfo>"#
+            )
+            .is_err()
+        );
+    }
+
+    fn file() -> Properties {
+        Properties {
+            path: DavPath::parse("/docs/a&b.txt").unwrap(),
+            collection: false,
+            size: Some(12),
+            content_type: Some("text/plain".to_string()),
+            etag: Some("\"abc\"".to_string()),
+            created: Some(OffsetDateTime::UNIX_EPOCH),
+            modified: Some(OffsetDateTime::UNIX_EPOCH),
+            quota: None,
+            locks: vec![],
+        }
+    }
+
+    #[test]
+    fn writes_properties() {
+        let mut multistatus = Multistatus::new();
+        multistatus.properties(
+            &file(),
+            &Propfind::Prop(vec![
+                prop(DAV, "displayname"),
+                prop(DAV, "getlastmodified"),
+                prop(DAV, "quota-used-bytes"),
+                prop("urn:example", "color"),
+            ]),
+        );
+        let body = multistatus.finish();
+
+        assert!(
+            body.contains("<D:href>/dav/docs/a%26b.txt</D:href>"),
+            "{body}"
+        );
+        assert!(body.contains("<D:displayname>a&amp;b.txt</D:displayname>"));
+        assert!(
+            body.contains("<D:getlastmodified>Thu, 01 Jan 1970 00:00:00 GMT</D:getlastmodified>")
+        );
+        assert!(body.contains(
+            "<D:prop><D:quota-used-bytes/><x:color xmlns:x=\"urn:example\"/></D:prop>\
+            <D:status>HTTP/1.1 404 Not Found</D:status>"
+        ));
+    }
+
+    #[test]
+    fn leaves_quota_out_of_allprop() {
+        let mut collection = file();
+        collection.collection = true;
+        collection.quota = Some((10, Some(90)));
+
+        let mut multistatus = Multistatus::new();
+        multistatus.properties(&collection, &Propfind::AllProp);
+        let body = multistatus.finish();
+        assert!(body.contains("<D:resourcetype><D:collection/></D:resourcetype>"));
+        assert!(!body.contains("quota"));
+
+        let mut multistatus = Multistatus::new();
+        multistatus.properties(&collection, &Propfind::PropName);
+        let body = multistatus.finish();
+        assert!(body.contains("<D:quota-available-bytes/>"));
+        assert!(!body.contains("404"));
+    }
+}
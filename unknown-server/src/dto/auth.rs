@@ -5,6 +5,21 @@ use std::fmt::{Debug, Formatter};
 pub struct UserInfoDto {
     pub id: uuid::Uuid,
     pub username: String,
+    /// Bytes used by the user's files
+    pub usage: u64,
+    /// Storage quota in bytes, `null` when unlimited
+    pub quota: Option<u64>,
+}
+
+impl UserInfoDto {
+    pub fn new(user: crate::models::user::User, usage: u64) -> Self {
+        Self {
+            id: user.id,
+            quota: user.quota(),
+            username: user.username,
+            usage,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -46,4 +61,93 @@ impl Debug for UserSignupDto {
     }
 }
 
-crate::make_mod!(prelude UserInfoDto, UserLoginDto, UserSignupDto);
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiTokenDto {
+    pub id: uuid::Uuid,
+    pub name: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: time::OffsetDateTime,
+    /// `null` if it was never used
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used: Option<time::OffsetDateTime>,
+}
+
+impl From<crate::models::api_token::ApiToken> for ApiTokenDto {
+    fn from(value: crate::models::api_token::ApiToken) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            created: value.created,
+            last_used: value.last_used,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiTokenCreateDto {
+    pub name: String,
+}
+
+/// A new API token. The token itself is only ever shown here.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApiTokenCreatedDto {
+    #[serde(flatten)]
+    pub info: ApiTokenDto,
+    pub token: String,
+}
+
+impl Debug for ApiTokenCreatedDto {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiTokenCreatedDto")
+            .field("info", &self.info)
+            .field("token", &"[protected]")
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessKeyDto {
+    /// The access key id
+    pub id: String,
+    pub name: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: time::OffsetDateTime,
+    /// `null` if it was never used
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used: Option<time::OffsetDateTime>,
+}
+
+impl From<crate::models::access_key::AccessKey> for AccessKeyDto {
+    fn from(value: crate::models::access_key::AccessKey) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            created: value.created,
+            last_used: value.last_used,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessKeyCreateDto {
+    pub name: String,
+}
+
+/// A new S3 access key. The secret is only ever shown here.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AccessKeyCreatedDto {
+    #[serde(flatten)]
+    pub info: AccessKeyDto,
+    pub secret_access_key: String,
+}
+
+impl Debug for AccessKeyCreatedDto {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessKeyCreatedDto")
+            .field("info", &self.info)
+            .field("secret_access_key", &"[protected]")
+            .finish()
+    }
+}
+
+crate::make_mod!(prelude UserInfoDto, UserLoginDto, UserSignupDto, ApiTokenDto, ApiTokenCreateDto, ApiTokenCreatedDto, AccessKeyDto, AccessKeyCreateDto, AccessKeyCreatedDto);
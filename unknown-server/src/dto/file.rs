@@ -0,0 +1,279 @@
+use crate::dto::shared::double_option;
+use crate::models::file::FileStatus;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileInfoDto {
+    pub id: uuid::Uuid,
+    /// `null` for files at the root
+    pub folder_id: Option<uuid::Uuid>,
+    /// Original filename
+    pub name: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Content type sent by the client
+    pub content_type: String,
+    /// Content type sniffed from the contents, `null` if it wasn't recognized
+    pub detected_type: Option<String>,
+    /// Hex encoded sha256
+    pub checksum: String,
+    /// Starts at 1 and counts up with every upload to the same path
+    pub version: i32,
+    /// Only `clean` files can be downloaded
+    pub status: FileStatus,
+    /// When the file is deleted, `null` for files that are kept
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub expires: Option<time::OffsetDateTime>,
+}
+
+impl From<crate::models::file::File> for FileInfoDto {
+    fn from(value: crate::models::file::File) -> Self {
+        Self {
+            id: value.id,
+            folder_id: value.folder_id,
+            name: value.name,
+            size: value.size as u64,
+            content_type: value.mime_type,
+            detected_type: value.detected_type,
+            checksum: value.sha256,
+            version: value.version,
+            status: value.status,
+            expires: value.expires,
+        }
+    }
+}
+
+/// How long an upload is kept, picked by the uploader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileExpiry {
+    #[serde(rename = "1h")]
+    Hour,
+    #[serde(rename = "1d")]
+    Day,
+    #[serde(rename = "7d")]
+    Week,
+    #[serde(rename = "30d")]
+    Month,
+    #[serde(rename = "never")]
+    Never,
+}
+
+impl FileExpiry {
+    /// `None` for files that are kept until they are deleted.
+    pub fn duration(self) -> Option<time::Duration> {
+        match self {
+            Self::Hour => Some(time::Duration::hours(1)),
+            Self::Day => Some(time::Duration::days(1)),
+            Self::Week => Some(time::Duration::days(7)),
+            Self::Month => Some(time::Duration::days(30)),
+            Self::Never => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Hour => "1h",
+            Self::Day => "1d",
+            Self::Week => "7d",
+            Self::Month => "30d",
+            Self::Never => "never",
+        }
+    }
+}
+
+/// For expiries that don't come as JSON, such as in tus metadata.
+impl FromStr for FileExpiry {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [Self::Hour, Self::Day, Self::Week, Self::Month, Self::Never]
+            .into_iter()
+            .find(|expiry| expiry.as_str() == value)
+            .ok_or(())
+    }
+}
+
+/// Rename and/or move a file. Missing fields are left alone, a `null` folder moves the file to
+/// the root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileUpdateDto {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub folder_id: Option<Option<Uuid>>,
+}
+
+/// One entry in the history of a file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionDto {
+    pub version: i32,
+    /// Size in bytes
+    pub size: u64,
+    pub content_type: String,
+    pub detected_type: Option<String>,
+    /// Hex encoded sha256
+    pub checksum: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: time::OffsetDateTime,
+    /// When a newer version replaced it. `null` for the current version
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub replaced: Option<time::OffsetDateTime>,
+    pub current: bool,
+    pub status: FileStatus,
+    /// Size difference to the next older version that is still kept
+    pub size_delta: Option<i64>,
+    /// Whether the contents differ from the next older version that is still kept
+    pub changed: Option<bool>,
+}
+
+impl VersionDto {
+    /// History of `file`, newest first. `versions` are its earlier versions, newest first.
+    pub fn history(
+        file: &crate::models::file::File,
+        versions: &[crate::models::version::FileVersion],
+    ) -> Vec<Self> {
+        let current = Self {
+            version: file.version,
+            size: file.size as u64,
+            content_type: file.mime_type.clone(),
+            detected_type: file.detected_type.clone(),
+            checksum: file.sha256.clone(),
+            created: file.uploaded,
+            replaced: None,
+            current: true,
+            status: file.status,
+            size_delta: None,
+            changed: None,
+        };
+        let earlier = versions.iter().map(|version| Self {
+            version: version.version,
+            size: version.size as u64,
+            content_type: version.mime_type.clone(),
+            detected_type: version.detected_type.clone(),
+            checksum: version.sha256.clone(),
+            created: version.created,
+            replaced: Some(version.replaced),
+            current: false,
+            status: version.status,
+            size_delta: None,
+            changed: None,
+        });
+
+        let mut history: Vec<Self> = std::iter::once(current).chain(earlier).collect();
+        for i in 1..history.len() {
+            let older = &history[i];
+            let (size, checksum) = (older.size as i64, older.checksum.clone());
+            let newer = &mut history[i - 1];
+            newer.size_delta = Some(newer.size as i64 - size);
+            newer.changed = Some(newer.checksum != checksum);
+        }
+        history
+    }
+}
+
+crate::make_mod!(prelude FileInfoDto, FileExpiry, FileUpdateDto, VersionDto);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::file::File;
+    use crate::models::version::FileVersion;
+    use time::OffsetDateTime;
+
+    fn day(day: i64) -> OffsetDateTime {
+        OffsetDateTime::UNIX_EPOCH + time::Duration::days(day)
+    }
+
+    fn file(version: i32, size: i64, sha256: &str) -> File {
+        File {
+            id: Uuid::nil(),
+            owner_id: Uuid::nil(),
+            folder_id: None,
+            name: "notes.txt".to_string(),
+            size,
+            mime_type: "text/plain".to_string(),
+            detected_type: None,
+            sha256: sha256.to_string(),
+            created: day(1),
+            modified: day(3),
+            version,
+            uploaded: day(3),
+            deleted_at: None,
+            trashed_by: None,
+            status: FileStatus::Clean,
+            expires: None,
+        }
+    }
+
+    fn version(version: i32, size: i64, sha256: &str) -> FileVersion {
+        FileVersion {
+            file_id: Uuid::nil(),
+            version,
+            size,
+            mime_type: "text/plain".to_string(),
+            detected_type: None,
+            sha256: sha256.to_string(),
+            created: day(1),
+            replaced: day(2),
+            status: FileStatus::Clean,
+        }
+    }
+
+    #[test]
+    fn history_without_versions() {
+        let history = VersionDto::history(&file(1, 10, "a"), &[]);
+        assert_eq!(history.len(), 1);
+        assert!(history[0].current);
+        assert_eq!(history[0].replaced, None);
+        assert_eq!(history[0].size_delta, None);
+        assert_eq!(history[0].changed, None);
+    }
+
+    #[test]
+    fn history_compares_with_next_older_kept_version() {
+        // Version 2 was pruned, so version 3 is compared with version 1
+        let history = VersionDto::history(
+            &file(4, 15, "c"),
+            &[version(3, 20, "b"), version(1, 20, "a")],
+        );
+
+        let versions: Vec<_> = history.iter().map(|entry| entry.version).collect();
+        assert_eq!(versions, [4, 3, 1]);
+        assert!(history[0].current);
+        assert!(!history[1].current && !history[2].current);
+
+        assert_eq!(history[0].size_delta, Some(-5));
+        assert_eq!(history[0].changed, Some(true));
+        // Same size, different contents
+        assert_eq!(history[1].size_delta, Some(0));
+        assert_eq!(history[1].changed, Some(true));
+        // Nothing older to compare the oldest with
+        assert_eq!(history[2].size_delta, None);
+        assert_eq!(history[2].changed, None);
+    }
+
+    #[test]
+    fn expiry_parses_like_it_serializes() {
+        for expiry in [
+            FileExpiry::Hour,
+            FileExpiry::Day,
+            FileExpiry::Week,
+            FileExpiry::Never,
+        ] {
+            let json = serde_json::to_string(&expiry).unwrap();
+            assert_eq!(json, format!("\"{}\"", expiry.as_str()));
+            assert_eq!(expiry.as_str().parse(), Ok(expiry));
+        }
+        assert_eq!("2d".parse::<FileExpiry>(), Err(()));
+    }
+
+    #[test]
+    fn history_detects_unchanged_uploads() {
+        let history = VersionDto::history(&file(2, 10, "a"), &[version(1, 10, "a")]);
+        assert_eq!(history[0].size_delta, Some(0));
+        assert_eq!(history[0].changed, Some(false));
+        assert_eq!(history[1].replaced, Some(day(2)));
+    }
+}
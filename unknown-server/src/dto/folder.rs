@@ -0,0 +1,94 @@
+use crate::dto::file::FileInfoDto;
+use crate::dto::shared::double_option;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderDto {
+    pub id: Uuid,
+    /// `null` for folders at the root
+    pub parent_id: Option<Uuid>,
+    pub name: String,
+}
+
+impl From<crate::models::folder::Folder> for FolderDto {
+    fn from(value: crate::models::folder::Folder) -> Self {
+        Self {
+            id: value.id,
+            parent_id: value.parent_id,
+            name: value.name,
+        }
+    }
+}
+
+/// Something that lives in a folder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum EntryDto {
+    Folder(FolderDto),
+    File(FileInfoDto),
+}
+
+/// Something the user put in the trash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntryDto {
+    #[serde(flatten)]
+    pub entry: EntryDto,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub deleted_at: Option<time::OffsetDateTime>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashListingDto {
+    /// Most recently deleted first, folders before files
+    pub entries: Vec<TrashEntryDto>,
+    /// Number of entries across all pages
+    pub total: u64,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderListingDto {
+    /// The listed folder, `null` for the root
+    pub folder: Option<FolderDto>,
+    /// Folders first, then files, each ordered by name
+    pub entries: Vec<EntryDto>,
+    /// Number of entries across all pages
+    pub total: u64,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderCreateDto {
+    pub name: String,
+    /// Created at the root if unset
+    #[serde(default)]
+    pub parent_id: Option<Uuid>,
+}
+
+/// Rename and/or move a folder. Missing fields are left alone, a `null` parent moves the folder
+/// to the root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderUpdateDto {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub parent_id: Option<Option<Uuid>>,
+}
+
+/// Files and folders to download as one ZIP archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveCreateDto {
+    #[serde(default)]
+    pub files: Vec<Uuid>,
+    /// Downloaded with everything in them
+    #[serde(default)]
+    pub folders: Vec<Uuid>,
+    /// Name of the archive without the `.zip`, `download` if unset
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+crate::make_mod!(prelude FolderDto, EntryDto, TrashEntryDto, TrashListingDto, FolderListingDto, FolderCreateDto, FolderUpdateDto, ArchiveCreateDto);
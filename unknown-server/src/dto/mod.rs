@@ -1,2 +1,5 @@
 pub mod auth;
+pub mod file;
+pub mod folder;
+pub mod paste;
 pub mod shared;
@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+/// A new paste, sent by the form at `/paste`. Empty fields count as unset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasteCreateDto {
+    pub content: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Name or file extension of a syntax, such as `Rust` or `rs`. Detected from the first
+    /// line if unset
+    #[serde(default)]
+    pub language: Option<String>,
+    /// One of the [`FileExpiry`](crate::dto::file::FileExpiry) choices, as long as allowed if
+    /// unset
+    #[serde(default)]
+    pub expiry: Option<String>,
+}
+
+crate::make_mod!(prelude PasteCreateDto);
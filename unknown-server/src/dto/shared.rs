@@ -4,3 +4,39 @@ use serde::{Deserialize, Serialize};
 pub struct SuccessResponse {
     pub message: String,
 }
+
+/// Offset pagination for listings
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PageQuery {
+    /// Entries per page, at most [`PageQuery::MAX_LIMIT`]. Defaults to 100
+    #[serde(default = "PageQuery::default_limit")]
+    pub limit: u32,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+impl PageQuery {
+    pub const MAX_LIMIT: u32 = 1000;
+
+    fn default_limit() -> u32 {
+        100
+    }
+
+    pub fn limit(&self) -> i64 {
+        self.limit.min(Self::MAX_LIMIT) as i64
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset as i64
+    }
+}
+
+/// Tells a missing field (`None`) apart from an explicit `null` (`Some(None)`).
+/// Use together with `#[serde(default)]`.
+pub fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
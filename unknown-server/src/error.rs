@@ -1,11 +1,16 @@
+use crate::models::file::FileStatus;
+use crate::policy::UploadRejection;
+use crate::state::AppStateRef;
+use crate::storage::StorageError;
 use crate::user::Backend;
-use axum::extract::FromRequest;
-use axum::http::StatusCode;
-use axum::response::{IntoResponse, Response};
+use axum::extract::{FromRequest, Request, State};
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{Html, IntoResponse, Response};
 use axum_login::Error;
 use serde::Serialize;
 use thiserror::Error;
-use tracing::error;
+use tracing::{debug, error};
 
 #[derive(Error, Debug)]
 #[non_exhaustive]
@@ -21,11 +26,42 @@ pub enum AppError {
 
     #[error(transparent)]
     JinjaError(#[from] minijinja::Error),
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+
+    #[error(transparent)]
+    Fred(#[from] fred::error::Error),
+
+    #[error("storage quota of {limit} bytes exceeded")]
+    QuotaExceeded { limit: u64 },
+
+    #[error("file is {0:?} and can't be served")]
+    NotServable(FileStatus),
+
+    #[error("file has expired")]
+    Expired,
+
+    #[error(transparent)]
+    UploadRejected(#[from] UploadRejection),
 }
 
+/// Status codes htmx swaps into the page on error, see `htmx-config` in `base.j2.html`.
+const HTMX_SWAPPED: [StatusCode; 2] = [
+    StatusCode::PAYLOAD_TOO_LARGE,
+    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+];
+
+/// The message of an error response, kept for `htmx_errors`.
+#[derive(Clone)]
+struct ErrorMessage(String);
+
 #[derive(Serialize)]
 struct AppErrorResponse {
     message: String,
+    /// Details on why an upload was refused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rejection: Option<UploadRejection>,
 }
 
 impl IntoResponse for AppError {
@@ -76,9 +112,82 @@ impl IntoResponse for AppError {
                     "Something went wrong".to_string(),
                 )
             }
+            AppError::Storage(StorageError::NotFound(key)) => {
+                debug!(key, "object not found");
+                (
+                    StatusCode::NOT_FOUND,
+                    StatusCode::NOT_FOUND
+                        .canonical_reason()
+                        .unwrap()
+                        .to_string(),
+                )
+            }
+            AppError::Storage(StorageError::Unsupported(feature)) => (
+                StatusCode::NOT_IMPLEMENTED,
+                format!("{feature} is not supported by the configured storage"),
+            ),
+            AppError::Storage(err) => {
+                error!(%err, "storage error");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Something went wrong".to_string(),
+                )
+            }
+            AppError::Fred(err) => {
+                error!(%err, "redis error");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Something went wrong".to_string(),
+                )
+            }
+            AppError::QuotaExceeded { limit } => {
+                debug!(limit, "quota exceeded");
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("Storage quota of {limit} bytes exceeded"),
+                )
+            }
+            AppError::NotServable(status) => match status {
+                FileStatus::Infected => (
+                    StatusCode::FORBIDDEN,
+                    "File is quarantined as malware".to_string(),
+                ),
+                FileStatus::Corrupt => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "File contents are damaged in storage".to_string(),
+                ),
+                _ => (
+                    StatusCode::CONFLICT,
+                    "File has not been scanned for malware yet".to_string(),
+                ),
+            },
+            AppError::Expired => (StatusCode::GONE, "File has expired".to_string()),
+            AppError::UploadRejected(rejection) => {
+                debug!(%rejection, "upload rejected");
+                let code = match rejection {
+                    UploadRejection::TooLargeForType { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                    _ => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                };
+                let message = rejection.to_string();
+                let body = AppErrorResponse {
+                    message: message.clone(),
+                    rejection: Some(rejection.clone()),
+                };
+                let mut response = (code, ErrorJson(body)).into_response();
+                // Picked up by `htmx_errors`
+                response.extensions_mut().insert(rejection);
+                response.extensions_mut().insert(ErrorMessage(message));
+                return response;
+            }
         };
 
-        (code, ErrorJson(AppErrorResponse { message })).into_response()
+        let body = AppErrorResponse {
+            message: message.clone(),
+            rejection: None,
+        };
+        let mut response = (code, ErrorJson(body)).into_response();
+        response.extensions_mut().insert(ErrorMessage(message));
+        response
     }
 }
 
@@ -95,6 +204,55 @@ where
     }
 }
 
+/// Renders the errors htmx swaps into the page as an html fragment for htmx requests, so
+/// forms can show them in place. JSON callers and every other error are left alone.
+pub(crate) async fn htmx_errors(
+    State(state): State<AppStateRef>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let htmx = request
+        .headers()
+        .get("HX-Request")
+        .is_some_and(|value| value == "true");
+    let response = next.run(request).await;
+    if !htmx || !HTMX_SWAPPED.contains(&response.status()) {
+        return response;
+    }
+    let Some(ErrorMessage(message)) = response.extensions().get::<ErrorMessage>().cloned() else {
+        return response;
+    };
+
+    let context = minijinja::context! {
+        message => message,
+        rejection => response.extensions().get::<UploadRejection>(),
+    };
+    let html = match state.render_template("errors/alert.j2.html", Some(context)) {
+        Ok(html) => html,
+        Err(err) => {
+            error!(%err, "failed to render error fragment");
+            return response;
+        }
+    };
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_TYPE);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert("HX-Reswap", HeaderValue::from_static("innerHTML"));
+    (parts, Html(html)).into_response()
+}
+
+/// Turns unique constraint violations, such as name clashes within a folder, into
+/// `409 Conflict`.
+pub(crate) fn conflict_on_unique(err: sqlx::Error) -> AppError {
+    match err.as_database_error() {
+        Some(db_err) if db_err.is_unique_violation() => StatusCode::CONFLICT.into(),
+        _ => err.into(),
+    }
+}
+
 impl From<StatusCode> for AppError {
     fn from(value: StatusCode) -> Self {
         Self::Code(value)
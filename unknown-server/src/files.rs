@@ -0,0 +1,858 @@
+//! File lifecycle. File contents are stored as blobs shared between all files with the same
+//! sha256, so storing and deleting files keeps the blob reference counts in sync.
+use crate::compress::Compression;
+use crate::error::conflict_on_unique;
+use crate::ingest::{self, IngestError, Ingested};
+use crate::models::blob::Blob;
+use crate::models::file::{File, FileInsert, FileStatus};
+use crate::models::folder::{Folder, FolderInsert};
+use crate::models::user::User;
+use crate::models::version::FileVersion;
+use crate::policy::{POLICY, UploadRejection};
+use crate::prelude::*;
+use crate::scan;
+use crate::storage::ByteStream;
+use sqlx::{PgConnection, PgPool};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Where and under what name a new file is recorded.
+#[derive(Debug, Clone)]
+pub(crate) struct NewFile {
+    pub(crate) owner: Uuid,
+    /// `None` for the root
+    pub(crate) folder_id: Option<Uuid>,
+    pub(crate) name: String,
+    pub(crate) content_type: String,
+    /// What the uploader picked, see [`NewFile::expires`]
+    pub(crate) expiry: Option<dto::file::FileExpiry>,
+}
+
+impl NewFile {
+    /// Reject bad names, names and types the upload policy refuses and folders that don't
+    /// belong to the owner before any bytes are stored.
+    pub(crate) async fn validate(&self, state: &AppState) -> Result<()> {
+        if !valid_name(&self.name) {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        POLICY.check_declared(&self.name, &self.content_type)?;
+        self.expires()?;
+        if let Some(folder_id) = self.folder_id
+            && Folder::get_owned(state.db(), folder_id, self.owner)
+                .await?
+                .is_none()
+        {
+            return Err(StatusCode::NOT_FOUND.into());
+        }
+
+        Ok(())
+    }
+
+    /// When the file expires if it's recorded now. Without a pick it expires after the longest
+    /// time allowed, picks beyond that are refused.
+    pub(crate) fn expires(&self) -> Result<Option<time::OffsetDateTime>> {
+        let max = CONFIG
+            .upload
+            .max_expiry
+            .map(|max| time::Duration::seconds(max as i64));
+        let duration = match self.expiry.map(dto::file::FileExpiry::duration) {
+            None => max,
+            Some(duration) => match (duration, max) {
+                (Some(duration), Some(max)) if duration > max => {
+                    return Err(StatusCode::BAD_REQUEST.into());
+                }
+                (None, Some(_)) => return Err(StatusCode::BAD_REQUEST.into()),
+                (duration, _) => duration,
+            },
+        };
+        Ok(duration.map(|duration| time::OffsetDateTime::now_utc() + duration))
+    }
+
+    /// Reject uploads announced to be larger than the upload policy allows for their type.
+    pub(crate) fn check_size(&self, size: u64) -> Result<()> {
+        match POLICY.max_size(&self.content_type) {
+            Some(limit) if size > limit => Err(UploadRejection::TooLargeForType {
+                content_type: self.content_type.clone(),
+                limit,
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether `name` can be used for a file or folder. Names are path segments, so they can't
+/// contain slashes or be `.` or `..`. Control characters would end up in headers and listings.
+pub(crate) fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 255
+        && name != "."
+        && name != ".."
+        && !name.contains('/')
+        && !name.chars().any(char::is_control)
+}
+
+/// Store `body` as a new file and record it in the database.
+///
+/// The body is always written to a new object first, since its hash isn't known until the
+/// upload is done. If a blob with the same contents already exists the new object is removed.
+/// Uploads that would take the owner over their quota are aborted as soon as they cross it.
+pub(crate) async fn store_file(
+    state: &AppState,
+    new: NewFile,
+    body: ByteStream<'_>,
+) -> Result<File> {
+    let (key, ingested) = ingest_file(state, &new, body).await?;
+    record_file(state, new, &key, ingested).await
+}
+
+/// Write `body` to a new blob object for `new` without recording it yet. Returns the key of the
+/// object.
+pub(crate) async fn ingest_file(
+    state: &AppState,
+    new: &NewFile,
+    body: ByteStream<'_>,
+) -> Result<(String, Ingested)> {
+    new.validate(state).await?;
+    let quota = remaining_quota(state.db(), new.owner).await?;
+
+    let key = ingest::blob_key();
+    let ingested = ingest::ingest(
+        state.storage(),
+        &key,
+        body,
+        &new.content_type,
+        quota.map(|quota| quota.remaining),
+        &POLICY,
+        Compression::for_upload(&new.name, &new.content_type),
+    )
+    .await
+    .map_err(|err| match err {
+        IngestError::TooLarge(_) => AppError::QuotaExceeded {
+            limit: quota.map(|quota| quota.limit).unwrap_or_default(),
+        },
+        IngestError::Rejected(rejection) => rejection.into(),
+        IngestError::Storage(err) => err.into(),
+    })?;
+
+    Ok((key, ingested))
+}
+
+/// Record a file for contents already written to `key`.
+///
+/// `key` is expected to be a fresh object nothing else points to. It is removed when recording
+/// fails or when a blob with the same contents already exists, unless those contents were
+/// found corrupt, then `key` takes their place. Uploading to the path of an existing file makes
+/// the upload its new version.
+pub(crate) async fn record_file(
+    state: &AppState,
+    new: NewFile,
+    key: &str,
+    ingested: Ingested,
+) -> Result<File> {
+    let owner = new.owner;
+    let recorded = async {
+        let expires = new.expires()?;
+        let mut tx = state.db().begin().await?;
+        // Locking the user also keeps concurrent uploads to the same path from racing
+        lock_with_quota(&mut tx, owner, ingested.size).await?;
+
+        let status = scan::initial_status(state);
+        // Contents the scrub found corrupt are repaired by any upload of them
+        let repaired = Blob::repair(&mut *tx, &ingested.sha256, key, ingested.encoding).await?;
+        if repaired.is_some() {
+            let from = &[FileStatus::Corrupt];
+            File::set_status_by_content(&mut *tx, &ingested.sha256, from, status).await?;
+            FileVersion::set_status_by_content(&mut *tx, &ingested.sha256, from, status).await?;
+        }
+        let blob = Blob::acquire(
+            &mut *tx,
+            &ingested.sha256,
+            key,
+            ingested.size as i64,
+            ingested.encoding,
+        )
+        .await?;
+        let current = File::find_in_folder(&mut *tx, owner, new.folder_id, &new.name).await?;
+        let (file, released) = match current {
+            Some(current) => {
+                replace_content(
+                    &mut tx,
+                    &current,
+                    ingested.size as i64,
+                    &new.content_type,
+                    ingested.detected_type.as_deref(),
+                    &ingested.sha256,
+                    status,
+                    expires,
+                )
+                .await?
+            }
+            None => {
+                let file = FileInsert::new(
+                    owner,
+                    new.folder_id,
+                    new.name,
+                    ingested.size as i64,
+                    new.content_type,
+                    ingested.sha256.clone(),
+                    status,
+                )
+                .with_detected_type(ingested.detected_type.clone())
+                .with_expires(expires)
+                .insert(&mut *tx)
+                .await
+                .map_err(conflict_on_unique)?;
+                (file, vec![])
+            }
+        };
+        tx.commit().await?;
+
+        Ok::<_, AppError>((blob, file, released, repaired))
+    }
+    .await;
+
+    let (blob, file, released, repaired) = match recorded {
+        Ok(recorded) => recorded,
+        Err(err) => {
+            // Don't leave an object behind that nothing points to
+            remove_object(state, key).await;
+            return Err(err);
+        }
+    };
+
+    let deduplicated = blob.storage_key != key;
+    if deduplicated {
+        remove_object(state, key).await;
+    }
+    if let Some(corrupt) = repaired {
+        warn!(
+            sha256 = blob.sha256,
+            key, corrupt, "repaired corrupt contents with an upload"
+        );
+        remove_object(state, &corrupt).await;
+    }
+    remove_released(state, released).await;
+    if file.status == FileStatus::Pending {
+        state.request_scan();
+    }
+    info!(id = %file.id, %owner, size = file.size, version = file.version, deduplicated, "stored upload");
+
+    Ok(file)
+}
+
+/// Make `version` of `file` its current contents again. The contents being replaced are kept
+/// as a version like on any other upload.
+pub(crate) async fn rollback_file(state: &AppState, file: &File, version: i32) -> Result<File> {
+    let mut tx = state.db().begin().await?;
+    // Re-read both with the user locked, an upload may have replaced the file since
+    User::lock(&mut tx, file.owner_id).await?;
+    let Some(file) = File::get_owned(&mut *tx, file.id, file.owner_id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let Some(version) = FileVersion::get(&mut *tx, file.id, version).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    lock_with_quota(&mut tx, file.owner_id, version.size as u64).await?;
+
+    Blob::retain(&mut *tx, &version.sha256).await?;
+    let (rolled_back, released) = replace_content(
+        &mut tx,
+        &file,
+        version.size,
+        &version.mime_type,
+        version.detected_type.as_deref(),
+        &version.sha256,
+        version.status,
+        file.expires,
+    )
+    .await?;
+    tx.commit().await?;
+
+    remove_released(state, released).await;
+    info!(id = %file.id, owner = %file.owner_id, from = version.version, version = rolled_back.version, "rolled back file");
+
+    Ok(rolled_back)
+}
+
+/// Keep the current contents of `file` as a version and make new contents current.
+/// The caller holds a reference to the new blob already. Returns the updated file and the blobs
+/// released by pruning old versions.
+#[allow(clippy::too_many_arguments)]
+async fn replace_content(
+    tx: &mut PgConnection,
+    file: &File,
+    size: i64,
+    mime_type: &str,
+    detected_type: Option<&str>,
+    sha256: &str,
+    status: FileStatus,
+    expires: Option<time::OffsetDateTime>,
+) -> Result<(File, Vec<Blob>)> {
+    FileVersion::archive(&mut *tx, file).await?;
+    let file = File::replace_content(
+        &mut *tx,
+        file.id,
+        size,
+        mime_type,
+        detected_type,
+        sha256,
+        status,
+        expires,
+    )
+    .await?;
+
+    let pruned = FileVersion::prune(
+        &mut *tx,
+        file.id,
+        i64::from(CONFIG.versions.max_versions),
+        versions_cutoff(),
+    )
+    .await?;
+    let released = release_all(tx, pruned.iter().map(|version| version.sha256.as_str())).await?;
+
+    Ok((file, released))
+}
+
+/// Versions replaced before this are too old to keep.
+fn versions_cutoff() -> Option<time::OffsetDateTime> {
+    CONFIG
+        .versions
+        .max_age
+        .map(|max_age| time::OffsetDateTime::now_utc() - std::time::Duration::from_secs(max_age))
+}
+
+/// Drop versions that are older than the configured max age.
+pub(crate) async fn prune_versions(state: AppStateRef) -> Result<()> {
+    let Some(cutoff) = versions_cutoff() else {
+        return Ok(());
+    };
+
+    let mut tx = state.db().begin().await?;
+    let pruned = FileVersion::prune_replaced_before(&mut *tx, cutoff).await?;
+    let released = release_all(
+        &mut tx,
+        pruned.iter().map(|version| version.sha256.as_str()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    remove_released(&state, released).await;
+    if !pruned.is_empty() {
+        info!(pruned = pruned.len(), "pruned old file versions");
+    }
+
+    Ok(())
+}
+
+/// Rename and/or move `file` to `folder_id`.
+pub(crate) async fn move_file(
+    state: &AppState,
+    file: &File,
+    folder_id: Option<Uuid>,
+    name: &str,
+) -> Result<File> {
+    if !valid_name(name) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if name != file.name {
+        // Otherwise a blocked extension is just a rename away
+        POLICY.check_name(name)?;
+    }
+    if let Some(folder_id) = folder_id
+        && Folder::get_owned(state.db(), folder_id, file.owner_id)
+            .await?
+            .is_none()
+    {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    let file = File::update(state.db(), file.id, folder_id, name)
+        .await
+        .map_err(conflict_on_unique)?;
+    Ok(file)
+}
+
+/// Rename and/or move `folder` to `parent_id`. Fails with `409 Conflict` when `parent_id` is
+/// the folder itself or below it.
+pub(crate) async fn move_folder(
+    state: &AppState,
+    folder: &Folder,
+    parent_id: Option<Uuid>,
+    name: &str,
+) -> Result<Folder> {
+    if !valid_name(name) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let mut tx = state.db().begin().await?;
+    // Two concurrent moves could otherwise each pass the cycle check and still form a cycle
+    User::lock(&mut tx, folder.owner_id).await?;
+
+    let Some(folder) = Folder::get_owned(&mut *tx, folder.id, folder.owner_id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    if let Some(parent_id) = parent_id {
+        if Folder::get_owned(&mut *tx, parent_id, folder.owner_id)
+            .await?
+            .is_none()
+        {
+            return Err(StatusCode::NOT_FOUND.into());
+        }
+        // A folder can't end up inside itself
+        if Folder::is_within(&mut *tx, parent_id, folder.id).await? {
+            return Err(StatusCode::CONFLICT.into());
+        }
+    }
+
+    let folder = Folder::update(&mut *tx, folder.id, parent_id, name)
+        .await
+        .map_err(conflict_on_unique)?;
+    tx.commit().await?;
+
+    Ok(folder)
+}
+
+/// Copy `file` to `folder_id` as `name`. The copy shares the contents of the original, but
+/// not its earlier versions, and counts towards the quota like any other file.
+pub(crate) async fn copy_file(
+    state: &AppState,
+    file: &File,
+    folder_id: Option<Uuid>,
+    name: &str,
+) -> Result<File> {
+    if !valid_name(name) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    POLICY.check_name(name)?;
+
+    let mut tx = state.db().begin().await?;
+    lock_with_quota(&mut tx, file.owner_id, file.size as u64).await?;
+    if let Some(folder_id) = folder_id
+        && Folder::get_owned(&mut *tx, folder_id, file.owner_id)
+            .await?
+            .is_none()
+    {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    let copy = insert_copy(&mut tx, file, folder_id, name.to_string()).await?;
+    tx.commit().await?;
+    info!(id = %copy.id, from = %file.id, owner = %file.owner_id, "copied file");
+
+    Ok(copy)
+}
+
+/// Make the contents of `file` the new version of `target`, like uploading them to its path.
+/// `target` is left as it is when the copy fails.
+pub(crate) async fn copy_file_over(state: &AppState, file: &File, target: &File) -> Result<File> {
+    let mut tx = state.db().begin().await?;
+    lock_with_quota(&mut tx, target.owner_id, file.size as u64).await?;
+    // Re-read with the user locked, an upload may have replaced it since
+    let Some(target) = File::get_owned(&mut *tx, target.id, target.owner_id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    Blob::retain(&mut *tx, &file.sha256).await?;
+    let (copy, released) = replace_content(
+        &mut tx,
+        &target,
+        file.size,
+        &file.mime_type,
+        file.detected_type.as_deref(),
+        &file.sha256,
+        file.status,
+        file.expires,
+    )
+    .await?;
+    tx.commit().await?;
+
+    remove_released(state, released).await;
+    info!(id = %copy.id, from = %file.id, owner = %file.owner_id, version = copy.version, "copied file over another");
+
+    Ok(copy)
+}
+
+/// Copy `folder` to `parent_id` as `name`, with everything in it if `recursive`.
+pub(crate) async fn copy_folder(
+    state: &AppState,
+    folder: &Folder,
+    parent_id: Option<Uuid>,
+    name: &str,
+    recursive: bool,
+) -> Result<Folder> {
+    if !valid_name(name) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let owner = folder.owner_id;
+
+    let mut tx = state.db().begin().await?;
+    User::lock(&mut tx, owner).await?;
+    if let Some(parent_id) = parent_id {
+        if Folder::get_owned(&mut *tx, parent_id, owner)
+            .await?
+            .is_none()
+        {
+            return Err(StatusCode::NOT_FOUND.into());
+        }
+        // Copying a folder into itself would never end
+        if recursive && Folder::is_within(&mut *tx, parent_id, folder.id).await? {
+            return Err(StatusCode::CONFLICT.into());
+        }
+    }
+
+    let copy = FolderInsert::new(owner, parent_id, name.to_string())
+        .insert(&mut *tx)
+        .await
+        .map_err(conflict_on_unique)?;
+    let mut copied = vec![(folder.id, copy.id)];
+    let mut pending = copied.clone();
+    while recursive && let Some((from, to)) = pending.pop() {
+        for child in Folder::children(&mut *tx, owner, Some(from), i64::MAX, 0).await? {
+            let child_copy = FolderInsert::new(owner, Some(to), child.name)
+                .insert(&mut *tx)
+                .await?;
+            copied.push((child.id, child_copy.id));
+            pending.push((child.id, child_copy.id));
+        }
+    }
+
+    let targets: HashMap<Uuid, Uuid> = copied.into_iter().collect();
+    let originals: Vec<Uuid> = targets.keys().copied().collect();
+    let files = match recursive {
+        true => File::in_folders(&mut *tx, owner, &originals).await?,
+        false => vec![],
+    };
+    let size = files.iter().map(|file| file.size as u64).sum();
+    lock_with_quota(&mut tx, owner, size).await?;
+    for file in &files {
+        let folder_id = file.folder_id.and_then(|id| targets.get(&id)).copied();
+        insert_copy(&mut tx, file, folder_id, file.name.clone()).await?;
+    }
+    tx.commit().await?;
+    info!(id = %copy.id, from = %folder.id, %owner, folders = targets.len(), files = files.len(), "copied folder");
+
+    Ok(copy)
+}
+
+/// Record a copy of `file`, taking another reference to its contents.
+async fn insert_copy(
+    tx: &mut PgConnection,
+    file: &File,
+    folder_id: Option<Uuid>,
+    name: String,
+) -> Result<File> {
+    Blob::retain(&mut *tx, &file.sha256).await?;
+    let copy = FileInsert::new(
+        file.owner_id,
+        folder_id,
+        name,
+        file.size,
+        file.mime_type.clone(),
+        file.sha256.clone(),
+        // Same contents, same verdict. Pending contents are picked up by the scan all the same
+        file.status,
+    )
+    .with_detected_type(file.detected_type.clone())
+    // A copy goes when the original does
+    .with_expires(file.expires)
+    .insert(&mut *tx)
+    .await
+    .map_err(conflict_on_unique)?;
+    Ok(copy)
+}
+
+/// Move `file` to the trash. It keeps counting towards the quota until it is purged.
+pub(crate) async fn trash_file(state: &AppState, file: &File) -> Result<()> {
+    File::trash(state.db(), file.id).await?;
+    info!(id = %file.id, owner = %file.owner_id, "trashed file");
+
+    Ok(())
+}
+
+/// Move `folder` and everything in it to the trash.
+pub(crate) async fn trash_folder(state: &AppState, folder: &Folder) -> Result<()> {
+    let mut tx = state.db().begin().await?;
+    Folder::trash(&mut tx, folder.id).await?;
+    tx.commit().await?;
+    info!(id = %folder.id, owner = %folder.owner_id, "trashed folder");
+
+    Ok(())
+}
+
+/// Take `file` out of the trash. It goes back to its folder, or to the root if the folder is
+/// gone or in the trash itself.
+pub(crate) async fn restore_file(state: &AppState, file: &File) -> Result<File> {
+    let mut tx = state.db().begin().await?;
+    let folder_id = match file.folder_id {
+        Some(folder_id) => Folder::get_owned(&mut *tx, folder_id, file.owner_id)
+            .await?
+            .map(|folder| folder.id),
+        None => None,
+    };
+    let file = File::restore(&mut *tx, file.id, folder_id)
+        .await
+        .map_err(conflict_on_unique)?;
+    tx.commit().await?;
+    info!(id = %file.id, owner = %file.owner_id, "restored file");
+
+    Ok(file)
+}
+
+/// Take `folder` and everything trashed with it out of the trash. It goes back to its parent,
+/// or to the root if the parent is gone or in the trash itself.
+pub(crate) async fn restore_folder(state: &AppState, folder: &Folder) -> Result<Folder> {
+    let mut tx = state.db().begin().await?;
+    let parent_id = match folder.parent_id {
+        Some(parent_id) => Folder::get_owned(&mut *tx, parent_id, folder.owner_id)
+            .await?
+            .map(|parent| parent.id),
+        None => None,
+    };
+    let folder = Folder::restore(&mut tx, folder.id, parent_id)
+        .await
+        .map_err(conflict_on_unique)?;
+    tx.commit().await?;
+    info!(id = %folder.id, owner = %folder.owner_id, "restored folder");
+
+    Ok(folder)
+}
+
+/// Permanently delete everything that has been in the trash for longer than the retention.
+pub(crate) async fn purge_trash(state: AppStateRef) -> Result<()> {
+    let before =
+        time::OffsetDateTime::now_utc() - std::time::Duration::from_secs(CONFIG.trash.retention);
+
+    // One item failing must not keep the rest of the trash around, it's retried on the next run
+    let mut failed = 0;
+    let folders = Folder::trashed_before(state.db(), before).await?;
+    for folder in &folders {
+        if let Err(err) = delete_folder(&state, folder).await {
+            error!(id = %folder.id, %err, "failed to purge trashed folder");
+            failed += 1;
+        }
+    }
+    // Files trashed on their own. Those trashed with a folder went with it
+    let files = File::trashed_before(state.db(), before).await?;
+    for file in &files {
+        if let Err(err) = delete_file(&state, file).await {
+            error!(id = %file.id, %err, "failed to purge trashed file");
+            failed += 1;
+        }
+    }
+
+    if !folders.is_empty() || !files.is_empty() {
+        info!(
+            folders = folders.len(),
+            files = files.len(),
+            failed,
+            "purged trash"
+        );
+    }
+
+    Ok(())
+}
+
+/// Permanently delete expired files. They are hidden from the moment they expire, this frees
+/// their storage.
+pub(crate) async fn purge_expired(state: AppStateRef) -> Result<()> {
+    let expired = File::expired(state.db()).await?;
+    let mut failed = 0;
+    for file in &expired {
+        if let Err(err) = delete_file(&state, file).await {
+            error!(id = %file.id, %err, "failed to delete expired file");
+            failed += 1;
+        }
+    }
+    if !expired.is_empty() {
+        info!(files = expired.len(), failed, "deleted expired files");
+    }
+
+    Ok(())
+}
+
+/// Permanently delete `file` and its versions, removing blobs no other file references anymore.
+pub(crate) async fn delete_file(state: &AppState, file: &File) -> Result<()> {
+    let mut tx = state.db().begin().await?;
+    let versions = FileVersion::delete_for_files(&mut *tx, &[file.id]).await?;
+    if File::delete(&mut *tx, file.id).await?.is_none() {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    let released = release_all(
+        &mut tx,
+        versions
+            .iter()
+            .map(|version| version.sha256.as_str())
+            .chain([file.sha256.as_str()]),
+    )
+    .await?;
+    tx.commit().await?;
+
+    remove_released(state, released).await;
+    info!(id = %file.id, owner = %file.owner_id, "deleted file");
+
+    Ok(())
+}
+
+/// Permanently delete every earlier version with the contents `sha256`, of any file.
+/// Returns how many were deleted.
+pub(crate) async fn delete_versions_with_content(state: &AppState, sha256: &str) -> Result<usize> {
+    let mut tx = state.db().begin().await?;
+    let versions = FileVersion::delete_with_content(&mut *tx, sha256).await?;
+    let released = release_all(
+        &mut tx,
+        versions.iter().map(|version| version.sha256.as_str()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    remove_released(state, released).await;
+    Ok(versions.len())
+}
+
+/// Permanently delete `folder` with everything below it, removing blobs no other file
+/// references anymore.
+pub(crate) async fn delete_folder(state: &AppState, folder: &Folder) -> Result<()> {
+    let mut tx = state.db().begin().await?;
+    let subtree = Folder::subtree(&mut *tx, folder.id).await?;
+    let versions = FileVersion::delete_in_folders(&mut *tx, &subtree).await?;
+    let deleted = File::delete_in_folders(&mut *tx, &subtree).await?;
+    let released = release_all(
+        &mut tx,
+        versions
+            .iter()
+            .map(|version| version.sha256.as_str())
+            .chain(deleted.iter().map(|file| file.sha256.as_str())),
+    )
+    .await?;
+    // Subfolders go with it
+    Folder::delete(&mut *tx, folder.id).await?;
+    tx.commit().await?;
+
+    remove_released(state, released).await;
+    info!(id = %folder.id, owner = %folder.owner_id, folders = subtree.len(), files = deleted.len(), "deleted folder");
+
+    Ok(())
+}
+
+/// Drop one reference per hash. Returns the blobs nothing references anymore, their objects
+/// should be removed once the transaction is committed.
+async fn release_all(
+    tx: &mut PgConnection,
+    sha256s: impl Iterator<Item = &str>,
+) -> sqlx::Result<Vec<Blob>> {
+    let mut released = vec![];
+    for sha256 in sha256s {
+        if let Some(blob) = Blob::release(&mut *tx, sha256).await? {
+            released.push(blob);
+        }
+    }
+    Ok(released)
+}
+
+async fn remove_released(state: &AppState, released: Vec<Blob>) {
+    for blob in released {
+        remove_object(state, &blob.storage_key).await;
+    }
+}
+
+/// Lock `owner` for the rest of the transaction and make sure `size` more bytes fit in their
+/// quota. Other uploads may have finished since the quota was first checked.
+async fn lock_with_quota(tx: &mut PgConnection, owner: Uuid, size: u64) -> Result<()> {
+    if let Some(limit) = User::lock_quota(&mut *tx, owner).await? {
+        let usage = User::usage(&mut *tx, owner).await?;
+        if usage + size > limit {
+            return Err(AppError::QuotaExceeded { limit });
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Quota {
+    pub(crate) limit: u64,
+    pub(crate) remaining: u64,
+}
+
+/// The quota of `owner` and how much of it is left. `None` when the owner has no quota.
+pub(crate) async fn remaining_quota(db: &PgPool, owner: Uuid) -> Result<Option<Quota>> {
+    let Some(user) = User::get(db, owner).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let Some(limit) = user.quota() else {
+        return Ok(None);
+    };
+    let usage = User::usage(db, owner).await?;
+
+    Ok(Some(Quota {
+        limit,
+        remaining: limit.saturating_sub(usage),
+    }))
+}
+
+/// Fail early when an upload of `size` bytes can't fit in the quota of `owner`.
+pub(crate) async fn check_quota(state: &AppState, owner: Uuid, size: u64) -> Result<()> {
+    match remaining_quota(state.db(), owner).await? {
+        Some(quota) if size > quota.remaining => {
+            Err(AppError::QuotaExceeded { limit: quota.limit })
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn remove_object(state: &AppState, key: &str) {
+    if let Err(err) = state.storage().delete(key).await {
+        error!(%err, key, "failed to remove unreferenced object");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn counts_versions_towards_quota(db: PgPool) {
+        let owner = Uuid::now_v7();
+        sqlx::query(
+            "INSERT INTO users (id, username, email, pw_hash, quota_bytes) \
+            VALUES ($1, 'quota', 'quota@example.com', '', 1000)",
+        )
+        .bind(owner)
+        .execute(&db)
+        .await
+        .unwrap();
+        let quota = remaining_quota(&db, owner).await.unwrap().unwrap();
+        assert_eq!((quota.limit, quota.remaining), (1000, 1000));
+
+        let sha256 = "0".repeat(64);
+        Blob::acquire(&db, &sha256, "blobs/test", 300, None)
+            .await
+            .unwrap();
+        let file = FileInsert::new(
+            owner,
+            None,
+            "notes.txt".into(),
+            300,
+            "text/plain".into(),
+            sha256,
+            FileStatus::Clean,
+        )
+        .insert(&db)
+        .await
+        .unwrap();
+        let quota = remaining_quota(&db, owner).await.unwrap().unwrap();
+        assert_eq!(quota.remaining, 700);
+
+        // The replaced contents still take up room until the version is pruned
+        FileVersion::archive(&db, &file).await.unwrap();
+        let quota = remaining_quota(&db, owner).await.unwrap().unwrap();
+        assert_eq!(quota.remaining, 400);
+
+        sqlx::query("UPDATE files SET size = 900 WHERE id = $1")
+            .bind(file.id)
+            .execute(&db)
+            .await
+            .unwrap();
+        let quota = remaining_quota(&db, owner).await.unwrap().unwrap();
+        assert_eq!(quota.remaining, 0);
+    }
+}
@@ -0,0 +1,197 @@
+//! Streams uploaded bodies into storage while collecting their size, checksum and type.
+use crate::compress::{self, Compression};
+use crate::models::blob::Encoding;
+use crate::policy::{SNIFF_LEN, UploadPolicy, UploadRejection, sniff};
+use crate::storage::{ByteStream, PutOptions, Storage, StorageError};
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::io;
+use uuid::Uuid;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum IngestError {
+    #[error("body is larger than {0} bytes")]
+    TooLarge(u64),
+
+    #[error(transparent)]
+    Rejected(#[from] UploadRejection),
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// The result of writing a body to storage.
+#[derive(Debug, Clone)]
+pub(crate) struct Ingested {
+    pub(crate) size: u64,
+    /// Hex encoded sha256 of the body
+    pub(crate) sha256: String,
+    /// Content type sniffed from the first bytes, if recognized
+    pub(crate) detected_type: Option<String>,
+    /// How the object is encoded, size and sha256 are of the body as it was sent
+    pub(crate) encoding: Option<Encoding>,
+}
+
+/// Every blob object is stored below this.
+pub(crate) const BLOB_PREFIX: &str = "blobs/";
+
+/// Objects of infected blobs are moved below this, see [`scan`](crate::scan).
+pub(crate) const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// Every prefix objects of blobs are stored below.
+pub(crate) const BLOB_PREFIXES: [&str; 2] = [BLOB_PREFIX, QUARANTINE_PREFIX];
+
+/// Storage key for a new blob. The key is unique per upload, so concurrent uploads of the same
+/// contents never write to the same object.
+pub(crate) fn blob_key() -> String {
+    format!("{BLOB_PREFIX}{}", Uuid::now_v7())
+}
+
+/// Read the object at `key` back, decoded from `encoding` if given, and hash it like [`ingest`]
+/// does. Returns the hex encoded sha256 and the size.
+pub(crate) async fn hash_object(
+    storage: &dyn Storage,
+    key: &str,
+    encoding: Option<Encoding>,
+) -> Result<(String, u64), StorageError> {
+    let mut body = storage.get(key, None).await?.body;
+    if let Some(encoding) = encoding {
+        body = compress::decode(body, encoding);
+    }
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        hasher.update(&chunk);
+    }
+    Ok((hex::encode(hasher.finalize()), size))
+}
+
+/// Read the object at `key`, which was written without going through [`ingest`], and check it
+/// like [`ingest`] does. Nothing is removed when `policy` refuses it.
+pub(crate) async fn inspect(
+    storage: &dyn Storage,
+    key: &str,
+    content_type: &str,
+    policy: &UploadPolicy,
+) -> Result<Ingested, IngestError> {
+    let mut body = storage.get(key, None).await?.body;
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(StorageError::from)?;
+        let take = (SNIFF_LEN - head.len()).min(chunk.len());
+        head.extend_from_slice(&chunk[..take]);
+        size += chunk.len() as u64;
+        hasher.update(&chunk);
+    }
+
+    let detected_type = sniff(&head);
+    policy.check(content_type, detected_type.as_deref(), size)?;
+
+    Ok(Ingested {
+        size,
+        sha256: hex::encode(hasher.finalize()),
+        detected_type,
+        encoding: None,
+    })
+}
+
+/// Write `body` to `key`, hashing it and sniffing its type on the way through. The body is
+/// compressed after that with `compression`, if any.
+///
+/// Bodies larger than `max_size` are cut off as soon as they cross it and nothing is stored.
+/// The same goes for bodies `policy` refuses once their type is known.
+pub(crate) async fn ingest(
+    storage: &dyn Storage,
+    key: &str,
+    body: ByteStream<'_>,
+    content_type: &str,
+    max_size: Option<u64>,
+    policy: &UploadPolicy,
+    compression: Option<Compression>,
+) -> Result<Ingested, IngestError> {
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    let mut too_large = false;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    // Set once the head is complete and checked
+    let mut type_limit: Option<Option<u64>> = None;
+    let mut rejection = None;
+
+    let body = body
+        .map(|chunk| {
+            let chunk = chunk?;
+            if type_limit.is_none() {
+                let take = (SNIFF_LEN - head.len()).min(chunk.len());
+                head.extend_from_slice(&chunk[..take]);
+                if head.len() == SNIFF_LEN {
+                    match policy.check_content(content_type, sniff(&head).as_deref()) {
+                        Ok(limit) => type_limit = Some(limit),
+                        Err(err) => {
+                            // Failing the stream makes the backend abort the write
+                            rejection = Some(err);
+                            return Err(io::Error::other("upload rejected"));
+                        }
+                    }
+                }
+            }
+
+            size += chunk.len() as u64;
+            if let Some(Some(limit)) = type_limit
+                && size > limit
+            {
+                rejection = Some(UploadRejection::TooLargeForType {
+                    content_type: sniff(&head).unwrap_or_else(|| content_type.to_string()),
+                    limit,
+                });
+                return Err(io::Error::other("upload rejected"));
+            }
+            if max_size.is_some_and(|max_size| size > max_size) {
+                too_large = true;
+                return Err(io::Error::other("body too large"));
+            }
+            hasher.update(&chunk);
+            Ok(chunk)
+        })
+        .boxed();
+    let body = match compression {
+        Some(compression) => compression.encode(body),
+        None => body,
+    };
+
+    let stored = storage
+        .put(
+            key,
+            body,
+            PutOptions {
+                content_type: Some(content_type.to_string()),
+            },
+        )
+        .await;
+    if let Some(rejection) = rejection {
+        return Err(rejection.into());
+    }
+    if too_large {
+        return Err(IngestError::TooLarge(max_size.unwrap_or_default()));
+    }
+    stored?;
+
+    // Bodies shorter than the head are only checked now that they are complete
+    let detected_type = sniff(&head);
+    if type_limit.is_none()
+        && let Err(rejection) = policy.check(content_type, detected_type.as_deref(), size)
+    {
+        let _ = storage.delete(key).await;
+        return Err(rejection.into());
+    }
+
+    Ok(Ingested {
+        size,
+        sha256: hex::encode(hasher.finalize()),
+        detected_type,
+        encoding: compression.map(|compression| compression.encoding),
+    })
+}
@@ -0,0 +1,48 @@
+//! Periodic background jobs. Each run is dispatched onto the actor pool, which spawns it as a
+//! task of its own.
+use crate::prelude::*;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use unknown_actor_lib::prelude::{Dispatch, Job};
+
+/// Run `job` every `period`, starting right away.
+/// A run is skipped while the previous one is still in progress.
+pub(crate) fn schedule<F, Fut>(state: AppStateRef, name: &'static str, period: Duration, job: F)
+where
+    F: Fn(AppStateRef) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let running = Arc::new(AtomicBool::new(false));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            if running.swap(true, Ordering::AcqRel) {
+                debug!(name, "previous run still in progress, skipping");
+                continue;
+            }
+
+            let run = job(state.clone());
+            let done = running.clone();
+            let msg = Job::new(async move {
+                debug!(name, "running job");
+                if let Err(err) = run.await {
+                    error!(name, %err, "job failed");
+                }
+                done.store(false, Ordering::Release);
+            });
+
+            // A full mailbox skips the run like one still in progress, instead of holding up
+            // the schedule until there is room
+            if let Err(err) = state.actor().tell(Dispatch(msg)).try_send() {
+                error!(name, %err, "failed to dispatch job");
+                running.store(false, Ordering::Release);
+            }
+        }
+    });
+}
@@ -1,20 +1,38 @@
-#![allow(clippy::borrow_interior_mutable_const)]
 #![allow(clippy::explicit_auto_deref)]
+mod archive;
+mod compress;
 mod config;
+mod dav;
 mod dto;
 mod error;
+mod files;
+mod ingest;
+mod jobs;
 mod models;
+mod paste;
+mod policy;
 mod prelude;
+mod progress;
+mod reconcile;
 mod routes;
+mod s3;
+mod scan;
+mod scrub;
+mod serve;
 mod state;
+mod storage;
 mod user;
 
 use crate::prelude::*;
-use axum::{Extension, Router, routing::get};
+use axum::{Router, routing::get};
 use axum_login::AuthManagerLayerBuilder;
 use axum_prometheus::PrometheusMetricLayer;
-use fred::prelude::{ClientLike, Config as FredConfig, Pool as FredPool};
+use clap::{Parser, Subcommand};
+use fred::prelude::{
+    Client as FredClient, ClientLike, Config as FredConfig, Pool as FredPool, ReconnectPolicy,
+};
 use memory_serve::{CacheControl, MemoryServe, load_assets};
+use sqlx::PgPool;
 use sqlx::postgres::PgPoolOptions;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -27,8 +45,24 @@ use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitEx
 
 type Result<T, E = Box<dyn core::error::Error>> = std::result::Result<T, E>;
 
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server, the default
+    Serve,
+    /// Copy every blob from STORAGE to STORAGE_TARGET and make the target the active backend
+    MigrateStorage(storage::migrate::MigrateArgs),
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
     dotenvy::dotenv()?;
 
     let fmt_layer = {
@@ -48,8 +82,13 @@ async fn main() -> Result<()> {
     // info!("{:?}",*CONFIG);
     // std::process::exit(0);
 
+    if let Some(Command::MigrateStorage(args)) = cli.command {
+        let pg_pool = connect_pg().await?;
+        return storage::migrate::run(pg_pool, args).await;
+    }
+
     // Fred connection
-    let fred_pool = {
+    let (fred_pool, progress_subscriber) = {
         let s = tracing::info_span!("startup_fred");
         let _ = s.enter();
         let connection = &CONFIG.redis.url;
@@ -57,7 +96,7 @@ async fn main() -> Result<()> {
         info!("Connecting pool");
         let config = FredConfig::from_url(connection)?;
         let pool = FredPool::new(
-            config,
+            config.clone(),
             None,
             None,
             None,
@@ -65,34 +104,51 @@ async fn main() -> Result<()> {
         )?;
 
         info!("Testing connection");
-        let _ = pool.connect();
+        let _connect_handle = pool.connect();
         pool.wait_for_connect().await?;
 
         info!("Connected");
-        pool
+        // Subscribed connections can't run other commands, progress gets one of its own
+        let subscriber = FredClient::new(config, None, None, Some(ReconnectPolicy::default()));
+        (pool, subscriber)
     };
 
-    let pg_pool = {
-        let s = tracing::info_span!("startup_pg");
-        let _ = s.enter();
-        let connection = &CONFIG.database.url;
-        info!("Connecting pool");
-        let pool = PgPoolOptions::new()
-            .max_connections(CONFIG.database.max_connections)
-            .connect(connection)
-            .await?;
-
-        info!("Testing connection");
-        let _ = sqlx::query("SELECT $1").bind(1).fetch_one(&pool).await?;
+    let pg_pool = connect_pg().await?;
 
-        info!("Applying migrations.");
-        sqlx::migrate!("./migrations").run(&pool).await?;
-        info!("Migrations done!");
+    let storage: storage::StorageRef = {
+        let s = tracing::info_span!("startup_storage");
+        let _ = s.enter();
+        info!("Connecting storage");
+        let storage = match &CONFIG.storage_target {
+            Some(target) => {
+                storage::migrate::from_config(&CONFIG.storage, target, &pg_pool).await?
+            }
+            None => storage::from_config(&CONFIG.storage).await?,
+        };
 
-        info!("Connected");
-        pool
+        info!("Using {} storage", storage.name());
+        match storage::Keyring::from_config(&CONFIG.encryption)? {
+            Some(keyring) => {
+                info!("Encrypting stored objects");
+                Arc::new(storage::EncryptedStorage::new(
+                    storage,
+                    keyring,
+                    pg_pool.clone(),
+                ))
+            }
+            // Encrypted objects would be handed out as ciphertext
+            None if models::object_key::ObjectKey::any(&pg_pool).await? => {
+                return Err(
+                    "stored objects are encrypted but no encryption keys are configured".into(),
+                );
+            }
+            None => storage,
+        }
     };
 
+    // Fail on bad globs now rather than on the first upload
+    std::sync::LazyLock::force(&policy::POLICY);
+
     // Metrics
     let (prometheus_layer, metric_handle) = PrometheusMetricLayer::pair();
 
@@ -120,13 +176,115 @@ async fn main() -> Result<()> {
     // Actor pool
     let actor_pool = unknown_actor_lib::pool::pool(None, None).await?;
 
-    let state = Arc::new(AppState::new(pg_pool, fred_pool, actor_pool));
+    let scanner = scan::from_config(&CONFIG.scan);
+
+    let state = Arc::new(AppState::new(
+        pg_pool, fred_pool, actor_pool, storage, scanner,
+    ));
+
+    progress::spawn_subscriber(state.clone(), progress_subscriber);
+
+    // Malware scanning of new uploads
+    scan::spawn_worker(state.clone());
+
+    // Background jobs
+    jobs::schedule(
+        state.clone(),
+        "tus_sweep",
+        std::time::Duration::from_secs(60 * 60),
+        routes::tus::sweep_expired,
+    );
+    jobs::schedule(
+        state.clone(),
+        "storage_sweep",
+        std::time::Duration::from_secs(60 * 60),
+        storage::sweep_incomplete,
+    );
+    jobs::schedule(
+        state.clone(),
+        "presign_sweep",
+        std::time::Duration::from_secs(60 * 60),
+        routes::presign::sweep_abandoned,
+    );
+    jobs::schedule(
+        state.clone(),
+        "trash_purge",
+        std::time::Duration::from_secs(60 * 60),
+        files::purge_trash,
+    );
+    jobs::schedule(
+        state.clone(),
+        "file_expiry",
+        std::time::Duration::from_secs(60 * 60),
+        files::purge_expired,
+    );
+    jobs::schedule(
+        state.clone(),
+        "storage_reconcile",
+        std::time::Duration::from_secs(CONFIG.reconcile.interval),
+        reconcile::reconcile,
+    );
+    if CONFIG.scrub.enabled {
+        jobs::schedule(
+            state.clone(),
+            "storage_scrub",
+            std::time::Duration::from_secs(CONFIG.scrub.interval),
+            scrub::scrub,
+        );
+    }
+    jobs::schedule(
+        state.clone(),
+        "key_rotation",
+        std::time::Duration::from_secs(60 * 60),
+        storage::rotate_keys,
+    );
+    jobs::schedule(
+        state.clone(),
+        "version_prune",
+        std::time::Duration::from_secs(60 * 60),
+        files::prune_versions,
+    );
+    jobs::schedule(
+        state.clone(),
+        "s3_multipart_sweep",
+        std::time::Duration::from_secs(60 * 60),
+        s3::multipart::sweep_expired,
+    );
+
+    // The S3 api wants buckets at the root of a host, so it gets a listener of its own
+    let s3_server = match &CONFIG.s3_api.host {
+        Some(s3_host) => {
+            let s3_router = routes::s3::router()
+                .with_state(state.clone())
+                .layer(prometheus_layer.clone())
+                .layer(TraceLayer::new_for_http());
+            let listener = tokio::net::TcpListener::bind(s3_host).await?;
+            info!("Serving the S3 api on {s3_host}");
+            Some(tokio::spawn(
+                axum::serve(listener, s3_router)
+                    .with_graceful_shutdown(shutdown_signal())
+                    .into_future(),
+            ))
+        }
+        None => None,
+    };
+
     let router = Router::new()
         .route("/", get(|| async { "Hello, World!" }))
         .route("/metrics", get(|| async move { metric_handle.render() }))
         .nest("/auth", routes::auth::router())
         .nest("/upload", routes::upload::router())
+        .nest("/files", routes::files::router())
+        .nest("/folders", routes::folders::router())
+        .nest("/trash", routes::trash::router())
+        .nest("/archive", routes::archive::router())
+        .nest("/paste", routes::paste::router())
+        .merge(routes::dav::router())
         .merge(assets_router)
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            error::htmx_errors,
+        ))
         .with_state(state)
         .layer(auth_layer)
         .layer(prometheus_layer)
@@ -146,11 +304,36 @@ async fn main() -> Result<()> {
     )
     .with_graceful_shutdown(shutdown_signal())
     .await?;
+    if let Some(s3_server) = s3_server {
+        s3_server.await??;
+    }
 
     info!("Done! Exiting...");
     Ok(())
 }
 
+/// Connect to Postgres and bring the schema up to date.
+async fn connect_pg() -> Result<PgPool> {
+    let s = tracing::info_span!("startup_pg");
+    let _ = s.enter();
+    let connection = &CONFIG.database.url;
+    info!("Connecting pool");
+    let pool = PgPoolOptions::new()
+        .max_connections(CONFIG.database.max_connections)
+        .connect(connection)
+        .await?;
+
+    info!("Testing connection");
+    let _ = sqlx::query("SELECT $1").bind(1).fetch_one(&pool).await?;
+
+    info!("Applying migrations.");
+    sqlx::migrate!("./migrations").run(&pool).await?;
+    info!("Migrations done!");
+
+    info!("Connected");
+    Ok(pool)
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor};
+use std::fmt::{Debug, Formatter};
+use uuid::Uuid;
+
+use crate::make_mod;
+
+/// A key pair S3 clients sign their requests with.
+#[derive(FromRow, Clone, Serialize, Deserialize)]
+pub struct AccessKey {
+    /// The access key id clients send along with their signatures
+    pub id: String,
+    pub user_id: Uuid,
+    /// Chosen by the user to tell their keys apart
+    pub name: String,
+    /// The secret access key
+    #[serde(skip)]
+    pub secret: String,
+    pub created: time::OffsetDateTime,
+    pub last_used: Option<time::OffsetDateTime>,
+}
+
+impl Debug for AccessKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessKey")
+            .field("id", &self.id)
+            .field("user_id", &self.user_id)
+            .field("name", &self.name)
+            .field("secret", &"[protected]")
+            .field("created", &self.created)
+            .field("last_used", &self.last_used)
+            .finish()
+    }
+}
+
+impl AccessKey {
+    pub async fn get(db: impl PgExecutor<'_>, id: &str) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM s3_access_keys WHERE id = $1")
+            .bind(id)
+            .fetch_optional(db)
+            .await
+    }
+
+    pub async fn list(db: impl PgExecutor<'_>, user_id: Uuid) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM s3_access_keys WHERE user_id = $1 ORDER BY created")
+            .bind(user_id)
+            .fetch_all(db)
+            .await
+    }
+
+    /// Record that the key was used. Clients send lots of requests in a row, so this only
+    /// writes once a minute.
+    pub async fn touch(db: impl PgExecutor<'_>, id: &str) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE s3_access_keys SET last_used = now() \
+            WHERE id = $1 AND (last_used IS NULL OR last_used < now() - interval '1 minute')",
+        )
+        .bind(id)
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Revoke a key, but only if it belongs to `user_id`. Returns whether it did.
+    pub async fn delete_owned(
+        db: impl PgExecutor<'_>,
+        id: &str,
+        user_id: Uuid,
+    ) -> sqlx::Result<bool> {
+        let deleted = sqlx::query("DELETE FROM s3_access_keys WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(db)
+            .await?;
+        Ok(deleted.rows_affected() > 0)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AccessKeyInsert {
+    pub id: String,
+    pub user_id: Uuid,
+    pub name: String,
+    #[serde(skip)]
+    pub secret: String,
+}
+
+impl Debug for AccessKeyInsert {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessKeyInsert")
+            .field("id", &self.id)
+            .field("user_id", &self.user_id)
+            .field("name", &self.name)
+            .field("secret", &"[protected]")
+            .finish()
+    }
+}
+
+impl AccessKeyInsert {
+    pub fn new(id: String, user_id: Uuid, name: String, secret: String) -> Self {
+        Self {
+            id,
+            user_id,
+            name,
+            secret,
+        }
+    }
+
+    pub async fn insert(self, db: impl PgExecutor<'_>) -> sqlx::Result<AccessKey> {
+        sqlx::query_as(
+            "INSERT INTO s3_access_keys (id, user_id, name, secret) values ($1, $2, $3, $4) \
+            returning *",
+        )
+        .bind(self.id)
+        .bind(self.user_id)
+        .bind(self.name)
+        .bind(self.secret)
+        .fetch_one(db)
+        .await
+    }
+}
+
+make_mod!(prelude AccessKey, AccessKeyInsert);
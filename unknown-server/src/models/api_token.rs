@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor};
+use uuid::Uuid;
+
+use crate::make_mod;
+
+/// A token a user signs in with instead of their password, in clients that can't use the login
+/// form.
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    /// Chosen by the user to tell their tokens apart
+    pub name: String,
+    /// Hex encoded sha256 of the token
+    pub token_hash: String,
+    pub created: time::OffsetDateTime,
+    pub last_used: Option<time::OffsetDateTime>,
+}
+
+impl ApiToken {
+    pub async fn list(db: impl PgExecutor<'_>, user_id: Uuid) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM api_tokens WHERE user_id = $1 ORDER BY created")
+            .bind(user_id)
+            .fetch_all(db)
+            .await
+    }
+
+    pub async fn find_by_hash(
+        db: impl PgExecutor<'_>,
+        token_hash: &str,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM api_tokens WHERE token_hash = $1")
+            .bind(token_hash)
+            .fetch_optional(db)
+            .await
+    }
+
+    pub async fn touch(db: impl PgExecutor<'_>, id: Uuid) -> sqlx::Result<()> {
+        sqlx::query("UPDATE api_tokens SET last_used = now() WHERE id = $1")
+            .bind(id)
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+
+    /// Revoke a token, but only if it belongs to `user_id`. Returns whether it did.
+    pub async fn delete_owned(
+        db: impl PgExecutor<'_>,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> sqlx::Result<bool> {
+        let deleted = sqlx::query("DELETE FROM api_tokens WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(db)
+            .await?;
+        Ok(deleted.rows_affected() > 0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiTokenInsert {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub token_hash: String,
+}
+
+impl ApiTokenInsert {
+    pub fn new(user_id: Uuid, name: String, token_hash: String) -> Self {
+        let id = Uuid::now_v7();
+        Self {
+            id,
+            user_id,
+            name,
+            token_hash,
+        }
+    }
+
+    pub async fn insert(self, db: impl PgExecutor<'_>) -> sqlx::Result<ApiToken> {
+        sqlx::query_as(
+            "INSERT INTO api_tokens (id, user_id, name, token_hash) values ($1, $2, $3, $4) \
+            returning *",
+        )
+        .bind(self.id)
+        .bind(self.user_id)
+        .bind(self.name)
+        .bind(self.token_hash)
+        .fetch_one(db)
+        .await
+    }
+}
+
+make_mod!(prelude ApiToken, ApiTokenInsert);
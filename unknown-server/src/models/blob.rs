@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor};
+
+use crate::make_mod;
+
+/// How the object of a blob is encoded, see `compress.rs`.
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Zstd,
+}
+
+impl Encoding {
+    /// As used in `Content-Encoding`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+/// Stored file contents, shared by every file with the same sha256.
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Blob {
+    /// Hex encoded sha256
+    pub sha256: String,
+    /// Key of the object in storage
+    pub storage_key: String,
+    /// Size in bytes, before encoding
+    pub size: i64,
+    /// `None` if the object holds the contents as they are
+    pub encoding: Option<Encoding>,
+    /// Number of files pointing at this blob
+    pub ref_count: i64,
+    pub created: time::OffsetDateTime,
+    /// Failed malware scans since the last successful one
+    pub scan_attempts: i32,
+    /// A failed scan isn't retried before this
+    pub scan_after: Option<time::OffsetDateTime>,
+    /// When the object was last read back and matched the sha256
+    pub verified: Option<time::OffsetDateTime>,
+    /// When the object was found not to match the sha256 anymore
+    pub corrupt: Option<time::OffsetDateTime>,
+}
+
+impl Blob {
+    pub async fn get(db: impl PgExecutor<'_>, sha256: &str) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM blobs WHERE sha256 = $1")
+            .bind(sha256)
+            .fetch_optional(db)
+            .await
+    }
+
+    /// The blob stored at `storage_key`.
+    pub async fn get_by_key(
+        db: impl PgExecutor<'_>,
+        storage_key: &str,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM blobs WHERE storage_key = $1")
+            .bind(storage_key)
+            .fetch_optional(db)
+            .await
+    }
+
+    /// Every blob recorded before `before`.
+    pub async fn created_before(
+        db: impl PgExecutor<'_>,
+        before: time::OffsetDateTime,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM blobs WHERE created < $1")
+            .bind(before)
+            .fetch_all(db)
+            .await
+    }
+
+    /// Blobs in order of their sha256, starting after `after`.
+    pub async fn page_after(
+        db: impl PgExecutor<'_>,
+        after: Option<&str>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM blobs WHERE $1::text IS NULL OR sha256 > $1 ORDER BY sha256 LIMIT $2",
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(db)
+        .await
+    }
+
+    /// Hashes of contents files or versions point to that have no blob.
+    pub async fn dangling_references(db: impl PgExecutor<'_>) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar(
+            "SELECT DISTINCT contents.sha256 FROM \
+            (SELECT sha256 FROM files UNION ALL SELECT sha256 FROM file_versions) contents \
+            WHERE NOT EXISTS (SELECT 1 FROM blobs WHERE blobs.sha256 = contents.sha256)",
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// Every blob with one of `sha256s`, in no particular order.
+    pub async fn get_many(db: impl PgExecutor<'_>, sha256s: &[String]) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM blobs WHERE sha256 = ANY($1)")
+            .bind(sha256s)
+            .fetch_all(db)
+            .await
+    }
+
+    /// Take a reference to the blob with `sha256`, creating it with `storage_key` encoded with
+    /// `encoding` if it doesn't exist yet. If the returned blob has a different key, the object
+    /// at `storage_key` is a duplicate and can be removed.
+    pub async fn acquire(
+        db: impl PgExecutor<'_>,
+        sha256: &str,
+        storage_key: &str,
+        size: i64,
+        encoding: Option<Encoding>,
+    ) -> sqlx::Result<Self> {
+        sqlx::query_as(
+            "INSERT INTO blobs (sha256, storage_key, size, encoding, ref_count) \
+            values ($1, $2, $3, $4, 1) \
+            ON CONFLICT (sha256) DO UPDATE SET ref_count = blobs.ref_count + 1 returning *",
+        )
+        .bind(sha256)
+        .bind(storage_key)
+        .bind(size)
+        .bind(encoding)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Take another reference to a blob that is already referenced.
+    pub async fn retain(db: impl PgExecutor<'_>, sha256: &str) -> sqlx::Result<()> {
+        sqlx::query("UPDATE blobs SET ref_count = ref_count + 1 WHERE sha256 = $1")
+            .bind(sha256)
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+
+    /// Point a blob at a different object encoded with `encoding`, after its contents were
+    /// copied there.
+    pub async fn relocate(
+        db: impl PgExecutor<'_>,
+        sha256: &str,
+        storage_key: &str,
+        encoding: Option<Encoding>,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "UPDATE blobs SET storage_key = $2, encoding = $3 WHERE sha256 = $1 returning *",
+        )
+        .bind(sha256)
+        .bind(storage_key)
+        .bind(encoding)
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Record a blob for contents files and versions point to that lost theirs, counting them as
+    /// its references. Returns `None` if the contents have a blob already.
+    pub async fn relink(
+        db: impl PgExecutor<'_>,
+        sha256: &str,
+        storage_key: &str,
+        size: i64,
+        encoding: Option<Encoding>,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "INSERT INTO blobs (sha256, storage_key, size, encoding, ref_count) \
+            SELECT $1, $2, $3, $4, (SELECT count(*) FROM files WHERE sha256 = $1) \
+                + (SELECT count(*) FROM file_versions WHERE sha256 = $1) \
+            ON CONFLICT (sha256) DO NOTHING returning *",
+        )
+        .bind(sha256)
+        .bind(storage_key)
+        .bind(size)
+        .bind(encoding)
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Drop the blob with `sha256` no matter how many references it has left.
+    pub async fn forget(db: impl PgExecutor<'_>, sha256: &str) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM blobs WHERE sha256 = $1")
+            .bind(sha256)
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+
+    /// Count a failed scan and put off the next one, doubling the delay every time up to a day.
+    pub async fn scan_failed(db: impl PgExecutor<'_>, sha256: &str) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE blobs SET scan_attempts = scan_attempts + 1, \
+            scan_after = now() + least(interval '1 minute' * power(2, scan_attempts), interval '1 day') \
+            WHERE sha256 = $1",
+        )
+        .bind(sha256)
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Forget failed scans once one went through.
+    pub async fn scan_succeeded(db: impl PgExecutor<'_>, sha256: &str) -> sqlx::Result<()> {
+        sqlx::query("UPDATE blobs SET scan_attempts = 0, scan_after = NULL WHERE sha256 = $1")
+            .bind(sha256)
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+
+    /// Blobs to verify, those never verified first, then those verified longest ago but before
+    /// `before`. Corrupt blobs and those in `skip` are left out.
+    pub async fn due_for_scrub(
+        db: impl PgExecutor<'_>,
+        before: time::OffsetDateTime,
+        skip: &[String],
+        limit: i64,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM blobs WHERE corrupt IS NULL AND (verified IS NULL OR verified < $1) \
+            AND sha256 <> ALL($2) ORDER BY verified NULLS FIRST LIMIT $3",
+        )
+        .bind(before)
+        .bind(skip)
+        .bind(limit)
+        .fetch_all(db)
+        .await
+    }
+
+    /// Record that the object of a blob matched its sha256 just now.
+    pub async fn verified(db: impl PgExecutor<'_>, sha256: &str) -> sqlx::Result<()> {
+        sqlx::query("UPDATE blobs SET verified = now() WHERE sha256 = $1")
+            .bind(sha256)
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+
+    /// Record that the object at `storage_key` doesn't match the sha256 of its blob.
+    /// Returns false if the blob was repaired, moved or marked already in the meantime.
+    pub async fn mark_corrupt(
+        db: impl PgExecutor<'_>,
+        sha256: &str,
+        storage_key: &str,
+    ) -> sqlx::Result<bool> {
+        let result = sqlx::query(
+            "UPDATE blobs SET corrupt = now() \
+            WHERE sha256 = $1 AND storage_key = $2 AND corrupt IS NULL",
+        )
+        .bind(sha256)
+        .bind(storage_key)
+        .execute(db)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Number of blobs marked corrupt.
+    pub async fn count_corrupt(db: impl PgExecutor<'_>) -> sqlx::Result<i64> {
+        sqlx::query_scalar("SELECT count(*) FROM blobs WHERE corrupt IS NOT NULL")
+            .fetch_one(db)
+            .await
+    }
+
+    /// Point a corrupt blob at `storage_key`, a fresh upload of the same contents encoded with
+    /// `encoding`. Returns the key of the corrupt object, `None` if the blob isn't corrupt.
+    pub async fn repair(
+        db: impl PgExecutor<'_>,
+        sha256: &str,
+        storage_key: &str,
+        encoding: Option<Encoding>,
+    ) -> sqlx::Result<Option<String>> {
+        sqlx::query_scalar(
+            "UPDATE blobs SET storage_key = $2, encoding = $3, corrupt = NULL, verified = now() \
+            FROM (SELECT storage_key FROM blobs WHERE sha256 = $1 AND corrupt IS NOT NULL FOR UPDATE) old \
+            WHERE blobs.sha256 = $1 returning old.storage_key",
+        )
+        .bind(sha256)
+        .bind(storage_key)
+        .bind(encoding)
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Drop a reference to the blob with `sha256`.
+    /// Returns the blob once nothing references it anymore, its object should then be removed.
+    pub async fn release(tx: &mut sqlx::PgConnection, sha256: &str) -> sqlx::Result<Option<Self>> {
+        sqlx::query("UPDATE blobs SET ref_count = ref_count - 1 WHERE sha256 = $1")
+            .bind(sha256)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query_as("DELETE FROM blobs WHERE sha256 = $1 AND ref_count <= 0 returning *")
+            .bind(sha256)
+            .fetch_optional(&mut *tx)
+            .await
+    }
+}
+
+make_mod!(prelude Blob, Encoding);
@@ -0,0 +1,524 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor};
+use uuid::Uuid;
+
+use crate::make_mod;
+
+/// Where the contents of a file are in malware scanning, or that they were damaged in storage.
+/// Only clean contents are ever served.
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    /// Waiting for the scanner
+    Pending,
+    Clean,
+    /// Quarantined, never served
+    Infected,
+    /// The scanner failed, it is tried again later
+    Error,
+    /// The stored contents no longer match their sha256, never served
+    Corrupt,
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct File {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    /// `None` for files at the root
+    pub folder_id: Option<Uuid>,
+    /// Original filename
+    pub name: String,
+    /// Size in bytes
+    pub size: i64,
+    /// Content type the client sent
+    pub mime_type: String,
+    /// Content type sniffed from the contents, if recognized
+    pub detected_type: Option<String>,
+    /// Hex encoded sha256
+    pub sha256: String,
+    pub created: time::OffsetDateTime,
+    pub modified: time::OffsetDateTime,
+    /// Current version, counts up with every upload to the same path
+    pub version: i32,
+    /// When the current version was uploaded
+    pub uploaded: time::OffsetDateTime,
+    /// Set while the file is in the trash
+    pub deleted_at: Option<time::OffsetDateTime>,
+    /// The trashed folder this file went to the trash with
+    pub trashed_by: Option<Uuid>,
+    pub status: FileStatus,
+    /// When the file is deleted. Expired files are hidden until the `file_expiry` job gets to
+    /// them
+    pub expires: Option<time::OffsetDateTime>,
+}
+
+impl File {
+    /// The content type the file is served with. What the contents are wins over what the
+    /// client claimed.
+    pub fn content_type(&self) -> &str {
+        self.detected_type.as_deref().unwrap_or(&self.mime_type)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= time::OffsetDateTime::now_utc())
+    }
+
+    /// Get a file, but only if it belongs to `owner_id`.
+    pub async fn get_owned(
+        db: impl PgExecutor<'_>,
+        id: Uuid,
+        owner_id: Uuid,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM files WHERE id = $1 AND owner_id = $2 AND deleted_at IS NULL \
+            AND (expires IS NULL OR expires > now())",
+        )
+        .bind(id)
+        .bind(owner_id)
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Get files, but only those that belong to `owner_id`.
+    pub async fn get_many_owned(
+        db: impl PgExecutor<'_>,
+        ids: &[Uuid],
+        owner_id: Uuid,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM files WHERE id = ANY($1) AND owner_id = $2 AND deleted_at IS NULL \
+            AND (expires IS NULL OR expires > now()) ORDER BY name",
+        )
+        .bind(ids)
+        .bind(owner_id)
+        .fetch_all(db)
+        .await
+    }
+
+    /// Files in any of `folder_ids`, ordered by name.
+    pub async fn in_folders(
+        db: impl PgExecutor<'_>,
+        owner_id: Uuid,
+        folder_ids: &[Uuid],
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM files WHERE owner_id = $1 AND folder_id = ANY($2) \
+            AND deleted_at IS NULL AND (expires IS NULL OR expires > now()) ORDER BY name",
+        )
+        .bind(owner_id)
+        .bind(folder_ids)
+        .fetch_all(db)
+        .await
+    }
+
+    /// Find the file called `name` in `folder_id`. Expired files are found too, uploads to
+    /// their path replace them.
+    pub async fn find_in_folder(
+        db: impl PgExecutor<'_>,
+        owner_id: Uuid,
+        folder_id: Option<Uuid>,
+        name: &str,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM files \
+            WHERE owner_id = $1 AND folder_id IS NOT DISTINCT FROM $2 AND name = $3 \
+            AND deleted_at IS NULL",
+        )
+        .bind(owner_id)
+        .bind(folder_id)
+        .bind(name)
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Files directly in `folder_id`, ordered by name.
+    pub async fn in_folder(
+        db: impl PgExecutor<'_>,
+        owner_id: Uuid,
+        folder_id: Option<Uuid>,
+        limit: i64,
+        offset: i64,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM files WHERE owner_id = $1 AND folder_id IS NOT DISTINCT FROM $2 \
+            AND deleted_at IS NULL AND (expires IS NULL OR expires > now()) \
+            ORDER BY name LIMIT $3 OFFSET $4",
+        )
+        .bind(owner_id)
+        .bind(folder_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(db)
+        .await
+    }
+
+    pub async fn count_in_folder(
+        db: impl PgExecutor<'_>,
+        owner_id: Uuid,
+        folder_id: Option<Uuid>,
+    ) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "SELECT count(*) FROM files WHERE owner_id = $1 AND folder_id IS NOT DISTINCT FROM $2 \
+            AND deleted_at IS NULL AND (expires IS NULL OR expires > now())",
+        )
+        .bind(owner_id)
+        .bind(folder_id)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Files below `folder_id` as objects keyed by their path relative to it, in byte order of
+    /// the keys. Only keys after `after` that start with `prefix` are listed. Empty folders
+    /// are listed as objects too, with keys ending in `/`, so they don't get lost.
+    pub async fn list_below(
+        db: impl PgExecutor<'_>,
+        folder_id: Uuid,
+        prefix: &str,
+        after: &str,
+        limit: i64,
+    ) -> sqlx::Result<Vec<ListedObject>> {
+        sqlx::query_as(
+            "WITH RECURSIVE tree AS ( \
+                SELECT id, '' AS path, modified FROM folders WHERE id = $1 \
+                UNION ALL \
+                SELECT folders.id, tree.path || folders.name || '/', folders.modified \
+                FROM folders JOIN tree ON folders.parent_id = tree.id \
+                WHERE folders.deleted_at IS NULL \
+                AND (starts_with(tree.path || folders.name || '/', $2) \
+                    OR starts_with($2, tree.path || folders.name || '/')) \
+            ), \
+            objects AS ( \
+                SELECT tree.path || files.name AS key, files.size, files.sha256, \
+                    files.uploaded AS modified \
+                FROM files JOIN tree ON files.folder_id = tree.id \
+                WHERE files.deleted_at IS NULL \
+                AND (files.expires IS NULL OR files.expires > now()) \
+                UNION ALL \
+                SELECT tree.path, 0, NULL, tree.modified FROM tree WHERE tree.path <> '' \
+                AND NOT EXISTS (SELECT 1 FROM folders \
+                    WHERE parent_id = tree.id AND deleted_at IS NULL) \
+                AND NOT EXISTS (SELECT 1 FROM files \
+                    WHERE folder_id = tree.id AND deleted_at IS NULL \
+                    AND (expires IS NULL OR expires > now())) \
+            ) \
+            SELECT * FROM objects \
+            WHERE starts_with(key, $2) AND key COLLATE \"C\" > $3 \
+            ORDER BY key COLLATE \"C\" LIMIT $4",
+        )
+        .bind(folder_id)
+        .bind(prefix)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(db)
+        .await
+    }
+
+    /// Move and rename a file.
+    pub async fn update(
+        db: impl PgExecutor<'_>,
+        id: Uuid,
+        folder_id: Option<Uuid>,
+        name: &str,
+    ) -> sqlx::Result<Self> {
+        sqlx::query_as(
+            "UPDATE files SET folder_id = $2, name = $3, modified = now() \
+            WHERE id = $1 returning *",
+        )
+        .bind(id)
+        .bind(folder_id)
+        .bind(name)
+        .fetch_one(db)
+        .await
+    }
+
+    /// A file the user put in the trash themselves, as opposed to one trashed with its folder.
+    pub async fn get_trashed(
+        db: impl PgExecutor<'_>,
+        id: Uuid,
+        owner_id: Uuid,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM files WHERE id = $1 AND owner_id = $2 \
+            AND deleted_at IS NOT NULL AND trashed_by IS NULL",
+        )
+        .bind(id)
+        .bind(owner_id)
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Files the user put in the trash, most recently deleted first.
+    pub async fn trashed(
+        db: impl PgExecutor<'_>,
+        owner_id: Uuid,
+        limit: i64,
+        offset: i64,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM files WHERE owner_id = $1 AND deleted_at IS NOT NULL \
+            AND trashed_by IS NULL ORDER BY deleted_at DESC LIMIT $2 OFFSET $3",
+        )
+        .bind(owner_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(db)
+        .await
+    }
+
+    pub async fn count_trashed(db: impl PgExecutor<'_>, owner_id: Uuid) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "SELECT count(*) FROM files WHERE owner_id = $1 AND deleted_at IS NOT NULL \
+            AND trashed_by IS NULL",
+        )
+        .bind(owner_id)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Files of any user that were put in the trash before `before`.
+    pub async fn trashed_before(
+        db: impl PgExecutor<'_>,
+        before: time::OffsetDateTime,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM files WHERE deleted_at < $1 AND trashed_by IS NULL")
+            .bind(before)
+            .fetch_all(db)
+            .await
+    }
+
+    /// Files of any user with the contents `sha256`, whether they are in the trash or not.
+    pub async fn with_content(db: impl PgExecutor<'_>, sha256: &str) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM files WHERE sha256 = $1")
+            .bind(sha256)
+            .fetch_all(db)
+            .await
+    }
+
+    /// Files of any user that have expired, whether they are in the trash or not.
+    pub async fn expired(db: impl PgExecutor<'_>) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM files WHERE expires <= now()")
+            .fetch_all(db)
+            .await
+    }
+
+    pub async fn trash(db: impl PgExecutor<'_>, id: Uuid) -> sqlx::Result<()> {
+        sqlx::query("UPDATE files SET deleted_at = now() WHERE id = $1 AND deleted_at IS NULL")
+            .bind(id)
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+
+    /// Take a file out of the trash and put it in `folder_id`.
+    pub async fn restore(
+        db: impl PgExecutor<'_>,
+        id: Uuid,
+        folder_id: Option<Uuid>,
+    ) -> sqlx::Result<Self> {
+        sqlx::query_as(
+            "UPDATE files SET deleted_at = NULL, trashed_by = NULL, folder_id = $2 \
+            WHERE id = $1 returning *",
+        )
+        .bind(id)
+        .bind(folder_id)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Make new contents the current version of a file, which expires at `expires` from now on.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn replace_content(
+        db: impl PgExecutor<'_>,
+        id: Uuid,
+        size: i64,
+        mime_type: &str,
+        detected_type: Option<&str>,
+        sha256: &str,
+        status: FileStatus,
+        expires: Option<time::OffsetDateTime>,
+    ) -> sqlx::Result<Self> {
+        sqlx::query_as(
+            "UPDATE files SET size = $2, mime_type = $3, detected_type = $4, sha256 = $5, \
+            status = $6, expires = $7, version = version + 1, uploaded = now(), modified = now() \
+            WHERE id = $1 returning *",
+        )
+        .bind(id)
+        .bind(size)
+        .bind(mime_type)
+        .bind(detected_type)
+        .bind(sha256)
+        .bind(status)
+        .bind(expires)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Hashes of contents that still have to be scanned, in files or in earlier versions.
+    /// Contents never scanned come first, oldest upload first. Contents whose last scan failed
+    /// are left out until their retry is due.
+    pub async fn unscanned_contents(
+        db: impl PgExecutor<'_>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar(
+            "WITH unscanned AS ( \
+                SELECT sha256, status, uploaded FROM files WHERE status IN ('pending', 'error') \
+                UNION ALL SELECT sha256, status, created FROM file_versions \
+                WHERE status IN ('pending', 'error') \
+            ) \
+            SELECT unscanned.sha256 FROM unscanned JOIN blobs USING (sha256) \
+            WHERE blobs.scan_after IS NULL OR blobs.scan_after <= now() \
+            GROUP BY unscanned.sha256 \
+            ORDER BY bool_or(unscanned.status = 'pending') DESC, min(unscanned.uploaded) \
+            LIMIT $1",
+        )
+        .bind(limit)
+        .fetch_all(db)
+        .await
+    }
+
+    /// Set the status of every file with the contents `sha256` that is in one of `from`.
+    /// Returns how many files changed.
+    pub async fn set_status_by_content(
+        db: impl PgExecutor<'_>,
+        sha256: &str,
+        from: &[FileStatus],
+        status: FileStatus,
+    ) -> sqlx::Result<u64> {
+        let result =
+            sqlx::query("UPDATE files SET status = $3 WHERE sha256 = $1 AND status = ANY($2)")
+                .bind(sha256)
+                .bind(from)
+                .bind(status)
+                .execute(db)
+                .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Delete every file in any of `folder_ids`.
+    pub async fn delete_in_folders(
+        db: impl PgExecutor<'_>,
+        folder_ids: &[Uuid],
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("DELETE FROM files WHERE folder_id = ANY($1) returning *")
+            .bind(folder_ids)
+            .fetch_all(db)
+            .await
+    }
+
+    pub async fn delete(db: impl PgExecutor<'_>, id: Uuid) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("DELETE FROM files WHERE id = $1 returning *")
+            .bind(id)
+            .fetch_optional(db)
+            .await
+    }
+}
+
+// Convert FileInsert into File
+impl From<FileInsert> for File {
+    fn from(value: FileInsert) -> Self {
+        let now = time::OffsetDateTime::now_local().expect("Cannot get time");
+        Self {
+            id: value.id,
+            owner_id: value.owner_id,
+            folder_id: value.folder_id,
+            name: value.name,
+            size: value.size,
+            mime_type: value.mime_type,
+            detected_type: value.detected_type,
+            sha256: value.sha256,
+            created: now,
+            modified: now,
+            version: 1,
+            uploaded: now,
+            deleted_at: None,
+            trashed_by: None,
+            status: value.status,
+            expires: value.expires,
+        }
+    }
+}
+
+/// A file or empty folder as an object in a listing, see [`File::list_below`].
+#[derive(FromRow, Debug, Clone)]
+pub struct ListedObject {
+    pub key: String,
+    pub size: i64,
+    /// `None` for folders
+    pub sha256: Option<String>,
+    pub modified: time::OffsetDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileInsert {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub folder_id: Option<Uuid>,
+    pub name: String,
+    pub size: i64,
+    pub mime_type: String,
+    pub detected_type: Option<String>,
+    pub sha256: String,
+    pub status: FileStatus,
+    pub expires: Option<time::OffsetDateTime>,
+}
+
+impl FileInsert {
+    pub fn new(
+        owner_id: Uuid,
+        folder_id: Option<Uuid>,
+        name: String,
+        size: i64,
+        mime_type: String,
+        sha256: String,
+        status: FileStatus,
+    ) -> Self {
+        let id = Uuid::now_v7();
+        Self {
+            id,
+            owner_id,
+            folder_id,
+            name,
+            size,
+            mime_type,
+            detected_type: None,
+            sha256,
+            status,
+            expires: None,
+        }
+    }
+
+    pub fn with_detected_type(mut self, detected_type: Option<String>) -> Self {
+        self.detected_type = detected_type;
+        self
+    }
+
+    pub fn with_expires(mut self, expires: Option<time::OffsetDateTime>) -> Self {
+        self.expires = expires;
+        self
+    }
+
+    pub async fn insert(self, db: impl PgExecutor<'_>) -> sqlx::Result<File> {
+        sqlx::query_as(
+            "INSERT INTO files \
+            (id, owner_id, folder_id, name, size, mime_type, detected_type, sha256, status, \
+            expires) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) returning *",
+        )
+        .bind(self.id)
+        .bind(self.owner_id)
+        .bind(self.folder_id)
+        .bind(self.name)
+        .bind(self.size)
+        .bind(self.mime_type)
+        .bind(self.detected_type)
+        .bind(self.sha256)
+        .bind(self.status)
+        .bind(self.expires)
+        .fetch_one(db)
+        .await
+    }
+}
+
+make_mod!(prelude File, FileInsert, FileStatus, ListedObject);
@@ -0,0 +1,432 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection, PgExecutor};
+use uuid::Uuid;
+
+use crate::make_mod;
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Folder {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    /// `None` for folders at the root
+    pub parent_id: Option<Uuid>,
+    pub name: String,
+    pub created: time::OffsetDateTime,
+    pub modified: time::OffsetDateTime,
+    /// Set while the folder is in the trash
+    pub deleted_at: Option<time::OffsetDateTime>,
+    /// The trashed folder this folder went to the trash with
+    pub trashed_by: Option<Uuid>,
+}
+
+impl Folder {
+    /// Get a folder, but only if it belongs to `owner_id`.
+    pub async fn get_owned(
+        db: impl PgExecutor<'_>,
+        id: Uuid,
+        owner_id: Uuid,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM folders WHERE id = $1 AND owner_id = $2 AND deleted_at IS NULL",
+        )
+        .bind(id)
+        .bind(owner_id)
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Find the folder called `name` in `parent`.
+    pub async fn find_child(
+        db: impl PgExecutor<'_>,
+        owner_id: Uuid,
+        parent_id: Option<Uuid>,
+        name: &str,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM folders \
+            WHERE owner_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND name = $3 \
+            AND deleted_at IS NULL",
+        )
+        .bind(owner_id)
+        .bind(parent_id)
+        .bind(name)
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Walk the folders of `owner_id` down `names` from the root. Returns the id of the last
+    /// one, `Some(None)` for the root, or `None` if one of them doesn't exist.
+    pub async fn walk<'c>(
+        db: impl PgExecutor<'c> + Copy,
+        owner_id: Uuid,
+        names: &[impl AsRef<str>],
+    ) -> sqlx::Result<Option<Option<Uuid>>> {
+        let mut parent_id = None;
+        for name in names {
+            let Some(folder) = Self::find_child(db, owner_id, parent_id, name.as_ref()).await?
+            else {
+                return Ok(None);
+            };
+            parent_id = Some(folder.id);
+        }
+        Ok(Some(parent_id))
+    }
+
+    /// Folders directly in `parent`, ordered by name.
+    pub async fn children(
+        db: impl PgExecutor<'_>,
+        owner_id: Uuid,
+        parent_id: Option<Uuid>,
+        limit: i64,
+        offset: i64,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM folders WHERE owner_id = $1 AND parent_id IS NOT DISTINCT FROM $2 \
+            AND deleted_at IS NULL ORDER BY name LIMIT $3 OFFSET $4",
+        )
+        .bind(owner_id)
+        .bind(parent_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(db)
+        .await
+    }
+
+    pub async fn count_children(
+        db: impl PgExecutor<'_>,
+        owner_id: Uuid,
+        parent_id: Option<Uuid>,
+    ) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "SELECT count(*) FROM folders WHERE owner_id = $1 AND parent_id IS NOT DISTINCT FROM $2 \
+            AND deleted_at IS NULL",
+        )
+        .bind(owner_id)
+        .bind(parent_id)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Whether `id` is `ancestor` or somewhere below it.
+    pub async fn is_within(
+        db: impl PgExecutor<'_>,
+        id: Uuid,
+        ancestor: Uuid,
+    ) -> sqlx::Result<bool> {
+        sqlx::query_scalar(
+            "WITH RECURSIVE ancestors AS ( \
+                SELECT id, parent_id FROM folders WHERE id = $1 \
+                UNION ALL \
+                SELECT folders.id, folders.parent_id FROM folders \
+                JOIN ancestors ON folders.id = ancestors.parent_id \
+            ) \
+            SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = $2)",
+        )
+        .bind(id)
+        .bind(ancestor)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Ids of `id` and every folder below it.
+    pub async fn subtree(db: impl PgExecutor<'_>, id: Uuid) -> sqlx::Result<Vec<Uuid>> {
+        sqlx::query_scalar(
+            "WITH RECURSIVE subtree AS ( \
+                SELECT id FROM folders WHERE id = $1 \
+                UNION ALL \
+                SELECT folders.id FROM folders JOIN subtree ON folders.parent_id = subtree.id \
+            ) \
+            SELECT id FROM subtree",
+        )
+        .bind(id)
+        .fetch_all(db)
+        .await
+    }
+
+    /// `id` and every folder below it that isn't in the trash, with their paths relative to the
+    /// parent of `id`. Parents come before their children.
+    pub async fn subtree_paths(db: impl PgExecutor<'_>, id: Uuid) -> sqlx::Result<Vec<FolderPath>> {
+        sqlx::query_as(
+            "WITH RECURSIVE subtree AS ( \
+                SELECT id, name AS path, 0 AS depth FROM folders WHERE id = $1 \
+                UNION ALL \
+                SELECT folders.id, subtree.path || '/' || folders.name, subtree.depth + 1 \
+                FROM folders JOIN subtree ON folders.parent_id = subtree.id \
+                WHERE folders.deleted_at IS NULL \
+            ) \
+            SELECT id, path FROM subtree ORDER BY depth, path",
+        )
+        .bind(id)
+        .fetch_all(db)
+        .await
+    }
+
+    /// A folder the user put in the trash themselves, as opposed to one trashed with its parent.
+    pub async fn get_trashed(
+        db: impl PgExecutor<'_>,
+        id: Uuid,
+        owner_id: Uuid,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM folders WHERE id = $1 AND owner_id = $2 \
+            AND deleted_at IS NOT NULL AND trashed_by IS NULL",
+        )
+        .bind(id)
+        .bind(owner_id)
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Folders the user put in the trash, most recently deleted first.
+    pub async fn trashed(
+        db: impl PgExecutor<'_>,
+        owner_id: Uuid,
+        limit: i64,
+        offset: i64,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM folders WHERE owner_id = $1 AND deleted_at IS NOT NULL \
+            AND trashed_by IS NULL ORDER BY deleted_at DESC LIMIT $2 OFFSET $3",
+        )
+        .bind(owner_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(db)
+        .await
+    }
+
+    pub async fn count_trashed(db: impl PgExecutor<'_>, owner_id: Uuid) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "SELECT count(*) FROM folders WHERE owner_id = $1 AND deleted_at IS NOT NULL \
+            AND trashed_by IS NULL",
+        )
+        .bind(owner_id)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Folders of any user that were put in the trash before `before`.
+    pub async fn trashed_before(
+        db: impl PgExecutor<'_>,
+        before: time::OffsetDateTime,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM folders WHERE deleted_at < $1 AND trashed_by IS NULL")
+            .bind(before)
+            .fetch_all(db)
+            .await
+    }
+
+    /// Move a folder to the trash, along with everything in it that isn't trashed yet.
+    pub async fn trash(tx: &mut PgConnection, id: Uuid) -> sqlx::Result<()> {
+        let now = time::OffsetDateTime::now_utc();
+        let descendants: Vec<Uuid> = sqlx::query_scalar(
+            "WITH RECURSIVE subtree AS ( \
+                SELECT id FROM folders WHERE id = $1 \
+                UNION ALL \
+                SELECT folders.id FROM folders JOIN subtree ON folders.parent_id = subtree.id \
+                WHERE folders.deleted_at IS NULL \
+            ) \
+            SELECT id FROM subtree",
+        )
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        sqlx::query("UPDATE folders SET deleted_at = $2 WHERE id = $1")
+            .bind(id)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "UPDATE folders SET deleted_at = $2, trashed_by = $1 \
+            WHERE id = ANY($3) AND id <> $1",
+        )
+        .bind(id)
+        .bind(now)
+        .bind(&descendants)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "UPDATE files SET deleted_at = $2, trashed_by = $1 \
+            WHERE folder_id = ANY($3) AND deleted_at IS NULL",
+        )
+        .bind(id)
+        .bind(now)
+        .bind(&descendants)
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Take a folder out of the trash and put it in `parent_id`, along with everything that was
+    /// trashed with it.
+    pub async fn restore(
+        tx: &mut PgConnection,
+        id: Uuid,
+        parent_id: Option<Uuid>,
+    ) -> sqlx::Result<Self> {
+        sqlx::query(
+            "UPDATE folders SET deleted_at = NULL, trashed_by = NULL WHERE trashed_by = $1",
+        )
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("UPDATE files SET deleted_at = NULL, trashed_by = NULL WHERE trashed_by = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query_as(
+            "UPDATE folders SET deleted_at = NULL, parent_id = $2 WHERE id = $1 returning *",
+        )
+        .bind(id)
+        .bind(parent_id)
+        .fetch_one(&mut *tx)
+        .await
+    }
+
+    /// Move and rename a folder.
+    pub async fn update(
+        db: impl PgExecutor<'_>,
+        id: Uuid,
+        parent_id: Option<Uuid>,
+        name: &str,
+    ) -> sqlx::Result<Self> {
+        sqlx::query_as(
+            "UPDATE folders SET parent_id = $2, name = $3, modified = now() \
+            WHERE id = $1 returning *",
+        )
+        .bind(id)
+        .bind(parent_id)
+        .bind(name)
+        .fetch_one(db)
+        .await
+    }
+
+    pub async fn delete(db: impl PgExecutor<'_>, id: Uuid) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM folders WHERE id = $1")
+            .bind(id)
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+}
+
+/// A folder with its path below some other folder.
+#[derive(FromRow, Debug, Clone)]
+pub struct FolderPath {
+    pub id: Uuid,
+    /// Names from the top folder down, joined with `/`
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderInsert {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub name: String,
+}
+
+impl FolderInsert {
+    pub fn new(owner_id: Uuid, parent_id: Option<Uuid>, name: String) -> Self {
+        let id = Uuid::now_v7();
+        Self {
+            id,
+            owner_id,
+            parent_id,
+            name,
+        }
+    }
+
+    pub async fn insert(self, db: impl PgExecutor<'_>) -> sqlx::Result<Folder> {
+        sqlx::query_as(
+            "INSERT INTO folders (id, owner_id, parent_id, name) values ($1, $2, $3, $4) \
+            returning *",
+        )
+        .bind(self.id)
+        .bind(self.owner_id)
+        .bind(self.parent_id)
+        .bind(self.name)
+        .fetch_one(db)
+        .await
+    }
+}
+
+make_mod!(prelude Folder, FolderInsert, FolderPath);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::PgPool;
+
+    async fn user(db: &PgPool) -> Uuid {
+        let id = Uuid::now_v7();
+        sqlx::query("INSERT INTO users (id, username, email, pw_hash) VALUES ($1, $2, $3, '')")
+            .bind(id)
+            .bind(id.to_string())
+            .bind(format!("{id}@example.com"))
+            .execute(db)
+            .await
+            .unwrap();
+        id
+    }
+
+    async fn folder(db: &PgPool, owner_id: Uuid, parent_id: Option<Uuid>, name: &str) -> Folder {
+        FolderInsert::new(owner_id, parent_id, name.into())
+            .insert(db)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn walks_paths(db: PgPool) {
+        let owner = user(&db).await;
+        let docs = folder(&db, owner, None, "docs").await;
+        let year = folder(&db, owner, Some(docs.id), "2026").await;
+        folder(&db, owner, None, "2026").await;
+
+        let walk = |names: &'static [&'static str]| Folder::walk(&db, owner, names);
+        assert_eq!(walk(&[]).await.unwrap(), Some(None));
+        assert_eq!(walk(&["docs"]).await.unwrap(), Some(Some(docs.id)));
+        assert_eq!(walk(&["docs", "2026"]).await.unwrap(), Some(Some(year.id)));
+        assert_eq!(walk(&["2026", "docs"]).await.unwrap(), None);
+        assert_eq!(walk(&["docs", "2025"]).await.unwrap(), None);
+
+        // Folders of other users and folders in the trash are not found
+        let other = user(&db).await;
+        assert_eq!(Folder::walk(&db, other, &["docs"]).await.unwrap(), None);
+        let mut tx = db.begin().await.unwrap();
+        Folder::trash(&mut tx, year.id).await.unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(walk(&["docs", "2026"]).await.unwrap(), None);
+    }
+
+    #[sqlx::test]
+    async fn finds_descendants(db: PgPool) {
+        let owner = user(&db).await;
+        let docs = folder(&db, owner, None, "docs").await;
+        let year = folder(&db, owner, Some(docs.id), "2026").await;
+        let month = folder(&db, owner, Some(year.id), "05").await;
+        let other = folder(&db, owner, None, "other").await;
+
+        // Moving `docs` into any of these would form a cycle
+        for id in [docs.id, year.id, month.id] {
+            assert!(Folder::is_within(&db, id, docs.id).await.unwrap());
+        }
+        assert!(!Folder::is_within(&db, other.id, docs.id).await.unwrap());
+        assert!(!Folder::is_within(&db, docs.id, month.id).await.unwrap());
+
+        let mut subtree = Folder::subtree(&db, docs.id).await.unwrap();
+        subtree.sort();
+        assert_eq!(subtree, vec![docs.id, year.id, month.id]);
+        let paths: Vec<String> = Folder::subtree_paths(&db, year.id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|folder| folder.path)
+            .collect();
+        assert_eq!(paths, vec!["2026", "2026/05"]);
+    }
+}
@@ -1 +1,10 @@
+pub(crate) mod access_key;
+pub(crate) mod api_token;
+pub(crate) mod blob;
+pub(crate) mod file;
+pub(crate) mod folder;
+pub(crate) mod object_key;
+pub(crate) mod paste;
+pub(crate) mod storage_migration;
 pub(crate) mod user;
+pub(crate) mod version;
@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor};
+
+use crate::make_mod;
+
+/// The data key of an encrypted object in storage.
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectKey {
+    pub storage_key: String,
+    /// Id of the master key `data_key` is wrapped with
+    pub key_id: String,
+    /// Nonce followed by the sealed data key
+    pub data_key: Vec<u8>,
+    /// Plain text size of the object
+    pub size: i64,
+    pub created: time::OffsetDateTime,
+}
+
+impl ObjectKey {
+    pub async fn get(db: impl PgExecutor<'_>, storage_key: &str) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM object_keys WHERE storage_key = $1")
+            .bind(storage_key)
+            .fetch_optional(db)
+            .await
+    }
+
+    /// Whether any object is encrypted at all.
+    pub async fn any(db: impl PgExecutor<'_>) -> sqlx::Result<bool> {
+        let exists: crate::prelude::DBExists =
+            sqlx::query_as("SELECT EXISTS (SELECT 1 FROM object_keys) AS exists")
+                .fetch_one(db)
+                .await?;
+        Ok(exists.exists())
+    }
+
+    /// Keys wrapped with any other master key than `key_id`, by storage key and starting after
+    /// `after`.
+    pub async fn wrapped_with_other(
+        db: impl PgExecutor<'_>,
+        key_id: &str,
+        after: &str,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "SELECT * FROM object_keys WHERE key_id <> $1 AND storage_key > $2 \
+            ORDER BY storage_key LIMIT $3",
+        )
+        .bind(key_id)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(db)
+        .await
+    }
+
+    /// Replace the wrapped key, unless the object was rewritten since it was read.
+    pub async fn rewrap(
+        db: impl PgExecutor<'_>,
+        storage_key: &str,
+        old_key_id: &str,
+        old_data_key: &[u8],
+        key_id: &str,
+        data_key: &[u8],
+    ) -> sqlx::Result<bool> {
+        let updated = sqlx::query(
+            "UPDATE object_keys SET key_id = $4, data_key = $5 \
+            WHERE storage_key = $1 AND key_id = $2 AND data_key = $3",
+        )
+        .bind(storage_key)
+        .bind(old_key_id)
+        .bind(old_data_key)
+        .bind(key_id)
+        .bind(data_key)
+        .execute(db)
+        .await?;
+        Ok(updated.rows_affected() > 0)
+    }
+
+    pub async fn delete(db: impl PgExecutor<'_>, storage_key: &str) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM object_keys WHERE storage_key = $1")
+            .bind(storage_key)
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectKeyInsert {
+    pub storage_key: String,
+    pub key_id: String,
+    pub data_key: Vec<u8>,
+    pub size: i64,
+}
+
+impl ObjectKeyInsert {
+    pub fn new(storage_key: String, key_id: String, data_key: Vec<u8>, size: i64) -> Self {
+        Self {
+            storage_key,
+            key_id,
+            data_key,
+            size,
+        }
+    }
+
+    /// Insert the key, replacing the one of an object previously stored under the same key.
+    pub async fn insert(self, db: impl PgExecutor<'_>) -> sqlx::Result<ObjectKey> {
+        sqlx::query_as(
+            "INSERT INTO object_keys (storage_key, key_id, data_key, size) \
+            values ($1, $2, $3, $4) \
+            ON CONFLICT (storage_key) DO UPDATE \
+            SET key_id = $2, data_key = $3, size = $4, created = now() returning *",
+        )
+        .bind(self.storage_key)
+        .bind(self.key_id)
+        .bind(self.data_key)
+        .bind(self.size)
+        .fetch_one(db)
+        .await
+    }
+}
+
+make_mod!(prelude ObjectKey, ObjectKeyInsert);
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor};
+use uuid::Uuid;
+
+use crate::make_mod;
+
+/// A text snippet shared by link, see `/paste`.
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Paste {
+    pub id: Uuid,
+    /// The file holding the text
+    pub file_id: Uuid,
+    pub owner_id: Uuid,
+    pub title: Option<String>,
+    /// Name of the syntax the text is highlighted with, detected when `None`
+    pub language: Option<String>,
+    pub created: time::OffsetDateTime,
+}
+
+impl Paste {
+    /// Get a paste. It expires with its file.
+    pub async fn get(db: impl PgExecutor<'_>, id: Uuid) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM pastes WHERE id = $1")
+            .bind(id)
+            .fetch_optional(db)
+            .await
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasteInsert {
+    pub id: Uuid,
+    pub file_id: Uuid,
+    pub owner_id: Uuid,
+    pub title: Option<String>,
+    pub language: Option<String>,
+}
+
+impl PasteInsert {
+    pub fn new(id: Uuid, file_id: Uuid, owner_id: Uuid) -> Self {
+        Self {
+            id,
+            file_id,
+            owner_id,
+            title: None,
+            language: None,
+        }
+    }
+
+    pub fn with_title(mut self, title: Option<String>) -> Self {
+        self.title = title;
+        self
+    }
+
+    pub fn with_language(mut self, language: Option<String>) -> Self {
+        self.language = language;
+        self
+    }
+
+    pub async fn insert(self, db: impl PgExecutor<'_>) -> sqlx::Result<Paste> {
+        sqlx::query_as(
+            "INSERT INTO pastes (id, file_id, owner_id, title, language) \
+            values ($1, $2, $3, $4, $5) returning *",
+        )
+        .bind(self.id)
+        .bind(self.file_id)
+        .bind(self.owner_id)
+        .bind(self.title)
+        .bind(self.language)
+        .fetch_one(db)
+        .await
+    }
+}
+
+make_mod!(prelude Paste, PasteInsert);
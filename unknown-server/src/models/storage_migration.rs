@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor};
+
+use crate::make_mod;
+
+/// Progress of copying every blob from one storage backend to another.
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct StorageMigration {
+    /// The backend blobs are copied from, as in `StorageConfig::describe`
+    pub source: String,
+    /// The backend blobs are copied to
+    pub target: String,
+    /// sha256 of the last blob copied, `None` before the first
+    pub checkpoint: Option<String>,
+    pub copied: i64,
+    pub copied_bytes: i64,
+    pub started: time::OffsetDateTime,
+    pub updated: time::OffsetDateTime,
+    /// When the target became the active backend
+    pub finished: Option<time::OffsetDateTime>,
+}
+
+impl StorageMigration {
+    pub async fn get(
+        db: impl PgExecutor<'_>,
+        source: &str,
+        target: &str,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM storage_migrations WHERE source = $1 AND target = $2")
+            .bind(source)
+            .bind(target)
+            .fetch_optional(db)
+            .await
+    }
+
+    /// Get the migration from `source` to `target`, starting it if there is none.
+    pub async fn start(db: impl PgExecutor<'_>, source: &str, target: &str) -> sqlx::Result<Self> {
+        sqlx::query_as(
+            "INSERT INTO storage_migrations (source, target) values ($1, $2) \
+            ON CONFLICT (source, target) DO UPDATE SET updated = now() returning *",
+        )
+        .bind(source)
+        .bind(target)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Forget the progress of a migration, so it copies everything again.
+    pub async fn reset(db: impl PgExecutor<'_>, source: &str, target: &str) -> sqlx::Result<Self> {
+        sqlx::query_as(
+            "UPDATE storage_migrations SET checkpoint = NULL, copied = 0, copied_bytes = 0, \
+            started = now(), updated = now(), finished = NULL \
+            WHERE source = $1 AND target = $2 returning *",
+        )
+        .bind(source)
+        .bind(target)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Record that the blob `sha256` of `size` bytes was copied.
+    pub async fn advance(
+        db: impl PgExecutor<'_>,
+        source: &str,
+        target: &str,
+        sha256: &str,
+        size: i64,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE storage_migrations SET checkpoint = $3, copied = copied + 1, \
+            copied_bytes = copied_bytes + $4, updated = now() \
+            WHERE source = $1 AND target = $2",
+        )
+        .bind(source)
+        .bind(target)
+        .bind(sha256)
+        .bind(size)
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Make the target the active backend.
+    pub async fn finish(db: impl PgExecutor<'_>, source: &str, target: &str) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE storage_migrations SET finished = now(), updated = now() \
+            WHERE source = $1 AND target = $2",
+        )
+        .bind(source)
+        .bind(target)
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+}
+
+make_mod!(prelude StorageMigration);
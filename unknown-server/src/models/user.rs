@@ -2,9 +2,10 @@ use axum_login::AuthUser;
 use std::fmt::{Debug, Formatter};
 use uuid::Uuid;
 
+use crate::config::CONFIG;
 use crate::make_mod;
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{FromRow, PgConnection, PgExecutor};
 
 #[derive(FromRow, Clone, Serialize, Deserialize)]
 pub struct User {
@@ -14,6 +15,63 @@ pub struct User {
     pub created: time::OffsetDateTime,
     pub modified: time::OffsetDateTime,
     pub pw_hash: String,
+    /// Storage quota in bytes, overriding the configured default
+    pub quota_bytes: Option<i64>,
+}
+
+impl User {
+    pub async fn get(db: impl PgExecutor<'_>, id: Uuid) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM users WHERE id = $1")
+            .bind(id)
+            .fetch_optional(db)
+            .await
+    }
+
+    /// Quota in bytes that applies to this user. `None` means unlimited.
+    pub fn quota(&self) -> Option<u64> {
+        effective_quota(self.quota_bytes, || CONFIG.quota)
+    }
+
+    /// Bytes used by files owned by `id`, including earlier versions and the trash.
+    pub async fn usage(db: impl PgExecutor<'_>, id: Uuid) -> sqlx::Result<u64> {
+        let usage: i64 = sqlx::query_scalar(
+            "SELECT (SELECT coalesce(sum(size), 0) FROM files WHERE owner_id = $1)::bigint \
+            + (SELECT coalesce(sum(file_versions.size), 0) FROM file_versions \
+                JOIN files ON files.id = file_versions.file_id WHERE files.owner_id = $1)::bigint",
+        )
+        .bind(id)
+        .fetch_one(db)
+        .await?;
+        Ok(usage.max(0) as u64)
+    }
+
+    /// Lock the user row for the rest of the transaction. Serializes changes to the user's
+    /// folder tree.
+    pub async fn lock(tx: &mut PgConnection, id: Uuid) -> sqlx::Result<()> {
+        sqlx::query("SELECT 1 FROM users WHERE id = $1 FOR UPDATE")
+            .bind(id)
+            .execute(tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Lock the user row for the rest of the transaction and return the quota that applies.
+    /// Used to keep concurrent uploads of one user from overshooting the quota together.
+    pub async fn lock_quota(tx: &mut PgConnection, id: Uuid) -> sqlx::Result<Option<u64>> {
+        let quota_bytes: Option<i64> =
+            sqlx::query_scalar("SELECT quota_bytes FROM users WHERE id = $1 FOR UPDATE")
+                .bind(id)
+                .fetch_one(tx)
+                .await?;
+        Ok(effective_quota(quota_bytes, || CONFIG.quota))
+    }
+}
+
+/// The override of a user if set, the `default` otherwise.
+fn effective_quota(quota_bytes: Option<i64>, default: impl FnOnce() -> Option<u64>) -> Option<u64> {
+    quota_bytes
+        .map(|quota| quota.max(0) as u64)
+        .or_else(default)
 }
 
 impl Debug for User {
@@ -25,6 +83,7 @@ impl Debug for User {
             .field("created", &self.created)
             .field("modified", &self.modified)
             .field("pw_hash", &"[protected]")
+            .field("quota_bytes", &self.quota_bytes)
             .finish()
     }
 }
@@ -53,6 +112,7 @@ impl From<UserInsert> for User {
             created: now,
             modified: now,
             pw_hash: value.pw_hash,
+            quota_bytes: None,
         }
     }
 }
@@ -87,3 +147,19 @@ impl UserInsert {
 }
 
 make_mod!(prelude User, UserInsert);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_default_quota() {
+        assert_eq!(effective_quota(Some(100), || Some(1000)), Some(100));
+        assert_eq!(effective_quota(Some(5000), || Some(1000)), Some(5000));
+        assert_eq!(effective_quota(Some(100), || None), Some(100));
+        // Negative overrides leave no room rather than lifting the quota
+        assert_eq!(effective_quota(Some(-1), || Some(1000)), Some(0));
+        assert_eq!(effective_quota(None, || Some(1000)), Some(1000));
+        assert_eq!(effective_quota(None, || None), None);
+    }
+}
@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor};
+use uuid::Uuid;
+
+use crate::make_mod;
+use crate::models::file::{File, FileStatus};
+
+/// A version of a file that has since been replaced by a newer upload.
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub file_id: Uuid,
+    /// Starts at 1 and counts up with every upload
+    pub version: i32,
+    /// Size in bytes
+    pub size: i64,
+    pub mime_type: String,
+    pub detected_type: Option<String>,
+    /// Hex encoded sha256
+    pub sha256: String,
+    /// When this version was uploaded
+    pub created: time::OffsetDateTime,
+    /// When a newer version replaced it
+    pub replaced: time::OffsetDateTime,
+    pub status: FileStatus,
+}
+
+impl FileVersion {
+    /// `file` as it was at this version.
+    pub fn as_file(&self, file: &File) -> File {
+        File {
+            size: self.size,
+            mime_type: self.mime_type.clone(),
+            detected_type: self.detected_type.clone(),
+            sha256: self.sha256.clone(),
+            version: self.version,
+            uploaded: self.created,
+            status: self.status,
+            ..file.clone()
+        }
+    }
+
+    pub async fn get(
+        db: impl PgExecutor<'_>,
+        file_id: Uuid,
+        version: i32,
+    ) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as("SELECT * FROM file_versions WHERE file_id = $1 AND version = $2")
+            .bind(file_id)
+            .bind(version)
+            .fetch_optional(db)
+            .await
+    }
+
+    /// Earlier versions of a file, newest first.
+    pub async fn list(db: impl PgExecutor<'_>, file_id: Uuid) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("SELECT * FROM file_versions WHERE file_id = $1 ORDER BY version DESC")
+            .bind(file_id)
+            .fetch_all(db)
+            .await
+    }
+
+    /// Keep the current contents of `file` as an earlier version.
+    pub async fn archive(db: impl PgExecutor<'_>, file: &File) -> sqlx::Result<Self> {
+        sqlx::query_as(
+            "INSERT INTO file_versions \
+            (file_id, version, size, mime_type, detected_type, sha256, created, status) \
+            values ($1, $2, $3, $4, $5, $6, $7, $8) returning *",
+        )
+        .bind(file.id)
+        .bind(file.version)
+        .bind(file.size)
+        .bind(&file.mime_type)
+        .bind(&file.detected_type)
+        .bind(&file.sha256)
+        .bind(file.uploaded)
+        .bind(file.status)
+        .fetch_one(db)
+        .await
+    }
+
+    /// Delete the versions of `file_id` beyond the newest `keep` and those replaced before
+    /// `replaced_before`.
+    pub async fn prune(
+        db: impl PgExecutor<'_>,
+        file_id: Uuid,
+        keep: i64,
+        replaced_before: Option<time::OffsetDateTime>,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "DELETE FROM file_versions WHERE file_id = $1 AND ( \
+                version NOT IN ( \
+                    SELECT version FROM file_versions WHERE file_id = $1 \
+                    ORDER BY version DESC LIMIT $2 \
+                ) \
+                OR replaced < $3 \
+            ) returning *",
+        )
+        .bind(file_id)
+        .bind(keep)
+        .bind(replaced_before)
+        .fetch_all(db)
+        .await
+    }
+
+    /// Set the status of every version with the contents `sha256` that is in one of `from`.
+    pub async fn set_status_by_content(
+        db: impl PgExecutor<'_>,
+        sha256: &str,
+        from: &[FileStatus],
+        status: FileStatus,
+    ) -> sqlx::Result<u64> {
+        let result = sqlx::query(
+            "UPDATE file_versions SET status = $3 WHERE sha256 = $1 AND status = ANY($2)",
+        )
+        .bind(sha256)
+        .bind(from)
+        .bind(status)
+        .execute(db)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Delete versions of any file replaced before `before`.
+    pub async fn prune_replaced_before(
+        db: impl PgExecutor<'_>,
+        before: time::OffsetDateTime,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("DELETE FROM file_versions WHERE replaced < $1 returning *")
+            .bind(before)
+            .fetch_all(db)
+            .await
+    }
+
+    /// Delete every version of the files in any of `folder_ids`.
+    pub async fn delete_in_folders(
+        db: impl PgExecutor<'_>,
+        folder_ids: &[Uuid],
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as(
+            "DELETE FROM file_versions \
+            WHERE file_id IN (SELECT id FROM files WHERE folder_id = ANY($1)) returning *",
+        )
+        .bind(folder_ids)
+        .fetch_all(db)
+        .await
+    }
+
+    /// Delete every version with the contents `sha256`, of any file.
+    pub async fn delete_with_content(
+        db: impl PgExecutor<'_>,
+        sha256: &str,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("DELETE FROM file_versions WHERE sha256 = $1 returning *")
+            .bind(sha256)
+            .fetch_all(db)
+            .await
+    }
+
+    /// Delete every version of the files in `file_ids`.
+    pub async fn delete_for_files(
+        db: impl PgExecutor<'_>,
+        file_ids: &[Uuid],
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as("DELETE FROM file_versions WHERE file_id = ANY($1) returning *")
+            .bind(file_ids)
+            .fetch_all(db)
+            .await
+    }
+}
+
+make_mod!(prelude FileVersion);
@@ -0,0 +1,207 @@
+//! Text snippets shared by link. The text of a paste is stored as a file in a folder of its
+//! owner, the paste adds a language hint for the syntax highlighting. A paste expires with its
+//! file, like any other upload.
+use crate::compress;
+use crate::files::{self, NewFile};
+use crate::models::blob::Blob;
+use crate::models::file::File;
+use crate::models::folder::{Folder, FolderInsert};
+use crate::models::paste::{Paste, PasteInsert};
+use crate::prelude::*;
+use crate::serve;
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt, stream};
+use std::sync::LazyLock;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use uuid::Uuid;
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+
+static THEME: LazyLock<Theme> = LazyLock::new(|| {
+    ThemeSet::load_defaults()
+        .themes
+        .remove("InspiredGitHub")
+        .expect("bundled theme")
+});
+
+/// Everything a new paste needs, checked by [`create_paste`].
+#[derive(Debug, Clone)]
+pub(crate) struct NewPaste {
+    pub(crate) owner: Uuid,
+    pub(crate) title: Option<String>,
+    /// Name or file extension of a syntax
+    pub(crate) language: Option<String>,
+    pub(crate) content: String,
+    pub(crate) expiry: Option<dto::file::FileExpiry>,
+}
+
+/// Names of the syntaxes pastes can be highlighted with, sorted.
+pub(crate) fn languages() -> Vec<&'static str> {
+    let mut languages: Vec<&str> = SYNTAXES
+        .syntaxes()
+        .iter()
+        .filter(|syntax| !syntax.hidden)
+        .map(|syntax| syntax.name.as_str())
+        .collect();
+    languages.sort_by_key(|name| name.to_lowercase());
+    languages.dedup();
+    languages
+}
+
+/// The name of the syntax `hint` stands for, given by its name or a file extension.
+pub(crate) fn resolve_language(hint: &str) -> Option<&'static str> {
+    SYNTAXES
+        .find_syntax_by_token(hint)
+        .map(|syntax| syntax.name.as_str())
+}
+
+/// `text` as highlighted HTML. Without a `language` it is guessed from the first line, such as
+/// a shebang, and falls back to plain text.
+pub(crate) fn highlight(text: &str, language: Option<&str>) -> Result<String, syntect::Error> {
+    let syntax = language
+        .and_then(|language| SYNTAXES.find_syntax_by_name(language))
+        .or_else(|| SYNTAXES.find_syntax_by_first_line(text))
+        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+    syntect::html::highlighted_html_for_string(text, &SYNTAXES, syntax, &THEME)
+}
+
+/// Store `new` as a text file in the paste folder of its owner and record the paste.
+pub(crate) async fn create_paste(state: &AppState, new: NewPaste) -> Result<Paste> {
+    if new.content.is_empty() {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if new.content.len() as u64 > CONFIG.paste.max_size {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+    }
+    if new.title.as_ref().is_some_and(|title| title.len() > 255) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let language = match new.language.as_deref() {
+        Some(hint) => match resolve_language(hint) {
+            Some(language) => Some(language.to_string()),
+            None => return Err(StatusCode::BAD_REQUEST.into()),
+        },
+        None => None,
+    };
+
+    let id = Uuid::new_v4();
+    let folder = paste_folder(state, new.owner).await?;
+    let body = stream::once(async move { Ok(Bytes::from(new.content)) }).boxed();
+    let file = files::store_file(
+        state,
+        NewFile {
+            owner: new.owner,
+            folder_id: Some(folder.id),
+            name: format!("{id}.txt"),
+            content_type: "text/plain".to_string(),
+            expiry: new.expiry,
+        },
+        body,
+    )
+    .await?;
+
+    let paste = PasteInsert::new(id, file.id, new.owner)
+        .with_title(new.title)
+        .with_language(language)
+        .insert(state.db())
+        .await;
+    match paste {
+        Ok(paste) => {
+            info!(id = %paste.id, owner = %paste.owner_id, "created paste");
+            Ok(paste)
+        }
+        Err(err) => {
+            files::delete_file(state, &file).await?;
+            Err(err.into())
+        }
+    }
+}
+
+/// The folder at the root of `owner` pastes are stored in, created when it's missing.
+async fn paste_folder(state: &AppState, owner: Uuid) -> Result<Folder> {
+    let name = &CONFIG.paste.folder;
+    if let Some(folder) = Folder::find_child(state.db(), owner, None, name).await? {
+        return Ok(folder);
+    }
+    match FolderInsert::new(owner, None, name.clone())
+        .insert(state.db())
+        .await
+    {
+        Ok(folder) => Ok(folder),
+        // Another paste just created it
+        Err(err)
+            if err
+                .as_database_error()
+                .is_some_and(|err| err.is_unique_violation()) =>
+        {
+            Folder::find_child(state.db(), owner, None, name)
+                .await?
+                .ok_or_else(|| StatusCode::CONFLICT.into())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// A paste and its file, as long as the file isn't in the trash. Expired pastes are found until
+/// the `file_expiry` job deletes them, reading them fails with [`AppError::Expired`].
+pub(crate) async fn find_paste(state: &AppState, id: Uuid) -> Result<(Paste, File)> {
+    let Some(paste) = Paste::get(state.db(), id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let Some(file) = File::get_owned(state.db(), paste.file_id, paste.owner_id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    Ok((paste, file))
+}
+
+/// The text of a paste. Files can be replaced with larger ones, anything past the largest
+/// size a paste may have is cut off.
+pub(crate) async fn read_text(state: &AppState, file: &File) -> Result<String> {
+    serve::ensure_servable(file)?;
+    let Some(blob) = Blob::get(state.db(), &file.sha256).await? else {
+        error!(id = %file.id, sha256 = file.sha256, "file points to a missing blob");
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let object = compress::read(state.storage(), &blob, Some(0..CONFIG.paste.max_size)).await?;
+    let text: Vec<u8> = object
+        .body
+        .try_fold(vec![], |mut text, chunk| async move {
+            text.extend_from_slice(&chunk);
+            Ok(text)
+        })
+        .await
+        .map_err(crate::storage::StorageError::from)?;
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_languages_by_name_and_extension() {
+        assert_eq!(resolve_language("rs"), Some("Rust"));
+        assert_eq!(resolve_language("Rust"), Some("Rust"));
+        assert_eq!(resolve_language("py"), Some("Python"));
+        assert_eq!(resolve_language("no such language"), None);
+        assert!(languages().contains(&"Rust"));
+    }
+
+    #[test]
+    fn highlights_escaped_html() {
+        let html = highlight("fn main() { println!(\"<b>\"); }\n", Some("Rust")).unwrap();
+        assert!(html.starts_with("<pre"));
+        assert!(html.contains("&lt;b&gt;"));
+        assert!(!html.contains("<b>"));
+    }
+
+    #[test]
+    fn guesses_from_the_first_line() {
+        let script = "#!/bin/bash\necho \"hi\"\n";
+        let plain = highlight(script, Some("Plain Text")).unwrap();
+        assert_ne!(highlight(script, None).unwrap(), plain);
+        let plain = highlight("echo \"hi\"\n", Some("Plain Text")).unwrap();
+        assert_eq!(highlight("echo \"hi\"\n", None).unwrap(), plain);
+    }
+}
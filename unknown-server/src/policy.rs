@@ -0,0 +1,257 @@
+//! What may be uploaded. Configured with `upload.policy`.
+//!
+//! Content types are checked against what the contents actually are, as sniffed from their
+//! first bytes, as well as against what the client claims. Names and claimed types are checked
+//! before any bytes are stored, sniffed types as soon as enough of the body arrived.
+use crate::config::{CONFIG, UploadPolicyConfig};
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+
+/// Bytes looked at to sniff a content type.
+pub(crate) const SNIFF_LEN: usize = 8192;
+
+pub(crate) static POLICY: LazyLock<UploadPolicy> =
+    LazyLock::new(|| UploadPolicy::new(&CONFIG.upload.policy).expect("Invalid upload policy"));
+
+/// Why an upload was refused.
+#[derive(Debug, Clone, Serialize, Deserialize, thiserror::Error)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum UploadRejection {
+    #[error("Files ending in .{extension} are not allowed")]
+    BlockedExtension { extension: String },
+
+    #[error("Files of type {content_type} are not allowed")]
+    DeniedType { content_type: String },
+
+    #[error("Files of type {content_type} can't be larger than {limit} bytes")]
+    TooLargeForType { content_type: String, limit: u64 },
+}
+
+pub(crate) struct UploadPolicy {
+    /// Everything is allowed when unset
+    allowed: Option<GlobSet>,
+    denied: GlobSet,
+    blocked_extensions: Vec<String>,
+    max_sizes: Vec<(GlobMatcher, u64)>,
+}
+
+fn glob(pattern: &str) -> Result<globset::Glob, globset::Error> {
+    GlobBuilder::new(pattern).case_insensitive(true).build()
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(glob(pattern)?);
+    }
+    builder.build()
+}
+
+/// `content_type` without parameters such as `; charset=utf-8`.
+fn essence(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or_default().trim()
+}
+
+/// The content type `head`, the first bytes of some contents, looks like. `None` if it isn't
+/// recognized, which is the case for most text formats.
+pub(crate) fn sniff(head: &[u8]) -> Option<String> {
+    infer::get(head).map(|kind| kind.mime_type().to_string())
+}
+
+impl UploadPolicy {
+    pub(crate) fn new(config: &UploadPolicyConfig) -> Result<Self, globset::Error> {
+        let allowed = match config.allowed_types.is_empty() {
+            true => None,
+            false => Some(glob_set(&config.allowed_types)?),
+        };
+        let max_sizes = config
+            .max_sizes
+            .iter()
+            .map(|(pattern, limit)| Ok((glob(pattern)?.compile_matcher(), *limit)))
+            .collect::<Result<_, globset::Error>>()?;
+
+        Ok(Self {
+            allowed,
+            denied: glob_set(&config.denied_types)?,
+            blocked_extensions: config
+                .blocked_extensions
+                .iter()
+                .map(|extension| extension.trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+            max_sizes,
+        })
+    }
+
+    /// Check what is known before the upload starts, its name and the type the client claims.
+    pub(crate) fn check_declared(
+        &self,
+        name: &str,
+        content_type: &str,
+    ) -> Result<(), UploadRejection> {
+        self.check_name(name)?;
+        let content_type = essence(content_type);
+        if self.denied.is_match(content_type) {
+            return Err(UploadRejection::DeniedType {
+                content_type: content_type.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Check the extension of a file name, for uploads as well as renames.
+    pub(crate) fn check_name(&self, name: &str) -> Result<(), UploadRejection> {
+        if let Some((_, extension)) = name.rsplit_once('.') {
+            let extension = extension.to_ascii_lowercase();
+            if self.blocked_extensions.contains(&extension) {
+                return Err(UploadRejection::BlockedExtension { extension });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the contents once their type is sniffed. Types the sniffer doesn't recognize are
+    /// taken from the client. Returns the largest size allowed for the contents, if limited.
+    pub(crate) fn check_content(
+        &self,
+        declared: &str,
+        detected: Option<&str>,
+    ) -> Result<Option<u64>, UploadRejection> {
+        let declared = essence(declared);
+        let content_type = detected.unwrap_or(declared);
+        let denied = |content_type: &str| UploadRejection::DeniedType {
+            content_type: content_type.to_string(),
+        };
+
+        if let Some(detected) = detected
+            && self.denied.is_match(detected)
+        {
+            return Err(denied(detected));
+        }
+        if self.denied.is_match(declared) {
+            return Err(denied(declared));
+        }
+        if let Some(allowed) = &self.allowed
+            && !allowed.is_match(content_type)
+        {
+            return Err(denied(content_type));
+        }
+
+        Ok(self.max_size(content_type))
+    }
+
+    /// The smallest limit of any size rule matching `content_type`.
+    pub(crate) fn max_size(&self, content_type: &str) -> Option<u64> {
+        let content_type = essence(content_type);
+        self.max_sizes
+            .iter()
+            .filter(|(matcher, _)| matcher.is_match(content_type))
+            .map(|(_, limit)| *limit)
+            .min()
+    }
+
+    /// Check the full contents in one go, for uploads that already ended up in storage.
+    pub(crate) fn check(
+        &self,
+        declared: &str,
+        detected: Option<&str>,
+        size: u64,
+    ) -> Result<(), UploadRejection> {
+        match self.check_content(declared, detected)? {
+            Some(limit) if size > limit => Err(UploadRejection::TooLargeForType {
+                content_type: detected.unwrap_or(declared).to_string(),
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> UploadPolicy {
+        UploadPolicy::new(&UploadPolicyConfig {
+            allowed_types: vec!["image/*".into(), "text/*".into(), "application/pdf".into()],
+            denied_types: vec!["image/svg+xml".into()],
+            blocked_extensions: vec![".EXE".into(), "bat".into()],
+            max_sizes: [("image/*".into(), 100), ("image/png".into(), 50)].into(),
+        })
+        .unwrap()
+    }
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn sniffs_contents() {
+        assert_eq!(sniff(PNG).as_deref(), Some("image/png"));
+        assert_eq!(sniff(b"%PDF-1.7\n").as_deref(), Some("application/pdf"));
+        assert_eq!(sniff(b"just some text"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn blocks_extensions() {
+        let policy = policy();
+        assert!(matches!(
+            policy.check_name("setup.Exe"),
+            Err(UploadRejection::BlockedExtension { extension }) if extension == "exe"
+        ));
+        assert!(policy.check_name("run.bat").is_err());
+        assert!(policy.check_name("exe").is_ok());
+        assert!(policy.check_name("notes.exe.txt").is_ok());
+    }
+
+    #[test]
+    fn matches_type_globs() {
+        let policy = policy();
+        assert!(policy.check_declared("a.svg", "IMAGE/SVG+XML").is_err());
+        assert!(policy.check_declared("a.png", "image/png; q=1").is_ok());
+
+        assert_eq!(
+            policy
+                .check_content("text/plain; charset=utf-8", None)
+                .unwrap(),
+            None
+        );
+        assert_eq!(policy.check_content("image/jpeg", None).unwrap(), Some(100));
+        // The smallest matching limit applies
+        assert_eq!(policy.check_content("image/png", None).unwrap(), Some(50));
+        assert!(policy.check_content("application/zip", None).is_err());
+        assert!(policy.check_content("video/mp4", None).is_err());
+    }
+
+    #[test]
+    fn checks_sniffed_type_over_declared() {
+        let policy = policy();
+        // Sniffed types win over what the client claims
+        assert_eq!(
+            policy
+                .check_content("application/zip", Some("image/png"))
+                .unwrap(),
+            Some(50)
+        );
+        assert!(
+            policy
+                .check_content("image/png", Some("application/x-msdownload"))
+                .is_err()
+        );
+        // Declared types are still denied when the contents look allowed
+        assert!(
+            policy
+                .check_content("image/svg+xml", Some("image/png"))
+                .is_err()
+        );
+
+        let detected = sniff(PNG);
+        assert!(policy.check("text/plain", detected.as_deref(), 50).is_ok());
+        assert!(matches!(
+            policy.check("text/plain", detected.as_deref(), 51),
+            Err(UploadRejection::TooLargeForType { content_type, limit: 50 })
+                if content_type == "image/png"
+        ));
+    }
+}
@@ -19,6 +19,7 @@ pub(crate) use crate::models;
 
 pub type Result<T, E = AppError> = axum::response::Result<T, E>;
 pub type ResultJson<T, E = AppError> = Result<Json<T>, E>;
+#[allow(unused)]
 pub type ResultForm<T, E = AppError> = Result<Form<T>, E>;
 pub type ResultHtml<E = AppError> = Result<Html<String>, E>;
 
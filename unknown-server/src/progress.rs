@@ -0,0 +1,670 @@
+//! Live progress of uploads, streamed to clients as server-sent events.
+//!
+//! Whatever handles an upload reports on it through a [`Progress`], which publishes
+//! [`ProgressEvent`]s on the redis channel `progress:{owner}:{upload id}`. The scan worker
+//! publishes [`ScanEvent`]s on `progress:scan:{sha256}` for the contents it scans. Events carry a
+//! sequence number counted per channel in redis. The last event of every channel is also kept
+//! in a key of the same name, so late watchers catch up without seeing an event twice.
+//!
+//! Every instance subscribes to the channels its own clients watch on a connection of its own
+//! and fans messages out to them through the [`Hub`], so progress can be watched from any
+//! instance no matter which one handles the upload.
+use crate::models::file::{File, FileStatus};
+use crate::policy::UploadRejection;
+use crate::prelude::*;
+use fred::prelude::{
+    Client, ClientLike, EventInterface, Expiration, KeysInterface, PubsubInterface,
+};
+use futures::future::select_all;
+use futures::{Stream, StreamExt, TryStreamExt, stream};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use uuid::Uuid;
+
+/// Seconds the last event of a channel is kept around for late watchers.
+const LAST_EVENT_TTL: i64 = 60 * 60;
+
+/// Received bytes are reported at most this often.
+const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Watchers give up after this long without news. Browsers reconnect an `EventSource` on their
+/// own, so this only cleans up after clients that went away or ids that are never uploaded to.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Messages buffered per channel before its watchers lag behind and have to catch up from
+/// redis.
+const HUB_CAPACITY: usize = 256;
+
+fn upload_channel(owner: Uuid, upload_id: Uuid) -> String {
+    format!("progress:{owner}:{upload_id}")
+}
+
+fn scan_channel(sha256: &str) -> String {
+    format!("progress:scan:{sha256}")
+}
+
+fn sequence_key(channel: &str) -> String {
+    format!("{channel}:seq")
+}
+
+/// Where an upload is at. Sent to clients as the data of server-sent events.
+///
+/// There is no stage for thumbnails, since nothing generates them yet. One belongs here once
+/// something does.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// Bytes are coming in. `persisted` counts bytes of finished files and chunks that are
+    /// safely in storage
+    Receiving {
+        received: u64,
+        persisted: u64,
+        /// Expected bytes, if known up front
+        total: Option<u64>,
+    },
+    /// The complete upload is read back from storage to hash and sniff it
+    Hashing,
+    /// A file was recorded. A `pending` file is followed by `scanning` and `scanned`
+    Recorded {
+        file_id: Uuid,
+        checksum: String,
+        status: FileStatus,
+    },
+    Scanning {
+        file_id: Uuid,
+    },
+    Scanned {
+        file_id: Uuid,
+        status: FileStatus,
+    },
+    /// The upload is finished and all of its files are recorded
+    Done,
+    Failed {
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rejection: Option<UploadRejection>,
+    },
+}
+
+/// Where some contents are in malware scanning.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum ScanEvent {
+    Scanning,
+    Scanned { status: FileStatus },
+}
+
+/// An event as published, numbered in the order it was published in on its channel.
+#[derive(Debug, Serialize, Deserialize)]
+struct Sequenced<T> {
+    seq: u64,
+    #[serde(flatten)]
+    event: T,
+}
+
+/// Number `event`, store it as the last one of `channel`, then publish it.
+async fn publish<T: Serialize>(client: &Client, channel: &str, event: &T) -> Result<()> {
+    let sequence_key = sequence_key(channel);
+    let seq: u64 = client.incr(&sequence_key).await?;
+    let _: () = client.expire(&sequence_key, LAST_EVENT_TTL, None).await?;
+
+    let payload =
+        serde_json::to_string(&Sequenced { seq, event }).expect("progress events serialize");
+    let _: () = client
+        .set(
+            channel,
+            payload.as_str(),
+            Some(Expiration::EX(LAST_EVENT_TTL)),
+            None,
+            false,
+        )
+        .await?;
+    let _: i64 = client.publish(channel, payload).await?;
+
+    Ok(())
+}
+
+async fn last_event<T: DeserializeOwned>(state: &AppState, channel: &str) -> Option<Sequenced<T>> {
+    let payload: Option<String> = state
+        .fred()
+        .get(channel)
+        .await
+        .inspect_err(|err| warn!(%err, channel, "failed to read last progress event"))
+        .ok()?;
+
+    serde_json::from_str(&payload?).ok()
+}
+
+/// Let watchers of uploads with `sha256` in them know how scanning goes.
+pub(crate) async fn report_scan(state: &AppState, sha256: &str, event: ScanEvent) {
+    if let Err(err) = publish(state.fred(), &scan_channel(sha256), &event).await {
+        warn!(%err, sha256, "failed to publish scan progress");
+    }
+}
+
+/// Reports on one upload. Cheap to clone, every clone reports on the same upload.
+///
+/// Events are published in order by a background task, so reporting never waits on redis. The
+/// default value reports nothing, for uploads nobody asked to watch.
+#[derive(Clone, Default)]
+pub(crate) struct Progress {
+    reporter: Option<Arc<Reporter>>,
+}
+
+struct Reporter {
+    sender: mpsc::UnboundedSender<ProgressEvent>,
+    total: Option<u64>,
+    received: AtomicU64,
+    persisted: AtomicU64,
+    last_report: Mutex<Instant>,
+}
+
+impl Progress {
+    /// Start reporting on upload `upload_id` of `owner`, of which `offset` bytes already arrived
+    /// and were persisted earlier.
+    pub(crate) fn start(
+        state: &AppState,
+        owner: Uuid,
+        upload_id: Uuid,
+        total: Option<u64>,
+        offset: u64,
+    ) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let client = state.fred().clone();
+        let channel = upload_channel(owner, upload_id);
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                if let Err(err) = publish(&client, &channel, &event).await {
+                    warn!(%err, channel, "failed to publish upload progress");
+                }
+            }
+        });
+
+        let progress = Self {
+            reporter: Some(Arc::new(Reporter {
+                sender,
+                total,
+                received: AtomicU64::new(offset),
+                persisted: AtomicU64::new(offset),
+                last_report: Mutex::new(Instant::now()),
+            })),
+        };
+        progress.report_bytes();
+        progress
+    }
+
+    fn send(&self, event: ProgressEvent) {
+        if let Some(reporter) = &self.reporter {
+            let _ = reporter.sender.send(event);
+        }
+    }
+
+    fn report_bytes(&self) {
+        let Some(reporter) = &self.reporter else {
+            return;
+        };
+        *reporter.last_report.lock().expect("not poisoned") = Instant::now();
+        self.send(ProgressEvent::Receiving {
+            received: reporter.received.load(Ordering::Relaxed),
+            persisted: reporter.persisted.load(Ordering::Relaxed),
+            total: reporter.total,
+        });
+    }
+
+    /// `len` more bytes arrived.
+    pub(crate) fn received(&self, len: u64) {
+        let Some(reporter) = &self.reporter else {
+            return;
+        };
+        let received = reporter.received.fetch_add(len, Ordering::Relaxed) + len;
+        let due = reporter.last_report.lock().expect("not poisoned").elapsed() >= REPORT_INTERVAL;
+        if due || reporter.total == Some(received) {
+            self.report_bytes();
+        }
+    }
+
+    /// `len` more bytes are safely in storage.
+    pub(crate) fn persisted(&self, len: u64) {
+        if let Some(reporter) = &self.reporter {
+            reporter.persisted.fetch_add(len, Ordering::Relaxed);
+            self.report_bytes();
+        }
+    }
+
+    /// Count the bytes of `body` as they are received.
+    pub(crate) fn track<'a, S, E>(
+        &self,
+        body: S,
+    ) -> impl Stream<Item = Result<bytes::Bytes, E>> + 'a
+    where
+        S: Stream<Item = Result<bytes::Bytes, E>> + 'a,
+    {
+        let progress = self.clone();
+        body.inspect_ok(move |chunk| progress.received(chunk.len() as u64))
+    }
+
+    pub(crate) fn hashing(&self) {
+        self.send(ProgressEvent::Hashing);
+    }
+
+    pub(crate) fn recorded(&self, file: &File) {
+        self.send(ProgressEvent::Recorded {
+            file_id: file.id,
+            checksum: file.sha256.clone(),
+            status: file.status,
+        });
+    }
+
+    pub(crate) fn done(&self) {
+        self.send(ProgressEvent::Done);
+    }
+
+    /// The upload failed with `err`. Only errors meant for the client are described.
+    pub(crate) fn failed(&self, err: &AppError) {
+        let (message, rejection) = match err {
+            AppError::UploadRejected(rejection) => (err.to_string(), Some(rejection.clone())),
+            AppError::QuotaExceeded { .. } => (err.to_string(), None),
+            AppError::Code(code) => (
+                code.canonical_reason()
+                    .unwrap_or("UNKNOWN CODE")
+                    .to_string(),
+                None,
+            ),
+            _ => ("Something went wrong".to_string(), None),
+        };
+        self.send(ProgressEvent::Failed { message, rejection });
+    }
+}
+
+/// A change to the channels this instance is subscribed to.
+enum Command {
+    Subscribe(String),
+    Unsubscribe(String),
+    /// Answered once every command before it went through
+    Flush(oneshot::Sender<()>),
+}
+
+/// Hands progress published by any instance to the watchers on this one.
+pub(crate) struct Hub {
+    /// Channels watched on this instance
+    channels: Mutex<HashMap<String, broadcast::Sender<String>>>,
+    commands: mpsc::UnboundedSender<Command>,
+    /// Taken by the subscriber task
+    command_receiver: Mutex<Option<mpsc::UnboundedReceiver<Command>>>,
+}
+
+impl Hub {
+    pub(crate) fn new() -> Self {
+        let (commands, command_receiver) = mpsc::unbounded_channel();
+        Self {
+            channels: Mutex::new(HashMap::new()),
+            commands,
+            command_receiver: Mutex::new(Some(command_receiver)),
+        }
+    }
+
+    fn dispatch(&self, channel: &str, payload: String) {
+        let channels = self.channels.lock().expect("not poisoned");
+        if let Some(sender) = channels.get(channel) {
+            // The last watcher may have just left
+            let _ = sender.send(payload);
+        }
+    }
+}
+
+/// Messages of one channel for one watcher. The channel is unsubscribed from once nobody on
+/// this instance watches it anymore.
+struct Subscription {
+    state: AppStateRef,
+    channel: String,
+    receiver: Option<broadcast::Receiver<String>>,
+}
+
+impl Subscription {
+    /// Subscribe to `channel`. Returns once redis confirmed the subscription, so anything read
+    /// from the channel's key afterwards is either that or older than what arrives here.
+    async fn new(state: &AppStateRef, channel: String) -> Self {
+        let hub = state.progress();
+        let (flushed, flush) = oneshot::channel();
+        let receiver = {
+            let mut channels = hub.channels.lock().expect("not poisoned");
+            let receiver = match channels.get(&channel) {
+                Some(sender) => sender.subscribe(),
+                None => {
+                    let (sender, receiver) = broadcast::channel(HUB_CAPACITY);
+                    channels.insert(channel.clone(), sender);
+                    let _ = hub.commands.send(Command::Subscribe(channel.clone()));
+                    receiver
+                }
+            };
+            // A subscription by an earlier watcher may still be on its way as well
+            let _ = hub.commands.send(Command::Flush(flushed));
+            receiver
+        };
+        // Without a subscriber there is nothing to wait for
+        let _ = flush.await;
+
+        Self {
+            state: state.clone(),
+            channel,
+            receiver: Some(receiver),
+        }
+    }
+
+    async fn recv(&mut self) -> std::result::Result<String, broadcast::error::RecvError> {
+        match &mut self.receiver {
+            Some(receiver) => receiver.recv().await,
+            None => Err(broadcast::error::RecvError::Closed),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let hub = self.state.progress();
+        let mut channels = hub.channels.lock().expect("not poisoned");
+        self.receiver.take();
+        // Commands are sent while holding the lock, so they reach redis in the same order
+        if channels
+            .get(&self.channel)
+            .is_some_and(|sender| sender.receiver_count() == 0)
+        {
+            channels.remove(&self.channel);
+            let _ = hub
+                .commands
+                .send(Command::Unsubscribe(self.channel.clone()));
+        }
+    }
+}
+
+/// Run the subscriptions of the hub on `subscriber`, a client of its own, and feed it the
+/// messages. Subscriptions are renewed whenever the client reconnects.
+pub(crate) fn spawn_subscriber(state: AppStateRef, subscriber: Client) {
+    let Some(mut commands) = state
+        .progress()
+        .command_receiver
+        .lock()
+        .expect("not poisoned")
+        .take()
+    else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut messages = subscriber.message_rx();
+        let _connect_handle = subscriber.connect();
+        if let Err(err) = subscriber.wait_for_connect().await {
+            error!(%err, "progress subscriber failed, upload progress is not available");
+            return;
+        }
+        let resubscriber = subscriber.clone();
+        let resubscribe_state = state.clone();
+        subscriber.on_reconnect(move |_| {
+            let resubscriber = resubscriber.clone();
+            let channels: Vec<String> = resubscribe_state
+                .progress()
+                .channels
+                .lock()
+                .expect("not poisoned")
+                .keys()
+                .cloned()
+                .collect();
+            async move {
+                if !channels.is_empty() {
+                    resubscriber.subscribe(channels).await?;
+                }
+                Ok(())
+            }
+        });
+        info!("Subscriber for upload progress connected");
+
+        loop {
+            tokio::select! {
+                command = commands.recv() => {
+                    let result = match command {
+                        Some(Command::Subscribe(channel)) => subscriber.subscribe(channel).await,
+                        Some(Command::Unsubscribe(channel)) => subscriber.unsubscribe(channel).await,
+                        Some(Command::Flush(flushed)) => {
+                            let _ = flushed.send(());
+                            Ok(())
+                        }
+                        None => break,
+                    };
+                    if let Err(err) = result {
+                        warn!(%err, "failed to change progress subscriptions");
+                    }
+                }
+                message = messages.recv() => match message {
+                    Ok(message) => {
+                        if let Some(payload) = message.value.as_string() {
+                            state.progress().dispatch(&message.channel, payload);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "progress subscriber lagged behind");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            }
+        }
+    });
+}
+
+/// Follows one upload and the scans of the files it recorded.
+struct Watch {
+    state: AppStateRef,
+    channel: String,
+    /// The upload channel and the scan channels of `scans`
+    subscriptions: HashMap<String, Subscription>,
+    /// Sequence number of the last event seen per channel
+    seen: HashMap<String, u64>,
+    /// Contents waiting for their scan, with the files of this upload using them
+    scans: HashMap<String, Vec<Uuid>>,
+    queue: VecDeque<ProgressEvent>,
+    /// Set once the upload itself is over
+    done: bool,
+}
+
+impl Watch {
+    fn finished(&self) -> bool {
+        self.done && self.scans.is_empty()
+    }
+
+    /// Whether event `seq` of `channel` wasn't seen yet, remembering it if so.
+    fn first_sight(&mut self, channel: &str, seq: u64) -> bool {
+        match self.seen.get_mut(channel) {
+            Some(seen) if *seen >= seq => false,
+            Some(seen) => {
+                *seen = seq;
+                true
+            }
+            None => {
+                self.seen.insert(channel.to_string(), seq);
+                true
+            }
+        }
+    }
+
+    /// Read the last events from redis, for when messages may have been missed.
+    async fn catch_up(&mut self) {
+        if let Some(event) = last_event(&self.state, &self.channel).await {
+            self.upload_event(event).await;
+        }
+        let pending: Vec<String> = self.scans.keys().cloned().collect();
+        for sha256 in pending {
+            if let Some(event) = last_event(&self.state, &scan_channel(&sha256)).await {
+                self.scan_event(&sha256, event);
+            }
+        }
+    }
+
+    async fn upload_event(&mut self, event: Sequenced<ProgressEvent>) {
+        let channel = self.channel.clone();
+        if !self.first_sight(&channel, event.seq) {
+            return;
+        }
+        let event = event.event;
+        match &event {
+            ProgressEvent::Recorded {
+                file_id,
+                checksum,
+                status: FileStatus::Pending,
+            } => {
+                let files = self.scans.entry(checksum.clone()).or_default();
+                if !files.contains(file_id) {
+                    files.push(*file_id);
+                }
+                let checksum = checksum.clone();
+                self.queue.push_back(event);
+
+                let channel = scan_channel(&checksum);
+                if !self.subscriptions.contains_key(&channel) {
+                    let subscription = Subscription::new(&self.state, channel.clone()).await;
+                    self.subscriptions.insert(channel.clone(), subscription);
+                }
+                // The scan may have started before this watcher knew about it
+                if let Some(scan) = last_event(&self.state, &channel).await {
+                    self.scan_event(&checksum, scan);
+                }
+                return;
+            }
+            ProgressEvent::Done => self.done = true,
+            ProgressEvent::Failed { .. } => {
+                self.done = true;
+                self.scans.clear();
+            }
+            _ => {}
+        }
+        self.queue.push_back(event);
+    }
+
+    fn scan_event(&mut self, sha256: &str, event: Sequenced<ScanEvent>) {
+        if !self.scans.contains_key(sha256) {
+            return;
+        }
+        let channel = scan_channel(sha256);
+        if !self.first_sight(&channel, event.seq) {
+            return;
+        }
+        match event.event {
+            ScanEvent::Scanning => self.queue.extend(
+                self.scans[sha256]
+                    .iter()
+                    .map(|file_id| ProgressEvent::Scanning { file_id: *file_id }),
+            ),
+            ScanEvent::Scanned { status } => {
+                for file_id in self.scans.remove(sha256).unwrap_or_default() {
+                    self.queue
+                        .push_back(ProgressEvent::Scanned { file_id, status });
+                }
+                self.subscriptions.remove(&channel);
+            }
+        }
+    }
+
+    async fn receive(&mut self, channel: &str, payload: &str) {
+        if channel == self.channel {
+            match serde_json::from_str(payload) {
+                Ok(event) => self.upload_event(event).await,
+                Err(err) => debug!(%err, channel, "bad progress event"),
+            }
+        } else if let Some(sha256) = channel.strip_prefix("progress:scan:") {
+            match serde_json::from_str(payload) {
+                Ok(event) => self.scan_event(sha256, event),
+                Err(err) => debug!(%err, channel, "bad scan event"),
+            }
+        }
+    }
+
+    async fn next(&mut self) -> Option<ProgressEvent> {
+        loop {
+            if let Some(event) = self.queue.pop_front() {
+                return Some(event);
+            }
+            if self.finished() {
+                return None;
+            }
+
+            let receivers = self
+                .subscriptions
+                .iter_mut()
+                .map(|(channel, subscription)| {
+                    Box::pin(async move { (channel.clone(), subscription.recv().await) })
+                });
+            let ((channel, message), _, _) =
+                tokio::time::timeout(IDLE_TIMEOUT, select_all(receivers))
+                    .await
+                    .ok()?;
+            match message {
+                Ok(payload) => self.receive(&channel, &payload).await,
+                Err(broadcast::error::RecvError::Lagged(_)) => self.catch_up().await,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Events of upload `upload_id` of `owner`, starting with the last one published before.
+/// Ends once the upload is done and all of its files are scanned, or once it failed.
+pub(crate) fn watch(
+    state: AppStateRef,
+    owner: Uuid,
+    upload_id: Uuid,
+) -> impl Stream<Item = ProgressEvent> {
+    stream::once(async move {
+        let channel = upload_channel(owner, upload_id);
+        // Subscribe before catching up, so nothing published in between is lost
+        let subscription = Subscription::new(&state, channel.clone()).await;
+        let mut watch = Watch {
+            state,
+            channel: channel.clone(),
+            subscriptions: HashMap::from([(channel, subscription)]),
+            seen: HashMap::new(),
+            scans: HashMap::new(),
+            queue: VecDeque::new(),
+            done: false,
+        };
+        watch.catch_up().await;
+        stream::unfold(watch, |mut watch| async move {
+            let event = watch.next().await?;
+            Some((event, watch))
+        })
+    })
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequenced_events_roundtrip() {
+        let payload = serde_json::to_string(&Sequenced {
+            seq: 7,
+            event: &ScanEvent::Scanned {
+                status: FileStatus::Clean,
+            },
+        })
+        .unwrap();
+        assert_eq!(payload, r#"{"seq":7,"stage":"scanned","status":"clean"}"#);
+
+        let event: Sequenced<ProgressEvent> = serde_json::from_str(
+            r#"{"seq":3,"stage":"receiving","received":5,"persisted":0,"total":null}"#,
+        )
+        .unwrap();
+        assert_eq!(event.seq, 3);
+        assert!(matches!(
+            event.event,
+            ProgressEvent::Receiving {
+                received: 5,
+                persisted: 0,
+                total: None
+            }
+        ));
+    }
+}
@@ -0,0 +1,490 @@
+//! Reconciliation of blob records with the objects in storage.
+//!
+//! Uploads are written to storage before they are recorded, so a crash in between leaves an
+//! object nothing points to. The other way around, objects can go missing behind the server's
+//! back. The `storage_reconcile` job walks both sides and reports what doesn't match up. Unless
+//! it's a dry run, it then relinks and deletes as configured in [`ReconcileConfig`].
+use crate::config::ReconcileConfig;
+use crate::files;
+use crate::ingest::{self, BLOB_PREFIXES};
+use crate::models::blob::{Blob, Encoding};
+use crate::models::file::File;
+use crate::prelude::*;
+use crate::storage::{ObjectMeta, Storage, StorageError, StorageResult};
+use futures::TryStreamExt;
+use metrics::counter;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Contents files or versions point to that aren't in storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Lost {
+    sha256: String,
+    /// Where the contents should be. `None` if even the blob record is missing
+    storage_key: Option<String>,
+}
+
+/// What doesn't match up between storage and the database.
+#[derive(Debug, Default)]
+struct Mismatches {
+    /// Objects without a blob that are old enough not to be uploads in progress
+    orphans: Vec<ObjectMeta>,
+    /// Blobs whose object isn't in storage
+    missing: Vec<Blob>,
+}
+
+/// When the object at a blob key was written, from the UUIDv7 in the key.
+fn key_created(key: &str) -> Option<SystemTime> {
+    let id: Uuid = BLOB_PREFIXES
+        .iter()
+        .find_map(|prefix| key.strip_prefix(prefix))?
+        .parse()
+        .ok()?;
+    let (secs, nanos) = id.get_timestamp()?.to_unix();
+    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+/// Match the listed `objects` against `blobs`. Only objects written before `cutoff` can be
+/// orphans, younger ones may belong to uploads that aren't recorded yet.
+fn compare(objects: Vec<ObjectMeta>, blobs: Vec<Blob>, cutoff: SystemTime) -> Mismatches {
+    let stored: HashSet<&str> = objects.iter().map(|object| object.key.as_str()).collect();
+    let missing: Vec<Blob> = blobs
+        .iter()
+        .filter(|blob| !stored.contains(blob.storage_key.as_str()))
+        .cloned()
+        .collect();
+
+    let recorded: HashSet<&str> = blobs.iter().map(|blob| blob.storage_key.as_str()).collect();
+    let orphans = objects
+        .into_iter()
+        .filter(|object| !recorded.contains(object.key.as_str()))
+        .filter(|object| key_created(&object.key).is_some_and(|created| created < cutoff))
+        .collect();
+
+    Mismatches { orphans, missing }
+}
+
+/// Label telling runs that change things from dry runs apart.
+fn mode(config: &ReconcileConfig) -> &'static str {
+    if config.dry_run { "dry_run" } else { "apply" }
+}
+
+/// Periodic job comparing the blobs table with the objects in storage.
+pub(crate) async fn reconcile(state: AppStateRef) -> Result<()> {
+    let config = &CONFIG.reconcile;
+    let mode = mode(config);
+
+    // Storage is listed first, blobs recorded after that may have objects the listing missed
+    let started = time::OffsetDateTime::now_utc();
+    let cutoff = SystemTime::now() - Duration::from_secs(CONFIG.upload.incomplete_expiration);
+    // Quarantined blobs are below a prefix of their own
+    let mut objects: Vec<ObjectMeta> = vec![];
+    for prefix in BLOB_PREFIXES {
+        let listed: Vec<ObjectMeta> = state.storage().list(prefix).try_collect().await?;
+        objects.extend(listed);
+    }
+    let blobs = Blob::created_before(state.db(), started).await?;
+    let dangling = Blob::dangling_references(state.db()).await?;
+    let Mismatches { orphans, missing } = compare(objects, blobs, cutoff);
+
+    for orphan in &orphans {
+        warn!(
+            key = orphan.key,
+            size = orphan.size,
+            "object without a record"
+        );
+    }
+    for blob in &missing {
+        warn!(
+            key = blob.storage_key,
+            sha256 = blob.sha256,
+            references = blob.ref_count,
+            "record without an object"
+        );
+    }
+    for sha256 in &dangling {
+        warn!(sha256, "files point to contents without a record");
+    }
+    counter!("storage_reconcile_runs_total", "mode" => mode).increment(1);
+    counter!("storage_reconcile_orphaned_objects_total", "mode" => mode)
+        .increment(orphans.len() as u64);
+    counter!("storage_reconcile_missing_objects_total", "mode" => mode)
+        .increment((missing.len() + dangling.len()) as u64);
+
+    let mut lost: Vec<Lost> = missing
+        .into_iter()
+        .map(|blob| Lost {
+            sha256: blob.sha256,
+            storage_key: Some(blob.storage_key),
+        })
+        .chain(dangling.into_iter().map(|sha256| Lost {
+            sha256,
+            storage_key: None,
+        }))
+        .collect();
+    let mut orphans = orphans;
+
+    let mut failed = 0;
+    let mut relinked = 0;
+    if config.relink && !lost.is_empty() && !orphans.is_empty() {
+        (lost, orphans, relinked) = relink(&state, config, lost, orphans, &mut failed).await;
+    }
+
+    let (mut deleted_objects, mut deleted_records) = (0, 0);
+    if config.delete {
+        for orphan in &orphans {
+            match delete_orphan(&state, config, orphan).await {
+                Ok(true) => deleted_objects += 1,
+                Ok(false) => {}
+                Err(err) => {
+                    error!(key = orphan.key, %err, "failed to delete orphaned object");
+                    failed += 1;
+                }
+            }
+        }
+        for lost in &lost {
+            match drop_records(&state, config, lost).await {
+                Ok(deleted) => deleted_records += deleted,
+                Err(err) => {
+                    error!(sha256 = lost.sha256, %err, "failed to delete records of missing contents");
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    counter!("storage_reconcile_relinked_total", "mode" => mode).increment(relinked);
+    counter!("storage_reconcile_deleted_objects_total", "mode" => mode).increment(deleted_objects);
+    counter!("storage_reconcile_deleted_records_total", "mode" => mode).increment(deleted_records);
+    counter!("storage_reconcile_failures_total", "mode" => mode).increment(failed);
+    info!(
+        dry_run = config.dry_run,
+        orphans = orphans.len(),
+        missing = lost.len(),
+        relinked,
+        deleted_objects,
+        deleted_records,
+        failed,
+        "reconciled storage"
+    );
+
+    Ok(())
+}
+
+/// Hash `orphans` and point lost contents at the ones that have them. Returns what is still lost,
+/// the orphans that weren't claimed and how many were relinked.
+async fn relink(
+    state: &AppState,
+    config: &ReconcileConfig,
+    lost: Vec<Lost>,
+    orphans: Vec<ObjectMeta>,
+    failed: &mut u64,
+) -> (Vec<Lost>, Vec<ObjectMeta>, u64) {
+    let mut lost: HashMap<String, Lost> = lost
+        .into_iter()
+        .map(|lost| (lost.sha256.clone(), lost))
+        .collect();
+    let mut unclaimed = vec![];
+    let mut relinked = 0;
+
+    for orphan in orphans {
+        let (sha256, found) = match identify(state.storage(), &orphan.key, &lost).await {
+            Ok(Some(identified)) => identified,
+            Ok(None) => {
+                unclaimed.push(orphan);
+                continue;
+            }
+            Err(err) => {
+                error!(key = orphan.key, %err, "failed to hash orphaned object");
+                *failed += 1;
+                unclaimed.push(orphan);
+                continue;
+            }
+        };
+        let contents = &lost[&sha256];
+
+        if config.dry_run {
+            info!(key = orphan.key, sha256, "would relink orphaned object");
+            lost.remove(&sha256);
+            relinked += 1;
+            continue;
+        }
+        match relink_one(state, contents, &orphan, found).await {
+            Ok(true) => {
+                info!(key = orphan.key, sha256, "relinked orphaned object");
+                lost.remove(&sha256);
+                relinked += 1;
+            }
+            Ok(false) => unclaimed.push(orphan),
+            Err(err) => {
+                error!(key = orphan.key, sha256, %err, "failed to relink orphaned object");
+                *failed += 1;
+                unclaimed.push(orphan);
+            }
+        }
+    }
+
+    (lost.into_values().collect(), unclaimed, relinked)
+}
+
+/// What an orphaned object turned out to hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Found {
+    /// Size of the contents as they were uploaded
+    size: u64,
+    encoding: Option<Encoding>,
+}
+
+/// Hash the object at `key` and find the `lost` contents it holds. Compressed objects are hashed
+/// as they were uploaded, so objects that don't match as they are are tried decoded as well.
+async fn identify(
+    storage: &dyn Storage,
+    key: &str,
+    lost: &HashMap<String, Lost>,
+) -> StorageResult<Option<(String, Found)>> {
+    for encoding in [None, Some(Encoding::Zstd)] {
+        let (sha256, size) = match ingest::hash_object(storage, key, encoding).await {
+            Ok(hashed) => hashed,
+            // It isn't encoded that way
+            Err(StorageError::Io(err)) if encoding.is_some() => {
+                debug!(key, %err, "orphaned object doesn't decode");
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if lost.contains_key(&sha256) {
+            return Ok(Some((sha256, Found { size, encoding })));
+        }
+    }
+    Ok(None)
+}
+
+/// Point the record of `lost` at `orphan`, which holds its contents as `found`. `false` if the
+/// contents turned up after all or someone else recorded them in the meantime.
+async fn relink_one(
+    state: &AppState,
+    lost: &Lost,
+    orphan: &ObjectMeta,
+    found: Found,
+) -> Result<bool> {
+    match &lost.storage_key {
+        Some(storage_key) => {
+            if still_stored(state, storage_key).await? {
+                return Ok(false);
+            }
+            Ok(
+                Blob::relocate(state.db(), &lost.sha256, &orphan.key, found.encoding)
+                    .await?
+                    .is_some(),
+            )
+        }
+        None => Ok(Blob::relink(
+            state.db(),
+            &lost.sha256,
+            &orphan.key,
+            found.size as i64,
+            found.encoding,
+        )
+        .await?
+        .is_some()),
+    }
+}
+
+/// Whether the object at `key` exists, asked again right before acting on a listing that may be
+/// outdated.
+async fn still_stored(state: &AppState, key: &str) -> Result<bool> {
+    match state.storage().head(key).await {
+        Ok(_) => Ok(true),
+        Err(StorageError::NotFound(_)) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Delete an object without a record, unless it was recorded since it was listed.
+async fn delete_orphan(
+    state: &AppState,
+    config: &ReconcileConfig,
+    orphan: &ObjectMeta,
+) -> Result<bool> {
+    if Blob::get_by_key(state.db(), &orphan.key).await?.is_some() {
+        return Ok(false);
+    }
+    if config.dry_run {
+        info!(
+            key = orphan.key,
+            size = orphan.size,
+            "would delete orphaned object"
+        );
+        return Ok(true);
+    }
+    state.storage().delete(&orphan.key).await?;
+    info!(
+        key = orphan.key,
+        size = orphan.size,
+        "deleted orphaned object"
+    );
+    Ok(true)
+}
+
+/// Delete the files and versions whose contents are lost, and what's left of their blob.
+/// Returns how many files and versions were deleted.
+async fn drop_records(state: &AppState, config: &ReconcileConfig, lost: &Lost) -> Result<u64> {
+    if let Some(storage_key) = &lost.storage_key
+        && still_stored(state, storage_key).await?
+    {
+        return Ok(0);
+    }
+
+    let files = File::with_content(state.db(), &lost.sha256).await?;
+    if config.dry_run {
+        info!(
+            sha256 = lost.sha256,
+            files = files.len(),
+            "would delete files with missing contents"
+        );
+        return Ok(files.len() as u64);
+    }
+    for file in &files {
+        files::delete_file(state, file).await?;
+    }
+    let versions = files::delete_versions_with_content(state, &lost.sha256).await?;
+    Blob::forget(state.db(), &lost.sha256).await?;
+    warn!(
+        sha256 = lost.sha256,
+        files = files.len(),
+        versions,
+        "deleted files with missing contents"
+    );
+
+    Ok((files.len() + versions) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::Compression;
+    use crate::storage::{LocalStorage, PutOptions};
+    use bytes::Bytes;
+    use futures::{StreamExt, stream};
+    use sha2::{Digest, Sha256};
+
+    fn object(key: &str) -> ObjectMeta {
+        ObjectMeta {
+            key: key.to_string(),
+            size: 1,
+        }
+    }
+
+    fn blob(sha256: &str, storage_key: &str) -> Blob {
+        Blob {
+            sha256: sha256.to_string(),
+            storage_key: storage_key.to_string(),
+            size: 1,
+            encoding: None,
+            ref_count: 1,
+            created: time::OffsetDateTime::UNIX_EPOCH,
+            scan_attempts: 0,
+            scan_after: None,
+            verified: None,
+            corrupt: None,
+        }
+    }
+
+    #[test]
+    fn reads_when_blob_keys_were_written() {
+        let before = SystemTime::now() - Duration::from_secs(1);
+        let created = key_created(&ingest::blob_key()).unwrap();
+        assert!(created > before && created <= SystemTime::now());
+
+        let quarantined = ingest::blob_key().replacen("blobs/", "quarantine/", 1);
+        assert!(key_created(&quarantined).is_some());
+        assert_eq!(key_created("blobs/not-a-uuid"), None);
+        assert_eq!(key_created(&format!("presign/{}", Uuid::now_v7())), None);
+        // Only v7 keys carry a timestamp
+        assert_eq!(key_created(&format!("blobs/{}", Uuid::new_v4())), None);
+    }
+
+    #[test]
+    fn finds_orphans_and_missing_objects() {
+        let written = uuid::Timestamp::from_unix(uuid::NoContext, 1_000_000_000, 0);
+        let old = format!("blobs/{}", Uuid::new_v7(written));
+        let recorded = format!("blobs/{}", Uuid::new_v7(written));
+        let young = format!("blobs/{}", Uuid::now_v7());
+        let quarantined = format!("quarantine/{}", Uuid::new_v7(written));
+        let cutoff = SystemTime::now() - Duration::from_secs(60);
+
+        let Mismatches { orphans, missing } = compare(
+            vec![
+                object(&old),
+                object(&recorded),
+                object(&young),
+                object(&quarantined),
+                object("blobs/odd"),
+            ],
+            vec![
+                blob("a", &recorded),
+                blob("b", "blobs/gone"),
+                blob("c", &quarantined),
+            ],
+            cutoff,
+        );
+
+        // The young object may still be uploading, the odd key can't be dated
+        let orphans: Vec<_> = orphans.iter().map(|object| object.key.as_str()).collect();
+        assert_eq!(orphans, [old.as_str()]);
+        let missing: Vec<_> = missing.iter().map(|blob| blob.sha256.as_str()).collect();
+        assert_eq!(missing, ["b"]);
+    }
+
+    #[tokio::test]
+    async fn identifies_compressed_orphans() {
+        let root = std::env::temp_dir().join(format!("reconcile-test-{}", Uuid::new_v4()));
+        let storage = LocalStorage::new(root).await.unwrap();
+        let text = "2024-01-01 INFO request served\n".repeat(100);
+        let body = || stream::once(async { Ok(Bytes::from(text.clone())) }).boxed();
+        let compression = Compression {
+            encoding: Encoding::Zstd,
+            level: 3,
+        };
+        storage
+            .put("blobs/plain", body(), PutOptions::default())
+            .await
+            .unwrap();
+        storage
+            .put(
+                "blobs/zstd",
+                compression.encode(body()),
+                PutOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        let sha256 = hex::encode(Sha256::digest(&text));
+        let lost = HashMap::from([(
+            sha256.clone(),
+            Lost {
+                sha256: sha256.clone(),
+                storage_key: None,
+            },
+        )]);
+        let size = text.len() as u64;
+
+        let plain = identify(&storage, "blobs/plain", &lost).await.unwrap();
+        let found = Found {
+            size,
+            encoding: None,
+        };
+        assert_eq!(plain, Some((sha256.clone(), found)));
+
+        let zstd = identify(&storage, "blobs/zstd", &lost).await.unwrap();
+        let found = Found {
+            size,
+            encoding: Some(Encoding::Zstd),
+        };
+        assert_eq!(zstd, Some((sha256, found)));
+
+        let unknown = identify(&storage, "blobs/plain", &HashMap::new()).await;
+        assert_eq!(unknown.unwrap(), None);
+    }
+}
@@ -0,0 +1,40 @@
+use crate::archive::{self, Selection};
+use crate::dto::folder::ArchiveCreateDto;
+use crate::files;
+use crate::prelude::*;
+use axum::response::Response;
+
+/// More files and folders than this have to be downloaded in several archives.
+const MAX_SELECTION: usize = 1000;
+
+pub(crate) fn router() -> Router<AppStateRef> {
+    Router::new()
+        .route("/", post(post_archive))
+        .route_layer(login_required!(Backend))
+}
+
+/// Download the selected files and folders as one ZIP archive.
+async fn post_archive(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Json(create): Json<ArchiveCreateDto>,
+) -> Result<Response> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let selected = create.files.len() + create.folders.len();
+    if selected == 0 || selected > MAX_SELECTION {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let name = create.name.unwrap_or_else(|| "download".to_string());
+    if !files::valid_name(&name) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let selection = Selection {
+        files: create.files,
+        folders: create.folders,
+    };
+    let collected = archive::collect(&state, user.id, &selection).await?;
+    Ok(archive::respond(&state, collected, &name))
+}
@@ -1,11 +1,21 @@
+use crate::models::access_key::{AccessKey, AccessKeyInsert};
+use crate::models::api_token::{ApiToken, ApiTokenInsert};
 use crate::prelude::*;
-use crate::user;
+use crate::{s3, user};
+use axum::extract::Path;
+use uuid::Uuid;
 
 pub(crate) fn router() -> Router<AppStateRef> {
     Router::new()
         .route("/", get(get_root))
         .route("/login", get(get_login).post(post_login))
         .route("/signup", get(get_signup).post(post_signup))
+        .route("/info", get(get_info))
+        .route("/logout", post(post_logout))
+        .route("/tokens", get(get_tokens).post(post_token))
+        .route("/tokens/{id}", delete(delete_token))
+        .route("/access-keys", get(get_access_keys).post(post_access_key))
+        .route("/access-keys/{id}", delete(delete_access_key))
 
     // .route("/login", get(get_info))
     // .route("/login", post(post_login))
@@ -28,6 +38,7 @@ async fn get_signup(State(state): State<AppStateRef>) -> ResultHtml {
 }
 
 async fn post_login(
+    State(state): State<AppStateRef>,
     mut auth_session: AuthSession,
     Form(credentials): Form<dto::auth::UserLoginDto>,
 ) -> ResultJson<dto::auth::UserInfoDto> {
@@ -43,10 +54,9 @@ async fn post_login(
         return Err(AppError::AxumLogin(err));
     }
 
-    Ok(Json(dto::auth::UserInfoDto {
-        id: user.id,
-        username: user.username,
-    }))
+    let usage = models::user::User::usage(state.db(), user.id).await?;
+
+    Ok(Json(dto::auth::UserInfoDto::new(user, usage)))
 }
 
 async fn post_signup(
@@ -97,22 +107,20 @@ async fn post_signup(
         return Err(AppError::AxumLogin(err));
     };
 
-    Ok(Json(dto::auth::UserInfoDto {
-        id: insert.id,
-        username: insert.username,
-    }))
+    Ok(Json(dto::auth::UserInfoDto::new(insert, 0)))
 }
 
-async fn get_info(auth_session: AuthSession) -> ResultJson<dto::auth::UserInfoDto> {
+async fn get_info(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+) -> ResultJson<dto::auth::UserInfoDto> {
     let user = match auth_session.user {
         Some(u) => u,
         None => return Err(StatusCode::UNAUTHORIZED.into()),
     };
+    let usage = models::user::User::usage(state.db(), user.id).await?;
 
-    Ok(Json(dto::auth::UserInfoDto {
-        id: user.id,
-        username: user.username,
-    }))
+    Ok(Json(dto::auth::UserInfoDto::new(user, usage)))
 }
 
 async fn post_logout(mut auth_session: AuthSession) -> ResultJson<dto::shared::SuccessResponse> {
@@ -124,3 +132,110 @@ async fn post_logout(mut auth_session: AuthSession) -> ResultJson<dto::shared::S
         message: "Success".to_string(),
     }))
 }
+
+async fn get_tokens(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+) -> ResultJson<Vec<dto::auth::ApiTokenDto>> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let tokens = ApiToken::list(state.db(), user.id).await?;
+
+    Ok(Json(tokens.into_iter().map(Into::into).collect()))
+}
+
+/// Create an API token. The response is the only place the token is ever shown.
+async fn post_token(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Json(create): Json<dto::auth::ApiTokenCreateDto>,
+) -> ResultJson<dto::auth::ApiTokenCreatedDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    if create.name.is_empty() || create.name.len() > 255 {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let (token, hash) = user::generate_api_token();
+    let created = ApiTokenInsert::new(user.id, create.name, hash)
+        .insert(state.db())
+        .await?;
+    info!(id = %created.id, user = %user.id, "created api token");
+
+    Ok(Json(dto::auth::ApiTokenCreatedDto {
+        info: created.into(),
+        token,
+    }))
+}
+
+async fn delete_token(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    if !ApiToken::delete_owned(state.db(), id, user.id).await? {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    info!(%id, user = %user.id, "revoked api token");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_access_keys(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+) -> ResultJson<Vec<dto::auth::AccessKeyDto>> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let keys = AccessKey::list(state.db(), user.id).await?;
+
+    Ok(Json(keys.into_iter().map(Into::into).collect()))
+}
+
+/// Create an access key for the S3 API. The response is the only place the secret is ever
+/// shown.
+async fn post_access_key(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Json(create): Json<dto::auth::AccessKeyCreateDto>,
+) -> ResultJson<dto::auth::AccessKeyCreatedDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    if create.name.is_empty() || create.name.len() > 255 {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let (id, secret) = s3::generate_access_key();
+    let created = AccessKeyInsert::new(id, user.id, create.name, secret.clone())
+        .insert(state.db())
+        .await?;
+    info!(id = created.id, user = %user.id, "created s3 access key");
+
+    Ok(Json(dto::auth::AccessKeyCreatedDto {
+        info: created.into(),
+        secret_access_key: secret,
+    }))
+}
+
+async fn delete_access_key(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<String>,
+) -> Result<StatusCode> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    if !AccessKey::delete_owned(state.db(), &id, user.id).await? {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    info!(id, user = %user.id, "revoked s3 access key");
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,600 @@
+use crate::dav::{
+    self, Change, DavPath, Lock, LockInfo, Locks, Multistatus, PREFIX, Properties, Propfind,
+    Resource,
+};
+use crate::error::conflict_on_unique;
+use crate::files;
+use crate::models::file::File;
+use crate::models::folder::{Folder, FolderInsert};
+use crate::models::user::User;
+use crate::prelude::*;
+use crate::serve::{self, Disposition};
+use axum::body::{Body, Bytes};
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::any;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use futures::{StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const DAV_HEADER: HeaderName = HeaderName::from_static("dav");
+const DEPTH: HeaderName = HeaderName::from_static("depth");
+const DESTINATION: HeaderName = HeaderName::from_static("destination");
+const OVERWRITE: HeaderName = HeaderName::from_static("overwrite");
+const IF: HeaderName = HeaderName::from_static("if");
+const LOCK_TOKEN: HeaderName = HeaderName::from_static("lock-token");
+const TIMEOUT: HeaderName = HeaderName::from_static("timeout");
+const MS_AUTHOR_VIA: HeaderName = HeaderName::from_static("ms-author-via");
+
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+
+/// `PROPFIND` and `LOCK` bodies larger than this are refused.
+const MAX_XML_BODY: usize = 64 * 1024;
+
+/// How long a successful password check is remembered, see [`authenticate`].
+const AUTH_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Not nested like the other routers, a nested `/` wouldn't match `/dav/`.
+pub(crate) fn router() -> Router<AppStateRef> {
+    Router::new()
+        .route(PREFIX, any(handle))
+        .route(&format!("{PREFIX}/"), any(handle))
+        .route(&format!("{PREFIX}/{{*path}}"), any(handle))
+}
+
+/// The user a WebDAV request was sent by. WebDAV clients don't do login forms or cookies, so
+/// every request carries HTTP Basic credentials.
+struct DavUser(User);
+
+impl FromRequestParts<AppStateRef> for DavUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppStateRef,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let Some((username, password)) = basic_credentials(&parts.headers) else {
+            return Err(unauthorized());
+        };
+        match authenticate(state, username, password).await {
+            Ok(Some(user)) => Ok(Self(user)),
+            Ok(None) => Err(unauthorized()),
+            Err(err) => Err(err.into_response()),
+        }
+    }
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(
+            header::WWW_AUTHENTICATE,
+            "Basic realm=\"unknown-server\", charset=\"UTF-8\"",
+        )],
+    )
+        .into_response()
+}
+
+/// User ids by a hash of the credentials, and when the password was checked.
+type AuthCache = HashMap<[u8; 32], (Uuid, Instant)>;
+
+/// Successful password checks. Password hashes are slow to check on purpose, and WebDAV
+/// clients send credentials with every single request.
+static AUTH_CACHE: LazyLock<Mutex<AuthCache>> = LazyLock::new(Default::default);
+
+/// Check credentials against the user's password or API tokens. Only password checks are
+/// cached, so revoking a token takes effect right away.
+async fn authenticate(
+    state: &AppState,
+    username: String,
+    password: String,
+) -> Result<Option<User>> {
+    let token = crate::user::is_api_token(&password);
+    let cache_key: [u8; 32] = Sha256::new()
+        .chain_update(username.as_bytes())
+        .chain_update([0])
+        .chain_update(password.as_bytes())
+        .finalize()
+        .into();
+
+    let cached = {
+        let mut cache = AUTH_CACHE.lock().expect("auth cache poisoned");
+        cache.retain(|_, (_, checked)| checked.elapsed() < AUTH_CACHE_TTL);
+        cache.get(&cache_key).map(|(id, _)| *id)
+    };
+    if !token && let Some(id) = cached {
+        return Ok(User::get(state.db(), id).await?);
+    }
+
+    let user = Backend::new(state.db().clone())
+        .authenticate_basic(username, password)
+        .await
+        .map_err(|err| AppError::AxumLogin(axum_login::Error::Backend(err)))?;
+    if !token && let Some(user) = &user {
+        let mut cache = AUTH_CACHE.lock().expect("auth cache poisoned");
+        cache.insert(cache_key, (user.id, Instant::now()));
+    }
+    Ok(user)
+}
+
+async fn handle(
+    State(state): State<AppStateRef>,
+    DavUser(user): DavUser,
+    request: Request,
+) -> Result<Response> {
+    let (parts, body) = request.into_parts();
+    let relative = parts.uri.path().strip_prefix(PREFIX).unwrap_or_default();
+    let Some(path) = DavPath::parse(relative) else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let headers = &parts.headers;
+
+    match parts.method.as_str() {
+        "OPTIONS" => Ok(options()),
+        "PROPFIND" => propfind(&state, &user, &path, headers, body).await,
+        "GET" | "HEAD" => get(&state, &user, &path, headers).await,
+        "PUT" => put(&state, &user, &path, headers, body).await,
+        "DELETE" => delete(&state, &user, &path, headers).await,
+        "MKCOL" => mkcol(&state, &user, &path, headers, body).await,
+        "COPY" => transfer(&state, &user, &path, headers, false).await,
+        "MOVE" => transfer(&state, &user, &path, headers, true).await,
+        "LOCK" => lock(&state, &user, &path, headers, body).await,
+        "UNLOCK" => unlock(&state, &user, &path, headers).await,
+        _ => Ok((StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, ALLOW)]).into_response()),
+    }
+}
+
+fn options() -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(DAV_HEADER, HeaderValue::from_static("1, 2"));
+    headers.insert(header::ALLOW, HeaderValue::from_static(ALLOW));
+    // Keeps Windows from trying FrontPage extensions first
+    headers.insert(MS_AUTHOR_VIA, HeaderValue::from_static("DAV"));
+    (StatusCode::OK, headers).into_response()
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn submitted_tokens(headers: &HeaderMap) -> Vec<String> {
+    dav::lock::submitted_tokens(header_str(headers, &IF))
+}
+
+async fn read_xml(body: Body) -> Result<String> {
+    let body: Bytes = axum::body::to_bytes(body, MAX_XML_BODY)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+    String::from_utf8(body.to_vec()).map_err(|_| StatusCode::BAD_REQUEST.into())
+}
+
+fn xml(status: StatusCode, body: String) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+/// WebDAV clients expect `507 Insufficient Storage` when the quota is used up.
+fn insufficient_storage(err: AppError) -> AppError {
+    match err {
+        AppError::QuotaExceeded { .. } => StatusCode::INSUFFICIENT_STORAGE.into(),
+        err => err,
+    }
+}
+
+async fn require(state: &AppState, user: &User, path: &DavPath) -> Result<Resource> {
+    match dav::resolve(state, user.id, path).await? {
+        Some(resource) => Ok(resource),
+        None => Err(StatusCode::NOT_FOUND.into()),
+    }
+}
+
+/// The folder `path` would be created in. Missing folders aren't created along the way, that
+/// is `409 Conflict`.
+async fn require_parent(state: &AppState, user: &User, path: &DavPath) -> Result<Option<Uuid>> {
+    match dav::resolve_parent(state, user.id, path).await? {
+        Some(parent) => Ok(parent),
+        None if path.is_root() => Err(StatusCode::FORBIDDEN.into()),
+        None => Err(StatusCode::CONFLICT.into()),
+    }
+}
+
+fn properties(
+    path: DavPath,
+    resource: &Resource,
+    locks: &Locks,
+    quota: (u64, Option<u64>),
+) -> Properties {
+    let mut properties = Properties {
+        locks: locks.covering(&path),
+        path,
+        collection: resource.is_collection(),
+        size: None,
+        content_type: None,
+        etag: None,
+        created: None,
+        modified: None,
+        quota: resource.is_collection().then_some(quota),
+    };
+    match resource {
+        Resource::Root => {}
+        Resource::Folder(folder) => {
+            properties.created = Some(folder.created);
+            properties.modified = Some(folder.modified);
+        }
+        Resource::File(file) => {
+            properties.size = Some(file.size as u64);
+            properties.content_type = Some(file.content_type().to_string());
+            // Same as downloads, so clients can revalidate either way
+            properties.etag = Some(format!("\"{}\"", file.sha256));
+            properties.created = Some(file.created);
+            properties.modified = Some(file.uploaded);
+        }
+    }
+    properties
+}
+
+/// Properties of a resource and, with `Depth: 1`, of everything directly in it.
+async fn propfind(
+    state: &AppState,
+    user: &User,
+    path: &DavPath,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response> {
+    let recursive = match header_str(headers, &DEPTH) {
+        Some("0") => false,
+        // Listing everything below a folder at once is refused, clients walk the tree instead.
+        // Clients that don't say get one level
+        Some("1") | None => true,
+        Some(_) => {
+            return Ok(xml(
+                StatusCode::FORBIDDEN,
+                dav::xml::precondition("propfind-finite-depth"),
+            ));
+        }
+    };
+    let request = Propfind::parse(&read_xml(body).await?).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let resource = require(state, user, path).await?;
+    let locks = Locks::load(state, user.id).await?;
+    let used = User::usage(state.db(), user.id).await?;
+    let quota = (used, user.quota().map(|quota| quota.saturating_sub(used)));
+
+    let mut multistatus = Multistatus::new();
+    multistatus.properties(
+        &properties(path.clone(), &resource, &locks, quota),
+        &request,
+    );
+    if recursive && let Some(parent_id) = resource.as_parent() {
+        for folder in Folder::children(state.db(), user.id, parent_id, i64::MAX, 0).await? {
+            let child = path.join(&folder.name);
+            let resource = Resource::Folder(folder);
+            multistatus.properties(&properties(child, &resource, &locks, quota), &request);
+        }
+        for file in File::in_folder(state.db(), user.id, parent_id, i64::MAX, 0).await? {
+            let child = path.join(&file.name);
+            let resource = Resource::File(file);
+            multistatus.properties(&properties(child, &resource, &locks, quota), &request);
+        }
+    }
+
+    Ok(xml(StatusCode::MULTI_STATUS, multistatus.finish()))
+}
+
+async fn get(
+    state: &AppState,
+    user: &User,
+    path: &DavPath,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    match require(state, user, path).await? {
+        Resource::File(file) => {
+            serve::serve_file(state, &file, headers, Disposition::Attachment).await
+        }
+        // Collections are listed with PROPFIND
+        _ => Ok((StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, ALLOW)]).into_response()),
+    }
+}
+
+/// Upload a file, or a new version of an existing one.
+async fn put(
+    state: &AppState,
+    user: &User,
+    path: &DavPath,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response> {
+    // Partial uploads would replace the whole file with just the part
+    if headers.contains_key(header::CONTENT_RANGE) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let Some(name) = path.name() else {
+        return Err(StatusCode::METHOD_NOT_ALLOWED.into());
+    };
+    let existing = dav::resolve(state, user.id, path).await?;
+    let change = match &existing {
+        Some(Resource::File(_)) => Change::Contents,
+        Some(_) => return Err(StatusCode::METHOD_NOT_ALLOWED.into()),
+        None => Change::Membership,
+    };
+    let folder_id = require_parent(state, user, path).await?;
+    Locks::load(state, user.id)
+        .await?
+        .check(path, change, &submitted_tokens(headers))?;
+
+    let new = files::NewFile {
+        owner: user.id,
+        folder_id,
+        name: name.to_string(),
+        content_type: header_str(headers, &header::CONTENT_TYPE)
+            .unwrap_or("application/octet-stream")
+            .to_string(),
+        expiry: None,
+    };
+    if let Some(length) =
+        header_str(headers, &header::CONTENT_LENGTH).and_then(|length| length.parse().ok())
+    {
+        new.check_size(length)?;
+    }
+    let body = body
+        .into_data_stream()
+        .map_err(std::io::Error::other)
+        .boxed();
+    let file = files::store_file(state, new, body)
+        .await
+        .map_err(insufficient_storage)?;
+
+    let status = match existing {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::CREATED,
+    };
+    let etag = HeaderValue::from_str(&format!("\"{}\"", file.sha256)).expect("hex is valid");
+    Ok((status, [(header::ETAG, etag)]).into_response())
+}
+
+/// Move a file or folder to the trash.
+async fn delete(
+    state: &AppState,
+    user: &User,
+    path: &DavPath,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let resource = require(state, user, path).await?;
+    let locks = Locks::load(state, user.id).await?;
+    locks.check(path, Change::Membership, &submitted_tokens(headers))?;
+
+    match resource {
+        Resource::Root => return Err(StatusCode::FORBIDDEN.into()),
+        Resource::Folder(folder) => files::trash_folder(state, &folder).await?,
+        Resource::File(file) => files::trash_file(state, &file).await?,
+    }
+    dav::lock::release_below(state, user.id, &locks, path).await;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Create a folder.
+async fn mkcol(
+    state: &AppState,
+    user: &User,
+    path: &DavPath,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response> {
+    // Bodies could ask for properties to be set along the way, which we can't do
+    if !read_xml(body).await?.is_empty() {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
+    }
+    if dav::resolve(state, user.id, path).await?.is_some() {
+        return Err(StatusCode::METHOD_NOT_ALLOWED.into());
+    }
+    let parent_id = require_parent(state, user, path).await?;
+    Locks::load(state, user.id).await?.check(
+        path,
+        Change::Membership,
+        &submitted_tokens(headers),
+    )?;
+
+    let name = path.name().expect("the root exists").to_string();
+    let folder = FolderInsert::new(user.id, parent_id, name)
+        .insert(state.db())
+        .await
+        .map_err(conflict_on_unique)?;
+    debug!(id = %folder.id, owner = %user.id, "created folder");
+
+    Ok(StatusCode::CREATED.into_response())
+}
+
+/// `COPY` or `MOVE` a file or folder to the `Destination`, replacing what's there unless
+/// `Overwrite: F` was sent.
+async fn transfer(
+    state: &AppState,
+    user: &User,
+    path: &DavPath,
+    headers: &HeaderMap,
+    moving: bool,
+) -> Result<Response> {
+    let Some(destination) = header_str(headers, &DESTINATION).and_then(DavPath::from_destination)
+    else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+    let overwrite = header_str(headers, &OVERWRITE) != Some("F");
+    let recursive = match header_str(headers, &DEPTH) {
+        Some("infinity") | None => true,
+        Some("0") if !moving => false,
+        _ => return Err(StatusCode::BAD_REQUEST.into()),
+    };
+
+    let source = require(state, user, path).await?;
+    if matches!(source, Resource::Root) || destination == *path {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    // Replacing the destination would take part of the source with it
+    if destination.is_within(path) {
+        return Err(StatusCode::CONFLICT.into());
+    }
+    let parent_id = require_parent(state, user, &destination).await?;
+
+    let locks = Locks::load(state, user.id).await?;
+    let tokens = submitted_tokens(headers);
+    if moving {
+        locks.check(path, Change::Membership, &tokens)?;
+    }
+    locks.check(&destination, Change::Membership, &tokens)?;
+
+    let existing = dav::resolve(state, user.id, &destination).await?;
+    match &existing {
+        Some(_) if !overwrite => return Err(StatusCode::PRECONDITION_FAILED.into()),
+        Some(Resource::Folder(folder)) => files::trash_folder(state, folder).await?,
+        Some(Resource::File(file)) => files::trash_file(state, file).await?,
+        Some(Resource::Root) => unreachable!("the destination has a parent"),
+        None => {}
+    }
+
+    let name = destination.name().expect("the destination has a parent");
+    match (source, moving) {
+        (Resource::File(file), true) => {
+            files::move_file(state, &file, parent_id, name).await?;
+        }
+        (Resource::File(file), false) => {
+            files::copy_file(state, &file, parent_id, name)
+                .await
+                .map_err(insufficient_storage)?;
+        }
+        (Resource::Folder(folder), true) => {
+            files::move_folder(state, &folder, parent_id, name).await?;
+        }
+        (Resource::Folder(folder), false) => {
+            files::copy_folder(state, &folder, parent_id, name, recursive)
+                .await
+                .map_err(insufficient_storage)?;
+        }
+        (Resource::Root, _) => unreachable!("checked above"),
+    }
+    if moving {
+        dav::lock::release_below(state, user.id, &locks, path).await;
+    }
+
+    match existing {
+        Some(_) => Ok(StatusCode::NO_CONTENT.into_response()),
+        None => Ok(StatusCode::CREATED.into_response()),
+    }
+}
+
+/// Take or refresh a write lock. Locking a path that doesn't exist creates an empty file.
+async fn lock(
+    state: &AppState,
+    user: &User,
+    path: &DavPath,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response> {
+    let info = LockInfo::parse(&read_xml(body).await?).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let timeout = dav::lock::requested_timeout(header_str(headers, &TIMEOUT));
+    let locks = Locks::load(state, user.id).await?;
+    let tokens = submitted_tokens(headers);
+
+    let Some(info) = info else {
+        // Refreshing, the lock to refresh is the one named in the `If` header
+        let Some(held) = tokens.iter().find_map(|token| locks.find(path, token)) else {
+            return Err(StatusCode::PRECONDITION_FAILED.into());
+        };
+        let lock = dav::lock::refresh(state, user.id, held, timeout).await?;
+        return Ok(xml(StatusCode::OK, dav::xml::lock_discovery(&lock)));
+    };
+    if !info.exclusive {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    let infinite = match header_str(headers, &DEPTH) {
+        Some("infinity") | None => true,
+        Some("0") => false,
+        _ => return Err(StatusCode::BAD_REQUEST.into()),
+    };
+    // Locks can't overlap, whatever tokens were sent
+    if locks.conflicts(path, infinite) {
+        return Ok(xml(
+            StatusCode::LOCKED,
+            dav::xml::precondition("no-conflicting-lock"),
+        ));
+    }
+
+    let created = match dav::resolve(state, user.id, path).await? {
+        Some(_) => false,
+        None => {
+            let folder_id = require_parent(state, user, path).await?;
+            let new = files::NewFile {
+                owner: user.id,
+                folder_id,
+                name: path.name().expect("the root exists").to_string(),
+                content_type: "application/octet-stream".to_string(),
+                expiry: None,
+            };
+            files::store_file(state, new, futures::stream::empty().boxed())
+                .await
+                .map_err(insufficient_storage)?;
+            true
+        }
+    };
+
+    let lock = Lock::new(path, infinite, info.owner, timeout);
+    if !dav::lock::acquire(state, user.id, &lock).await? {
+        return Ok(xml(
+            StatusCode::LOCKED,
+            dav::xml::precondition("no-conflicting-lock"),
+        ));
+    }
+    debug!(path = lock.path, owner = %user.id, infinite, "locked webdav resource");
+
+    let status = match created {
+        true => StatusCode::CREATED,
+        false => StatusCode::OK,
+    };
+    let mut response = xml(status, dav::xml::lock_discovery(&lock));
+    let token = HeaderValue::from_str(&format!("<{}>", lock.token)).expect("tokens are ascii");
+    response.headers_mut().insert(LOCK_TOKEN, token);
+    Ok(response)
+}
+
+async fn unlock(
+    state: &AppState,
+    user: &User,
+    path: &DavPath,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let Some(token) = header_str(headers, &LOCK_TOKEN)
+        .and_then(|token| token.trim().strip_prefix('<')?.strip_suffix('>'))
+    else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+    let locks = Locks::load(state, user.id).await?;
+    let Some(lock) = locks.find(path, token) else {
+        return Ok(xml(
+            StatusCode::CONFLICT,
+            dav::xml::precondition("lock-token-matches-request-uri"),
+        ));
+    };
+    dav::lock::release(state, user.id, lock).await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
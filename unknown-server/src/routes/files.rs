@@ -0,0 +1,215 @@
+use crate::files;
+use crate::prelude::*;
+use crate::serve::{self, Disposition};
+use crate::storage::{PresignGetOptions, StorageError};
+use axum::extract::{Path, Query};
+use axum::http::HeaderMap;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub(crate) fn router() -> Router<AppStateRef> {
+    Router::new()
+        .route("/{id}", get(get_file).patch(patch_file).delete(delete_file))
+        .route("/{id}/presign", get(get_presigned))
+        .route("/{id}/versions", get(get_versions))
+        .route("/{id}/versions/{version}", get(get_version))
+        .route("/{id}/versions/{version}/rollback", post(post_rollback))
+        .route("/{id}/{filename}", get(get_file_named))
+        .route_layer(login_required!(Backend))
+}
+
+#[derive(Debug, Deserialize)]
+struct DownloadQuery {
+    /// Ask the browser to save the file instead of displaying it
+    #[serde(default)]
+    download: bool,
+}
+
+async fn get_file(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Query(query): Query<DownloadQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(file) = models::file::File::get_owned(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    let disposition = match query.download {
+        true => Disposition::Attachment,
+        false => Disposition::Inline,
+    };
+    serve::serve_file(&state, &file, &headers, disposition).await
+}
+
+#[derive(Debug, Serialize)]
+struct PresignedDownload {
+    url: String,
+    #[serde(with = "time::serde::rfc3339")]
+    expires: time::OffsetDateTime,
+}
+
+/// A time limited url that downloads the file straight from storage.
+async fn get_presigned(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Query(query): Query<DownloadQuery>,
+) -> ResultJson<PresignedDownload> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(file) = models::file::File::get_owned(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    serve::ensure_servable(&file)?;
+    let Some(blob) = models::blob::Blob::get(state.db(), &file.sha256).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    // Storage would hand out the compressed object
+    if blob.encoding.is_some() {
+        return Err(StorageError::Unsupported("presigning compressed files").into());
+    }
+
+    let disposition = match query.download {
+        true => Disposition::Attachment,
+        false => Disposition::Inline,
+    };
+    let presigned = state
+        .storage()
+        .presign_get(
+            &blob.storage_key,
+            Duration::from_secs(CONFIG.upload.presign_expiration),
+            PresignGetOptions {
+                content_type: Some(file.content_type().to_string()),
+                content_disposition: Some(serve::content_disposition(disposition, &file.name)),
+            },
+        )
+        .await?;
+
+    Ok(Json(PresignedDownload {
+        url: presigned.url,
+        expires: presigned.expires,
+    }))
+}
+
+/// Same as [`get_file`]. The filename only makes for nicer urls.
+async fn get_file_named(
+    state: State<AppStateRef>,
+    auth_session: AuthSession,
+    Path((id, _filename)): Path<(Uuid, String)>,
+    query: Query<DownloadQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    get_file(state, auth_session, Path(id), query, headers).await
+}
+
+/// Rename and/or move a file.
+async fn patch_file(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(update): Json<dto::file::FileUpdateDto>,
+) -> ResultJson<dto::file::FileInfoDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(file) = models::file::File::get_owned(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    let name = update.name.unwrap_or_else(|| file.name.clone());
+    let folder_id = update.folder_id.unwrap_or(file.folder_id);
+    let file = files::move_file(&state, &file, folder_id, &name).await?;
+
+    Ok(Json(file.into()))
+}
+
+async fn delete_file(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(file) = models::file::File::get_owned(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    files::trash_file(&state, &file).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The current version of a file followed by the earlier versions that are still kept.
+async fn get_versions(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> ResultJson<Vec<dto::file::VersionDto>> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(file) = models::file::File::get_owned(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let versions = models::version::FileVersion::list(state.db(), file.id).await?;
+
+    Ok(Json(dto::file::VersionDto::history(&file, &versions)))
+}
+
+/// Download a file as it was at `version`.
+async fn get_version(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path((id, version)): Path<(Uuid, i32)>,
+    Query(query): Query<DownloadQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(file) = models::file::File::get_owned(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let file = match version == file.version {
+        true => file,
+        false => match models::version::FileVersion::get(state.db(), file.id, version).await? {
+            Some(version) => version.as_file(&file),
+            None => return Err(StatusCode::NOT_FOUND.into()),
+        },
+    };
+
+    let disposition = match query.download {
+        true => Disposition::Attachment,
+        false => Disposition::Inline,
+    };
+    serve::serve_file(&state, &file, &headers, disposition).await
+}
+
+/// Make an earlier version current again. The replaced contents become a version themselves.
+async fn post_rollback(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path((id, version)): Path<(Uuid, i32)>,
+) -> ResultJson<dto::file::FileInfoDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(file) = models::file::File::get_owned(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    // Already current, nothing to roll back to
+    if version == file.version {
+        return Err(StatusCode::CONFLICT.into());
+    }
+
+    let file = files::rollback_file(&state, &file, version).await?;
+    Ok(Json(file.into()))
+}
@@ -0,0 +1,209 @@
+use crate::archive::{self, Selection};
+use crate::dto::folder::{EntryDto, FolderCreateDto, FolderListingDto, FolderUpdateDto};
+use crate::dto::shared::PageQuery;
+use crate::error::conflict_on_unique;
+use crate::files;
+use crate::models::file::File;
+use crate::models::folder::{Folder, FolderInsert};
+use crate::prelude::*;
+use axum::extract::{Path, Query};
+use axum::response::Response;
+use serde::Deserialize;
+use uuid::Uuid;
+
+pub(crate) fn router() -> Router<AppStateRef> {
+    Router::new()
+        .route("/", get(get_root).post(post_folder))
+        .route("/resolve", get(get_resolve))
+        .route(
+            "/{id}",
+            get(get_folder).patch(patch_folder).delete(delete_folder),
+        )
+        .route("/{id}/archive", get(get_archive))
+        .route_layer(login_required!(Backend))
+}
+
+/// List the contents of `folder`. Folders come before files, so a page may hold both.
+async fn list(
+    state: &AppState,
+    owner_id: Uuid,
+    folder: Option<Folder>,
+    page: PageQuery,
+) -> Result<FolderListingDto> {
+    let folder_id = folder.as_ref().map(|folder| folder.id);
+    let folder_count = Folder::count_children(state.db(), owner_id, folder_id).await?;
+    let file_count = File::count_in_folder(state.db(), owner_id, folder_id).await?;
+
+    let mut entries: Vec<EntryDto> = vec![];
+    if page.offset() < folder_count {
+        let folders =
+            Folder::children(state.db(), owner_id, folder_id, page.limit(), page.offset()).await?;
+        entries.extend(folders.into_iter().map(|f| EntryDto::Folder(f.into())));
+    }
+    let remaining = page.limit() - entries.len() as i64;
+    if remaining > 0 {
+        let offset = (page.offset() - folder_count).max(0);
+        let files = File::in_folder(state.db(), owner_id, folder_id, remaining, offset).await?;
+        entries.extend(files.into_iter().map(|f| EntryDto::File(f.into())));
+    }
+
+    Ok(FolderListingDto {
+        folder: folder.map(Into::into),
+        entries,
+        total: (folder_count + file_count) as u64,
+        offset: page.offset() as u64,
+        limit: page.limit() as u64,
+    })
+}
+
+async fn get_root(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Query(page): Query<PageQuery>,
+) -> ResultJson<FolderListingDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+
+    Ok(Json(list(&state, user.id, None, page).await?))
+}
+
+async fn get_folder(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Query(page): Query<PageQuery>,
+) -> ResultJson<FolderListingDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(folder) = Folder::get_owned(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    Ok(Json(list(&state, user.id, Some(folder), page).await?))
+}
+
+async fn post_folder(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Json(create): Json<FolderCreateDto>,
+) -> ResultJson<dto::folder::FolderDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    if !files::valid_name(&create.name) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if let Some(parent_id) = create.parent_id
+        && Folder::get_owned(state.db(), parent_id, user.id)
+            .await?
+            .is_none()
+    {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    let folder = FolderInsert::new(user.id, create.parent_id, create.name)
+        .insert(state.db())
+        .await
+        .map_err(conflict_on_unique)?;
+    debug!(id = %folder.id, owner = %user.id, "created folder");
+
+    Ok(Json(folder.into()))
+}
+
+async fn patch_folder(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    Json(update): Json<FolderUpdateDto>,
+) -> ResultJson<dto::folder::FolderDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+
+    let Some(folder) = Folder::get_owned(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let name = update.name.unwrap_or_else(|| folder.name.clone());
+    let parent_id = update.parent_id.unwrap_or(folder.parent_id);
+    let folder = files::move_folder(&state, &folder, parent_id, &name).await?;
+
+    Ok(Json(folder.into()))
+}
+
+async fn delete_folder(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(folder) = Folder::get_owned(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    files::trash_folder(&state, &folder).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct ResolveQuery {
+    /// Slash separated path such as `/docs/2026/report.pdf`
+    path: String,
+}
+
+/// Find the file or folder at a path. The root itself isn't an entry, list it with
+/// `GET /folders` instead.
+async fn get_resolve(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Query(query): Query<ResolveQuery>,
+) -> ResultJson<EntryDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+
+    let mut segments: Vec<&str> = query
+        .path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let Some(last) = segments.pop() else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let Some(parent_id) = Folder::walk(state.db(), user.id, &segments).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    if let Some(folder) = Folder::find_child(state.db(), user.id, parent_id, last).await? {
+        return Ok(Json(EntryDto::Folder(folder.into())));
+    }
+    match File::find_in_folder(state.db(), user.id, parent_id, last).await? {
+        Some(file) => Ok(Json(EntryDto::File(file.into()))),
+        None => Err(StatusCode::NOT_FOUND.into()),
+    }
+}
+
+/// Download `id` with everything in it as a ZIP archive.
+async fn get_archive(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Result<Response> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(folder) = Folder::get_owned(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    let selection = Selection {
+        folders: vec![folder.id],
+        ..Default::default()
+    };
+    let collected = archive::collect(&state, user.id, &selection).await?;
+    Ok(archive::respond(&state, collected, &folder.name))
+}
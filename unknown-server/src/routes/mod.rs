@@ -1,2 +1,11 @@
+pub(crate) mod archive;
 pub(crate) mod auth;
+pub(crate) mod dav;
+pub(crate) mod files;
+pub(crate) mod folders;
+pub(crate) mod paste;
+pub(crate) mod presign;
+pub(crate) mod s3;
+pub(crate) mod trash;
+pub(crate) mod tus;
 pub(crate) mod upload;
@@ -0,0 +1,157 @@
+use crate::files;
+use crate::models::paste::Paste;
+use crate::paste::{self, NewPaste};
+use crate::prelude::*;
+use axum::extract::{DefaultBodyLimit, Path};
+use axum::http::{HeaderValue, header};
+use axum::response::{IntoResponse, Response};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+pub(crate) fn router() -> Router<AppStateRef> {
+    Router::new()
+        .route("/", get(get_new).post(post_paste))
+        .route("/{id}", get(get_paste).delete(delete_paste))
+        .route("/{id}/raw", get(get_raw))
+        .route_layer(login_required!(Backend))
+        // Form encoding can make a paste up to three times as large
+        .layer(DefaultBodyLimit::max(
+            CONFIG.paste.max_size as usize * 3 + 64 * 1024,
+        ))
+}
+
+/// Form fields browsers send empty when nothing was entered.
+fn non_empty(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn display_time(time: OffsetDateTime) -> String {
+    let time = time.to_offset(time::UtcOffset::UTC);
+    format!(
+        "{} {:02}:{:02} UTC",
+        time.date(),
+        time.hour(),
+        time.minute()
+    )
+}
+
+async fn get_new(State(state): State<AppStateRef>) -> ResultHtml {
+    let context = context! {
+        languages => paste::languages(),
+        max_size => CONFIG.paste.max_size,
+    };
+    let template = state.render_template("paste/new.j2.html", Some(context))?;
+    Ok(Html(template))
+}
+
+/// Create a paste from the form and show it.
+async fn post_paste(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Form(create): Form<dto::paste::PasteCreateDto>,
+) -> Result<Redirect> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+
+    let expiry = non_empty(create.expiry)
+        .map(|expiry| expiry.parse())
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let new = NewPaste {
+        owner: user.id,
+        title: non_empty(create.title),
+        language: non_empty(create.language),
+        content: create.content,
+        expiry,
+    };
+    let paste = paste::create_paste(&state, new).await?;
+
+    Ok(Redirect::to(&format!("/paste/{}", paste.id)))
+}
+
+/// The paste, highlighted. Any signed in user with the link can see it.
+async fn get_paste(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> ResultHtml {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let (paste, file) = paste::find_paste(&state, id).await?;
+    let text = paste::read_text(&state, &file).await?;
+
+    // Highlighting large pastes takes a while, keep it off the async workers
+    let language = paste.language.clone();
+    let highlighted =
+        tokio::task::spawn_blocking(move || paste::highlight(&text, language.as_deref()))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|err| {
+                error!(%id, %err, "failed to highlight paste");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+    let Paste {
+        title,
+        language,
+        created,
+        owner_id,
+        ..
+    } = paste;
+    let context = context! {
+        id => id,
+        title => title,
+        language => language,
+        created => display_time(created),
+        expires => file.expires.map(display_time),
+        size => file.size,
+        own => owner_id == user.id,
+        highlighted => highlighted,
+    };
+    let template = state.render_template("paste/view.j2.html", Some(context))?;
+    Ok(Html(template))
+}
+
+/// The paste as plain text, for copying or `curl`.
+async fn get_raw(State(state): State<AppStateRef>, Path(id): Path<Uuid>) -> Result<Response> {
+    let (_, file) = paste::find_paste(&state, id).await?;
+    let text = paste::read_text(&state, &file).await?;
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+            ),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+            (header::CACHE_CONTROL, HeaderValue::from_static("private")),
+        ],
+        text,
+    )
+        .into_response())
+}
+
+/// Move the file of a paste to the trash, which takes the paste down until it is restored.
+async fn delete_paste(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let (paste, file) = paste::find_paste(&state, id).await?;
+    if paste.owner_id != user.id {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    files::trash_file(&state, &file).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
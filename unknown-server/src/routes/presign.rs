@@ -0,0 +1,468 @@
+//! Direct uploads to object storage with presigned urls.
+//!
+//! A client asks for a presigned PUT, sends the body straight to the bucket and then calls the
+//! completion endpoint. The presigned url only ever points at a staging object under
+//! `presign/`, never at a blob, so a client holding it can't change contents once they are
+//! recorded. Completion only looks at the size of the staging object and moves it to a new blob
+//! within the bucket, so the body never passes through the server in a request. Hashing it and
+//! checking it against the upload policy happens in the background after that, which can be
+//! followed at `/upload/progress/{id}`. Checks that stall are started again by
+//! [`sweep_abandoned`], which also removes staging objects whose upload is never completed.
+use crate::files;
+use crate::ingest::{self, IngestError};
+use crate::policy::POLICY;
+use crate::prelude::*;
+use crate::progress::Progress;
+use crate::storage::{PresignPutOptions, StorageError};
+use axum::extract::Path;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use fred::prelude::{
+    HashesInterface, KeysInterface, SetOptions, SortedSetsInterface, TransactionInterface,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Seconds a client has to call the completion endpoint after the presigned url expired.
+const COMPLETE_GRACE: u64 = 60 * 60;
+
+/// Sorted set of storage keys of uncompleted uploads, scored by when they may be removed.
+const PENDING_KEY: &str = "presign:pending";
+
+/// Sorted set of ids of completed uploads whose contents are being checked, scored by when the
+/// check counts as stalled.
+const VERIFYING_KEY: &str = "presign:verifying";
+
+/// Seconds a check may go without news before the sweep starts it again. Running checks keep
+/// pushing this back, see [`holding_claim`].
+const VERIFY_TIMEOUT: u64 = 5 * 60;
+
+pub(crate) fn router() -> Router<AppStateRef> {
+    Router::new()
+        .route("/", post(post_presign))
+        .route("/{id}/complete", post(post_complete))
+}
+
+/// Storage prefix of the objects clients upload to.
+pub(crate) const STAGING_PREFIX: &str = "presign/";
+
+fn upload_key(id: Uuid) -> String {
+    format!("presign:upload:{id}")
+}
+
+/// Storage key the client uploads to.
+fn staging_key(id: Uuid) -> String {
+    format!("{STAGING_PREFIX}{id}")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Debug, Deserialize)]
+struct PresignRequest {
+    name: String,
+    /// Folder the file is stored in. The root if unset
+    folder_id: Option<Uuid>,
+    content_type: Option<String>,
+    /// Exact size of the upload in bytes. Enforced by the bucket when given
+    size: Option<u64>,
+    /// Hex encoded sha256 the upload is checked against on completion
+    sha256: Option<String>,
+    /// How long the file is kept, counted from completion
+    expiry: Option<dto::file::FileExpiry>,
+}
+
+#[derive(Debug, Serialize)]
+struct PresignResponse {
+    id: Uuid,
+    method: String,
+    url: String,
+    /// Headers that have to be sent with the upload
+    headers: HashMap<String, String>,
+    #[serde(with = "time::serde::rfc3339")]
+    expires: time::OffsetDateTime,
+}
+
+/// Upload state as stored in redis
+#[derive(Debug)]
+struct PresignedUpload {
+    owner_id: Uuid,
+    staging_key: String,
+    folder_id: Option<Uuid>,
+    name: String,
+    content_type: String,
+    size: Option<u64>,
+    sha256: Option<String>,
+    expiry: Option<dto::file::FileExpiry>,
+    /// Where the contents were moved on completion
+    blob_key: Option<String>,
+}
+
+impl PresignedUpload {
+    async fn load(state: &AppState, id: Uuid) -> Result<Option<Self>> {
+        let mut fields: HashMap<String, String> = state.fred().hgetall(upload_key(id)).await?;
+        let mut field = |name: &str| fields.remove(name);
+        let upload = (|| {
+            Some(Self {
+                owner_id: field("owner_id")?.parse().ok()?,
+                staging_key: field("staging_key")?,
+                folder_id: field("folder_id").and_then(|id| id.parse().ok()),
+                name: field("name")?,
+                content_type: field("content_type")?,
+                size: field("size").and_then(|size| size.parse().ok()),
+                sha256: field("sha256"),
+                expiry: field("expiry").and_then(|expiry| expiry.parse().ok()),
+                blob_key: field("blob_key"),
+            })
+        })();
+        Ok(upload)
+    }
+
+    async fn load_owned(state: &AppState, id: Uuid, owner_id: Uuid) -> Result<Self> {
+        match Self::load(state, id).await? {
+            Some(upload) if upload.owner_id == owner_id => Ok(upload),
+            _ => Err(StatusCode::NOT_FOUND.into()),
+        }
+    }
+
+    fn new_file(&self) -> files::NewFile {
+        files::NewFile {
+            owner: self.owner_id,
+            folder_id: self.folder_id,
+            name: self.name.clone(),
+            content_type: self.content_type.clone(),
+            expiry: self.expiry,
+        }
+    }
+
+    async fn create(&self, state: &AppState, id: Uuid, expires: u64) -> Result<()> {
+        let mut fields = vec![
+            ("owner_id", self.owner_id.to_string()),
+            ("staging_key", self.staging_key.clone()),
+            ("name", self.name.clone()),
+            ("content_type", self.content_type.clone()),
+        ];
+        if let Some(folder_id) = self.folder_id {
+            fields.push(("folder_id", folder_id.to_string()));
+        }
+        if let Some(size) = self.size {
+            fields.push(("size", size.to_string()));
+        }
+        if let Some(sha256) = &self.sha256 {
+            fields.push(("sha256", sha256.clone()));
+        }
+        if let Some(expiry) = self.expiry {
+            fields.push(("expiry", expiry.as_str().to_string()));
+        }
+
+        let key = upload_key(id);
+        let _: () = state.fred().hset(&key, fields).await?;
+        let _: () = state.fred().expire_at(&key, expires as i64, None).await?;
+        let _: () = state
+            .fred()
+            .zadd(
+                PENDING_KEY,
+                None,
+                None,
+                false,
+                false,
+                (expires as f64, self.staging_key.as_str()),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+async fn post_presign(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Json(request): Json<PresignRequest>,
+) -> ResultJson<PresignResponse> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let sha256 = match request.sha256 {
+        Some(sha256) if sha256.len() != 64 || hex::decode(&sha256).is_err() => {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        sha256 => sha256.map(|sha256| sha256.to_ascii_lowercase()),
+    };
+    if let Some(size) = request.size {
+        files::check_quota(&state, user.id, size).await?;
+    }
+
+    let id = Uuid::now_v7();
+    let upload = PresignedUpload {
+        owner_id: user.id,
+        staging_key: staging_key(id),
+        folder_id: request.folder_id,
+        name: request.name,
+        content_type: request
+            .content_type
+            .unwrap_or_else(|| "application/octet-stream".to_string()),
+        size: request.size,
+        sha256,
+        expiry: request.expiry,
+        blob_key: None,
+    };
+    upload.new_file().validate(&state).await?;
+    if let Some(size) = upload.size {
+        upload.new_file().check_size(size)?;
+    }
+
+    let expires_in = Duration::from_secs(CONFIG.upload.presign_expiration);
+    let presigned = state
+        .storage()
+        .presign_put(
+            &upload.staging_key,
+            expires_in,
+            PresignPutOptions {
+                content_type: Some(upload.content_type.clone()),
+                content_length: upload.size,
+            },
+        )
+        .await?;
+    upload
+        .create(&state, id, now() + expires_in.as_secs() + COMPLETE_GRACE)
+        .await?;
+    debug!(%id, owner = %user.id, "presigned upload");
+
+    Ok(Json(PresignResponse {
+        id,
+        method: presigned.method,
+        url: presigned.url,
+        headers: presigned.headers.into_iter().collect(),
+        expires: presigned.expires,
+    }))
+}
+
+async fn post_complete(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Result<Response> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let mut upload = PresignedUpload::load_owned(&state, id, user.id).await?;
+    if upload.blob_key.is_some() {
+        return Err(StatusCode::CONFLICT.into());
+    }
+
+    // Nothing to complete until the client actually uploaded something
+    let size = match state.storage().head(&upload.staging_key).await {
+        Ok(meta) => meta.size,
+        Err(StorageError::NotFound(_)) => return Err(StatusCode::CONFLICT.into()),
+        Err(err) => return Err(err.into()),
+    };
+    // The url is still good for another try until the upload is claimed
+    if upload.size.is_some_and(|expected| expected != size) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+    }
+    upload.new_file().check_size(size)?;
+    files::check_quota(&state, user.id, size).await?;
+
+    // Claim the upload, so neither a second completion nor the sweeper can touch it anymore
+    let claimed: i64 = state
+        .fred()
+        .zrem(PENDING_KEY, upload.staging_key.as_str())
+        .await?;
+    if claimed == 0 {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    let key = ingest::blob_key();
+    if let Err(err) = state.storage().rename(&upload.staging_key, &key).await {
+        if let Err(err) = state.storage().delete(&upload.staging_key).await {
+            warn!(%id, %err, "failed to remove presign staging object");
+        }
+        let _: () = state.fred().del(upload_key(id)).await?;
+        return Err(err.into());
+    }
+    upload.blob_key = Some(key.clone());
+
+    // From here on the contents are the check's to record or remove, also after a restart
+    let transaction = state.fred().multi();
+    let _: () = transaction.hset(upload_key(id), ("blob_key", key)).await?;
+    let _: () = transaction.persist(upload_key(id)).await?;
+    let _: () = transaction
+        .zadd(
+            VERIFYING_KEY,
+            None,
+            None,
+            false,
+            false,
+            ((now() + VERIFY_TIMEOUT) as f64, id.to_string()),
+        )
+        .await?;
+    let _: () = transaction.exec(true).await?;
+    debug!(%id, owner = %user.id, size, "completed presigned upload");
+
+    let progress = Progress::start(&state, user.id, id, Some(size), size);
+    tokio::spawn(async move { verify(&state, id, upload, progress).await });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, format!("/upload/progress/{id}"))],
+    )
+        .into_response())
+}
+
+/// Run `work` while keeping the sweep from starting the check of upload `id` again.
+async fn holding_claim<T>(state: &AppState, id: Uuid, work: impl Future<Output = T>) -> T {
+    let mut refresh = tokio::time::interval(Duration::from_secs(VERIFY_TIMEOUT / 3));
+    refresh.tick().await;
+    tokio::pin!(work);
+    loop {
+        tokio::select! {
+            result = &mut work => return result,
+            _ = refresh.tick() => {
+                let deadline = (now() + VERIFY_TIMEOUT) as f64;
+                let refreshed: Result<i64, _> = state
+                    .fred()
+                    .zadd(VERIFYING_KEY, Some(SetOptions::XX), None, false, false, (deadline, id.to_string()))
+                    .await;
+                if let Err(err) = refreshed {
+                    warn!(%id, %err, "failed to refresh presigned upload check");
+                }
+            }
+        }
+    }
+}
+
+/// Check the contents of a completed upload and record them. Whatever the outcome, the upload
+/// is done with afterwards.
+async fn verify(state: &AppState, id: Uuid, upload: PresignedUpload, progress: Progress) {
+    progress.hashing();
+    match holding_claim(state, id, record(state, id, upload)).await {
+        Ok(file) => {
+            progress.recorded(&file);
+            progress.done();
+        }
+        Err(err) => {
+            warn!(%id, %err, "failed to record presigned upload");
+            progress.failed(&err);
+        }
+    }
+
+    let forgotten = async {
+        let _: () = state.fred().zrem(VERIFYING_KEY, id.to_string()).await?;
+        let _: () = state.fred().del(upload_key(id)).await?;
+        Ok::<_, AppError>(())
+    };
+    if let Err(err) = forgotten.await {
+        warn!(%id, %err, "failed to forget presigned upload");
+    }
+}
+
+/// Hash and check the moved contents of a completed upload like any other upload, then record
+/// them. They are removed when that fails.
+async fn record(state: &AppState, id: Uuid, upload: PresignedUpload) -> Result<models::file::File> {
+    let Some(key) = upload.blob_key.as_deref() else {
+        return Err(StatusCode::CONFLICT.into());
+    };
+    let new = upload.new_file();
+    let checked = async {
+        // The folder may be gone since the url was handed out
+        new.validate(state).await?;
+        let ingested = ingest::inspect(state.storage(), key, &new.content_type, &POLICY)
+            .await
+            .map_err(|err| match err {
+                IngestError::Rejected(rejection) => rejection.into(),
+                IngestError::Storage(err) => err.into(),
+                IngestError::TooLarge(_) => AppError::from(StatusCode::PAYLOAD_TOO_LARGE),
+            })?;
+        if upload
+            .sha256
+            .as_ref()
+            .is_some_and(|sha256| *sha256 != ingested.sha256)
+        {
+            warn!(%id, "presigned upload does not match its sha256");
+            return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+        }
+        Ok(ingested)
+    }
+    .await;
+
+    let ingested = match checked {
+        Ok(ingested) => ingested,
+        Err(err) => {
+            if let Err(err) = state.storage().delete(key).await {
+                warn!(%id, %err, "failed to remove refused presigned upload");
+            }
+            return Err(err);
+        }
+    };
+    files::record_file(state, new, key, ingested).await
+}
+
+/// Removes objects of presigned uploads that were never completed, and starts checks of
+/// completed ones again that stalled, like when the instance running them went away.
+pub(crate) async fn sweep_abandoned(state: AppStateRef) -> Result<()> {
+    let stalled: Vec<String> = state
+        .fred()
+        .zrangebyscore(VERIFYING_KEY, "-inf", now() as i64, false, None)
+        .await?;
+    for id in stalled {
+        // Only one instance gets to remove it, that one starts the check again
+        let claimed: i64 = state.fred().zrem(VERIFYING_KEY, id.as_str()).await?;
+        let Ok(id) = id.parse::<Uuid>() else {
+            continue;
+        };
+        if claimed == 0 {
+            continue;
+        }
+        let Some(upload) = PresignedUpload::load(&state, id).await? else {
+            continue;
+        };
+        let deadline = (now() + VERIFY_TIMEOUT) as f64;
+        let _: () = state
+            .fred()
+            .zadd(
+                VERIFYING_KEY,
+                None,
+                None,
+                false,
+                false,
+                (deadline, id.to_string()),
+            )
+            .await?;
+        info!(%id, "checking stalled presigned upload again");
+        let progress = Progress::start(
+            &state,
+            upload.owner_id,
+            id,
+            upload.size,
+            upload.size.unwrap_or_default(),
+        );
+        verify(&state, id, upload, progress).await;
+    }
+
+    let abandoned: Vec<String> = state
+        .fred()
+        .zrangebyscore(PENDING_KEY, "-inf", now() as i64, false, None)
+        .await?;
+
+    let mut removed = 0;
+    for key in abandoned {
+        // Only remove what we managed to claim, a completion might have been faster
+        let claimed: i64 = state.fred().zrem(PENDING_KEY, key.as_str()).await?;
+        if claimed == 0 {
+            continue;
+        }
+        state.storage().delete(&key).await?;
+        removed += 1;
+    }
+    if removed > 0 {
+        info!(removed, "removed abandoned presigned uploads");
+    }
+
+    Ok(())
+}
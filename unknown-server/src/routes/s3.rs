@@ -0,0 +1,737 @@
+//! The S3 compatible API, see [`crate::s3`] for how buckets and keys map onto files.
+//!
+//! It is served on a listener of its own, since S3 clients expect buckets right at the root of
+//! the host. Only path style addressing is supported, clients have to be told to use it.
+use crate::files::{self, NewFile};
+use crate::models::access_key::AccessKey;
+use crate::models::file::File;
+use crate::models::folder::{Folder, FolderInsert};
+use crate::models::user::User;
+use crate::policy::POLICY;
+use crate::prelude::*;
+use crate::s3::multipart::{MAX_PART_NUMBER, Upload};
+use crate::s3::sigv4::{self, ChunkSigner, PayloadCheck, Signature, verified_body};
+use crate::s3::xml::{self, ListRequest};
+use crate::s3::{self, MARKER_ETAG, MAX_KEYS, Object, ObjectKey, S3Error, S3Result};
+use crate::serve::{self, Disposition};
+use axum::body::Body;
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, header};
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use futures::StreamExt;
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use std::time::SystemTime;
+use time::OffsetDateTime;
+
+const X_AMZ_COPY_SOURCE: HeaderName = HeaderName::from_static("x-amz-copy-source");
+const X_AMZ_BUCKET_REGION: HeaderName = HeaderName::from_static("x-amz-bucket-region");
+
+/// `DeleteObjects` and `CompleteMultipartUpload` bodies larger than this are refused.
+const MAX_XML_BODY: usize = 2 * 1024 * 1024;
+
+/// Content type of the empty objects folders show up as.
+const FOLDER_CONTENT_TYPE: &str = "application/x-directory";
+
+/// Bucket subresources that exist in S3 but not here.
+const UNSUPPORTED_BUCKET: &[&str] = &[
+    "accelerate",
+    "acl",
+    "analytics",
+    "cors",
+    "encryption",
+    "intelligent-tiering",
+    "inventory",
+    "lifecycle",
+    "logging",
+    "metrics",
+    "notification",
+    "object-lock",
+    "ownershipControls",
+    "policy",
+    "publicAccessBlock",
+    "replication",
+    "requestPayment",
+    "tagging",
+    "uploads",
+    "versioning",
+    "versions",
+    "website",
+];
+
+/// Object subresources that exist in S3 but not here.
+const UNSUPPORTED_OBJECT: &[&str] = &[
+    "acl",
+    "attributes",
+    "legal-hold",
+    "restore",
+    "retention",
+    "select",
+    "tagging",
+    "torrent",
+];
+
+/// Every request goes through [`handle`], S3 tells operations apart by method and query.
+pub(crate) fn router() -> Router<AppStateRef> {
+    Router::new().fallback(handle)
+}
+
+/// The user a request was signed by. S3 clients sign every request with an access key, see
+/// `/auth/access-keys`.
+struct S3User {
+    user: User,
+    signature: Signature,
+    signer: ChunkSigner,
+}
+
+impl FromRequestParts<AppStateRef> for S3User {
+    type Rejection = S3Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppStateRef,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let Some(signature) = Signature::from_request(&parts.uri, &parts.headers)? else {
+            return Err(S3Error::access_denied("Anonymous access is not allowed"));
+        };
+        signature.check_time(OffsetDateTime::now_utc())?;
+        let Some(key) = AccessKey::get(state.db(), &signature.credential.access_key).await? else {
+            return Err(S3Error::invalid_access_key_id());
+        };
+        let signer = signature.verify(&key.secret, &parts.method, &parts.uri, &parts.headers)?;
+        AccessKey::touch(state.db(), &key.id).await?;
+        let Some(user) = User::get(state.db(), key.user_id).await? else {
+            return Err(S3Error::invalid_access_key_id());
+        };
+
+        Ok(Self {
+            user,
+            signature,
+            signer,
+        })
+    }
+}
+
+impl S3User {
+    fn payload(&self, headers: &HeaderMap) -> S3Result<PayloadCheck> {
+        PayloadCheck::new(&self.signature, self.signer.clone(), headers)
+    }
+}
+
+/// The query of a request, decoded.
+struct Query(Vec<(String, String)>);
+
+impl Query {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    fn has_any(&self, names: &[&str]) -> bool {
+        names.iter().any(|name| self.has(name))
+    }
+}
+
+async fn handle(State(state): State<AppStateRef>, auth: S3User, request: Request) -> Response {
+    match dispatch(&state, auth, request).await {
+        Ok(response) => response,
+        Err(err) => err.into_response(),
+    }
+}
+
+async fn dispatch(state: &AppState, auth: S3User, request: Request) -> S3Result<Response> {
+    let path = request.uri().path().trim_start_matches('/');
+    let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+    let decode = |part: &str| {
+        percent_decode_str(part)
+            .decode_utf8()
+            .map(|part| part.into_owned())
+            .map_err(|_| S3Error::invalid_argument("Keys have to be UTF-8"))
+    };
+    let (bucket, key) = (decode(bucket)?, decode(key)?);
+    let query = Query(sigv4::query_pairs(
+        request.uri().query().unwrap_or_default(),
+    ));
+    let method = request.method().clone();
+
+    if bucket.is_empty() {
+        return match method {
+            Method::GET => list_buckets(state, &auth.user).await,
+            _ => Err(S3Error::method_not_allowed()),
+        };
+    }
+    if key.is_empty() {
+        if query.has_any(UNSUPPORTED_BUCKET) {
+            return Err(S3Error::not_implemented(
+                "This bucket operation is not supported",
+            ));
+        }
+        return match method {
+            Method::GET if query.has("location") => {
+                s3::find_bucket(state, auth.user.id, &bucket).await?;
+                Ok(xml_response(xml::location(&CONFIG.s3_api.region)))
+            }
+            Method::GET => list_objects(state, &auth.user, bucket, &query).await,
+            Method::HEAD => {
+                s3::find_bucket(state, auth.user.id, &bucket).await?;
+                Ok(([(X_AMZ_BUCKET_REGION, CONFIG.s3_api.region.as_str())]).into_response())
+            }
+            Method::PUT => create_bucket(state, &auth.user, &bucket).await,
+            Method::DELETE => delete_bucket(state, &auth.user, &bucket).await,
+            Method::POST if query.has("delete") => {
+                delete_objects(state, &auth, &bucket, request).await
+            }
+            _ => Err(S3Error::method_not_allowed()),
+        };
+    }
+
+    if query.has_any(UNSUPPORTED_OBJECT) {
+        return Err(S3Error::not_implemented(
+            "This object operation is not supported",
+        ));
+    }
+    let bucket = s3::find_bucket(state, auth.user.id, &bucket).await?;
+    let object_key = ObjectKey::parse(&key)?;
+    let upload_id = query.get("uploadId");
+    match (method, upload_id) {
+        (Method::GET, Some(id)) => list_parts(state, &auth.user, &bucket, &key, id, &query).await,
+        (Method::GET, None) => get_object(state, &auth.user, &bucket, &object_key, request).await,
+        (Method::HEAD, None) => head_object(state, &auth.user, &bucket, &object_key).await,
+        (Method::PUT, Some(id)) => {
+            let id = id.to_string();
+            upload_part(state, &auth, &bucket, &key, &id, &query, request).await
+        }
+        (Method::PUT, None) if request.headers().contains_key(X_AMZ_COPY_SOURCE) => {
+            copy_object(state, &auth.user, &bucket, &object_key, request.headers()).await
+        }
+        (Method::PUT, None) => put_object(state, &auth, &bucket, &object_key, request).await,
+        (Method::POST, None) if query.has("uploads") => {
+            create_multipart_upload(state, &auth.user, &bucket, &key, request.headers()).await
+        }
+        (Method::POST, Some(id)) => {
+            let id = id.to_string();
+            complete_multipart_upload(state, &auth, &bucket, &key, &id, request).await
+        }
+        (Method::DELETE, Some(id)) => {
+            Upload::load(state, id, auth.user.id, bucket.id, &key)
+                .await?
+                .discard(state)
+                .await?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        (Method::DELETE, None) => {
+            delete_object(state, &auth.user, &bucket, &object_key).await?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        _ => Err(S3Error::method_not_allowed()),
+    }
+}
+
+fn xml_response(body: String) -> Response {
+    ([(header::CONTENT_TYPE, "application/xml")], body).into_response()
+}
+
+/// Names and content types come from clients, anything a header can't hold is refused.
+fn header_value(value: &str) -> S3Result<HeaderValue> {
+    HeaderValue::from_str(value).map_err(|_| {
+        error!(value, "not a valid header value");
+        S3Error::internal()
+    })
+}
+
+fn etag(file: &File) -> String {
+    format!("\"{}\"", file.sha256)
+}
+
+fn content_type(headers: &HeaderMap) -> String {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string()
+}
+
+/// A small XML body, verified like any other.
+async fn read_xml(auth: &S3User, request: Request) -> S3Result<String> {
+    let check = auth.payload(request.headers())?;
+    let (mut body, failure) = verified_body(request.into_body(), check);
+    let mut xml = vec![];
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|_| failure.or(S3Error::incomplete_body()))?;
+        if xml.len() + chunk.len() > MAX_XML_BODY {
+            return Err(S3Error::malformed_xml());
+        }
+        xml.extend_from_slice(&chunk);
+    }
+    String::from_utf8(xml).map_err(|_| S3Error::malformed_xml())
+}
+
+async fn list_buckets(state: &AppState, user: &User) -> S3Result<Response> {
+    let buckets = Folder::children(state.db(), user.id, None, i64::MAX, 0).await?;
+    Ok(xml_response(xml::list_buckets(user, &buckets)))
+}
+
+async fn create_bucket(state: &AppState, user: &User, name: &str) -> S3Result<Response> {
+    if !files::valid_name(name) {
+        return Err(S3Error::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidBucketName",
+            "The specified bucket is not valid",
+        ));
+    }
+    let exists = || {
+        S3Error::new(
+            StatusCode::CONFLICT,
+            "BucketAlreadyOwnedByYou",
+            "Your previous request to create the named bucket succeeded and you already own it",
+        )
+    };
+    if Folder::find_child(state.db(), user.id, None, name)
+        .await?
+        .is_some()
+    {
+        return Err(exists());
+    }
+    match FolderInsert::new(user.id, None, name.to_string())
+        .insert(state.db())
+        .await
+    {
+        Ok(_) => {}
+        Err(err)
+            if err
+                .as_database_error()
+                .is_some_and(|err| err.is_unique_violation()) =>
+        {
+            return Err(exists());
+        }
+        Err(err) => return Err(err.into()),
+    }
+    info!(owner = %user.id, name, "created bucket");
+
+    let location = utf8_percent_encode(name, NON_ALPHANUMERIC);
+    Ok(([(header::LOCATION, header_value(&format!("/{location}"))?)]).into_response())
+}
+
+async fn delete_bucket(state: &AppState, user: &User, name: &str) -> S3Result<Response> {
+    let bucket = s3::find_bucket(state, user.id, name).await?;
+    let folders = Folder::count_children(state.db(), user.id, Some(bucket.id)).await?;
+    let files = File::count_in_folder(state.db(), user.id, Some(bucket.id)).await?;
+    if folders + files > 0 {
+        return Err(S3Error::new(
+            StatusCode::CONFLICT,
+            "BucketNotEmpty",
+            "The bucket you tried to delete is not empty",
+        ));
+    }
+    files::trash_folder(state, &bucket).await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn list_objects(
+    state: &AppState,
+    user: &User,
+    bucket_name: String,
+    query: &Query,
+) -> S3Result<Response> {
+    let bucket = s3::find_bucket(state, user.id, &bucket_name).await?;
+    let v2 = query.get("list-type") == Some("2");
+    let max_keys = match query.get("max-keys") {
+        Some(max_keys) => max_keys
+            .parse::<usize>()
+            .map_err(|_| S3Error::invalid_argument("max-keys has to be a number"))?
+            .min(MAX_KEYS),
+        None => MAX_KEYS,
+    };
+    let encoding = query.get("encoding-type");
+    if encoding.is_some_and(|encoding| encoding != "url") {
+        return Err(S3Error::invalid_argument(
+            "Invalid Encoding Method specified in Request",
+        ));
+    }
+    let request = ListRequest {
+        bucket: bucket_name,
+        prefix: query.get("prefix").unwrap_or_default().to_string(),
+        delimiter: query
+            .get("delimiter")
+            .filter(|delimiter| !delimiter.is_empty())
+            .map(str::to_string),
+        max_keys,
+        v2,
+        url_encoded: encoding.is_some(),
+        start_after: query
+            .get(if v2 { "start-after" } else { "marker" })
+            .filter(|start_after| !start_after.is_empty())
+            .map(str::to_string),
+        continuation_token: query
+            .get("continuation-token")
+            .filter(|_| v2)
+            .map(str::to_string),
+    };
+
+    let after = match &request.continuation_token {
+        Some(token) => BASE64_URL_SAFE_NO_PAD
+            .decode(token)
+            .ok()
+            .and_then(|after| String::from_utf8(after).ok())
+            .ok_or_else(|| {
+                S3Error::invalid_argument("The continuation token provided is incorrect")
+            })?,
+        None => request.start_after.clone().unwrap_or_default(),
+    };
+    let listing = s3::list(state, user.id, &bucket, &request, &after).await?;
+    let next = match (listing.truncated, listing.last_key()) {
+        (true, Some(last)) if v2 => Some(BASE64_URL_SAFE_NO_PAD.encode(last)),
+        (true, Some(last)) => Some(last.to_string()),
+        _ => None,
+    };
+
+    Ok(xml_response(xml::list_objects(
+        &request,
+        &listing,
+        next.as_deref(),
+    )))
+}
+
+/// The headers of an empty object standing for `folder`.
+fn folder_headers(folder: &Folder) -> S3Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, HeaderValue::from_static(MARKER_ETAG));
+    headers.insert(
+        header::LAST_MODIFIED,
+        header_value(&httpdate::fmt_http_date(SystemTime::from(folder.modified)))?,
+    );
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(FOLDER_CONTENT_TYPE),
+    );
+    headers.insert(header::CONTENT_LENGTH, 0.into());
+    Ok(headers)
+}
+
+async fn get_object(
+    state: &AppState,
+    user: &User,
+    bucket: &Folder,
+    key: &ObjectKey,
+    request: Request,
+) -> S3Result<Response> {
+    match s3::resolve(state, user.id, bucket, key).await? {
+        Some(Object::File(file)) => {
+            Ok(serve::serve_file(state, &file, request.headers(), Disposition::Inline).await?)
+        }
+        Some(Object::Folder(folder)) => {
+            Ok((folder_headers(&folder)?, Body::empty()).into_response())
+        }
+        None => Err(S3Error::no_such_key()),
+    }
+}
+
+/// Like `GetObject` without the body, so storage isn't touched.
+async fn head_object(
+    state: &AppState,
+    user: &User,
+    bucket: &Folder,
+    key: &ObjectKey,
+) -> S3Result<Response> {
+    let file = match s3::resolve(state, user.id, bucket, key).await? {
+        Some(Object::File(file)) => file,
+        Some(Object::Folder(folder)) => return Ok(folder_headers(&folder)?.into_response()),
+        None => return Err(S3Error::no_such_key()),
+    };
+    serve::ensure_servable(&file)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, header_value(&etag(&file))?);
+    headers.insert(
+        header::LAST_MODIFIED,
+        header_value(&httpdate::fmt_http_date(SystemTime::from(file.uploaded)))?,
+    );
+    headers.insert(header::CONTENT_TYPE, header_value(file.content_type())?);
+    headers.insert(header::CONTENT_LENGTH, (file.size as u64).into());
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    Ok(headers.into_response())
+}
+
+async fn put_object(
+    state: &AppState,
+    auth: &S3User,
+    bucket: &Folder,
+    key: &ObjectKey,
+    request: Request,
+) -> S3Result<Response> {
+    let check = auth.payload(request.headers())?;
+    let Some(length) = check.length(request.headers()) else {
+        return Err(S3Error::missing_content_length());
+    };
+    let Some(name) = key.name() else {
+        if length > 0 {
+            return Err(S3Error::invalid_request(
+                "Keys ending in `/` stand for folders and can't have contents",
+            ));
+        }
+        s3::ensure_folders(state, auth.user.id, bucket, key.folders()).await?;
+        return Ok(([(header::ETAG, MARKER_ETAG)]).into_response());
+    };
+
+    let content_type = content_type(request.headers());
+    // Refuse before any folders are created for it
+    POLICY
+        .check_declared(name, &content_type)
+        .map_err(AppError::from)?;
+    let folder = s3::ensure_folders(state, auth.user.id, bucket, key.folders()).await?;
+    let new = NewFile {
+        owner: auth.user.id,
+        folder_id: Some(folder.id),
+        name: name.to_string(),
+        content_type,
+        expiry: None,
+    };
+    new.check_size(length)?;
+    files::check_quota(state, auth.user.id, length).await?;
+
+    let (body, failure) = verified_body(request.into_body(), check);
+    let file = files::store_file(state, new, body)
+        .await
+        .map_err(|err| failure.or(err.into()))?;
+    debug!(id = %file.id, owner = %file.owner_id, "stored s3 object");
+
+    Ok(([(header::ETAG, header_value(&etag(&file))?)]).into_response())
+}
+
+async fn copy_object(
+    state: &AppState,
+    user: &User,
+    bucket: &Folder,
+    key: &ObjectKey,
+    headers: &HeaderMap,
+) -> S3Result<Response> {
+    let source = headers
+        .get(X_AMZ_COPY_SOURCE)
+        .and_then(|source| source.to_str().ok())
+        .and_then(|source| percent_decode_str(source).decode_utf8().ok())
+        .ok_or_else(|| {
+            S3Error::invalid_argument("Copy Source must mention the source bucket and key")
+        })?;
+    if source.contains("?versionId=") {
+        return Err(S3Error::not_implemented(
+            "Objects have no versions to copy from",
+        ));
+    }
+    let Some((source_bucket, source_key)) = source.trim_start_matches('/').split_once('/') else {
+        return Err(S3Error::invalid_argument(
+            "Copy Source must mention the source bucket and key",
+        ));
+    };
+    let Some(name) = key.name() else {
+        return Err(S3Error::invalid_request("Folders can't be copied"));
+    };
+
+    let source_bucket = s3::find_bucket(state, user.id, source_bucket).await?;
+    let source_key = ObjectKey::parse(source_key)?;
+    let Some(Object::File(file)) = s3::resolve(state, user.id, &source_bucket, &source_key).await?
+    else {
+        return Err(S3Error::no_such_key());
+    };
+    serve::ensure_servable(&file)?;
+
+    let folder = s3::ensure_folders(state, user.id, bucket, key.folders()).await?;
+    let existing = File::find_in_folder(state.db(), user.id, Some(folder.id), name).await?;
+    let copy = match existing {
+        // Copying an object onto itself is how S3 clients change metadata, of which there is none
+        Some(existing) if existing.id == file.id => existing,
+        // Like an upload to the key, the copy becomes the new version of what is there
+        Some(existing) => files::copy_file_over(state, &file, &existing).await?,
+        None => files::copy_file(state, &file, Some(folder.id), name).await?,
+    };
+
+    Ok(xml_response(xml::copy_object(&etag(&copy), copy.uploaded)))
+}
+
+async fn create_multipart_upload(
+    state: &AppState,
+    user: &User,
+    bucket: &Folder,
+    key: &str,
+    headers: &HeaderMap,
+) -> S3Result<Response> {
+    let Some(name) = ObjectKey::parse(key)?.name().map(str::to_string) else {
+        return Err(S3Error::invalid_request("Folders can't be uploaded"));
+    };
+    let content_type = content_type(headers);
+    POLICY
+        .check_declared(&name, &content_type)
+        .map_err(AppError::from)?;
+    let upload = Upload::create(state, user.id, bucket.id, key.to_string(), content_type).await?;
+    debug!(id = %upload.id, owner = %user.id, "created s3 multipart upload");
+
+    Ok(xml_response(xml::initiate_multipart_upload(
+        &bucket.name,
+        key,
+        &upload.id.to_string(),
+    )))
+}
+
+async fn upload_part(
+    state: &AppState,
+    auth: &S3User,
+    bucket: &Folder,
+    key: &str,
+    upload_id: &str,
+    query: &Query,
+    request: Request,
+) -> S3Result<Response> {
+    if request.headers().contains_key(X_AMZ_COPY_SOURCE) {
+        return Err(S3Error::not_implemented("Parts can't be copied"));
+    }
+    let number = query
+        .get("partNumber")
+        .and_then(|number| number.parse::<u32>().ok())
+        .filter(|number| (1..=MAX_PART_NUMBER).contains(number))
+        .ok_or_else(|| {
+            S3Error::invalid_argument(format!(
+                "Part number must be an integer between 1 and {MAX_PART_NUMBER}, inclusive"
+            ))
+        })?;
+    let upload = Upload::load(state, upload_id, auth.user.id, bucket.id, key).await?;
+    let check = auth.payload(request.headers())?;
+    let Some(length) = check.length(request.headers()) else {
+        return Err(S3Error::missing_content_length());
+    };
+
+    let (body, failure) = verified_body(request.into_body(), check);
+    let part = upload
+        .put_part(state, number, length, body)
+        .await
+        .map_err(|err| failure.or(err))?;
+
+    Ok(([(header::ETAG, header_value(&part.etag)?)]).into_response())
+}
+
+async fn complete_multipart_upload(
+    state: &AppState,
+    auth: &S3User,
+    bucket: &Folder,
+    key: &str,
+    upload_id: &str,
+    request: Request,
+) -> S3Result<Response> {
+    let upload = Upload::load(state, upload_id, auth.user.id, bucket.id, key).await?;
+    let body = read_xml(auth, request).await?;
+    let parts =
+        xml::parse_complete_multipart_upload(&body).map_err(|_| S3Error::malformed_xml())?;
+
+    let object_key = ObjectKey::parse(key)?;
+    let Some(name) = object_key.name() else {
+        return Err(S3Error::invalid_request("Folders can't be uploaded"));
+    };
+    let folder = s3::ensure_folders(state, auth.user.id, bucket, object_key.folders()).await?;
+    let new = NewFile {
+        owner: auth.user.id,
+        folder_id: Some(folder.id),
+        name: name.to_string(),
+        content_type: upload.content_type.clone(),
+        expiry: None,
+    };
+    let file = upload.complete(state, &parts, new).await?;
+    debug!(id = %file.id, upload = %upload.id, "completed s3 multipart upload");
+
+    Ok(xml_response(xml::complete_multipart_upload(
+        &bucket.name,
+        key,
+        &etag(&file),
+    )))
+}
+
+async fn list_parts(
+    state: &AppState,
+    user: &User,
+    bucket: &Folder,
+    key: &str,
+    upload_id: &str,
+    query: &Query,
+) -> S3Result<Response> {
+    let upload = Upload::load(state, upload_id, user.id, bucket.id, key).await?;
+    let number = |name: &str, default: u32| match query.get(name) {
+        Some(value) => value
+            .parse::<u32>()
+            .map_err(|_| S3Error::invalid_argument(format!("{name} has to be a number"))),
+        None => Ok(default),
+    };
+    let marker = number("part-number-marker", 0)?;
+    let max_parts = number("max-parts", 1000)?.min(1000) as usize;
+
+    let mut parts = upload.parts(state).await?;
+    parts.retain(|part| part.number > marker);
+    let truncated = parts.len() > max_parts;
+    parts.truncate(max_parts);
+
+    Ok(xml_response(xml::list_parts(
+        &bucket.name,
+        key,
+        upload_id,
+        marker,
+        max_parts,
+        &parts,
+        truncated,
+    )))
+}
+
+/// Trash what `key` points at. Missing keys are fine, like in S3. Folders are only removed
+/// when empty, their contents are objects of their own.
+async fn delete_object(
+    state: &AppState,
+    user: &User,
+    bucket: &Folder,
+    key: &ObjectKey,
+) -> S3Result<()> {
+    match s3::resolve(state, user.id, bucket, key).await? {
+        Some(Object::File(file)) => files::trash_file(state, &file).await?,
+        Some(Object::Folder(folder)) => {
+            let folders = Folder::count_children(state.db(), user.id, Some(folder.id)).await?;
+            let files = File::count_in_folder(state.db(), user.id, Some(folder.id)).await?;
+            if folders + files == 0 {
+                files::trash_folder(state, &folder).await?;
+            }
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+async fn delete_objects(
+    state: &AppState,
+    auth: &S3User,
+    bucket: &str,
+    request: Request,
+) -> S3Result<Response> {
+    let bucket = s3::find_bucket(state, auth.user.id, bucket).await?;
+    let body = read_xml(auth, request).await?;
+    let (keys, quiet) = xml::parse_delete(&body).map_err(|_| S3Error::malformed_xml())?;
+    if keys.len() > MAX_KEYS {
+        return Err(S3Error::malformed_xml());
+    }
+
+    let mut deleted = vec![];
+    let mut errors = vec![];
+    for key in keys {
+        let result = match ObjectKey::parse(&key) {
+            Ok(object_key) => delete_object(state, &auth.user, &bucket, &object_key).await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => deleted.push(key),
+            Err(err) => errors.push((key, err)),
+        }
+    }
+
+    Ok(xml_response(xml::delete_result(quiet, &deleted, &errors)))
+}
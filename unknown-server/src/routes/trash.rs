@@ -0,0 +1,143 @@
+//! The trash. Deleted files and folders end up here until they are restored or purged, either
+//! by hand or by [`files::purge_trash`] once the retention runs out.
+use crate::dto::folder::{EntryDto, TrashEntryDto, TrashListingDto};
+use crate::dto::shared::PageQuery;
+use crate::files;
+use crate::models::file::File;
+use crate::models::folder::Folder;
+use crate::prelude::*;
+use axum::extract::{Path, Query};
+use uuid::Uuid;
+
+pub(crate) fn router() -> Router<AppStateRef> {
+    Router::new()
+        .route("/", get(get_trash).delete(delete_trash))
+        .route("/files/{id}", delete(delete_file))
+        .route("/files/{id}/restore", post(post_restore_file))
+        .route("/folders/{id}", delete(delete_folder))
+        .route("/folders/{id}/restore", post(post_restore_folder))
+        .route_layer(login_required!(Backend))
+}
+
+async fn get_trash(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Query(page): Query<PageQuery>,
+) -> ResultJson<TrashListingDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let folder_count = Folder::count_trashed(state.db(), user.id).await?;
+    let file_count = File::count_trashed(state.db(), user.id).await?;
+
+    let mut entries = vec![];
+    if page.offset() < folder_count {
+        let folders = Folder::trashed(state.db(), user.id, page.limit(), page.offset()).await?;
+        entries.extend(folders.into_iter().map(|folder| TrashEntryDto {
+            deleted_at: folder.deleted_at,
+            entry: EntryDto::Folder(folder.into()),
+        }));
+    }
+    let remaining = page.limit() - entries.len() as i64;
+    if remaining > 0 {
+        let offset = (page.offset() - folder_count).max(0);
+        let files = File::trashed(state.db(), user.id, remaining, offset).await?;
+        entries.extend(files.into_iter().map(|file| TrashEntryDto {
+            deleted_at: file.deleted_at,
+            entry: EntryDto::File(file.into()),
+        }));
+    }
+
+    Ok(Json(TrashListingDto {
+        entries,
+        total: (folder_count + file_count) as u64,
+        offset: page.offset() as u64,
+        limit: page.limit() as u64,
+    }))
+}
+
+/// Permanently delete everything in the trash.
+async fn delete_trash(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+) -> Result<StatusCode> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+
+    let folders = Folder::trashed(state.db(), user.id, i64::MAX, 0).await?;
+    for folder in &folders {
+        files::delete_folder(&state, folder).await?;
+    }
+    // Fetched after the folders, files inside them are gone by now
+    let files = File::trashed(state.db(), user.id, i64::MAX, 0).await?;
+    for file in &files {
+        files::delete_file(&state, file).await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_file(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(file) = File::get_trashed(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    files::delete_file(&state, &file).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn post_restore_file(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> ResultJson<dto::file::FileInfoDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(file) = File::get_trashed(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    let file = files::restore_file(&state, &file).await?;
+    Ok(Json(file.into()))
+}
+
+async fn delete_folder(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(folder) = Folder::get_trashed(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    files::delete_folder(&state, &folder).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn post_restore_folder(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> ResultJson<dto::folder::FolderDto> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let Some(folder) = Folder::get_trashed(state.db(), id, user.id).await? else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    let folder = files::restore_folder(&state, &folder).await?;
+    Ok(Json(folder.into()))
+}
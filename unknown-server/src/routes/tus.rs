@@ -0,0 +1,772 @@
+//! Resumable uploads using the [tus 1.0](https://tus.io/protocols/resumable-upload) protocol.
+//! Supports the core protocol with the `creation`, `termination` and `expiration` extensions.
+//!
+//! Upload state lives in redis so any instance can continue an upload. Every PATCH is written to
+//! storage as its own chunk object and the chunks are joined into the final file once the last
+//! byte arrives. Progress can be followed at `/upload/progress/{id}`.
+use crate::files;
+use crate::prelude::*;
+use crate::progress::Progress;
+use crate::storage::PutOptions;
+use axum::body::Body;
+use axum::extract::{Path, Request};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::head;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use bytes::Bytes;
+use fred::prelude::{
+    Expiration, HashesInterface, KeysInterface, ListInterface, SetOptions, TransactionInterface,
+};
+use futures::{Stream, StreamExt, TryStreamExt, future, stream};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const TUS_VERSION_HEADER: HeaderName = HeaderName::from_static("tus-version");
+const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+const TUS_MAX_SIZE: HeaderName = HeaderName::from_static("tus-max-size");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+/// Not part of tus, lets clients find the file a finished upload created.
+const FILE_ID: HeaderName = HeaderName::from_static("x-file-id");
+
+/// Seconds the lock of an upload lasts unless the request holding it refreshes it, see
+/// [`holding_lock`]. Short, so a crashed instance doesn't block an upload for long.
+const LOCK_SECONDS: i64 = 60;
+
+/// Storage prefix for chunk objects
+pub(crate) const CHUNK_PREFIX: &str = "tus/";
+
+pub(crate) fn router() -> Router<AppStateRef> {
+    Router::new()
+        .route("/", post(post_create).options(options))
+        .route(
+            "/{id}",
+            head(head_upload)
+                .patch(patch_upload)
+                .delete(delete_upload)
+                .options(options),
+        )
+        .layer(middleware::from_fn(tus_resumable))
+}
+
+fn upload_key(id: Uuid) -> String {
+    format!("tus:upload:{id}")
+}
+
+fn chunks_key(id: Uuid) -> String {
+    format!("tus:chunks:{id}")
+}
+
+fn lock_key(id: Uuid) -> String {
+    format!("tus:lock:{id}")
+}
+
+/// Chunk keys are zero padded so they sort in upload order.
+fn chunk_storage_key(id: Uuid, offset: u64) -> String {
+    format!("{CHUNK_PREFIX}{id}/{offset:020}")
+}
+
+/// Upload state as stored in redis
+#[derive(Debug)]
+struct TusUpload {
+    id: Uuid,
+    owner_id: Uuid,
+    length: u64,
+    offset: u64,
+    /// The raw `Upload-Metadata` header
+    metadata: Option<String>,
+    /// Unix timestamp after which the upload is discarded
+    expires: u64,
+    /// Set once the upload finished and the file was stored
+    file_id: Option<Uuid>,
+}
+
+impl TusUpload {
+    async fn load(state: &AppState, id: Uuid) -> Result<Option<Self>> {
+        let mut fields: HashMap<String, String> = state.fred().hgetall(upload_key(id)).await?;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+
+        let mut field = |name: &str| fields.remove(name);
+        let upload = (|| {
+            Some(Self {
+                id,
+                owner_id: field("owner_id")?.parse().ok()?,
+                length: field("length")?.parse().ok()?,
+                offset: field("offset")?.parse().ok()?,
+                metadata: field("metadata"),
+                expires: field("expires")?.parse().ok()?,
+                file_id: field("file_id").and_then(|id| id.parse().ok()),
+            })
+        })();
+
+        if upload.is_none() {
+            warn!(%id, "corrupt tus upload state");
+        }
+        Ok(upload)
+    }
+
+    /// Loads an upload, hiding uploads that belong to someone else.
+    async fn load_owned(state: &AppState, id: Uuid, owner_id: Uuid) -> Result<Self> {
+        match Self::load(state, id).await? {
+            Some(upload) if upload.owner_id == owner_id => Ok(upload),
+            _ => Err(StatusCode::NOT_FOUND.into()),
+        }
+    }
+
+    async fn create(&self, state: &AppState) -> Result<()> {
+        let mut fields = vec![
+            ("owner_id", self.owner_id.to_string()),
+            ("length", self.length.to_string()),
+            ("offset", self.offset.to_string()),
+            ("expires", self.expires.to_string()),
+        ];
+        if let Some(metadata) = &self.metadata {
+            fields.push(("metadata", metadata.clone()));
+        }
+
+        let key = upload_key(self.id);
+        let _: () = state.fred().hset(&key, fields).await?;
+        let _: () = state
+            .fred()
+            .expire_at(&key, self.expires as i64, None)
+            .await?;
+
+        Ok(())
+    }
+
+    fn expires_header(&self) -> HeaderValue {
+        let expires = UNIX_EPOCH + Duration::from_secs(self.expires);
+        HeaderValue::from_str(&httpdate::fmt_http_date(expires)).expect("valid http date")
+    }
+
+    /// Decoded metadata pairs. Values are base64 and may be omitted.
+    fn metadata(&self) -> HashMap<String, String> {
+        let Some(metadata) = &self.metadata else {
+            return HashMap::new();
+        };
+
+        metadata
+            .split(',')
+            .filter_map(|pair| {
+                let mut parts = pair.trim().splitn(2, ' ');
+                let key = parts.next().filter(|key| !key.is_empty())?;
+                let value = match parts.next() {
+                    Some(value) => String::from_utf8(BASE64_STANDARD.decode(value).ok()?).ok()?,
+                    None => String::new(),
+                };
+                Some((key.to_string(), value))
+            })
+            .collect()
+    }
+
+    /// Bytes a PATCH starting at `offset` may still send. Fails with a conflict unless
+    /// `offset` is where the upload currently ends.
+    fn remaining_from(&self, offset: u64) -> Result<u64> {
+        if offset != self.offset {
+            return Err(StatusCode::CONFLICT.into());
+        }
+        Ok(self.length.saturating_sub(self.offset))
+    }
+
+    /// The file this upload turns into, as described by its metadata. An `expiry` that isn't
+    /// one of the [`FileExpiry`](dto::file::FileExpiry) choices is refused.
+    fn new_file(&self) -> Result<files::NewFile> {
+        // `filename`/`filetype` are what tus-js-client sends, Uppy uses `name`/`type`
+        let metadata = self.metadata();
+        let field = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| metadata.get(*name).filter(|value| !value.is_empty()))
+                .cloned()
+        };
+
+        let expiry = field(&["expiry"])
+            .map(|expiry| expiry.parse())
+            .transpose()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        Ok(files::NewFile {
+            owner: self.owner_id,
+            folder_id: field(&["folder_id"]).and_then(|id| id.parse().ok()),
+            name: field(&["filename", "name"]).unwrap_or_else(|| self.id.to_string()),
+            content_type: field(&["filetype", "type"])
+                .unwrap_or_else(|| "application/octet-stream".to_string()),
+            expiry,
+        })
+    }
+}
+
+/// Checks the `Tus-Resumable` header and adds it to every response.
+async fn tus_resumable(request: Request, next: Next) -> Response {
+    let supported = request.method() == Method::OPTIONS
+        || request
+            .headers()
+            .get(TUS_RESUMABLE)
+            .is_some_and(|version| version == TUS_VERSION);
+
+    let mut response = if supported {
+        next.run(request).await
+    } else {
+        (
+            StatusCode::PRECONDITION_FAILED,
+            [(TUS_VERSION_HEADER, TUS_VERSION)],
+        )
+            .into_response()
+    };
+
+    response
+        .headers_mut()
+        .insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+fn parse_header<T: std::str::FromStr>(headers: &HeaderMap, name: HeaderName) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+async fn options() -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(TUS_VERSION_HEADER, HeaderValue::from_static(TUS_VERSION));
+    headers.insert(TUS_EXTENSION, HeaderValue::from_static(TUS_EXTENSIONS));
+    if let Some(max_size) = CONFIG.upload.tus_max_size {
+        headers.insert(TUS_MAX_SIZE, max_size.into());
+    }
+
+    (StatusCode::NO_CONTENT, headers).into_response()
+}
+
+async fn post_create(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+
+    // Deferred lengths are an extension we don't offer
+    let Some(length) = parse_header::<u64>(&headers, UPLOAD_LENGTH) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+    if CONFIG.upload.tus_max_size.is_some_and(|max| length > max) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+    }
+    files::check_quota(&state, user.id, length).await?;
+
+    let metadata = headers
+        .get(UPLOAD_METADATA)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let mut upload = TusUpload {
+        id: Uuid::now_v7(),
+        owner_id: user.id,
+        length,
+        offset: 0,
+        metadata,
+        expires: now() + CONFIG.upload.tus_expiration,
+        file_id: None,
+    };
+    let new = upload.new_file()?;
+    new.validate(&state).await?;
+    new.check_size(length)?;
+    upload.create(&state).await?;
+    debug!(id = %upload.id, length, "created tus upload");
+
+    // Nothing will ever be PATCHed to an empty upload, so finish it right away
+    if length == 0 {
+        let progress = Progress::start(&state, user.id, upload.id, Some(0), 0);
+        upload.file_id = Some(finish(&state, &upload, &progress).await?);
+    }
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        header::LOCATION,
+        HeaderValue::from_str(&format!("/upload/tus/{}", upload.id)).expect("valid location"),
+    );
+    response_headers.insert(UPLOAD_EXPIRES, upload.expires_header());
+    if let Some(file_id) = upload.file_id {
+        response_headers.insert(
+            FILE_ID,
+            HeaderValue::from_str(&file_id.to_string()).expect("valid uuid"),
+        );
+    }
+
+    Ok((StatusCode::CREATED, response_headers).into_response())
+}
+
+async fn head_upload(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Result<Response> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let mut upload = TusUpload::load_owned(&state, id, user.id).await?;
+
+    // All bytes arrived but recording the file failed, try again before reporting it complete
+    if upload.offset == upload.length && upload.file_id.is_none() {
+        if !lock(&state, id).await? {
+            return Err(StatusCode::LOCKED.into());
+        }
+        let result = holding_lock(&state, id, retry_finish(&state, id, user.id)).await;
+        unlock(&state, id).await;
+        upload = result?;
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert(UPLOAD_OFFSET, upload.offset.into());
+    headers.insert(UPLOAD_LENGTH, upload.length.into());
+    headers.insert(UPLOAD_EXPIRES, upload.expires_header());
+    if let Some(metadata) = upload
+        .metadata
+        .as_deref()
+        .and_then(|metadata| HeaderValue::from_str(metadata).ok())
+    {
+        headers.insert(UPLOAD_METADATA, metadata);
+    }
+
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+async fn patch_upload(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    if headers
+        .get(header::CONTENT_TYPE)
+        .is_none_or(|content_type| content_type != OFFSET_CONTENT_TYPE)
+    {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
+    }
+    let Some(offset) = parse_header::<u64>(&headers, UPLOAD_OFFSET) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let upload = TusUpload::load_owned(&state, id, user.id).await?;
+    upload.remaining_from(offset)?;
+
+    // Only one PATCH may write to an upload at a time
+    if !lock(&state, id).await? {
+        return Err(StatusCode::LOCKED.into());
+    }
+
+    let result = holding_lock(&state, id, write_chunk(&state, id, user.id, offset, body)).await;
+
+    unlock(&state, id).await;
+    let upload = result?;
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(UPLOAD_OFFSET, upload.offset.into());
+    response_headers.insert(UPLOAD_EXPIRES, upload.expires_header());
+    if let Some(file_id) = upload.file_id {
+        response_headers.insert(
+            FILE_ID,
+            HeaderValue::from_str(&file_id.to_string()).expect("valid uuid"),
+        );
+    }
+
+    Ok((StatusCode::NO_CONTENT, response_headers).into_response())
+}
+
+/// Take the lock of an upload. Returns whether it was free.
+async fn lock(state: &AppState, id: Uuid) -> Result<bool> {
+    let locked: Option<String> = state
+        .fred()
+        .set(
+            lock_key(id),
+            "1",
+            Some(Expiration::EX(LOCK_SECONDS)),
+            Some(SetOptions::NX),
+            false,
+        )
+        .await?;
+    Ok(locked.is_some())
+}
+
+/// Run `work` while keeping the lock of an upload taken with [`lock`] from expiring.
+async fn holding_lock<T>(
+    state: &AppState,
+    id: Uuid,
+    work: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let mut refresh = tokio::time::interval(Duration::from_secs(LOCK_SECONDS as u64 / 3));
+    // The first tick completes right away, the lock was just taken
+    refresh.tick().await;
+    tokio::pin!(work);
+    loop {
+        tokio::select! {
+            result = &mut work => return result,
+            _ = refresh.tick() => {
+                let refreshed: Result<bool, _> =
+                    state.fred().expire(lock_key(id), LOCK_SECONDS, None).await;
+                if let Err(err) = refreshed {
+                    warn!(%id, %err, "failed to refresh tus upload lock");
+                }
+            }
+        }
+    }
+}
+
+/// Release the lock of an upload. A failure only delays the next request until the lock
+/// expires, so it must not replace the outcome of the request holding it.
+async fn unlock(state: &AppState, id: Uuid) {
+    let released: Result<(), _> = state.fred().del(lock_key(id)).await;
+    if let Err(err) = released {
+        warn!(%id, %err, "failed to release tus upload lock");
+    }
+}
+
+/// Finish a complete upload whose file wasn't recorded yet. Must be called while holding the
+/// upload lock.
+async fn retry_finish(state: &AppState, id: Uuid, owner_id: Uuid) -> Result<TusUpload> {
+    let mut upload = TusUpload::load_owned(state, id, owner_id).await?;
+    if upload.offset == upload.length && upload.file_id.is_none() {
+        let progress = Progress::start(state, owner_id, id, Some(upload.length), upload.offset);
+        upload.file_id = Some(finish(state, &upload, &progress).await?);
+    }
+    Ok(upload)
+}
+
+/// Store a PATCH body as a chunk. Must be called while holding the upload lock.
+async fn write_chunk(
+    state: &AppState,
+    id: Uuid,
+    owner_id: Uuid,
+    offset: u64,
+    body: Body,
+) -> Result<TusUpload> {
+    // Check again now that nobody else can move the offset
+    let mut upload = TusUpload::load_owned(state, id, owner_id).await?;
+    let remaining = upload.remaining_from(offset)?;
+
+    let progress = Progress::start(state, owner_id, id, Some(upload.length), upload.offset);
+    let received = AtomicU64::new(0);
+    let too_long = AtomicBool::new(false);
+
+    // A dropped connection ends the chunk instead of failing it, so whatever arrived is kept
+    let chunks = body.into_data_stream().scan((), |_, chunk| {
+        future::ready(
+            chunk
+                .inspect_err(|err| debug!(%err, "tus patch interrupted"))
+                .ok(),
+        )
+    });
+    let chunk = up_to(chunks, remaining, &received, &too_long)
+        .inspect_ok(|chunk| progress.received(chunk.len() as u64))
+        .boxed();
+
+    let key = chunk_storage_key(id, offset);
+    let stored = state
+        .storage()
+        .put(&key, chunk, PutOptions::default())
+        .await;
+    if too_long.into_inner() {
+        state.storage().delete(&key).await?;
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+    }
+    stored?;
+
+    let received = received.into_inner();
+    if received == 0 {
+        state.storage().delete(&key).await?;
+        // An empty PATCH is how clients retry an upload whose file wasn't recorded
+        if upload.offset == upload.length && upload.file_id.is_none() {
+            upload.file_id = Some(finish(state, &upload, &progress).await?);
+        }
+        return Ok(upload);
+    }
+
+    // The chunk and the offset it moves to go together, or a failure in between would leave
+    // an offset that doesn't match the chunks
+    let transaction = state.fred().multi();
+    let _: () = transaction.rpush(chunks_key(id), key).await?;
+    let _: () = transaction
+        .hincrby(upload_key(id), "offset", received as i64)
+        .await?;
+    for key in [upload_key(id), chunks_key(id)] {
+        let _: () = transaction
+            .expire_at(key, upload.expires as i64, None)
+            .await?;
+    }
+    let (_, new_offset, _, _): (i64, i64, bool, bool) = transaction.exec(true).await?;
+    upload.offset = new_offset as u64;
+    progress.persisted(received);
+
+    if upload.offset == upload.length {
+        upload.file_id = Some(finish(state, &upload, &progress).await?);
+    }
+
+    Ok(upload)
+}
+
+/// Pass `chunks` on while they add up to at most `remaining` bytes, counting them in
+/// `received`. Fails as soon as they go beyond that, setting `too_long`.
+fn up_to<'a>(
+    chunks: impl Stream<Item = Bytes> + Send + 'a,
+    remaining: u64,
+    received: &'a AtomicU64,
+    too_long: &'a AtomicBool,
+) -> impl Stream<Item = io::Result<Bytes>> + Send + 'a {
+    chunks.map(move |chunk| {
+        let len = chunk.len() as u64;
+        if received.fetch_add(len, Ordering::Relaxed) + len > remaining {
+            too_long.store(true, Ordering::Relaxed);
+            return Err(io::Error::other("body exceeds Upload-Length"));
+        }
+        Ok(chunk)
+    })
+}
+
+/// Whether `err` means the upload can never be recorded, as opposed to a failure worth retrying.
+fn is_rejection(err: &AppError) -> bool {
+    match err {
+        AppError::UploadRejected(_) | AppError::QuotaExceeded { .. } => true,
+        AppError::Code(code) => code.is_client_error(),
+        _ => false,
+    }
+}
+
+/// Join the chunks of a complete upload into a file, then drop the chunks.
+///
+/// Uploads that are refused are dropped right away. On any other failure the chunks are kept,
+/// so the next HEAD or an empty PATCH can try again.
+async fn finish(state: &AppState, upload: &TusUpload, progress: &Progress) -> Result<Uuid> {
+    let chunks: Vec<String> = state.fred().lrange(chunks_key(upload.id), 0, -1).await?;
+
+    let storage = state.storage();
+    let body = stream::iter(chunks.iter())
+        .then(move |key| async move {
+            storage
+                .get(key, None)
+                .await
+                .map(|object| object.body)
+                .map_err(io::Error::other)
+        })
+        .try_flatten()
+        .boxed();
+    progress.hashing();
+    // The metadata was checked when the upload was created
+    let file = match files::store_file(state, upload.new_file()?, body).await {
+        Ok(file) => file,
+        Err(err) => {
+            progress.failed(&err);
+            if is_rejection(&err) {
+                debug!(id = %upload.id, %err, "dropping refused tus upload");
+                discard_chunks(state, upload.id).await?;
+                let _: () = state.fred().del(upload_key(upload.id)).await?;
+            }
+            return Err(err);
+        }
+    };
+    progress.recorded(&file);
+    progress.done();
+
+    let _: () = state
+        .fred()
+        .hset(upload_key(upload.id), ("file_id", file.id.to_string()))
+        .await?;
+    discard_chunks(state, upload.id).await?;
+
+    Ok(file.id)
+}
+
+async fn discard_chunks(state: &AppState, id: Uuid) -> Result<()> {
+    let chunks: Vec<String> = state.fred().lrange(chunks_key(id), 0, -1).await?;
+    for key in chunks {
+        state.storage().delete(&key).await?;
+    }
+    let _: () = state.fred().del(chunks_key(id)).await?;
+
+    Ok(())
+}
+
+async fn delete_upload(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let upload = TusUpload::load_owned(&state, id, user.id).await?;
+
+    discard_chunks(&state, upload.id).await?;
+    let _: () = state.fred().del(upload_key(upload.id)).await?;
+    debug!(%id, "terminated tus upload");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Removes chunks left behind by uploads that expired in redis.
+pub(crate) async fn sweep_expired(state: AppStateRef) -> Result<()> {
+    let mut alive: HashMap<Uuid, bool> = HashMap::new();
+    let mut stale = vec![];
+
+    let mut objects = state.storage().list(CHUNK_PREFIX);
+    while let Some(object) = objects.try_next().await? {
+        let Some(id) = object
+            .key
+            .strip_prefix(CHUNK_PREFIX)
+            .and_then(|rest| rest.split('/').next())
+            .and_then(|id| id.parse::<Uuid>().ok())
+        else {
+            continue;
+        };
+
+        let exists = match alive.get(&id) {
+            Some(exists) => *exists,
+            None => {
+                let exists: bool = state.fred().exists(upload_key(id)).await?;
+                alive.insert(id, exists);
+                exists
+            }
+        };
+        if !exists {
+            stale.push(object.key);
+        }
+    }
+    drop(objects);
+
+    for key in &stale {
+        state.storage().delete(key).await?;
+    }
+    if !stale.is_empty() {
+        info!(removed = stale.len(), "removed expired tus chunks");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dto::file::FileExpiry;
+
+    fn upload(metadata: Option<&str>) -> TusUpload {
+        TusUpload {
+            id: Uuid::now_v7(),
+            owner_id: Uuid::now_v7(),
+            length: 10,
+            offset: 4,
+            metadata: metadata.map(str::to_string),
+            expires: 0,
+            file_id: None,
+        }
+    }
+
+    fn encoded(value: &str) -> String {
+        BASE64_STANDARD.encode(value)
+    }
+
+    #[test]
+    fn parses_metadata() {
+        let header = format!(
+            "filename {}, is_confidential,filetype {} ,bad !!!,, ",
+            encoded("report 2026.pdf"),
+            encoded("application/pdf"),
+        );
+        let metadata = upload(Some(&header)).metadata();
+        assert_eq!(metadata["filename"], "report 2026.pdf");
+        assert_eq!(metadata["filetype"], "application/pdf");
+        assert_eq!(metadata["is_confidential"], "");
+        // Values that aren't base64 drop their pair, not the whole header
+        assert!(!metadata.contains_key("bad"));
+        assert_eq!(metadata.len(), 3);
+
+        assert!(upload(None).metadata().is_empty());
+        assert!(upload(Some("")).metadata().is_empty());
+    }
+
+    #[test]
+    fn describes_new_file() {
+        let folder_id = Uuid::now_v7();
+        let header = format!(
+            "name {},type {},filetype {},folder_id {},expiry {}",
+            encoded("notes.txt"),
+            encoded("text/plain"),
+            encoded(""),
+            encoded(&folder_id.to_string()),
+            encoded("1d"),
+        );
+        let described = upload(Some(&header));
+        let new = described.new_file().unwrap();
+        assert_eq!(new.owner, described.owner_id);
+        assert_eq!(new.name, "notes.txt");
+        // Empty values fall through to the other name of the field
+        assert_eq!(new.content_type, "text/plain");
+        assert_eq!(new.folder_id, Some(folder_id));
+        assert_eq!(new.expiry, Some(FileExpiry::Day));
+
+        let bare = upload(None);
+        let new = bare.new_file().unwrap();
+        assert_eq!(new.name, bare.id.to_string());
+        assert_eq!(new.content_type, "application/octet-stream");
+        assert_eq!((new.folder_id, new.expiry), (None, None));
+
+        let header = format!("filename {},expiry {}", encoded("a"), encoded("2d"));
+        assert!(upload(Some(&header)).new_file().is_err());
+    }
+
+    #[test]
+    fn checks_offsets() {
+        let upload = upload(None);
+        assert_eq!(upload.remaining_from(4).unwrap(), 6);
+        for offset in [0, 3, 5, 10] {
+            assert!(matches!(
+                upload.remaining_from(offset),
+                Err(AppError::Code(StatusCode::CONFLICT))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn cuts_off_bodies_beyond_the_length() {
+        let chunks = || stream::iter([Bytes::from_static(b"abc"), Bytes::from_static(b"def")]);
+
+        let (received, too_long) = (AtomicU64::new(0), AtomicBool::new(false));
+        let passed: Vec<Bytes> = up_to(chunks(), 6, &received, &too_long)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(passed.concat(), b"abcdef");
+        assert_eq!(received.into_inner(), 6);
+        assert!(!too_long.into_inner());
+
+        let (received, too_long) = (AtomicU64::new(0), AtomicBool::new(false));
+        let results: Vec<io::Result<Bytes>> =
+            up_to(chunks(), 5, &received, &too_long).collect().await;
+        assert!(matches!(&results[..], [Ok(_), Err(_)]));
+        assert!(too_long.into_inner());
+    }
+}
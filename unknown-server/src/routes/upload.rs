@@ -1,7 +1,130 @@
+use crate::files;
 use crate::prelude::*;
+use crate::progress::{self, Progress};
+use crate::routes::{presign, tus};
+use axum::extract::multipart::Multipart;
+use axum::extract::{DefaultBodyLimit, Path, Query};
+use axum::http::{HeaderMap, header};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use uuid::Uuid;
 
 pub(crate) fn router() -> Router<AppStateRef> {
     Router::new()
-        .route("/", get(|| async { "Hello, World!" }))
+        .route("/", get(get_upload).post(post_upload))
+        .route("/progress/{id}", get(get_progress))
+        .nest("/presign", presign::router())
+        .route_layer(login_required!(Backend))
+        // tus checks logins in its handlers, so CORS preflight and capability discovery with
+        // OPTIONS work without one
+        .nest("/tus", tus::router())
+        // Uploads are streamed to storage, so the default 2MB limit doesn't apply here
+        .layer(DefaultBodyLimit::disable())
 }
 
+#[derive(Debug, Deserialize)]
+struct UploadQuery {
+    /// Folder the files are stored in. The root if unset
+    folder_id: Option<Uuid>,
+    /// Picked by the client to follow the upload at `/upload/progress/{upload_id}`
+    upload_id: Option<Uuid>,
+    /// How long the files are kept
+    expiry: Option<dto::file::FileExpiry>,
+}
+
+async fn get_upload(State(state): State<AppStateRef>) -> ResultHtml {
+    let template = state.render_template("upload/index.j2.html", None)?;
+    Ok(Html(template))
+}
+
+async fn post_upload(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> ResultJson<Vec<dto::file::FileInfoDto>> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+
+    let progress = match query.upload_id {
+        Some(upload_id) => {
+            // The whole multipart body, a little more than the files in it
+            let total = headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|length| length.to_str().ok()?.parse().ok());
+            Progress::start(&state, user.id, upload_id, total, 0)
+        }
+        None => Progress::default(),
+    };
+
+    let stored = store_parts(&state, user.id, &query, multipart, &progress).await;
+    match &stored {
+        Ok(_) => progress.done(),
+        Err(err) => progress.failed(err),
+    }
+
+    Ok(Json(stored?))
+}
+
+async fn store_parts(
+    state: &AppState,
+    owner: Uuid,
+    query: &UploadQuery,
+    mut multipart: Multipart,
+    progress: &Progress,
+) -> Result<Vec<dto::file::FileInfoDto>> {
+    let mut files = vec![];
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+    {
+        // Only file parts are stored, plain form fields are ignored
+        let Some(name) = field.file_name().map(str::to_string) else {
+            continue;
+        };
+        let content_type = field
+            .content_type()
+            .unwrap_or("application/octet-stream")
+            .to_string();
+
+        let body = progress.track(field).map_err(std::io::Error::other).boxed();
+        let new = files::NewFile {
+            owner,
+            folder_id: query.folder_id,
+            name,
+            content_type,
+            expiry: query.expiry,
+        };
+        let file = files::store_file(state, new, body).await?;
+        progress.persisted(file.size as u64);
+        progress.recorded(&file);
+
+        files.push(file.into());
+    }
+
+    if files.is_empty() {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    Ok(files)
+}
+
+/// Server-sent events on the progress of one of the user's uploads. `id` is the `upload_id`
+/// of a multipart upload or the id of a tus or presigned upload. Watching may start before the
+/// upload does.
+async fn get_progress(
+    State(state): State<AppStateRef>,
+    auth_session: AuthSession,
+    Path(id): Path<Uuid>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>> {
+    let Some(user) = auth_session.user else {
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+
+    let events = progress::watch(state, user.id, id).map(|event| Event::default().json_data(event));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
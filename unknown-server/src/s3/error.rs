@@ -0,0 +1,268 @@
+//! Errors in the shape S3 clients expect, an XML body with a code they can act on.
+use crate::models::file::FileStatus;
+use crate::policy::UploadRejection;
+use crate::prelude::*;
+use crate::storage::StorageError;
+use axum::http::{HeaderValue, header};
+use axum::response::{IntoResponse, Response};
+use std::borrow::Cow;
+
+pub(crate) type S3Result<T> = std::result::Result<T, S3Error>;
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{code}: {message}")]
+pub(crate) struct S3Error {
+    pub(crate) status: StatusCode,
+    pub(crate) code: &'static str,
+    pub(crate) message: Cow<'static, str>,
+}
+
+impl S3Error {
+    pub(crate) fn new(
+        status: StatusCode,
+        code: &'static str,
+        message: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn access_denied(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "AccessDenied", message)
+    }
+
+    pub(crate) fn authorization_malformed() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "AuthorizationHeaderMalformed",
+            "The authorization header is malformed",
+        )
+    }
+
+    pub(crate) fn authorization_query_malformed() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "AuthorizationQueryParametersError",
+            "The authorization query parameters are malformed",
+        )
+    }
+
+    pub(crate) fn invalid_access_key_id() -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "InvalidAccessKeyId",
+            "The access key Id you provided does not exist in our records",
+        )
+    }
+
+    pub(crate) fn signature_does_not_match() -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "SignatureDoesNotMatch",
+            "The request signature we calculated does not match the signature you provided",
+        )
+    }
+
+    pub(crate) fn request_time_too_skewed() -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "RequestTimeTooSkewed",
+            "The difference between the request time and the server's time is too large",
+        )
+    }
+
+    pub(crate) fn invalid_request(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "InvalidRequest", message)
+    }
+
+    pub(crate) fn invalid_argument(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+    }
+
+    pub(crate) fn malformed_xml() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "MalformedXML",
+            "The XML you provided was not well-formed",
+        )
+    }
+
+    pub(crate) fn incomplete_body() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "IncompleteBody",
+            "The request body is incomplete or malformed",
+        )
+    }
+
+    pub(crate) fn missing_content_length() -> Self {
+        Self::new(
+            StatusCode::LENGTH_REQUIRED,
+            "MissingContentLength",
+            "You must provide the Content-Length HTTP header",
+        )
+    }
+
+    pub(crate) fn content_sha256_mismatch() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "XAmzContentSHA256Mismatch",
+            "The provided 'x-amz-content-sha256' header does not match what was computed",
+        )
+    }
+
+    pub(crate) fn invalid_digest() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidDigest",
+            "The Content-MD5 you specified is not valid",
+        )
+    }
+
+    pub(crate) fn bad_digest() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "BadDigest",
+            "The Content-MD5 you specified did not match what we received",
+        )
+    }
+
+    pub(crate) fn no_such_bucket() -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchBucket",
+            "The specified bucket does not exist",
+        )
+    }
+
+    pub(crate) fn no_such_key() -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchKey",
+            "The specified key does not exist",
+        )
+    }
+
+    pub(crate) fn no_such_upload() -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchUpload",
+            "The specified multipart upload does not exist",
+        )
+    }
+
+    pub(crate) fn not_implemented(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::NOT_IMPLEMENTED, "NotImplemented", message)
+    }
+
+    pub(crate) fn method_not_allowed() -> Self {
+        Self::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "MethodNotAllowed",
+            "The specified method is not allowed against this resource",
+        )
+    }
+
+    pub(crate) fn internal() -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "InternalError",
+            "We encountered an internal error. Please try again",
+        )
+    }
+}
+
+impl IntoResponse for S3Error {
+    fn into_response(self) -> Response {
+        let request_id = uuid::Uuid::new_v4().simple().to_string();
+        let body = super::xml::error(self.code, &self.message, &request_id);
+        let mut response = (
+            self.status,
+            [(header::CONTENT_TYPE, "application/xml")],
+            body,
+        )
+            .into_response();
+        response.headers_mut().insert(
+            "x-amz-request-id",
+            HeaderValue::from_str(&request_id).expect("hex is valid"),
+        );
+        response
+    }
+}
+
+impl From<AppError> for S3Error {
+    fn from(err: AppError) -> Self {
+        match err {
+            AppError::Code(code) => match code {
+                StatusCode::NOT_FOUND => Self::no_such_key(),
+                StatusCode::CONFLICT => Self::new(
+                    code,
+                    "OperationAborted",
+                    "A conflicting operation is in progress against this resource",
+                ),
+                StatusCode::PAYLOAD_TOO_LARGE => Self::new(
+                    StatusCode::BAD_REQUEST,
+                    "EntityTooLarge",
+                    "Your proposed upload exceeds the maximum allowed object size",
+                ),
+                code if code.is_server_error() => Self::internal(),
+                code => Self::invalid_request(code.canonical_reason().unwrap_or("Bad request")),
+            },
+            // 5xx would have clients retry an upload that can never fit
+            AppError::QuotaExceeded { limit } => Self::new(
+                StatusCode::FORBIDDEN,
+                "QuotaExceeded",
+                format!("Storage quota of {limit} bytes exceeded"),
+            ),
+            AppError::UploadRejected(rejection) => match rejection {
+                UploadRejection::TooLargeForType { .. } => Self::new(
+                    StatusCode::BAD_REQUEST,
+                    "EntityTooLarge",
+                    rejection.to_string(),
+                ),
+                rejection => Self::access_denied(rejection.to_string()),
+            },
+            AppError::NotServable(FileStatus::Infected) => {
+                Self::access_denied("File is quarantined as malware")
+            }
+            AppError::NotServable(FileStatus::Corrupt) => Self::internal(),
+            // Clients retry on 503, which is what they should do until the scan is done
+            AppError::NotServable(_) => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "SlowDown",
+                "File has not been scanned for malware yet",
+            ),
+            // To S3 clients an expired object is one that is gone already
+            AppError::Expired => Self::no_such_key(),
+            AppError::Storage(StorageError::NotFound(key)) => {
+                error!(key, "file points to a missing object");
+                Self::no_such_key()
+            }
+            err => {
+                error!(%err, "s3 api error");
+                Self::internal()
+            }
+        }
+    }
+}
+
+impl From<sqlx::Error> for S3Error {
+    fn from(err: sqlx::Error) -> Self {
+        AppError::from(err).into()
+    }
+}
+
+impl From<StorageError> for S3Error {
+    fn from(err: StorageError) -> Self {
+        AppError::from(err).into()
+    }
+}
+
+impl From<fred::error::Error> for S3Error {
+    fn from(err: fred::error::Error) -> Self {
+        AppError::from(err).into()
+    }
+}
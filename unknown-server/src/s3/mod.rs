@@ -0,0 +1,415 @@
+//! S3 compatible view of every user's files, for tools that already speak S3.
+//!
+//! Buckets are the folders at the root of a user's files and keys are paths below them,
+//! `docs/2026/report.pdf` in bucket `work` is the file `report.pdf` in the folder `2026` of
+//! the folder `docs` of the root folder `work`. Folders are created along the way when objects
+//! are put, and empty folders show up as objects with keys ending in `/`, like the directory
+//! markers other tools leave in S3. Deleting moves things to the trash like the rest of the API
+//! does.
+pub(crate) mod error;
+pub(crate) mod multipart;
+pub(crate) mod sigv4;
+pub(crate) mod xml;
+
+pub(crate) use error::{S3Error, S3Result};
+
+use crate::models::file::{File, ListedObject};
+use crate::models::folder::{Folder, FolderInsert};
+use crate::prelude::*;
+use rand::Rng;
+use rand::distr::Alphanumeric;
+use uuid::Uuid;
+use xml::ListRequest;
+
+/// The ETag S3 reports for empty objects, md5 of nothing. Folders are listed with it.
+pub(crate) const MARKER_ETAG: &str = "\"d41d8cd98f00b204e9800998ecf8427e\"";
+
+/// Listings never return more keys at once, same as S3.
+pub(crate) const MAX_KEYS: usize = 1000;
+
+/// Longer keys are refused, same as S3.
+const MAX_KEY_LEN: usize = 1024;
+
+/// A new access key id and its secret.
+pub(crate) fn generate_access_key() -> (String, String) {
+    let mut rng = rand::rng();
+    let id: String = (&mut rng)
+        .sample_iter(Alphanumeric)
+        .take(18)
+        .map(|char| char.to_ascii_uppercase() as char)
+        .collect();
+    let secret: String = rng
+        .sample_iter(Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect();
+    (format!("UK{id}"), secret)
+}
+
+/// An object key split into the folders it is in and its name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ObjectKey {
+    folders: Vec<String>,
+    /// `None` for keys ending in `/`, which stand for the folder itself
+    name: Option<String>,
+}
+
+impl ObjectKey {
+    /// Fails for keys that can't be a path, like those with empty or `..` segments.
+    pub(crate) fn parse(key: &str) -> S3Result<Self> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "KeyTooLongError",
+                "Your key is too long",
+            ));
+        }
+        let (folders, name) = match key.strip_suffix('/') {
+            Some(folder) => (Some(folder), None),
+            None => match key.rsplit_once('/') {
+                Some((folders, name)) => (Some(folders), Some(name)),
+                None => (None, Some(key)),
+            },
+        };
+        let folders: Vec<String> = folders
+            .map(|folders| folders.split('/').map(str::to_string).collect())
+            .unwrap_or_default();
+        let valid = folders
+            .iter()
+            .map(String::as_str)
+            .chain(name)
+            .all(crate::files::valid_name);
+        if !valid || folders.is_empty() && name.is_none() {
+            return Err(S3Error::invalid_argument(
+                "Object keys have to be paths without empty, `.` or `..` segments",
+            ));
+        }
+
+        Ok(Self {
+            folders,
+            name: name.map(str::to_string),
+        })
+    }
+
+    /// The folders down to the file, or down to and including the folder.
+    pub(crate) fn folders(&self) -> &[String] {
+        &self.folders
+    }
+
+    /// The name of the file, `None` for folders.
+    pub(crate) fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+/// The folder a bucket stands for.
+pub(crate) async fn find_bucket(state: &AppState, owner: Uuid, name: &str) -> S3Result<Folder> {
+    match Folder::find_child(state.db(), owner, None, name).await? {
+        Some(bucket) => Ok(bucket),
+        None => Err(S3Error::no_such_bucket()),
+    }
+}
+
+/// Walk the folders of `owner` down `segments` from `bucket`. `None` if one of them doesn't
+/// exist.
+pub(crate) async fn walk(
+    state: &AppState,
+    owner: Uuid,
+    bucket: &Folder,
+    segments: &[String],
+) -> S3Result<Option<Folder>> {
+    let mut folder = bucket.clone();
+    for segment in segments {
+        let Some(child) = Folder::find_child(state.db(), owner, Some(folder.id), segment).await?
+        else {
+            return Ok(None);
+        };
+        folder = child;
+    }
+    Ok(Some(folder))
+}
+
+/// Walk the folders of `owner` down `segments` from `bucket`, creating those that don't exist.
+/// Returns the last one.
+pub(crate) async fn ensure_folders(
+    state: &AppState,
+    owner: Uuid,
+    bucket: &Folder,
+    segments: &[String],
+) -> S3Result<Folder> {
+    let mut folder = bucket.clone();
+    for segment in segments {
+        if let Some(child) = Folder::find_child(state.db(), owner, Some(folder.id), segment).await?
+        {
+            folder = child;
+            continue;
+        }
+        folder = match FolderInsert::new(owner, Some(folder.id), segment.clone())
+            .insert(state.db())
+            .await
+        {
+            Ok(child) => child,
+            // Someone else just created it
+            Err(err)
+                if err
+                    .as_database_error()
+                    .is_some_and(|err| err.is_unique_violation()) =>
+            {
+                Folder::find_child(state.db(), owner, Some(folder.id), segment)
+                    .await?
+                    .ok_or_else(S3Error::internal)?
+            }
+            Err(err) => return Err(err.into()),
+        };
+    }
+    Ok(folder)
+}
+
+/// What a key points at.
+#[derive(Debug, Clone)]
+pub(crate) enum Object {
+    File(File),
+    Folder(Folder),
+}
+
+/// Find what `key` points at in `bucket`.
+pub(crate) async fn resolve(
+    state: &AppState,
+    owner: Uuid,
+    bucket: &Folder,
+    key: &ObjectKey,
+) -> S3Result<Option<Object>> {
+    let Some(folder) = walk(state, owner, bucket, key.folders()).await? else {
+        return Ok(None);
+    };
+    let Some(name) = key.name() else {
+        return Ok(Some(Object::Folder(folder)));
+    };
+    let file = File::find_in_folder(state.db(), owner, Some(folder.id), name).await?;
+    Ok(file.map(Object::File))
+}
+
+/// One page of a listing.
+#[derive(Debug, Default)]
+pub(crate) struct Listing {
+    pub(crate) objects: Vec<ListedObject>,
+    /// Keys rolled up at the delimiter, ending in it
+    pub(crate) prefixes: Vec<String>,
+    pub(crate) truncated: bool,
+}
+
+impl Listing {
+    /// The last key of the page, where the next one continues.
+    pub(crate) fn last_key(&self) -> Option<&str> {
+        let object = self.objects.last().map(|object| object.key.as_str());
+        let prefix = self.prefixes.last().map(String::as_str);
+        object.max(prefix)
+    }
+}
+
+/// An entry of a listing that rolls keys up at `/`.
+enum Entry {
+    Object(ListedObject),
+    Prefix(String),
+}
+
+impl Entry {
+    fn key(&self) -> &str {
+        match self {
+            Entry::Object(object) => &object.key,
+            Entry::Prefix(prefix) => prefix,
+        }
+    }
+}
+
+/// The page of `entries` after `after`, sorted by key.
+fn page(mut entries: Vec<Entry>, after: &str, max_keys: usize) -> Listing {
+    entries.retain(|entry| match entry {
+        Entry::Object(object) => object.key.as_str() > after,
+        // Keys below a prefix can come after `after` while the prefix itself doesn't
+        Entry::Prefix(prefix) => {
+            prefix.as_str() > after || after.starts_with(prefix.as_str()) && after != prefix
+        }
+    });
+    entries.sort_by(|a, b| a.key().cmp(b.key()));
+
+    let mut listing = Listing {
+        truncated: entries.len() > max_keys,
+        ..Listing::default()
+    };
+    for entry in entries.into_iter().take(max_keys) {
+        match entry {
+            Entry::Object(object) => listing.objects.push(object),
+            Entry::Prefix(prefix) => listing.prefixes.push(prefix),
+        }
+    }
+    listing
+}
+
+/// List the objects in `bucket` after `after`.
+pub(crate) async fn list(
+    state: &AppState,
+    owner: Uuid,
+    bucket: &Folder,
+    request: &ListRequest,
+    after: &str,
+) -> S3Result<Listing> {
+    match request.delimiter.as_deref() {
+        None => {
+            let mut objects = File::list_below(
+                state.db(),
+                bucket.id,
+                &request.prefix,
+                after,
+                request.max_keys as i64 + 1,
+            )
+            .await?;
+            let truncated = objects.len() > request.max_keys;
+            objects.truncate(request.max_keys);
+            Ok(Listing {
+                objects,
+                prefixes: vec![],
+                truncated,
+            })
+        }
+        // Only what's directly in the folder the prefix ends in has to be looked at
+        Some("/") => {
+            let (directory, name_prefix) = match request.prefix.rsplit_once('/') {
+                Some((directory, name_prefix)) => (Some(directory), name_prefix),
+                None => (None, request.prefix.as_str()),
+            };
+            let segments: Vec<String> = directory
+                .map(|directory| directory.split('/').map(str::to_string).collect())
+                .unwrap_or_default();
+            if !segments
+                .iter()
+                .all(|segment| crate::files::valid_name(segment))
+            {
+                return Ok(Listing::default());
+            }
+            let Some(folder) = walk(state, owner, bucket, &segments).await? else {
+                return Ok(Listing::default());
+            };
+            let base = match directory {
+                Some(directory) => format!("{directory}/"),
+                None => String::new(),
+            };
+
+            let mut entries = vec![];
+            for child in Folder::children(state.db(), owner, Some(folder.id), i64::MAX, 0).await? {
+                if child.name.starts_with(name_prefix) {
+                    entries.push(Entry::Prefix(format!("{base}{}/", child.name)));
+                }
+            }
+            for file in File::in_folder(state.db(), owner, Some(folder.id), i64::MAX, 0).await? {
+                if file.name.starts_with(name_prefix) {
+                    entries.push(Entry::Object(ListedObject {
+                        key: format!("{base}{}", file.name),
+                        size: file.size,
+                        sha256: Some(file.sha256),
+                        modified: file.uploaded,
+                    }));
+                }
+            }
+            Ok(page(entries, after, request.max_keys))
+        }
+        Some(_) => Err(S3Error::not_implemented(
+            "Only `/` is supported as a delimiter",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(folders: &[&str], name: Option<&str>) -> ObjectKey {
+        ObjectKey {
+            folders: folders.iter().map(|folder| folder.to_string()).collect(),
+            name: name.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(ObjectKey::parse("a.txt").unwrap(), key(&[], Some("a.txt")));
+        assert_eq!(
+            ObjectKey::parse("docs/2026/a b.txt").unwrap(),
+            key(&["docs", "2026"], Some("a b.txt"))
+        );
+        assert_eq!(
+            ObjectKey::parse("docs/2026/").unwrap(),
+            key(&["docs", "2026"], None)
+        );
+        assert_eq!(ObjectKey::parse("docs/2026/").unwrap().name(), None);
+
+        for invalid in [
+            "",
+            "/",
+            "/a",
+            "a//b",
+            "a/../b",
+            "./a",
+            "a/.",
+            "a\nb",
+            "docs/\r/a",
+            &"a".repeat(1025),
+        ] {
+            assert!(ObjectKey::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    fn object(key: &str) -> Entry {
+        Entry::Object(ListedObject {
+            key: key.to_string(),
+            size: 1,
+            sha256: None,
+            modified: time::OffsetDateTime::UNIX_EPOCH,
+        })
+    }
+
+    fn keys(listing: &Listing) -> (Vec<&str>, Vec<&str>) {
+        (
+            listing
+                .objects
+                .iter()
+                .map(|object| object.key.as_str())
+                .collect(),
+            listing.prefixes.iter().map(String::as_str).collect(),
+        )
+    }
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            object("b.txt"),
+            Entry::Prefix("a/".to_string()),
+            object("a.txt"),
+            Entry::Prefix("a-b/".to_string()),
+            object("Z.txt"),
+        ]
+    }
+
+    #[test]
+    fn pages_in_byte_order() {
+        let listing = page(entries(), "", 3);
+        assert!(listing.truncated);
+        assert_eq!(keys(&listing), (vec!["Z.txt", "a.txt"], vec!["a-b/"]));
+        assert_eq!(listing.last_key(), Some("a.txt"));
+
+        let listing = page(entries(), "a.txt", 3);
+        assert!(!listing.truncated);
+        assert_eq!(keys(&listing), (vec!["b.txt"], vec!["a/"]));
+        assert_eq!(listing.last_key(), Some("b.txt"));
+    }
+
+    #[test]
+    fn keeps_prefixes_with_keys_after_the_marker() {
+        // Continuing after the prefix itself doesn't list it again
+        assert_eq!(keys(&page(entries(), "a/", 10)), (vec!["b.txt"], vec![]));
+        // A marker somewhere below it does
+        assert_eq!(
+            keys(&page(entries(), "a/c.txt", 10)),
+            (vec!["b.txt"], vec!["a/"])
+        );
+    }
+}
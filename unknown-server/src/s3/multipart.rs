@@ -0,0 +1,329 @@
+//! Multipart uploads. Like tus uploads their state lives in redis and every part is written to
+//! storage as an object of its own, the parts are joined into the file once the upload is
+//! completed.
+use super::{S3Error, S3Result};
+use crate::files::{self, NewFile};
+use crate::models::file::File;
+use crate::prelude::*;
+use crate::storage::{ByteStream, PutOptions};
+use fred::prelude::{HashesInterface, KeysInterface};
+use futures::{StreamExt, TryStreamExt, stream};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+/// Parts other than the last one can't be smaller, same as S3.
+pub(crate) const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Part numbers go from 1 up to this, same as S3.
+pub(crate) const MAX_PART_NUMBER: u32 = 10_000;
+
+/// Storage prefix for part objects
+pub(crate) const PART_PREFIX: &str = "s3-parts/";
+
+fn upload_key(id: Uuid) -> String {
+    format!("s3:upload:{id}")
+}
+
+fn parts_key(id: Uuid) -> String {
+    format!("s3:parts:{id}")
+}
+
+/// Every write of a part gets its own object, so a part uploaded twice at once can't mix.
+fn part_storage_key(id: Uuid, number: u32) -> String {
+    format!("{PART_PREFIX}{id}/{number:05}/{}", Uuid::new_v4())
+}
+
+/// A multipart upload as stored in redis.
+#[derive(Debug)]
+pub(crate) struct Upload {
+    pub(crate) id: Uuid,
+    pub(crate) owner: Uuid,
+    /// The folder of the bucket the upload is in
+    pub(crate) bucket_id: Uuid,
+    pub(crate) key: String,
+    pub(crate) content_type: String,
+    /// Unix timestamp after which the upload is discarded
+    pub(crate) expires: i64,
+}
+
+/// An uploaded part.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Part {
+    pub(crate) number: u32,
+    pub(crate) storage_key: String,
+    /// Quoted md5 of the part, like S3 does
+    pub(crate) etag: String,
+    pub(crate) size: u64,
+    #[serde(with = "time::serde::timestamp")]
+    pub(crate) modified: OffsetDateTime,
+}
+
+impl Upload {
+    pub(crate) async fn create(
+        state: &AppState,
+        owner: Uuid,
+        bucket_id: Uuid,
+        key: String,
+        content_type: String,
+    ) -> S3Result<Self> {
+        let upload = Self {
+            id: Uuid::new_v4(),
+            owner,
+            bucket_id,
+            key,
+            content_type,
+            expires: OffsetDateTime::now_utc().unix_timestamp()
+                + CONFIG.s3_api.multipart_expiration as i64,
+        };
+
+        let key = upload_key(upload.id);
+        let fields = vec![
+            ("owner", upload.owner.to_string()),
+            ("bucket_id", upload.bucket_id.to_string()),
+            ("key", upload.key.clone()),
+            ("content_type", upload.content_type.clone()),
+            ("expires", upload.expires.to_string()),
+        ];
+        let _: () = state.fred().hset(&key, fields).await?;
+        let _: () = state.fred().expire_at(&key, upload.expires, None).await?;
+        Ok(upload)
+    }
+
+    /// Loads an upload of `key` in the bucket, hiding uploads of someone else or other keys.
+    pub(crate) async fn load(
+        state: &AppState,
+        id: &str,
+        owner: Uuid,
+        bucket_id: Uuid,
+        key: &str,
+    ) -> S3Result<Self> {
+        let Ok(id) = Uuid::parse_str(id) else {
+            return Err(S3Error::no_such_upload());
+        };
+        let mut fields: HashMap<String, String> = state.fred().hgetall(upload_key(id)).await?;
+        let mut field = |name: &str| fields.remove(name);
+        let upload = (|| {
+            Some(Self {
+                id,
+                owner: field("owner")?.parse().ok()?,
+                bucket_id: field("bucket_id")?.parse().ok()?,
+                key: field("key")?,
+                content_type: field("content_type")?,
+                expires: field("expires")?.parse().ok()?,
+            })
+        })();
+
+        match upload {
+            Some(upload)
+                if upload.owner == owner && upload.bucket_id == bucket_id && upload.key == key =>
+            {
+                Ok(upload)
+            }
+            _ => Err(S3Error::no_such_upload()),
+        }
+    }
+
+    /// Every part uploaded so far, by number.
+    pub(crate) async fn parts(&self, state: &AppState) -> S3Result<Vec<Part>> {
+        let parts: HashMap<String, String> = state.fred().hgetall(parts_key(self.id)).await?;
+        let mut parts: Vec<Part> = parts
+            .values()
+            .filter_map(|part| serde_json::from_str(part).ok())
+            .collect();
+        parts.sort_by_key(|part| part.number);
+        Ok(parts)
+    }
+
+    /// Store `body` as part `number`, replacing an earlier upload of it. `length` is what the
+    /// client announced, the body has to be exactly that long.
+    pub(crate) async fn put_part(
+        &self,
+        state: &AppState,
+        number: u32,
+        length: u64,
+        body: ByteStream<'_>,
+    ) -> S3Result<Part> {
+        let parts = self.parts(state).await?;
+        let stored: u64 = parts
+            .iter()
+            .filter(|part| part.number != number)
+            .map(|part| part.size)
+            .sum();
+        files::check_quota(state, self.owner, stored + length).await?;
+
+        let received = Arc::new(Mutex::new((0u64, Md5::new())));
+        let counted = received.clone();
+        let body = body
+            .map(move |chunk| {
+                let chunk = chunk?;
+                let mut received = counted.lock().expect("part hasher poisoned");
+                received.0 += chunk.len() as u64;
+                if received.0 > length {
+                    return Err(io::Error::other("body exceeds Content-Length"));
+                }
+                received.1.update(&chunk);
+                Ok(chunk)
+            })
+            .boxed();
+
+        let storage_key = part_storage_key(self.id, number);
+        let stored = state
+            .storage()
+            .put(&storage_key, body, PutOptions::default())
+            .await;
+        let (size, md5) = std::mem::take(&mut *received.lock().expect("part hasher poisoned"));
+        if let Err(err) = stored {
+            state.storage().delete(&storage_key).await?;
+            return Err(err.into());
+        }
+        if size != length {
+            state.storage().delete(&storage_key).await?;
+            return Err(S3Error::incomplete_body());
+        }
+
+        let part = Part {
+            number,
+            storage_key,
+            etag: format!("\"{}\"", hex::encode(md5.finalize())),
+            size,
+            modified: OffsetDateTime::now_utc(),
+        };
+        let json = serde_json::to_string(&part).expect("parts serialize");
+        let _: () = state
+            .fred()
+            .hset(parts_key(self.id), (number.to_string(), json))
+            .await?;
+        let _: () = state
+            .fred()
+            .expire_at(parts_key(self.id), self.expires, None)
+            .await?;
+        if let Some(replaced) = parts.iter().find(|part| part.number == number) {
+            state.storage().delete(&replaced.storage_key).await?;
+        }
+
+        Ok(part)
+    }
+
+    /// Join the `requested` parts into a file, then drop the upload. The ETags have to match
+    /// those of the parts and every part but the last has to be at least [`MIN_PART_SIZE`].
+    pub(crate) async fn complete(
+        &self,
+        state: &AppState,
+        requested: &[(u32, String)],
+        new: NewFile,
+    ) -> S3Result<File> {
+        let parts = self.parts(state).await?;
+        let invalid_part = || {
+            S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidPart",
+                "One or more of the specified parts could not be found",
+            )
+        };
+        if requested.is_empty() {
+            return Err(S3Error::malformed_xml());
+        }
+        if !requested.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+            return Err(S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidPartOrder",
+                "The list of parts was not in ascending order",
+            ));
+        }
+
+        let mut joined = vec![];
+        for (index, (number, etag)) in requested.iter().enumerate() {
+            let part = parts
+                .iter()
+                .find(|part| part.number == *number)
+                .ok_or_else(invalid_part)?;
+            if part.etag.trim_matches('"') != etag.trim_matches('"') {
+                return Err(invalid_part());
+            }
+            if index + 1 < requested.len() && part.size < MIN_PART_SIZE {
+                return Err(S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "EntityTooSmall",
+                    "Your proposed upload is smaller than the minimum allowed object size",
+                ));
+            }
+            joined.push(part.storage_key.as_str());
+        }
+
+        let storage = state.storage();
+        let body = stream::iter(joined)
+            .then(move |key| async move {
+                storage
+                    .get(key, None)
+                    .await
+                    .map(|object| object.body)
+                    .map_err(io::Error::other)
+            })
+            .try_flatten()
+            .boxed();
+        let file = files::store_file(state, new, body).await?;
+
+        self.discard(state).await?;
+        Ok(file)
+    }
+
+    /// Drop the upload and every part of it.
+    pub(crate) async fn discard(&self, state: &AppState) -> S3Result<()> {
+        let _: () = state
+            .fred()
+            .del(vec![upload_key(self.id), parts_key(self.id)])
+            .await?;
+        // Listed rather than taken from redis to also catch parts that lost a race
+        let prefix = format!("{PART_PREFIX}{}/", self.id);
+        let objects: Vec<_> = state.storage().list(&prefix).try_collect().await?;
+        for object in objects {
+            state.storage().delete(&object.key).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes parts left behind by uploads that expired in redis.
+pub(crate) async fn sweep_expired(state: AppStateRef) -> Result<()> {
+    let mut alive: HashMap<Uuid, bool> = HashMap::new();
+    let mut stale = vec![];
+
+    let mut objects = state.storage().list(PART_PREFIX);
+    while let Some(object) = objects.try_next().await? {
+        let Some(id) = object
+            .key
+            .strip_prefix(PART_PREFIX)
+            .and_then(|rest| rest.split('/').next())
+            .and_then(|id| id.parse::<Uuid>().ok())
+        else {
+            continue;
+        };
+
+        let exists = match alive.get(&id) {
+            Some(exists) => *exists,
+            None => {
+                let exists: bool = state.fred().exists(upload_key(id)).await?;
+                alive.insert(id, exists);
+                exists
+            }
+        };
+        if !exists {
+            stale.push(object.key);
+        }
+    }
+    drop(objects);
+
+    for key in &stale {
+        state.storage().delete(key).await?;
+    }
+    if !stale.is_empty() {
+        info!(removed = stale.len(), "removed expired s3 multipart parts");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,462 @@
+//! The XML bodies of S3 requests and responses.
+use super::multipart::Part;
+use super::{Listing, MARKER_ETAG, S3Error};
+use crate::models::file::ListedObject;
+use crate::models::folder::Folder;
+use crate::models::user::User;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use quick_xml::Reader;
+use quick_xml::escape::{escape, resolve_predefined_entity};
+use quick_xml::events::Event;
+use std::fmt::{Display, Write};
+use time::OffsetDateTime;
+
+pub(crate) const NAMESPACE: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// Keys are encoded like this in listings that asked for `encoding-type=url`.
+const KEY: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid request body")]
+pub(crate) struct InvalidBody;
+
+fn document(root: &str, body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><{root} xmlns=\"{NAMESPACE}\">{body}</{root}>"
+    )
+}
+
+/// An element with `value` as its escaped text.
+fn field(out: &mut String, name: &str, value: impl Display) {
+    let _ = write!(
+        out,
+        "<{name}>{}</{name}>",
+        escape(value.to_string().as_str())
+    );
+}
+
+/// Timestamps with exactly three fractional digits, some clients insist on them.
+pub(crate) fn timestamp(time: OffsetDateTime) -> String {
+    let time = time.to_offset(time::UtcOffset::UTC);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time.year(),
+        time.month() as u8,
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second(),
+        time.millisecond()
+    )
+}
+
+pub(crate) fn error(code: &str, message: &str, request_id: &str) -> String {
+    let mut body = String::new();
+    field(&mut body, "Code", code);
+    field(&mut body, "Message", message);
+    field(&mut body, "RequestId", request_id);
+    // Errors are the one body without a namespace
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error>{body}</Error>")
+}
+
+pub(crate) fn list_buckets(user: &User, buckets: &[Folder]) -> String {
+    let mut body = String::from("<Owner>");
+    field(&mut body, "ID", user.id);
+    field(&mut body, "DisplayName", &user.username);
+    body.push_str("</Owner><Buckets>");
+    for bucket in buckets {
+        body.push_str("<Bucket>");
+        field(&mut body, "Name", &bucket.name);
+        field(&mut body, "CreationDate", timestamp(bucket.created));
+        body.push_str("</Bucket>");
+    }
+    body.push_str("</Buckets>");
+    document("ListAllMyBucketsResult", &body)
+}
+
+pub(crate) fn location(region: &str) -> String {
+    document("LocationConstraint", &escape(region))
+}
+
+/// How a listing was asked for, to echo it back.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ListRequest {
+    pub(crate) bucket: String,
+    pub(crate) prefix: String,
+    pub(crate) delimiter: Option<String>,
+    pub(crate) max_keys: usize,
+    /// `ListObjectsV2` rather than the original `ListObjects`
+    pub(crate) v2: bool,
+    /// Keys are percent-encoded in the response
+    pub(crate) url_encoded: bool,
+    /// `marker` or `start-after`
+    pub(crate) start_after: Option<String>,
+    pub(crate) continuation_token: Option<String>,
+}
+
+impl ListRequest {
+    fn key(&self, key: &str) -> String {
+        match self.url_encoded {
+            true => utf8_percent_encode(key, KEY).to_string(),
+            false => key.to_string(),
+        }
+    }
+}
+
+fn object(out: &mut String, request: &ListRequest, object: &ListedObject) {
+    out.push_str("<Contents>");
+    field(out, "Key", request.key(&object.key));
+    field(out, "LastModified", timestamp(object.modified));
+    let etag = match &object.sha256 {
+        Some(sha256) => format!("\"{sha256}\""),
+        None => MARKER_ETAG.to_string(),
+    };
+    field(out, "ETag", etag);
+    field(out, "Size", object.size);
+    field(out, "StorageClass", "STANDARD");
+    out.push_str("</Contents>");
+}
+
+/// The response to `ListObjects` or `ListObjectsV2`. `next` continues a truncated listing.
+pub(crate) fn list_objects(request: &ListRequest, listing: &Listing, next: Option<&str>) -> String {
+    let mut body = String::new();
+    field(&mut body, "Name", &request.bucket);
+    field(&mut body, "Prefix", request.key(&request.prefix));
+    if let Some(delimiter) = &request.delimiter {
+        field(&mut body, "Delimiter", request.key(delimiter));
+    }
+    field(&mut body, "MaxKeys", request.max_keys);
+    if request.url_encoded {
+        field(&mut body, "EncodingType", "url");
+    }
+    field(&mut body, "IsTruncated", listing.truncated);
+    match request.v2 {
+        true => {
+            field(
+                &mut body,
+                "KeyCount",
+                listing.objects.len() + listing.prefixes.len(),
+            );
+            if let Some(token) = &request.continuation_token {
+                field(&mut body, "ContinuationToken", token);
+            }
+            if let Some(next) = next {
+                field(&mut body, "NextContinuationToken", next);
+            }
+            if let Some(start_after) = &request.start_after {
+                field(&mut body, "StartAfter", request.key(start_after));
+            }
+        }
+        false => {
+            field(
+                &mut body,
+                "Marker",
+                request.key(request.start_after.as_deref().unwrap_or_default()),
+            );
+            if let Some(next) = next {
+                field(&mut body, "NextMarker", request.key(next));
+            }
+        }
+    }
+    for listed in &listing.objects {
+        object(&mut body, request, listed);
+    }
+    for prefix in &listing.prefixes {
+        body.push_str("<CommonPrefixes>");
+        field(&mut body, "Prefix", request.key(prefix));
+        body.push_str("</CommonPrefixes>");
+    }
+    document("ListBucketResult", &body)
+}
+
+pub(crate) fn copy_object(etag: &str, modified: OffsetDateTime) -> String {
+    let mut body = String::new();
+    field(&mut body, "ETag", etag);
+    field(&mut body, "LastModified", timestamp(modified));
+    document("CopyObjectResult", &body)
+}
+
+pub(crate) fn initiate_multipart_upload(bucket: &str, key: &str, upload_id: &str) -> String {
+    let mut body = String::new();
+    field(&mut body, "Bucket", bucket);
+    field(&mut body, "Key", key);
+    field(&mut body, "UploadId", upload_id);
+    document("InitiateMultipartUploadResult", &body)
+}
+
+pub(crate) fn complete_multipart_upload(bucket: &str, key: &str, etag: &str) -> String {
+    let mut body = String::new();
+    field(&mut body, "Location", format!("/{bucket}/{key}"));
+    field(&mut body, "Bucket", bucket);
+    field(&mut body, "Key", key);
+    field(&mut body, "ETag", etag);
+    document("CompleteMultipartUploadResult", &body)
+}
+
+pub(crate) fn list_parts(
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    marker: u32,
+    max_parts: usize,
+    parts: &[Part],
+    truncated: bool,
+) -> String {
+    let mut body = String::new();
+    field(&mut body, "Bucket", bucket);
+    field(&mut body, "Key", key);
+    field(&mut body, "UploadId", upload_id);
+    field(&mut body, "PartNumberMarker", marker);
+    if let Some(last) = parts.last() {
+        field(&mut body, "NextPartNumberMarker", last.number);
+    }
+    field(&mut body, "MaxParts", max_parts);
+    field(&mut body, "IsTruncated", truncated);
+    for part in parts {
+        body.push_str("<Part>");
+        field(&mut body, "PartNumber", part.number);
+        field(&mut body, "LastModified", timestamp(part.modified));
+        field(&mut body, "ETag", &part.etag);
+        field(&mut body, "Size", part.size);
+        body.push_str("</Part>");
+    }
+    document("ListPartsResult", &body)
+}
+
+/// The response to `DeleteObjects`. Keys that were deleted are only listed unless `quiet`.
+pub(crate) fn delete_result(
+    quiet: bool,
+    deleted: &[String],
+    errors: &[(String, S3Error)],
+) -> String {
+    let mut body = String::new();
+    for key in deleted.iter().filter(|_| !quiet) {
+        body.push_str("<Deleted>");
+        field(&mut body, "Key", key);
+        body.push_str("</Deleted>");
+    }
+    for (key, err) in errors {
+        body.push_str("<Error>");
+        field(&mut body, "Key", key);
+        field(&mut body, "Code", err.code);
+        field(&mut body, "Message", &err.message);
+        body.push_str("</Error>");
+    }
+    document("DeleteResult", &body)
+}
+
+/// Element names and their text, in document order.
+type Fields = Vec<(String, String)>;
+
+/// The fields of the `record` elements in the `root` of a request body, like the `Part`s of a
+/// `CompleteMultipartUpload`, and the fields directly in the root. Namespaces aren't checked,
+/// clients don't agree on sending one.
+fn records(body: &str, root: &str, record: &str) -> Result<(Vec<Fields>, Fields), InvalidBody> {
+    let mut reader = Reader::from_reader(body.as_bytes());
+    let mut path: Vec<String> = vec![];
+    let mut records = vec![];
+    let mut fields = vec![];
+    let mut text = String::new();
+    let mut seen_root = false;
+    loop {
+        match reader.read_event().map_err(|_| InvalidBody)? {
+            Event::Start(start) => {
+                let name = std::str::from_utf8(start.local_name().into_inner())
+                    .map_err(|_| InvalidBody)?
+                    .to_string();
+                match path.len() {
+                    0 if name != root || seen_root => return Err(InvalidBody),
+                    0 => seen_root = true,
+                    1 if name == record => records.push(vec![]),
+                    _ => {}
+                }
+                path.push(name);
+                text.clear();
+            }
+            Event::Empty(start) => {
+                let name = std::str::from_utf8(start.local_name().into_inner())
+                    .map_err(|_| InvalidBody)?
+                    .to_string();
+                match path.len() {
+                    0 if name != root || seen_root => return Err(InvalidBody),
+                    0 => seen_root = true,
+                    1 if name == record => records.push(vec![]),
+                    1 => fields.push((name, String::new())),
+                    2 if path[1] == record => {
+                        records
+                            .last_mut()
+                            .expect("in a record")
+                            .push((name, String::new()));
+                    }
+                    _ => {}
+                }
+            }
+            Event::Text(content) => {
+                text.push_str(&content.xml_content().map_err(|_| InvalidBody)?);
+            }
+            Event::GeneralRef(reference) => {
+                match reference.resolve_char_ref().map_err(|_| InvalidBody)? {
+                    Some(char) => text.push(char),
+                    None => {
+                        let name = reference.decode().map_err(|_| InvalidBody)?;
+                        text.push_str(resolve_predefined_entity(&name).ok_or(InvalidBody)?);
+                    }
+                }
+            }
+            Event::CData(content) => {
+                text.push_str(&content.decode().map_err(|_| InvalidBody)?);
+            }
+            Event::End(_) => {
+                let name = path.pop().ok_or(InvalidBody)?;
+                let value = std::mem::take(&mut text);
+                match path.len() {
+                    1 if name != record => fields.push((name, value)),
+                    2 if path[1] == record => {
+                        records.last_mut().expect("in a record").push((name, value));
+                    }
+                    _ => {}
+                }
+            }
+            Event::Eof if path.is_empty() && seen_root => return Ok((records, fields)),
+            Event::Eof => return Err(InvalidBody),
+            _ => {}
+        }
+    }
+}
+
+fn value<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(field, _)| field == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// The part numbers and ETags of a `CompleteMultipartUpload`, in the order they were sent.
+pub(crate) fn parse_complete_multipart_upload(
+    body: &str,
+) -> Result<Vec<(u32, String)>, InvalidBody> {
+    let (parts, _) = records(body, "CompleteMultipartUpload", "Part")?;
+    parts
+        .iter()
+        .map(|part| {
+            let number = value(part, "PartNumber")
+                .and_then(|number| number.trim().parse().ok())
+                .ok_or(InvalidBody)?;
+            let etag = value(part, "ETag").ok_or(InvalidBody)?.trim().to_string();
+            Ok((number, etag))
+        })
+        .collect()
+}
+
+/// The keys of a `DeleteObjects` and whether only failures should be reported.
+pub(crate) fn parse_delete(body: &str) -> Result<(Vec<String>, bool), InvalidBody> {
+    let (objects, fields) = records(body, "Delete", "Object")?;
+    let keys = objects
+        .iter()
+        .map(|object| value(object, "Key").map(str::to_string).ok_or(InvalidBody))
+        .collect::<Result<Vec<_>, _>>()?;
+    let quiet = value(&fields, "Quiet").is_some_and(|quiet| quiet.trim() == "true");
+    Ok((keys, quiet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_complete_multipart_upload() {
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <CompleteMultipartUpload xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+                <Part><ETag>"a54357aff0632cce46d942af68356b38"</ETag><PartNumber>1</PartNumber></Part>
+                <Part><PartNumber>2</PartNumber><ETag>&quot;0c78aef83f66abc1fa1e8477f296d394&quot;</ETag></Part>
+            </CompleteMultipartUpload>"#;
+        assert_eq!(
+            parse_complete_multipart_upload(body).unwrap(),
+            vec![
+                (1, "\"a54357aff0632cce46d942af68356b38\"".to_string()),
+                (2, "\"0c78aef83f66abc1fa1e8477f296d394\"".to_string()),
+            ]
+        );
+
+        for invalid in [
+            "",
+            "<CompleteMultipartUpload><Part><PartNumber>x</PartNumber><ETag>a</ETag></Part>",
+            "<Delete></Delete>",
+            "<CompleteMultipartUpload><Part><ETag>a</ETag></Part></CompleteMultipartUpload>",
+            "<CompleteMultipartUpload/><CompleteMultipartUpload/>",
+        ] {
+            assert!(
+                parse_complete_multipart_upload(invalid).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_delete() {
+        let body = "<Delete><Quiet>true</Quiet>\
+            <Object><Key>a &amp; b.txt</Key></Object>\
+            <Object><Key><![CDATA[docs/<c>.txt]]></Key><VersionId>1</VersionId></Object>\
+            </Delete>";
+        assert_eq!(
+            parse_delete(body).unwrap(),
+            (
+                vec!["a & b.txt".to_string(), "docs/<c>.txt".to_string()],
+                true
+            )
+        );
+        assert_eq!(
+            parse_delete("<Delete><Object><Key>a</Key></Object></Delete>").unwrap(),
+            (vec!["a".to_string()], false)
+        );
+        assert!(parse_delete("<Delete><Object></Object></Delete>").is_err());
+    }
+
+    #[test]
+    fn writes_listings() {
+        let request = ListRequest {
+            bucket: "work".to_string(),
+            prefix: "a b/".to_string(),
+            delimiter: Some("/".to_string()),
+            max_keys: 2,
+            v2: true,
+            url_encoded: true,
+            ..ListRequest::default()
+        };
+        let listing = Listing {
+            objects: vec![ListedObject {
+                key: "a b/c&d.txt".to_string(),
+                size: 3,
+                sha256: Some("abc".to_string()),
+                modified: OffsetDateTime::UNIX_EPOCH,
+            }],
+            prefixes: vec!["a b/e/".to_string()],
+            truncated: true,
+        };
+        let body = list_objects(&request, &listing, Some("token"));
+        for expected in [
+            "<Name>work</Name><Prefix>a%20b/</Prefix><Delimiter>/</Delimiter>",
+            "<IsTruncated>true</IsTruncated><KeyCount>2</KeyCount>",
+            "<NextContinuationToken>token</NextContinuationToken>",
+            "<Key>a%20b/c%26d.txt</Key><LastModified>1970-01-01T00:00:00.000Z</LastModified>",
+            "<ETag>&quot;abc&quot;</ETag><Size>3</Size>",
+            "<CommonPrefixes><Prefix>a%20b/e/</Prefix></CommonPrefixes>",
+        ] {
+            assert!(body.contains(expected), "{expected} in {body}");
+        }
+    }
+
+    #[test]
+    fn writes_errors_without_namespace() {
+        assert_eq!(
+            error("NoSuchKey", "a < b", "1"),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>NoSuchKey</Code>\
+            <Message>a &lt; b</Message><RequestId>1</RequestId></Error>"
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! Scanning with clamd through its `INSTREAM` command.
+//!
+//! The body is sent as chunks, each prefixed with its length as a 4 byte big endian integer,
+//! and ended with a zero length chunk. clamd then answers with a single null terminated line:
+//! `stream: OK`, `stream: <signature> FOUND` or `<message> ERROR`.
+use super::{ScanError, ScanResult, ScanVerdict, Scanner};
+use crate::config::ClamdScanConfig;
+use crate::storage::ByteStream;
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Longest reply accepted from clamd.
+const MAX_REPLY: usize = 4096;
+
+#[derive(Debug, Clone)]
+enum Address {
+    Tcp(String),
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Unix(String),
+}
+
+pub(crate) struct ClamdScanner {
+    address: Address,
+    timeout: Duration,
+    chunk_size: usize,
+}
+
+impl ClamdScanner {
+    pub(crate) fn new(config: &ClamdScanConfig) -> Self {
+        let address = match config.address.strip_prefix("unix:") {
+            Some(path) => Address::Unix(path.to_string()),
+            None => Address::Tcp(config.address.clone()),
+        };
+
+        Self {
+            address,
+            timeout: Duration::from_secs(config.timeout),
+            chunk_size: config.chunk_size.max(1),
+        }
+    }
+
+    async fn scan_inner(&self, body: ByteStream<'_>) -> ScanResult<ScanVerdict> {
+        match &self.address {
+            Address::Tcp(address) => {
+                let conn = TcpStream::connect(address).await?;
+                instream(conn, body, self.chunk_size).await
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                let conn = tokio::net::UnixStream::connect(path).await?;
+                instream(conn, body, self.chunk_size).await
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(ScanError::Io(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl Scanner for ClamdScanner {
+    fn name(&self) -> &'static str {
+        "clamd"
+    }
+
+    async fn scan(&self, body: ByteStream<'_>) -> ScanResult<ScanVerdict> {
+        with_timeout(self.timeout, self.scan_inner(body)).await
+    }
+}
+
+/// Give up on a scan that takes longer than `timeout`, connecting included.
+async fn with_timeout(
+    timeout: Duration,
+    scan: impl Future<Output = ScanResult<ScanVerdict>>,
+) -> ScanResult<ScanVerdict> {
+    tokio::time::timeout(timeout, scan)
+        .await
+        .map_err(|_| ScanError::Timeout)?
+}
+
+/// Run `INSTREAM` over an open connection to clamd. Any connection works, which keeps this
+/// usable against a fake clamd on an in-memory pipe.
+pub(crate) async fn instream<C>(
+    mut conn: C,
+    body: ByteStream<'_>,
+    chunk_size: usize,
+) -> ScanResult<ScanVerdict>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let sent = send(&mut conn, body, chunk_size).await;
+    // clamd replies and hangs up early when the stream goes over its size limit, so there may
+    // be a reply even if sending failed
+    let reply = read_reply(&mut conn).await;
+
+    match (sent, reply) {
+        (_, Ok(reply)) if !reply.is_empty() => parse_reply(&reply),
+        (Err(err), _) => Err(err.into()),
+        (Ok(()), Err(err)) => Err(err.into()),
+        (Ok(()), Ok(_)) => Err(ScanError::Protocol(String::new())),
+    }
+}
+
+async fn send<C>(conn: &mut C, mut body: ByteStream<'_>, chunk_size: usize) -> io::Result<()>
+where
+    C: AsyncWrite + Unpin,
+{
+    conn.write_all(b"zINSTREAM\0").await?;
+    while let Some(chunk) = body.try_next().await? {
+        for part in chunk.chunks(chunk_size) {
+            conn.write_all(&(part.len() as u32).to_be_bytes()).await?;
+            conn.write_all(part).await?;
+        }
+    }
+    conn.write_all(&0u32.to_be_bytes()).await?;
+    conn.flush().await
+}
+
+/// Read up to the null byte ending the reply, or until clamd hangs up.
+async fn read_reply<C>(conn: &mut C) -> io::Result<String>
+where
+    C: AsyncRead + Unpin,
+{
+    let mut reply = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let read = conn.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        reply.extend_from_slice(&buf[..read]);
+        if let Some(end) = reply.iter().position(|&byte| byte == 0) {
+            reply.truncate(end);
+            break;
+        }
+        if reply.len() > MAX_REPLY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "clamd reply too long",
+            ));
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&reply).trim_end().to_string())
+}
+
+fn parse_reply(reply: &str) -> ScanResult<ScanVerdict> {
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+
+    if result == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(ScanVerdict::Infected(signature.to_string()))
+    } else if let Some(message) = result.strip_suffix(" ERROR") {
+        Err(ScanError::Scanner(message.to_string()))
+    } else {
+        Err(ScanError::Protocol(reply.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::{StreamExt, stream};
+    use tokio::io::{DuplexStream, duplex};
+
+    fn body(chunks: &[&'static [u8]]) -> ByteStream<'static> {
+        let chunks: Vec<_> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk)))
+            .collect();
+        stream::iter(chunks).boxed()
+    }
+
+    /// Read an `INSTREAM` request like clamd does. Returns the length of every chunk and the
+    /// joined body.
+    async fn receive(conn: &mut DuplexStream) -> (Vec<usize>, Vec<u8>) {
+        let mut command = [0u8; 10];
+        conn.read_exact(&mut command).await.unwrap();
+        assert_eq!(&command, b"zINSTREAM\0");
+
+        let (mut lengths, mut received) = (vec![], vec![]);
+        loop {
+            let length = conn.read_u32().await.unwrap() as usize;
+            if length == 0 {
+                return (lengths, received);
+            }
+            let mut chunk = vec![0; length];
+            conn.read_exact(&mut chunk).await.unwrap();
+            lengths.push(length);
+            received.extend(chunk);
+        }
+    }
+
+    /// A clamd that reads a full request and answers with `reply`.
+    fn fake_clamd(reply: &'static [u8]) -> (DuplexStream, tokio::task::JoinHandle<Vec<usize>>) {
+        let (client, mut server) = duplex(1024);
+        let handle = tokio::spawn(async move {
+            let (lengths, _) = receive(&mut server).await;
+            server.write_all(reply).await.unwrap();
+            lengths
+        });
+        (client, handle)
+    }
+
+    #[tokio::test]
+    async fn clean() {
+        let (conn, clamd) = fake_clamd(b"stream: OK\0");
+        let verdict = instream(conn, body(&[b"hello"]), 1024).await.unwrap();
+        assert_eq!(verdict, ScanVerdict::Clean);
+        clamd.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn infected() {
+        let (conn, clamd) = fake_clamd(b"stream: Eicar-Signature FOUND\0");
+        let verdict = instream(conn, body(&[b"X5O!P%@AP"]), 1024).await.unwrap();
+        assert_eq!(
+            verdict,
+            ScanVerdict::Infected("Eicar-Signature".to_string())
+        );
+        clamd.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn scanner_error() {
+        let (conn, clamd) = fake_clamd(b"INSTREAM size limit exceeded. ERROR\0");
+        let err = instream(conn, body(&[b"hello"]), 1024).await.unwrap_err();
+        assert!(
+            matches!(&err, ScanError::Scanner(message) if message == "INSTREAM size limit exceeded."),
+            "{err}"
+        );
+        clamd.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unexpected_reply() {
+        let (conn, clamd) = fake_clamd(b"UNKNOWN COMMAND\0");
+        let err = instream(conn, body(&[b"hello"]), 1024).await.unwrap_err();
+        assert!(matches!(err, ScanError::Protocol(_)), "{err}");
+        clamd.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn splits_chunks_larger_than_chunk_size() {
+        let (client, mut server) = duplex(1024);
+        let clamd = tokio::spawn(async move {
+            let received = receive(&mut server).await;
+            server.write_all(b"stream: OK\0").await.unwrap();
+            received
+        });
+
+        let verdict = instream(client, body(&[b"abcdefghij", b"", b"klm"]), 4)
+            .await
+            .unwrap();
+        assert_eq!(verdict, ScanVerdict::Clean);
+
+        let (lengths, received) = clamd.await.unwrap();
+        // Empty chunks are skipped, a zero length would end the stream early
+        assert_eq!(lengths, [4, 4, 2, 3]);
+        assert_eq!(received, b"abcdefghijklm");
+    }
+
+    #[tokio::test]
+    async fn empty_body() {
+        let (conn, clamd) = fake_clamd(b"stream: OK\0");
+        let verdict = instream(conn, body(&[]), 1024).await.unwrap();
+        assert_eq!(verdict, ScanVerdict::Clean);
+        assert!(clamd.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reply_after_early_hangup() {
+        // clamd answers and closes as soon as a stream goes over its limit
+        let (client, mut server) = duplex(64);
+        let clamd = tokio::spawn(async move {
+            let mut command = [0u8; 10];
+            server.read_exact(&mut command).await.unwrap();
+            server
+                .write_all(b"INSTREAM size limit exceeded. ERROR\0")
+                .await
+                .unwrap();
+        });
+
+        let large: &'static [u8] = &[0; 4096];
+        let err = instream(client, body(&[large]), 1024).await.unwrap_err();
+        assert!(matches!(err, ScanError::Scanner(_)), "{err}");
+        clamd.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let (client, mut server) = duplex(1024);
+        // Reads the request, but never answers
+        let clamd = tokio::spawn(async move {
+            receive(&mut server).await;
+            std::future::pending::<()>().await;
+        });
+
+        let err = with_timeout(
+            Duration::from_millis(50),
+            instream(client, body(&[b"hello"]), 1024),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, ScanError::Timeout), "{err}");
+        clamd.abort();
+    }
+}
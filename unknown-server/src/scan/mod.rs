@@ -0,0 +1,223 @@
+//! Malware scanning of uploaded contents.
+//!
+//! New contents start out [`FileStatus::Pending`] when a [`Scanner`] is configured. The worker
+//! started with [`spawn_worker`] scans them once their upload is recorded and marks every file
+//! and version with those contents clean or infected. Infected contents are moved to
+//! `quarantine/` in storage and never served again. Failed scans are retried with a growing
+//! delay.
+mod clamd;
+
+pub(crate) use clamd::ClamdScanner;
+
+use crate::compress;
+use crate::config::ScanConfig;
+use crate::ingest;
+use crate::models::blob::Blob;
+use crate::models::file::{File, FileStatus};
+use crate::models::version::FileVersion;
+use crate::prelude::*;
+use crate::progress::{self, ScanEvent};
+use crate::storage::{ByteStream, PutOptions, StorageError};
+use async_trait::async_trait;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Contents scanned per round of the worker.
+const BATCH_SIZE: i64 = 32;
+
+/// How often the worker looks for work without being woken up, to pick up failed scans and
+/// uploads recorded while it wasn't running.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+pub type ScanResult<T> = Result<T, ScanError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScanError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("scanner reported an error: {0}")]
+    Scanner(String),
+
+    #[error("unexpected scanner reply `{0}`")]
+    Protocol(String),
+
+    #[error("scan timed out")]
+    Timeout,
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// What a scanner found in some contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    /// Name of the signature that matched
+    Infected(String),
+}
+
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    /// Short name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Scan `body` in full.
+    async fn scan(&self, body: ByteStream<'_>) -> ScanResult<ScanVerdict>;
+}
+
+pub type ScannerRef = Arc<dyn Scanner>;
+
+/// Build the scanner described by `config`. `None` when scanning is disabled.
+pub(crate) fn from_config(config: &ScanConfig) -> Option<ScannerRef> {
+    match config {
+        ScanConfig::None => None,
+        ScanConfig::Clamd(config) => Some(Arc::new(ClamdScanner::new(config))),
+    }
+}
+
+/// Status new contents start out with.
+pub(crate) fn initial_status(state: &AppState) -> FileStatus {
+    match state.scanner() {
+        Some(_) => FileStatus::Pending,
+        None => FileStatus::Clean,
+    }
+}
+
+/// Scan pending contents whenever uploads are recorded, and every [`RETRY_INTERVAL`] in case
+/// a wake up was missed or a scan failed.
+pub(crate) fn spawn_worker(state: AppStateRef) {
+    let Some(scanner) = state.scanner() else {
+        return;
+    };
+    info!("Scanning uploads with {}", scanner.name());
+
+    tokio::spawn(async move {
+        loop {
+            let _ = tokio::time::timeout(RETRY_INTERVAL, state.scan_requested()).await;
+            loop {
+                match scan_pending(&state).await {
+                    // A full batch may mean there is more waiting
+                    Ok(scanned) if scanned == BATCH_SIZE as usize => continue,
+                    Ok(_) => break,
+                    Err(err) => {
+                        error!(%err, "scanning pending uploads failed");
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Scan a batch of pending contents. Returns how many were scanned successfully.
+async fn scan_pending(state: &AppState) -> Result<usize> {
+    let contents = File::unscanned_contents(state.db(), BATCH_SIZE).await?;
+
+    let mut scanned = 0;
+    for sha256 in contents {
+        if scan_content(state, &sha256).await? {
+            scanned += 1;
+        }
+    }
+
+    Ok(scanned)
+}
+
+/// Scan the blob with `sha256` and record the verdict on every file and version using it.
+/// Returns whether the scan itself succeeded.
+async fn scan_content(state: &AppState, sha256: &str) -> Result<bool> {
+    let Some(scanner) = state.scanner() else {
+        return Ok(false);
+    };
+    let Some(blob) = Blob::get(state.db(), sha256).await? else {
+        // Deleted since it was queued
+        return Ok(false);
+    };
+
+    progress::report_scan(state, sha256, ScanEvent::Scanning).await;
+    let verdict = async {
+        let object = compress::read(state.storage(), &blob, None).await?;
+        scanner.scan(object.body).await
+    }
+    .await;
+
+    let (status, from) = match verdict {
+        Ok(ScanVerdict::Clean) => (
+            FileStatus::Clean,
+            &[FileStatus::Pending, FileStatus::Error][..],
+        ),
+        // Every copy of infected contents goes, including ones a scan with older signatures
+        // found clean
+        Ok(ScanVerdict::Infected(signature)) => {
+            warn!(sha256, signature, "infected upload");
+            quarantine(state, &blob).await?;
+            (
+                FileStatus::Infected,
+                &[FileStatus::Pending, FileStatus::Error, FileStatus::Clean][..],
+            )
+        }
+        Err(err) => {
+            error!(sha256, attempts = blob.scan_attempts + 1, %err, "scan failed");
+            let mut tx = state.db().begin().await?;
+            Blob::scan_failed(&mut *tx, sha256).await?;
+            File::set_status_by_content(
+                &mut *tx,
+                sha256,
+                &[FileStatus::Pending],
+                FileStatus::Error,
+            )
+            .await?;
+            FileVersion::set_status_by_content(
+                &mut *tx,
+                sha256,
+                &[FileStatus::Pending],
+                FileStatus::Error,
+            )
+            .await?;
+            tx.commit().await?;
+            let status = FileStatus::Error;
+            progress::report_scan(state, sha256, ScanEvent::Scanned { status }).await;
+            return Ok(false);
+        }
+    };
+
+    let mut tx = state.db().begin().await?;
+    Blob::scan_succeeded(&mut *tx, sha256).await?;
+    let files = File::set_status_by_content(&mut *tx, sha256, from, status).await?;
+    let versions = FileVersion::set_status_by_content(&mut *tx, sha256, from, status).await?;
+    tx.commit().await?;
+    debug!(sha256, ?status, files, versions, "scanned upload");
+    progress::report_scan(state, sha256, ScanEvent::Scanned { status }).await;
+
+    Ok(true)
+}
+
+/// Move the object of an infected blob out of `blobs/`, so nothing that goes by the key prefix
+/// picks it up by accident.
+async fn quarantine(state: &AppState, blob: &Blob) -> Result<()> {
+    if blob.storage_key.starts_with(ingest::QUARANTINE_PREFIX) {
+        return Ok(());
+    }
+
+    let key = ingest::blob_key().replacen(ingest::BLOB_PREFIX, ingest::QUARANTINE_PREFIX, 1);
+    let object = state.storage().get(&blob.storage_key, None).await?;
+    state
+        .storage()
+        .put(&key, object.body, PutOptions::default())
+        .await?;
+    if Blob::relocate(state.db(), &blob.sha256, &key, blob.encoding)
+        .await?
+        .is_none()
+    {
+        // Every file with these contents was deleted meanwhile, and the original object with
+        // them
+        state.storage().delete(&key).await?;
+        return Ok(());
+    }
+    state.storage().delete(&blob.storage_key).await?;
+    info!(sha256 = blob.sha256, key, "quarantined upload");
+
+    Ok(())
+}
@@ -0,0 +1,278 @@
+//! Integrity scrubbing of stored contents.
+//!
+//! Storage can damage objects behind the server's back, and with a single replica nothing
+//! repairs them. The `storage_scrub` job re-reads objects at the rate set in [`ScrubConfig`] and
+//! hashes them again. Contents that no longer match their sha256 are marked corrupt, along with
+//! every file and version pointing to them, so they aren't served anymore. Each one raises an
+//! alert in the log and in the `storage_scrub_corrupt_blobs` gauge. Uploading the same contents
+//! again repairs them, see [`files::record_file`](crate::files::record_file).
+use crate::compress;
+use crate::config::ScrubConfig;
+use crate::models::blob::Blob;
+use crate::models::file::{File, FileStatus};
+use crate::models::version::FileVersion;
+use crate::prelude::*;
+use crate::storage::{Storage, StorageError, StorageResult};
+use futures::StreamExt;
+use metrics::{counter, gauge};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Blobs loaded per query.
+const BATCH: i64 = 100;
+
+/// Keeps reads at `rate` bytes per second on average.
+#[derive(Debug)]
+struct Throttle {
+    rate: u64,
+    started: Instant,
+    read: u64,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            started: Instant::now(),
+            read: 0,
+        }
+    }
+
+    /// How long to pause after `bytes` more were read, `elapsed` after starting.
+    fn pause(&mut self, bytes: u64, elapsed: Duration) -> Duration {
+        self.read += bytes;
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let due = Duration::from_secs_f64(self.read as f64 / self.rate as f64);
+        due.saturating_sub(elapsed)
+    }
+
+    async fn consume(&mut self, bytes: u64) {
+        let pause = self.pause(bytes, self.started.elapsed());
+        if !pause.is_zero() {
+            tokio::time::sleep(pause).await;
+        }
+    }
+}
+
+/// What reading an object back turned up.
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    Intact,
+    /// The reason it doesn't match
+    Corrupt(String),
+    /// Left to `storage_reconcile`
+    Missing,
+}
+
+/// Read the object of `blob` back and compare it with the blob. Errors are for reads that
+/// failed without saying anything about the contents, like an unreachable backend.
+async fn check(
+    storage: &dyn Storage,
+    blob: &Blob,
+    throttle: &mut Throttle,
+) -> StorageResult<Verdict> {
+    let mut body = match compress::read(storage, blob, None).await {
+        Ok(object) => object.body,
+        Err(StorageError::NotFound(_)) => return Ok(Verdict::Missing),
+        Err(err) => return Err(err),
+    };
+
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            // Encrypted chunks that fail to decrypt, compressed ones that fail to decode, or
+            // either cut short
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                return Ok(Verdict::Corrupt(err.to_string()));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        size += chunk.len() as u64;
+        hasher.update(&chunk);
+        throttle.consume(chunk.len() as u64).await;
+    }
+
+    let sha256 = hex::encode(hasher.finalize());
+    if size != blob.size as u64 {
+        return Ok(Verdict::Corrupt(format!(
+            "{size} bytes stored instead of {}",
+            blob.size
+        )));
+    }
+    if sha256 != blob.sha256 {
+        return Ok(Verdict::Corrupt(format!(
+            "stored contents hash to {sha256}"
+        )));
+    }
+    Ok(Verdict::Intact)
+}
+
+/// Mark `blob` and every file and version with its contents corrupt. Returns false if the blob
+/// changed since it was read, the verdict doesn't apply to it then.
+async fn mark_corrupt(state: &AppState, blob: &Blob, reason: &str) -> Result<bool> {
+    let from = &[FileStatus::Pending, FileStatus::Clean, FileStatus::Error];
+    let mut tx = state.db().begin().await?;
+    if !Blob::mark_corrupt(&mut *tx, &blob.sha256, &blob.storage_key).await? {
+        return Ok(false);
+    }
+    let files =
+        File::set_status_by_content(&mut *tx, &blob.sha256, from, FileStatus::Corrupt).await?;
+    let versions =
+        FileVersion::set_status_by_content(&mut *tx, &blob.sha256, from, FileStatus::Corrupt)
+            .await?;
+    tx.commit().await?;
+
+    error!(
+        alert = true,
+        sha256 = blob.sha256,
+        key = blob.storage_key,
+        files,
+        versions,
+        reason,
+        "stored contents are corrupt, uploading them again repairs them"
+    );
+    Ok(true)
+}
+
+/// Periodic job verifying the stored contents due for it, up to [`ScrubConfig::max_bytes`].
+pub(crate) async fn scrub(state: AppStateRef) -> Result<()> {
+    let config: &ScrubConfig = &CONFIG.scrub;
+    let before = time::OffsetDateTime::now_utc() - Duration::from_secs(config.reverify_after);
+    let mut throttle = Throttle::new(config.rate);
+    counter!("storage_scrub_runs_total").increment(1);
+
+    // Blobs that stay due because they were skipped, so they aren't loaded again
+    let mut skipped: Vec<String> = vec![];
+    let (mut verified, mut corrupt) = (0u64, 0u64);
+    'run: loop {
+        let blobs = Blob::due_for_scrub(state.db(), before, &skipped, BATCH).await?;
+        if blobs.is_empty() {
+            break;
+        }
+        for blob in blobs {
+            if throttle.read >= config.max_bytes {
+                break 'run;
+            }
+            let read = throttle.read;
+            let verdict = check(state.storage(), &blob, &mut throttle).await;
+            counter!("storage_scrub_read_bytes_total").increment(throttle.read - read);
+            match verdict? {
+                Verdict::Intact => {
+                    Blob::verified(state.db(), &blob.sha256).await?;
+                    counter!("storage_scrub_verified_total").increment(1);
+                    verified += 1;
+                }
+                Verdict::Corrupt(reason) => {
+                    if mark_corrupt(&state, &blob, &reason).await? {
+                        counter!("storage_scrub_corrupt_total").increment(1);
+                        corrupt += 1;
+                    } else {
+                        skipped.push(blob.sha256);
+                    }
+                }
+                Verdict::Missing => {
+                    warn!(
+                        sha256 = blob.sha256,
+                        key = blob.storage_key,
+                        "record without an object"
+                    );
+                    counter!("storage_scrub_missing_total").increment(1);
+                    skipped.push(blob.sha256);
+                }
+            }
+        }
+    }
+
+    let total = Blob::count_corrupt(state.db()).await?;
+    gauge!("storage_scrub_corrupt_blobs").set(total as f64);
+    info!(
+        verified,
+        corrupt,
+        read = throttle.read,
+        total,
+        "scrubbed stored contents"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{LocalStorage, PutOptions};
+    use bytes::Bytes;
+    use futures::stream;
+
+    fn blob(data: &[u8], storage_key: &str) -> Blob {
+        Blob {
+            sha256: hex::encode(Sha256::digest(data)),
+            storage_key: storage_key.to_string(),
+            size: data.len() as i64,
+            encoding: None,
+            ref_count: 1,
+            created: time::OffsetDateTime::UNIX_EPOCH,
+            scan_attempts: 0,
+            scan_after: None,
+            verified: None,
+            corrupt: None,
+        }
+    }
+
+    #[test]
+    fn throttle_keeps_to_the_rate() {
+        let mut throttle = Throttle::new(1000);
+        assert_eq!(
+            throttle.pause(500, Duration::ZERO),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            throttle.pause(500, Duration::from_millis(400)),
+            Duration::from_millis(600)
+        );
+        // Slow reads count towards the rate
+        assert_eq!(throttle.pause(1000, Duration::from_secs(5)), Duration::ZERO);
+
+        let mut unlimited = Throttle::new(0);
+        assert_eq!(unlimited.pause(1 << 30, Duration::ZERO), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn finds_damaged_contents() {
+        let root = std::env::temp_dir().join(format!("scrub-test-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::new(root).await.unwrap();
+        for (key, data) in [
+            ("blobs/intact", &b"intact"[..]),
+            ("blobs/flipped", b"flipped"),
+        ] {
+            let body = stream::once(async { Ok(Bytes::from_static(data)) }).boxed();
+            storage.put(key, body, PutOptions::default()).await.unwrap();
+        }
+        let mut throttle = Throttle::new(0);
+
+        let intact = blob(b"intact", "blobs/intact");
+        let verdict = check(&storage, &intact, &mut throttle).await.unwrap();
+        assert_eq!(verdict, Verdict::Intact);
+
+        let flipped = blob(b"flippee", "blobs/flipped");
+        let verdict = check(&storage, &flipped, &mut throttle).await.unwrap();
+        assert!(matches!(verdict, Verdict::Corrupt(_)));
+
+        let truncated = blob(b"intact!", "blobs/intact");
+        let verdict = check(&storage, &truncated, &mut throttle).await.unwrap();
+        assert!(matches!(verdict, Verdict::Corrupt(_)));
+
+        let missing = blob(b"missing", "blobs/missing");
+        let verdict = check(&storage, &missing, &mut throttle).await.unwrap();
+        assert_eq!(verdict, Verdict::Missing);
+    }
+}
@@ -0,0 +1,484 @@
+//! Serves stored files over HTTP with support for ranges and conditional requests.
+use crate::compress;
+use crate::prelude::*;
+use axum::body::Body;
+use axum::http::{HeaderMap, HeaderValue, header};
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt, stream};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use std::ops::Range;
+use std::time::{Duration, SystemTime};
+
+/// More ranges than this in one request are ignored and the whole file is sent instead.
+const MAX_RANGES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Disposition {
+    /// Let the browser display the file
+    Inline,
+    /// Ask the browser to save the file
+    Attachment,
+}
+
+/// Only contents the scanner found clean are ever handed out, and only until the file expires.
+pub(crate) fn ensure_servable(file: &models::file::File) -> Result<()> {
+    if file.is_expired() {
+        return Err(AppError::Expired);
+    }
+    match file.status {
+        models::file::FileStatus::Clean => Ok(()),
+        status => Err(AppError::NotServable(status)),
+    }
+}
+
+/// Respond with the contents of `file`, honoring `Range`, `If-Range`, `If-None-Match` and
+/// `If-Modified-Since` from `request_headers`.
+///
+/// Compressed contents are sent as they are stored to clients that accept their encoding, unless
+/// they ask for ranges. Ranges are always of the contents as they were uploaded, all ranges of
+/// one request are cut from a single pass decoding them.
+pub(crate) async fn serve_file(
+    state: &AppState,
+    file: &models::file::File,
+    request_headers: &HeaderMap,
+    disposition: Disposition,
+) -> Result<Response> {
+    ensure_servable(file)?;
+    let Some(blob) = models::blob::Blob::get(state.db(), &file.sha256).await? else {
+        error!(id = %file.id, sha256 = file.sha256, "file points to a missing blob");
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let size = file.size as u64;
+    let etag = format!("\"{}\"", file.sha256);
+    let last_modified = truncate_to_secs(SystemTime::from(file.uploaded));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, header_value(&etag));
+    headers.insert(
+        header::LAST_MODIFIED,
+        header_value(&httpdate::fmt_http_date(last_modified)),
+    );
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("private"));
+    if blob.encoding.is_some() {
+        headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
+    if not_modified(request_headers, &etag, last_modified) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        header_value(&content_disposition(disposition, &file.name)),
+    );
+    // User content is served from our own origin, keep browsers from running any of it
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static("sandbox"),
+    );
+
+    let ranges = match request_headers.get(header::RANGE) {
+        Some(range) if if_range_matches(request_headers, &etag, last_modified) => range
+            .to_str()
+            .ok()
+            .and_then(|range| parse_ranges(range, size)),
+        _ => None,
+    };
+
+    match ranges {
+        None => {
+            headers.insert(header::CONTENT_TYPE, header_value(file.content_type()));
+            let passed_through = blob
+                .encoding
+                .filter(|encoding| accepts_encoding(request_headers, encoding.as_str()));
+            let object = match passed_through {
+                Some(encoding) => {
+                    let object = state.storage().get(&blob.storage_key, None).await?;
+                    headers.insert(header::CONTENT_ENCODING, header_value(encoding.as_str()));
+                    headers.insert(header::CONTENT_LENGTH, object.meta.size.into());
+                    // Another representation of the same contents
+                    headers.insert(header::ETAG, header_value(&format!("W/{etag}")));
+                    object
+                }
+                None => {
+                    headers.insert(header::CONTENT_LENGTH, size.into());
+                    compress::read(state.storage(), &blob, None).await?
+                }
+            };
+
+            Ok((StatusCode::OK, headers, Body::from_stream(object.body)).into_response())
+        }
+        Some(ranges) if ranges.is_empty() => {
+            headers.insert(
+                header::CONTENT_RANGE,
+                header_value(&format!("bytes */{size}")),
+            );
+            Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response())
+        }
+        Some(ranges) if ranges.len() == 1 => {
+            let object = compress::read(state.storage(), &blob, Some(ranges[0].clone())).await?;
+            // What storage actually returned, clamped to the object
+            let range = object.range;
+            headers.insert(header::CONTENT_TYPE, header_value(file.content_type()));
+            headers.insert(header::CONTENT_LENGTH, (range.end - range.start).into());
+            headers.insert(
+                header::CONTENT_RANGE,
+                header_value(&format!("bytes {}-{}/{size}", range.start, range.end - 1)),
+            );
+
+            Ok((
+                StatusCode::PARTIAL_CONTENT,
+                headers,
+                Body::from_stream(object.body),
+            )
+                .into_response())
+        }
+        Some(ranges) => {
+            let boundary = uuid::Uuid::new_v4().simple().to_string();
+            let part_headers: Vec<Bytes> = ranges
+                .iter()
+                .map(|range| {
+                    Bytes::from(format!(
+                        "\r\n--{boundary}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{size}\r\n\r\n",
+                        file.content_type(),
+                        range.start,
+                        range.end - 1
+                    ))
+                })
+                .collect();
+            let closing = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+
+            let length = part_headers.iter().map(|h| h.len() as u64).sum::<u64>()
+                + ranges.iter().map(|r| r.end - r.start).sum::<u64>()
+                + closing.len() as u64;
+
+            let parts = match blob.encoding {
+                // Each part is a range read of its own
+                None => {
+                    let storage = state.storage_ref();
+                    stream::iter(part_headers.into_iter().zip(ranges))
+                        .then(move |(part_header, range)| {
+                            let storage = storage.clone();
+                            let blob = blob.clone();
+                            async move {
+                                let object = compress::read(&*storage, &blob, Some(range))
+                                    .await
+                                    .map_err(std::io::Error::other)?;
+                                Ok::<_, std::io::Error>(
+                                    stream::once(async { Ok(part_header) }).chain(object.body),
+                                )
+                            }
+                        })
+                        .try_flatten()
+                        .boxed()
+                }
+                // Decoding always starts at the beginning, so all parts are cut from one pass
+                Some(_) => {
+                    let span = ranges[0].start..ranges[ranges.len() - 1].end;
+                    let object = compress::read(state.storage(), &blob, Some(span)).await?;
+                    let mut next = 0;
+                    compress::split(object.body, object.range.start, ranges)
+                        .map_ok(move |(index, chunk)| {
+                            // The headers of the parts starting here go first
+                            let started = part_headers[next..=index].to_vec();
+                            next = index + 1;
+                            stream::iter(started.into_iter().chain([chunk]).map(Ok))
+                        })
+                        .try_flatten()
+                        .boxed()
+                }
+            }
+            .chain(stream::once(async { Ok(closing) }));
+
+            headers.insert(
+                header::CONTENT_TYPE,
+                header_value(&format!("multipart/byteranges; boundary={boundary}")),
+            );
+            headers.insert(header::CONTENT_LENGTH, length.into());
+
+            Ok((
+                StatusCode::PARTIAL_CONTENT,
+                headers,
+                Body::from_stream(parts),
+            )
+                .into_response())
+        }
+    }
+}
+
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+/// HTTP dates only have second precision
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Compare entity tags, ignoring the weak marker.
+fn etag_matches(candidate: &str, etag: &str) -> bool {
+    let candidate = candidate.trim();
+    candidate == "*" || candidate.trim_start_matches("W/") == etag
+}
+
+fn not_modified(headers: &HeaderMap, etag: &str, last_modified: SystemTime) -> bool {
+    // If-Modified-Since is ignored when If-None-Match is present
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        return if_none_match
+            .to_str()
+            .is_ok_and(|value| value.split(',').any(|tag| etag_matches(tag, etag)));
+    }
+
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+        .is_some_and(|since| last_modified <= since)
+}
+
+/// Whether `Accept-Encoding` lets the response be encoded with `encoding`.
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    let Some(accepted) = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    accepted.split(',').any(|coding| {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let refused = params.any(|param| {
+            param
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        name.eq_ignore_ascii_case(encoding) && !refused
+    })
+}
+
+/// A `Range` only applies when `If-Range` is missing or still matches the file.
+fn if_range_matches(headers: &HeaderMap, etag: &str, last_modified: SystemTime) -> bool {
+    let Some(if_range) = headers
+        .get(header::IF_RANGE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return true;
+    };
+
+    if if_range.starts_with('"') {
+        // Strong comparison only
+        if_range == etag
+    } else {
+        httpdate::parse_http_date(if_range).is_ok_and(|date| date == last_modified)
+    }
+}
+
+/// Parse a `Range` header for a file of `size` bytes.
+///
+/// Returns `None` when the header should be ignored and an empty list when none of the
+/// ranges can be satisfied. Overlapping and adjacent ranges are merged and sorted, so a
+/// response never holds more bytes than the file.
+fn parse_ranges(header: &str, size: u64) -> Option<Vec<Range<u64>>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+
+    let mut ranges = vec![];
+    for spec in specs.split(',') {
+        let (start, end) = spec.trim().split_once('-')?;
+        let range = match (start.trim(), end.trim()) {
+            ("", "") => return None,
+            // Suffix range, the last `n` bytes
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                size.saturating_sub(suffix)..size
+            }
+            (start, "") => start.parse().ok()?..size,
+            (start, end) => {
+                let start: u64 = start.parse().ok()?;
+                let end: u64 = end.parse().ok()?;
+                if end < start {
+                    return None;
+                }
+                start..end.saturating_add(1).min(size)
+            }
+        };
+
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+
+    Some(merge_ranges(ranges))
+}
+
+fn merge_ranges(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_unstable_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Builds a `Content-Disposition` with an ascii fallback and the utf-8 name per RFC 6266.
+pub(crate) fn content_disposition(disposition: Disposition, name: &str) -> String {
+    let kind = match disposition {
+        Disposition::Inline => "inline",
+        Disposition::Attachment => "attachment",
+    };
+    let fallback: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let encoded = utf8_percent_encode(name, NON_ALPHANUMERIC);
+
+    format!("{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+#[cfg(test)]
+// Expected ranges are spelled as lists of ranges on purpose
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_ranges("bytes=0-99", 1000), Some(vec![0..100]));
+        assert_eq!(parse_ranges("bytes=900-2000", 1000), Some(vec![900..1000]));
+        // Open-ended
+        assert_eq!(parse_ranges("bytes=500-", 1000), Some(vec![500..1000]));
+        // Suffix, longer than the file too
+        assert_eq!(parse_ranges("bytes=-100", 1000), Some(vec![900..1000]));
+        assert_eq!(parse_ranges("bytes=-5000", 1000), Some(vec![0..1000]));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_empty() {
+        assert_eq!(parse_ranges("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=1000-1100", 1000), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=0-", 0), Some(vec![]));
+        // Satisfiable ones are still served
+        assert_eq!(
+            parse_ranges("bytes=2000-3000, 0-9", 1000),
+            Some(vec![0..10])
+        );
+    }
+
+    #[test]
+    fn invalid_ranges_are_ignored() {
+        assert_eq!(parse_ranges("items=0-10", 1000), None);
+        assert_eq!(parse_ranges("bytes=-", 1000), None);
+        assert_eq!(parse_ranges("bytes=10-5", 1000), None);
+        assert_eq!(parse_ranges("bytes=a-5", 1000), None);
+        assert_eq!(parse_ranges("bytes=5", 1000), None);
+
+        let many = (0..=MAX_RANGES)
+            .map(|i| format!("{}-{}", i * 10, i * 10))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(parse_ranges(&format!("bytes={many}"), 1000), None);
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_ranges() {
+        assert_eq!(parse_ranges("bytes=0-99,50-149", 1000), Some(vec![0..150]));
+        assert_eq!(parse_ranges("bytes=0-99,100-199", 1000), Some(vec![0..200]));
+        assert_eq!(
+            parse_ranges("bytes=500-599,0-9,-100", 1000),
+            Some(vec![0..10, 500..600, 900..1000])
+        );
+        // The same range many times never adds up to more than the file
+        let repeated = vec!["0-"; MAX_RANGES].join(",");
+        assert_eq!(
+            parse_ranges(&format!("bytes={repeated}"), 1000),
+            Some(vec![0..1000])
+        );
+    }
+
+    #[test]
+    fn accepted_encodings() {
+        let accepts =
+            |value: &str| accepts_encoding(&headers(&[(header::ACCEPT_ENCODING, value)]), "zstd");
+        assert!(accepts("gzip, deflate, br, zstd"));
+        assert!(accepts("ZSTD;q=0.5"));
+        assert!(!accepts("gzip, br"));
+        assert!(!accepts("zstd;q=0, gzip"));
+        // Anything else than asking for it by name gets the contents as they were uploaded
+        assert!(!accepts("*"));
+        assert!(!accepts_encoding(&HeaderMap::new(), "zstd"));
+    }
+
+    #[test]
+    fn if_range() {
+        let etag = "\"abc\"";
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let date = httpdate::fmt_http_date(modified);
+        let later = httpdate::fmt_http_date(modified + Duration::from_secs(1));
+
+        assert!(if_range_matches(&HeaderMap::new(), etag, modified));
+        assert!(if_range_matches(
+            &headers(&[(header::IF_RANGE, etag)]),
+            etag,
+            modified
+        ));
+        assert!(!if_range_matches(
+            &headers(&[(header::IF_RANGE, "\"other\"")]),
+            etag,
+            modified
+        ));
+        // Weak tags never match
+        assert!(!if_range_matches(
+            &headers(&[(header::IF_RANGE, "W/\"abc\"")]),
+            etag,
+            modified
+        ));
+        assert!(if_range_matches(
+            &headers(&[(header::IF_RANGE, &date)]),
+            etag,
+            modified
+        ));
+        assert!(!if_range_matches(
+            &headers(&[(header::IF_RANGE, &later)]),
+            etag,
+            modified
+        ));
+        assert!(!if_range_matches(
+            &headers(&[(header::IF_RANGE, "yesterday")]),
+            etag,
+            modified
+        ));
+    }
+}
@@ -1,19 +1,35 @@
 use crate::prelude::*;
+use crate::progress::Hub;
+use crate::scan::{Scanner, ScannerRef};
+use crate::storage::{Storage, StorageRef};
 use fred::prelude::{Client, Pool};
 use minijinja::{Environment, Value};
 use sqlx::PgPool;
 use std::sync::Arc;
+use tokio::sync::Notify;
 use unknown_actor_lib::pool::ActorPoolRef;
 
 pub struct AppState {
     pg_pool: PgPool,
     fred_pool: Pool,
     actor_pool: ActorPoolRef,
+    storage: StorageRef,
+    scanner: Option<ScannerRef>,
+    /// Wakes the scan worker up when new uploads are recorded
+    scan_notify: Notify,
+    /// Upload progress published by any instance
+    progress: Hub,
     jinja_env: Environment<'static>,
 }
 
 impl AppState {
-    pub(crate) fn new(pg_pool: PgPool, fred_pool: Pool, actor_pool: ActorPoolRef) -> Self {
+    pub(crate) fn new(
+        pg_pool: PgPool,
+        fred_pool: Pool,
+        actor_pool: ActorPoolRef,
+        storage: StorageRef,
+        scanner: Option<ScannerRef>,
+    ) -> Self {
         let mut jinja_env = Environment::new();
         minijinja_embed::load_templates!(&mut jinja_env);
 
@@ -21,6 +37,10 @@ impl AppState {
             pg_pool,
             fred_pool,
             actor_pool,
+            storage,
+            scanner,
+            scan_notify: Notify::new(),
+            progress: Hub::new(),
             jinja_env,
         }
     }
@@ -29,16 +49,42 @@ impl AppState {
         &self.pg_pool
     }
 
-    #[allow(unused)]
     pub fn fred(&self) -> &Client {
         self.fred_pool.next()
     }
 
-    #[allow(unused)]
     pub fn actor(&self) -> ActorPoolRef {
         self.actor_pool.clone()
     }
 
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    /// An owned handle to storage, for bodies that outlive the request handler.
+    pub fn storage_ref(&self) -> StorageRef {
+        self.storage.clone()
+    }
+
+    /// The malware scanner, `None` when scanning is disabled.
+    pub fn scanner(&self) -> Option<&dyn Scanner> {
+        self.scanner.as_deref()
+    }
+
+    /// Let the scan worker know there are new uploads to scan.
+    pub(crate) fn request_scan(&self) {
+        self.scan_notify.notify_one();
+    }
+
+    /// Resolves once [`Self::request_scan`] was called.
+    pub(crate) async fn scan_requested(&self) {
+        self.scan_notify.notified().await
+    }
+
+    pub(crate) fn progress(&self) -> &Hub {
+        &self.progress
+    }
+
     pub fn render_template(&self, name: &str, ctx: Option<Value>) -> Result<String> {
         let template = self.jinja_env.get_template(name)?;
         let context = ctx.unwrap_or_default();
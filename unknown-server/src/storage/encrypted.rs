@@ -0,0 +1,617 @@
+//! Envelope encryption on top of another backend.
+//!
+//! Every object gets a random data key of its own and is sealed with AES-256-GCM in chunks of
+//! [`CHUNK_SIZE`], so ranges can be read without decrypting the whole object. Chunk nonces are
+//! the chunk index plus a flag marking the final chunk, which keeps chunks from being reordered
+//! or an object from being cut short unnoticed. Data keys are wrapped with a master key from
+//! the [`Keyring`] and kept in `object_keys`, so rotating the master key only rewrites those
+//! rows, never the objects.
+//!
+//! Objects without a data key are refused rather than handed out as they are, since a missing
+//! row is just as likely a lost one as plain text written before encryption was turned on.
+//! Listing still names them, so sweeps can find them. Presigning is not supported, clients
+//! would get to see or write ciphertext.
+use super::{
+    ByteStream, GetObject, ObjectMeta, PutOptions, Storage, StorageError, StorageRef,
+    StorageResult, clamp_range,
+};
+use crate::config::EncryptionConfig;
+use crate::models::object_key::{ObjectKey, ObjectKeyInsert};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use async_trait::async_trait;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use rand::RngCore;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Plain text bytes per chunk.
+const CHUNK_SIZE: u64 = 64 * 1024;
+const TAG_SIZE: u64 = 16;
+const NONCE_SIZE: usize = 12;
+/// Stored bytes per full chunk.
+const SEALED_CHUNK_SIZE: u64 = CHUNK_SIZE + TAG_SIZE;
+
+/// Data keys re-wrapped per query when rotating.
+const REWRAP_BATCH: i64 = 256;
+
+fn encryption_error(err: impl std::fmt::Display) -> StorageError {
+    StorageError::Encryption(err.to_string())
+}
+
+/// Chunks in an object of `size` plain text bytes. Empty objects still have one, so even they
+/// are authenticated.
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE).max(1)
+}
+
+/// Stored size of an object of `size` plain text bytes.
+fn sealed_size(size: u64) -> u64 {
+    size + chunk_count(size) * TAG_SIZE
+}
+
+/// Plain text size of an object stored in `sealed` bytes.
+fn plain_size(sealed: u64) -> u64 {
+    let full = sealed / SEALED_CHUNK_SIZE;
+    let rest = sealed % SEALED_CHUNK_SIZE;
+    full * CHUNK_SIZE + rest.saturating_sub(TAG_SIZE)
+}
+
+/// Where reading `range` of an object of `size` plain text bytes starts. Returns the stored
+/// bytes holding it, the index of their first chunk and how many plain text bytes of that chunk
+/// come before the range.
+fn sealed_range(range: &Range<u64>, size: u64) -> (Range<u64>, u64, u64) {
+    let first = range.start / CHUNK_SIZE;
+    let last = (range.end.saturating_sub(1) / CHUNK_SIZE).max(first);
+    let sealed = first * SEALED_CHUNK_SIZE..((last + 1) * SEALED_CHUNK_SIZE).min(sealed_size(size));
+    (sealed, first, range.start - first * CHUNK_SIZE)
+}
+
+fn chunk_nonce(index: u64, last: bool) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_SIZE - 1] = last as u8;
+    nonce.into()
+}
+
+/// The master keys configured with `encryption`.
+pub(crate) struct Keyring {
+    current: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl Keyring {
+    /// `None` when no keys are configured.
+    pub(crate) fn from_config(config: &EncryptionConfig) -> StorageResult<Option<Self>> {
+        if config.keys.is_empty() {
+            return Ok(None);
+        }
+
+        let mut keys = HashMap::new();
+        for (id, key) in &config.keys {
+            let key = BASE64_STANDARD
+                .decode(key.trim())
+                .map_err(|err| encryption_error(format!("master key `{id}`: {err}")))?;
+            if key.len() != 32 {
+                return Err(encryption_error(format!(
+                    "master key `{id}` has to be 32 bytes, not {}",
+                    key.len()
+                )));
+            }
+            keys.insert(
+                id.clone(),
+                Aes256Gcm::new_from_slice(&key).map_err(encryption_error)?,
+            );
+        }
+
+        let current = match &config.current {
+            Some(current) if keys.contains_key(current) => current.clone(),
+            Some(current) => {
+                return Err(encryption_error(format!(
+                    "current master key `{current}` is not configured"
+                )));
+            }
+            None if keys.len() == 1 => config.keys.keys().next().cloned().unwrap_or_default(),
+            None => {
+                return Err(encryption_error(
+                    "`encryption.current` has to be set with more than one key",
+                ));
+            }
+        };
+
+        Ok(Some(Self { current, keys }))
+    }
+
+    /// Seal `data_key` with the current master key. The storage key is authenticated along with
+    /// it, so wrapped keys can't be swapped between objects.
+    fn wrap(&self, storage_key: &str, data_key: &[u8]) -> StorageResult<(String, Vec<u8>)> {
+        let cipher = &self.keys[&self.current];
+        let mut nonce = [0; NONCE_SIZE];
+        rand::rng().fill_bytes(&mut nonce);
+        let sealed = cipher
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: data_key,
+                    aad: storage_key.as_bytes(),
+                },
+            )
+            .map_err(|_| encryption_error("failed to wrap data key"))?;
+
+        Ok((self.current.clone(), [nonce.as_slice(), &sealed].concat()))
+    }
+
+    /// Open a data key sealed by [`Self::wrap`] with any of the configured master keys.
+    fn unwrap(&self, storage_key: &str, key_id: &str, wrapped: &[u8]) -> StorageResult<Vec<u8>> {
+        let cipher = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| encryption_error(format!("master key `{key_id}` is not configured")))?;
+        if wrapped.len() < NONCE_SIZE {
+            return Err(encryption_error("wrapped data key is too short"));
+        }
+        let (nonce, sealed) = wrapped.split_at(NONCE_SIZE);
+        let nonce: [u8; NONCE_SIZE] = nonce.try_into().expect("split at the nonce size");
+        cipher
+            .decrypt(
+                &nonce.into(),
+                Payload {
+                    msg: sealed,
+                    aad: storage_key.as_bytes(),
+                },
+            )
+            .map_err(|_| encryption_error(format!("failed to unwrap data key of `{storage_key}`")))
+    }
+
+    /// The cipher for the object `object_key` belongs to.
+    fn cipher(&self, object_key: &ObjectKey) -> StorageResult<Aes256Gcm> {
+        let data_key = self.unwrap(
+            &object_key.storage_key,
+            &object_key.key_id,
+            &object_key.data_key,
+        )?;
+        Aes256Gcm::new_from_slice(&data_key).map_err(encryption_error)
+    }
+}
+
+/// Seal `body` chunk by chunk. A full chunk is held back until it is known whether more
+/// follows, since the final chunk is sealed differently.
+fn seal(cipher: Aes256Gcm, body: ByteStream<'_>) -> ByteStream<'_> {
+    struct State<'a> {
+        body: ByteStream<'a>,
+        buffer: BytesMut,
+        index: u64,
+        ended: bool,
+        finished: bool,
+    }
+
+    let state = State {
+        body,
+        buffer: BytesMut::new(),
+        index: 0,
+        ended: false,
+        finished: false,
+    };
+    stream::try_unfold((cipher, state), |(cipher, mut state)| async move {
+        loop {
+            if state.finished {
+                return Ok(None);
+            }
+            let full = state.buffer.len() as u64 > CHUNK_SIZE;
+            if full || state.ended {
+                let take = (state.buffer.len() as u64).min(CHUNK_SIZE) as usize;
+                let chunk = state.buffer.split_to(take);
+                let last = !full;
+                let sealed = cipher
+                    .encrypt(&chunk_nonce(state.index, last), chunk.as_ref())
+                    .map_err(|_| io::Error::other("failed to encrypt chunk"))?;
+                state.index += 1;
+                state.finished = last;
+                return Ok(Some((Bytes::from(sealed), (cipher, state))));
+            }
+
+            match state.body.try_next().await? {
+                Some(chunk) => state.buffer.extend_from_slice(&chunk),
+                None => state.ended = true,
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Open the sealed chunks in `body`, starting with chunk `first` of an object of `size` plain
+/// text bytes. The first `skip` bytes are dropped and output ends after `length` bytes.
+fn open(
+    cipher: Aes256Gcm,
+    body: ByteStream<'static>,
+    size: u64,
+    first: u64,
+    skip: u64,
+    length: u64,
+) -> ByteStream<'static> {
+    struct State {
+        body: ByteStream<'static>,
+        buffer: BytesMut,
+        index: u64,
+        skip: u64,
+        remaining: u64,
+        ended: bool,
+    }
+
+    let state = State {
+        body,
+        buffer: BytesMut::new(),
+        index: first,
+        skip,
+        remaining: length,
+        ended: false,
+    };
+    let chunks = chunk_count(size);
+    stream::try_unfold((cipher, state), move |(cipher, mut state)| async move {
+        loop {
+            if state.remaining == 0 {
+                return Ok(None);
+            }
+            let buffered = state.buffer.len() as u64;
+            if buffered >= SEALED_CHUNK_SIZE || (state.ended && buffered > 0) {
+                let take = buffered.min(SEALED_CHUNK_SIZE) as usize;
+                let sealed = state.buffer.split_to(take);
+                let last = state.index + 1 == chunks;
+                let mut chunk = Bytes::from(
+                    cipher
+                        .decrypt(&chunk_nonce(state.index, last), sealed.as_ref())
+                        .map_err(|_| {
+                            io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt chunk")
+                        })?,
+                );
+                state.index += 1;
+
+                let skip = state.skip.min(chunk.len() as u64);
+                chunk.advance(skip as usize);
+                state.skip -= skip;
+                chunk.truncate(state.remaining.min(chunk.len() as u64) as usize);
+                state.remaining -= chunk.len() as u64;
+                if chunk.is_empty() {
+                    continue;
+                }
+                return Ok(Some((chunk, (cipher, state))));
+            }
+            if state.ended {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "encrypted object is cut short",
+                ));
+            }
+
+            match state.body.try_next().await? {
+                Some(chunk) => state.buffer.extend_from_slice(&chunk),
+                None => state.ended = true,
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Encrypts everything written to `inner` and decrypts it again on the way out.
+pub(crate) struct EncryptedStorage {
+    inner: StorageRef,
+    keyring: Keyring,
+    db: PgPool,
+}
+
+impl EncryptedStorage {
+    pub(crate) fn new(inner: StorageRef, keyring: Keyring, db: PgPool) -> Self {
+        Self { inner, keyring, db }
+    }
+
+    async fn object_key(&self, key: &str) -> StorageResult<ObjectKey> {
+        ObjectKey::get(&self.db, key)
+            .await
+            .map_err(encryption_error)?
+            .ok_or_else(|| encryption_error(format!("`{key}` has no data key")))
+    }
+}
+
+#[async_trait]
+impl Storage for EncryptedStorage {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        body: ByteStream<'_>,
+        opts: PutOptions,
+    ) -> StorageResult<ObjectMeta> {
+        let data_key = Aes256Gcm::generate_key(aes_gcm::aead::OsRng);
+        let (key_id, wrapped) = self.keyring.wrap(key, &data_key)?;
+        let cipher = Aes256Gcm::new(&data_key);
+
+        let mut meta = self.inner.put(key, seal(cipher, body), opts).await?;
+        meta.size = plain_size(meta.size);
+        let inserted = ObjectKeyInsert::new(key.to_string(), key_id, wrapped, meta.size as i64)
+            .insert(&self.db)
+            .await;
+        // Without its key the object is of no use to anyone
+        if let Err(err) = inserted {
+            if let Err(err) = self.inner.delete(key).await {
+                warn!(key, %err, "failed to remove object without a data key");
+            }
+            return Err(encryption_error(err));
+        }
+
+        Ok(meta)
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> StorageResult<GetObject> {
+        let object_key = self.object_key(key).await?;
+        let cipher = self.keyring.cipher(&object_key)?;
+
+        let size = object_key.size as u64;
+        let range = clamp_range(range, size);
+        let (sealed_range, first, skip) = sealed_range(&range, size);
+
+        let object = self.inner.get(key, Some(sealed_range)).await?;
+        let mut meta = object.meta;
+        meta.size = size;
+        let body = open(
+            cipher,
+            object.body,
+            size,
+            first,
+            skip,
+            range.end.saturating_sub(range.start),
+        );
+
+        Ok(GetObject { meta, range, body })
+    }
+
+    async fn head(&self, key: &str) -> StorageResult<ObjectMeta> {
+        let mut meta = self.inner.head(key).await?;
+        meta.size = self.object_key(key).await?.size as u64;
+        Ok(meta)
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        self.inner.delete(key).await?;
+        ObjectKey::delete(&self.db, key)
+            .await
+            .map_err(encryption_error)
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxStream<'a, StorageResult<ObjectMeta>> {
+        self.inner
+            .list(prefix)
+            .and_then(move |mut meta| async move {
+                // Objects without a key are listed with their stored size
+                if let Some(object_key) = ObjectKey::get(&self.db, &meta.key)
+                    .await
+                    .map_err(encryption_error)?
+                {
+                    meta.size = object_key.size as u64;
+                }
+                Ok(meta)
+            })
+            .boxed()
+    }
+
+    async fn cleanup_incomplete(&self, older_than: Duration) -> StorageResult<usize> {
+        self.inner.cleanup_incomplete(older_than).await
+    }
+
+    async fn rewrap_keys(&self) -> StorageResult<usize> {
+        let (mut rewrapped, mut skipped) = (0, 0);
+        let mut after = String::new();
+        loop {
+            let stale = ObjectKey::wrapped_with_other(
+                &self.db,
+                &self.keyring.current,
+                &after,
+                REWRAP_BATCH,
+            )
+            .await
+            .map_err(encryption_error)?;
+            let Some(last) = stale.last() else {
+                break;
+            };
+            after = last.storage_key.clone();
+
+            for object_key in stale {
+                let storage_key = &object_key.storage_key;
+                // A key that can't be unwrapped stays as it is, the rest still gets rotated
+                let wrapped = self
+                    .keyring
+                    .unwrap(storage_key, &object_key.key_id, &object_key.data_key)
+                    .and_then(|data_key| self.keyring.wrap(storage_key, &data_key));
+                let (key_id, wrapped) = match wrapped {
+                    Ok(wrapped) => wrapped,
+                    Err(err) => {
+                        warn!(storage_key, key_id = object_key.key_id, %err, "can't re-wrap data key");
+                        skipped += 1;
+                        continue;
+                    }
+                };
+
+                if ObjectKey::rewrap(
+                    &self.db,
+                    storage_key,
+                    &object_key.key_id,
+                    &object_key.data_key,
+                    &key_id,
+                    &wrapped,
+                )
+                .await
+                .map_err(encryption_error)?
+                {
+                    rewrapped += 1;
+                }
+            }
+        }
+
+        if rewrapped > 0 || skipped > 0 {
+            info!(
+                rewrapped,
+                skipped,
+                key_id = self.keyring.current,
+                "re-wrapped data keys"
+            );
+        }
+        Ok(rewrapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> Aes256Gcm {
+        Aes256Gcm::new(&Aes256Gcm::generate_key(aes_gcm::aead::OsRng))
+    }
+
+    fn plain(size: u64) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn collect(body: ByteStream<'_>) -> io::Result<Vec<u8>> {
+        body.try_fold(vec![], |mut all, chunk| async move {
+            all.extend_from_slice(&chunk);
+            Ok(all)
+        })
+        .await
+    }
+
+    /// Seal `plain` in uneven pieces, the way bodies come in.
+    async fn sealed(cipher: &Aes256Gcm, plain: &[u8]) -> Vec<u8> {
+        let pieces: Vec<io::Result<Bytes>> = plain
+            .chunks(10_000)
+            .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+            .collect();
+        collect(seal(cipher.clone(), stream::iter(pieces).boxed()))
+            .await
+            .unwrap()
+    }
+
+    /// Read `range` like [`EncryptedStorage::get`] does.
+    async fn read(cipher: &Aes256Gcm, sealed: &[u8], size: u64, range: Range<u64>) -> Vec<u8> {
+        let range = clamp_range(Some(range), size);
+        let (stored, first, skip) = sealed_range(&range, size);
+        let body = Bytes::copy_from_slice(&sealed[stored.start as usize..stored.end as usize]);
+        let body = open(
+            cipher.clone(),
+            stream::once(async { Ok(body) }).boxed(),
+            size,
+            first,
+            skip,
+            range.end - range.start,
+        );
+        collect(body).await.unwrap()
+    }
+
+    #[test]
+    fn sizes() {
+        for size in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE,
+            3 * CHUNK_SIZE + 17,
+        ] {
+            assert_eq!(plain_size(sealed_size(size)), size, "size {size}");
+        }
+        assert_eq!(sealed_size(0), TAG_SIZE);
+        assert_eq!(sealed_size(CHUNK_SIZE), SEALED_CHUNK_SIZE);
+        assert_eq!(
+            sealed_size(CHUNK_SIZE + 1),
+            SEALED_CHUNK_SIZE + 1 + TAG_SIZE
+        );
+    }
+
+    #[tokio::test]
+    async fn seals_to_the_expected_size() {
+        let cipher = cipher();
+        for size in [0, 5, CHUNK_SIZE, 2 * CHUNK_SIZE + 3] {
+            let sealed = sealed(&cipher, &plain(size)).await;
+            assert_eq!(sealed.len() as u64, sealed_size(size), "size {size}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_ranges_around_chunk_edges() {
+        let cipher = cipher();
+        let size = 3 * CHUNK_SIZE + 100;
+        let plain = plain(size);
+        let sealed = sealed(&cipher, &plain).await;
+
+        let c = CHUNK_SIZE;
+        let ranges = [
+            0..size,
+            0..1,
+            0..c,
+            c - 1..c,
+            c - 1..c + 1,
+            c..c + 1,
+            c..2 * c,
+            c + 1..2 * c - 1,
+            c - 1..2 * c + 1,
+            3 * c..size,
+            3 * c - 1..size,
+            size - 1..size,
+            // Past the end is clamped
+            size - 10..size + 50,
+        ];
+        for range in ranges {
+            let expected = &plain[range.start as usize..range.end.min(size) as usize];
+            assert_eq!(
+                read(&cipher, &sealed, size, range.clone()).await,
+                expected,
+                "range {range:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_objects_of_exactly_one_chunk() {
+        let cipher = cipher();
+        let plain = plain(CHUNK_SIZE);
+        let sealed = sealed(&cipher, &plain).await;
+
+        assert_eq!(
+            read(&cipher, &sealed, CHUNK_SIZE, 0..CHUNK_SIZE).await,
+            plain
+        );
+        assert_eq!(
+            read(&cipher, &sealed, CHUNK_SIZE, CHUNK_SIZE - 1..CHUNK_SIZE).await,
+            &plain[CHUNK_SIZE as usize - 1..]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_objects() {
+        let cipher = cipher();
+        let size = 2 * CHUNK_SIZE + 1;
+        let sealed = sealed(&cipher, &plain(size)).await;
+
+        // Dropping the final chunk must not pass for a complete object
+        let cut = Bytes::copy_from_slice(&sealed[..2 * SEALED_CHUNK_SIZE as usize]);
+        let body = open(
+            cipher.clone(),
+            stream::once(async { Ok(cut) }).boxed(),
+            2 * CHUNK_SIZE,
+            0,
+            0,
+            2 * CHUNK_SIZE,
+        );
+        assert!(collect(body).await.is_err());
+    }
+}
@@ -0,0 +1,351 @@
+use super::{
+    ByteStream, GetObject, ObjectMeta, PutOptions, Storage, StorageError, StorageResult,
+    clamp_range, validate_key,
+};
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+/// Directory used for partially written objects. Objects are renamed into place once complete.
+const TMP_DIR: &str = ".tmp";
+
+/// Stores objects as plain files below a root directory. Meant for development and tests.
+pub(crate) struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub(crate) async fn new(root: impl AsRef<Path>) -> StorageResult<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join(TMP_DIR)).await?;
+
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> StorageResult<PathBuf> {
+        validate_key(key)?;
+        if key == TMP_DIR || key.starts_with(&format!("{TMP_DIR}/")) {
+            return Err(StorageError::InvalidKey(key.to_string()));
+        }
+
+        Ok(self.root.join(key))
+    }
+
+    fn meta(key: &str, metadata: &std::fs::Metadata) -> ObjectMeta {
+        ObjectMeta {
+            key: key.to_string(),
+            size: metadata.len(),
+        }
+    }
+}
+
+fn not_found(key: &str, err: io::Error) -> StorageError {
+    match err.kind() {
+        io::ErrorKind::NotFound => StorageError::NotFound(key.to_string()),
+        _ => err.into(),
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        mut body: ByteStream<'_>,
+        _opts: PutOptions,
+    ) -> StorageResult<ObjectMeta> {
+        let path = self.path(key)?;
+        let tmp_path = self
+            .root
+            .join(TMP_DIR)
+            .join(uuid::Uuid::new_v4().to_string());
+
+        let written = async {
+            let mut file = fs::File::create(&tmp_path).await?;
+            while let Some(chunk) = body.try_next().await? {
+                file.write_all(&chunk).await?;
+            }
+            file.sync_all().await?;
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::rename(&tmp_path, &path).await
+        }
+        .await;
+
+        if let Err(err) = written {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(err.into());
+        }
+
+        self.head(key).await
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> StorageResult<GetObject> {
+        let path = self.path(key)?;
+        let mut file = fs::File::open(&path)
+            .await
+            .map_err(|err| not_found(key, err))?;
+        let meta = Self::meta(key, &file.metadata().await?);
+
+        let range = clamp_range(range, meta.size);
+        file.seek(SeekFrom::Start(range.start)).await?;
+        let body = ReaderStream::new(file.take(range.end - range.start)).boxed();
+
+        Ok(GetObject { meta, range, body })
+    }
+
+    async fn head(&self, key: &str) -> StorageResult<ObjectMeta> {
+        let path = self.path(key)?;
+        let metadata = fs::metadata(&path)
+            .await
+            .map_err(|err| not_found(key, err))?;
+        if !metadata.is_file() {
+            return Err(StorageError::NotFound(key.to_string()));
+        }
+
+        Ok(Self::meta(key, &metadata))
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        let path = self.path(key)?;
+        match fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> StorageResult<()> {
+        let (from_path, to_path) = (self.path(from)?, self.path(to)?);
+        if let Some(parent) = to_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&from_path, &to_path)
+            .await
+            .map_err(|err| not_found(from, err))
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxStream<'a, StorageResult<ObjectMeta>> {
+        // Walk the tree depth first, yielding files as they are found.
+        let state = (vec![self.root.clone()], Vec::<ObjectMeta>::new());
+
+        stream::try_unfold(state, move |(mut dirs, mut found)| async move {
+            loop {
+                if let Some(meta) = found.pop() {
+                    return Ok(Some((meta, (dirs, found))));
+                }
+                let Some(dir) = dirs.pop() else {
+                    return Ok(None);
+                };
+
+                let mut entries = match fs::read_dir(&dir).await {
+                    Ok(entries) => entries,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err.into()),
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    let path = entry.path();
+                    let Ok(relative) = path.strip_prefix(&self.root) else {
+                        continue;
+                    };
+                    let key = relative
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    if key == TMP_DIR {
+                        continue;
+                    }
+
+                    let metadata = entry.metadata().await?;
+                    if metadata.is_dir() {
+                        // Only descend into directories that can contain matching keys
+                        let dir_key = format!("{key}/");
+                        if dir_key.starts_with(prefix) || prefix.starts_with(&dir_key) {
+                            dirs.push(path);
+                        }
+                    } else if key.starts_with(prefix) {
+                        found.push(Self::meta(&key, &metadata));
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+
+    async fn cleanup_incomplete(&self, older_than: Duration) -> StorageResult<usize> {
+        let cutoff = SystemTime::now() - older_than;
+        let mut removed = 0;
+
+        let mut entries = fs::read_dir(self.root.join(TMP_DIR)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let modified = entry.metadata().await?.modified()?;
+            if modified < cutoff {
+                fs::remove_file(entry.path()).await?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    async fn local() -> LocalStorage {
+        let root = std::env::temp_dir().join(format!("local-test-{}", uuid::Uuid::new_v4()));
+        LocalStorage::new(root).await.unwrap()
+    }
+
+    fn body(data: &'static [u8]) -> ByteStream<'static> {
+        stream::iter(data.chunks(3).map(|chunk| Ok(Bytes::from_static(chunk)))).boxed()
+    }
+
+    async fn read(storage: &LocalStorage, key: &str, range: Option<Range<u64>>) -> Vec<u8> {
+        let object = storage.get(key, range).await.unwrap();
+        let chunks: Vec<Bytes> = object.body.try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    async fn keys(storage: &LocalStorage, prefix: &str) -> Vec<String> {
+        let mut keys: Vec<String> = storage
+            .list(prefix)
+            .map_ok(|meta| meta.key)
+            .try_collect()
+            .await
+            .unwrap();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn validates_keys() {
+        for key in ["blobs/a", "a", "a/b/c", "a.b/..c"] {
+            assert!(validate_key(key).is_ok(), "{key}");
+        }
+        for key in [
+            "", "/a", "/", "a/", "a//b", "..", "a/../b", "../a", "./a", "a/.",
+        ] {
+            assert!(
+                matches!(validate_key(key), Err(StorageError::InvalidKey(_))),
+                "{key}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_keys_outside_its_root() {
+        let storage = local().await;
+        for key in [
+            "../escape",
+            "/etc/passwd",
+            "blobs/../../escape",
+            ".tmp",
+            ".tmp/a",
+        ] {
+            assert!(matches!(
+                storage.put(key, body(b"data"), PutOptions::default()).await,
+                Err(StorageError::InvalidKey(_))
+            ));
+            assert!(matches!(
+                storage.head(key).await,
+                Err(StorageError::InvalidKey(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_objects() {
+        let storage = local().await;
+        let meta = storage
+            .put("blobs/a", body(b"hello world"), PutOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(meta.size, 11);
+        storage
+            .put("other/b", body(b"b"), PutOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(read(&storage, "blobs/a", None).await, b"hello world");
+        assert_eq!(read(&storage, "blobs/a", Some(6..11)).await, b"world");
+        assert_eq!(read(&storage, "blobs/a", Some(6..100)).await, b"world");
+        assert_eq!(storage.head("blobs/a").await.unwrap().size, 11);
+        // Directories and partial writes aren't objects
+        assert!(matches!(
+            storage.head("blobs").await,
+            Err(StorageError::NotFound(_))
+        ));
+        assert_eq!(keys(&storage, "").await, vec!["blobs/a", "other/b"]);
+        assert_eq!(keys(&storage, "blobs/").await, vec!["blobs/a"]);
+
+        storage.delete("blobs/a").await.unwrap();
+        storage.delete("blobs/a").await.unwrap();
+        assert!(matches!(
+            storage.get("blobs/a", None).await,
+            Err(StorageError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn leaves_nothing_of_failed_writes() {
+        let storage = local().await;
+        let failing = stream::iter([
+            Ok(Bytes::from_static(b"partial")),
+            Err(io::Error::other("client went away")),
+        ])
+        .boxed();
+        assert!(
+            storage
+                .put("blobs/a", failing, PutOptions::default())
+                .await
+                .is_err()
+        );
+
+        assert!(matches!(
+            storage.head("blobs/a").await,
+            Err(StorageError::NotFound(_))
+        ));
+        assert_eq!(storage.cleanup_incomplete(Duration::ZERO).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn renames_objects() {
+        let storage = local().await;
+        storage
+            .put("presign/a", body(b"contents"), PutOptions::default())
+            .await
+            .unwrap();
+
+        storage.rename("presign/a", "blobs/nested/a").await.unwrap();
+        assert_eq!(read(&storage, "blobs/nested/a", None).await, b"contents");
+        assert!(matches!(
+            storage.head("presign/a").await,
+            Err(StorageError::NotFound(_))
+        ));
+        assert!(matches!(
+            storage.rename("presign/a", "blobs/b").await,
+            Err(StorageError::NotFound(key)) if key == "presign/a"
+        ));
+        assert!(matches!(
+            storage.rename("blobs/nested/a", "../escape").await,
+            Err(StorageError::InvalidKey(_))
+        ));
+    }
+}
@@ -0,0 +1,497 @@
+//! Moving every object from one storage backend to another without downtime.
+//!
+//! While `STORAGE_TARGET` is configured the server runs on a [`MigratingStorage`], which writes
+//! new objects to the target and reads from the target first, falling back to the source. The
+//! `migrate-storage` command then copies every blob from the source to the target in sha256
+//! order, checking each copy, and keeps a checkpoint in `storage_migrations` to resume from.
+//! Once everything is copied it marks the migration finished, and running servers stop using
+//! the source. After that `STORAGE` can be pointed at the target and `STORAGE_TARGET` removed.
+//!
+//! Objects are copied as they are stored. Encrypted objects stay encrypted with the same data
+//! keys, since those are kept by storage key. Quarantined blobs are copied like any other, and
+//! so are the tus chunks, presigned uploads and S3 multipart parts of uploads in progress that
+//! are still on the source, so those can finish after the switch.
+use super::{
+    ByteStream, GetObject, ObjectMeta, PresignGetOptions, PresignPutOptions, PresignedRequest,
+    PutOptions, Storage, StorageError, StorageRef, StorageResult,
+};
+use crate::config::{CONFIG, StorageConfig};
+use crate::ingest::{self, BLOB_PREFIXES};
+use crate::models::blob::Blob;
+use crate::models::object_key::ObjectKey;
+use crate::models::storage_migration::StorageMigration;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt, future};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::collections::HashSet;
+use std::error::Error;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Prefixes of objects uploads in progress keep until they are recorded as blobs.
+const STAGING_PREFIXES: [&str; 3] = [
+    crate::routes::tus::CHUNK_PREFIX,
+    crate::routes::presign::STAGING_PREFIX,
+    crate::s3::multipart::PART_PREFIX,
+];
+
+/// How often servers check whether the migration they run on has finished.
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct MigrateArgs {
+    /// Copy everything again instead of resuming from the checkpoint
+    #[arg(long)]
+    restart: bool,
+    /// Copy and check everything, but leave the source the active backend
+    #[arg(long)]
+    no_flip: bool,
+    /// Blobs loaded per query
+    #[arg(long, default_value_t = 100)]
+    batch: i64,
+}
+
+/// The backend the server runs on while `target` is configured. Unless the migration from
+/// `source` finished already, that's a [`MigratingStorage`].
+pub(crate) async fn from_config(
+    source: &StorageConfig,
+    target: &StorageConfig,
+    db: &PgPool,
+) -> Result<StorageRef, Box<dyn Error>> {
+    let (source_name, target_name) = (source.describe(), target.describe());
+    let target = super::from_config(target).await?;
+    let migration = StorageMigration::get(db, &source_name, &target_name).await?;
+    if migration.is_some_and(|migration| migration.finished.is_some()) {
+        info!("Migration to {target_name} is finished, not using {source_name} anymore");
+        return Ok(target);
+    }
+
+    info!("Migrating storage from {source_name} to {target_name}");
+    let storage = MigratingStorage::new(super::from_config(source).await?, target);
+    tokio::spawn(watch(
+        db.clone(),
+        source_name,
+        target_name,
+        storage.finished.clone(),
+    ));
+    Ok(Arc::new(storage))
+}
+
+/// Switch to the target alone once the migration is marked finished.
+async fn watch(db: PgPool, source: String, target: String, finished: Arc<AtomicBool>) {
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        interval.tick().await;
+        match StorageMigration::get(&db, &source, &target).await {
+            Ok(Some(migration)) if migration.finished.is_some() => {
+                finished.store(true, Ordering::Release);
+                info!(
+                    source,
+                    target, "storage migration finished, switched to the target"
+                );
+                return;
+            }
+            Ok(_) => {}
+            Err(err) => warn!(%err, "failed to check the storage migration"),
+        }
+    }
+}
+
+/// Writes to the target and reads from the target first, falling back to the source until the
+/// migration finished.
+pub(crate) struct MigratingStorage {
+    source: StorageRef,
+    target: StorageRef,
+    finished: Arc<AtomicBool>,
+}
+
+impl MigratingStorage {
+    pub(crate) fn new(source: StorageRef, target: StorageRef) -> Self {
+        Self {
+            source,
+            target,
+            finished: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// The source while it may still hold objects the target doesn't.
+    fn source(&self) -> Option<&StorageRef> {
+        (!self.finished.load(Ordering::Acquire)).then_some(&self.source)
+    }
+}
+
+#[async_trait]
+impl Storage for MigratingStorage {
+    fn name(&self) -> &'static str {
+        "migrating"
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        body: ByteStream<'_>,
+        opts: PutOptions,
+    ) -> StorageResult<ObjectMeta> {
+        self.target.put(key, body, opts).await
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> StorageResult<GetObject> {
+        let result = self.target.get(key, range.clone()).await;
+        match (result, self.source()) {
+            (Err(StorageError::NotFound(_)), Some(source)) => source.get(key, range).await,
+            (result, _) => result,
+        }
+    }
+
+    async fn head(&self, key: &str) -> StorageResult<ObjectMeta> {
+        let result = self.target.head(key).await;
+        match (result, self.source()) {
+            (Err(StorageError::NotFound(_)), Some(source)) => source.head(key).await,
+            (result, _) => result,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        self.target.delete(key).await?;
+        if let Some(source) = self.source() {
+            source.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxStream<'a, StorageResult<ObjectMeta>> {
+        let Some(source) = self.source() else {
+            return self.target.list(prefix);
+        };
+        // Copied objects are in both, name them once
+        let mut seen = HashSet::new();
+        self.target
+            .list(prefix)
+            .chain(source.list(prefix))
+            .try_filter(move |object| future::ready(seen.insert(object.key.clone())))
+            .boxed()
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> StorageResult<()> {
+        let result = self.target.rename(from, to).await;
+        match (result, self.source()) {
+            (Err(StorageError::NotFound(_)), Some(source)) => source.rename(from, to).await,
+            (result, _) => result,
+        }
+    }
+
+    async fn presign_put(
+        &self,
+        key: &str,
+        expires_in: Duration,
+        opts: PresignPutOptions,
+    ) -> StorageResult<PresignedRequest> {
+        self.target.presign_put(key, expires_in, opts).await
+    }
+
+    async fn presign_get(
+        &self,
+        key: &str,
+        expires_in: Duration,
+        opts: PresignGetOptions,
+    ) -> StorageResult<PresignedRequest> {
+        if let Some(source) = self.source()
+            && let Err(StorageError::NotFound(_)) = self.target.head(key).await
+        {
+            return source.presign_get(key, expires_in, opts).await;
+        }
+        self.target.presign_get(key, expires_in, opts).await
+    }
+
+    async fn cleanup_incomplete(&self, older_than: Duration) -> StorageResult<usize> {
+        let mut removed = self.target.cleanup_incomplete(older_than).await?;
+        if let Some(source) = self.source() {
+            removed += source.cleanup_incomplete(older_than).await?;
+        }
+        Ok(removed)
+    }
+}
+
+/// The `migrate-storage` command. Copies every blob from `STORAGE` to `STORAGE_TARGET`, then
+/// makes the target the active backend.
+pub(crate) async fn run(db: PgPool, args: MigrateArgs) -> Result<(), Box<dyn Error>> {
+    let Some(target_config) = &CONFIG.storage_target else {
+        return Err("set STORAGE_TARGET to the storage to migrate to".into());
+    };
+    let (source_name, target_name) = (CONFIG.storage.describe(), target_config.describe());
+    if source_name == target_name {
+        return Err("STORAGE and STORAGE_TARGET are the same".into());
+    }
+    let source = super::from_config(&CONFIG.storage).await?;
+    let target = super::from_config(target_config).await?;
+
+    let mut migration = StorageMigration::start(&db, &source_name, &target_name).await?;
+    if args.restart {
+        migration = StorageMigration::reset(&db, &source_name, &target_name).await?;
+    }
+    if migration.finished.is_some() {
+        info!("Migration from {source_name} to {target_name} is finished already");
+        return Ok(());
+    }
+    info!(
+        checkpoint = migration.checkpoint,
+        copied = migration.copied,
+        "Migrating storage from {source_name} to {target_name}"
+    );
+
+    let mut checkpoint = migration.checkpoint;
+    let (mut copied, mut copied_bytes) = (migration.copied, migration.copied_bytes);
+    loop {
+        let blobs = Blob::page_after(&db, checkpoint.as_deref(), args.batch).await?;
+        let Some(last) = blobs.last() else {
+            break;
+        };
+        for blob in &blobs {
+            let size = copy_blob(&db, &*source, &*target, blob).await?;
+            StorageMigration::advance(&db, &source_name, &target_name, &blob.sha256, size as i64)
+                .await?;
+            copied += 1;
+            copied_bytes += size as i64;
+        }
+        checkpoint = Some(last.sha256.clone());
+        info!(copied, copied_bytes, checkpoint, "copied blobs");
+    }
+
+    // Blobs behind the checkpoint recorded by servers that still wrote to the source
+    let mut stored: HashSet<String> = HashSet::new();
+    for prefix in BLOB_PREFIXES {
+        let listed: Vec<String> = target
+            .list(prefix)
+            .map_ok(|object| object.key)
+            .try_collect()
+            .await?;
+        stored.extend(listed);
+    }
+    let mut after: Option<String> = None;
+    let mut caught_up = 0;
+    loop {
+        let blobs = Blob::page_after(&db, after.as_deref(), args.batch).await?;
+        let Some(last) = blobs.last() else {
+            break;
+        };
+        for blob in blobs
+            .iter()
+            .filter(|blob| !stored.contains(&blob.storage_key))
+        {
+            warn!(
+                sha256 = blob.sha256,
+                "blob was written to the source after its turn"
+            );
+            copy_blob(&db, &*source, &*target, blob).await?;
+            caught_up += 1;
+        }
+        after = Some(last.sha256.clone());
+    }
+    if caught_up > 0 {
+        warn!(
+            caught_up,
+            "copied blobs written to the source during the migration, every server should run with STORAGE_TARGET set"
+        );
+    }
+
+    let mut drained = 0;
+    for prefix in STAGING_PREFIXES {
+        let keys: Vec<String> = source
+            .list(prefix)
+            .map_ok(|object| object.key)
+            .try_collect()
+            .await?;
+        for key in keys {
+            if copy_staged(&*source, &*target, &key).await? {
+                drained += 1;
+            }
+        }
+    }
+    if drained > 0 {
+        info!(drained, "copied objects of uploads in progress");
+    }
+
+    if args.no_flip {
+        info!("Everything is copied, {source_name} stays the active backend");
+        return Ok(());
+    }
+    StorageMigration::finish(&db, &source_name, &target_name).await?;
+    info!(
+        "{target_name} is the active backend now. Point STORAGE at it and remove STORAGE_TARGET \
+        before the next restart"
+    );
+
+    Ok(())
+}
+
+/// Copy the object an upload in progress keeps at `key` to `target` unless it's there already.
+/// Returns whether it was copied. Unlike blobs there is nothing to check it against.
+async fn copy_staged(
+    source: &dyn Storage,
+    target: &dyn Storage,
+    key: &str,
+) -> Result<bool, Box<dyn Error>> {
+    match target.head(key).await {
+        Ok(_) => return Ok(false),
+        Err(StorageError::NotFound(_)) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let object = match source.get(key, None).await {
+        Ok(object) => object,
+        // The upload finished or was dropped since the listing
+        Err(StorageError::NotFound(_)) => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    target.put(key, object.body, PutOptions::default()).await?;
+    Ok(true)
+}
+
+/// Copy the object of `blob` to `target` unless it's there already, and check what arrived.
+/// Returns the bytes copied.
+async fn copy_blob(
+    db: &PgPool,
+    source: &dyn Storage,
+    target: &dyn Storage,
+    blob: &Blob,
+) -> Result<u64, Box<dyn Error>> {
+    let key = &blob.storage_key;
+    match target.head(key).await {
+        Ok(_) => return Ok(0),
+        Err(StorageError::NotFound(_)) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let object = match source.get(key, None).await {
+        Ok(object) => object,
+        // Deleted since it was loaded
+        Err(StorageError::NotFound(_)) if Blob::get(db, &blob.sha256).await?.is_none() => {
+            return Ok(0);
+        }
+        Err(StorageError::NotFound(_)) => {
+            return Err(format!(
+                "`{key}` of blob {} is missing from the source, see the storage_reconcile job",
+                blob.sha256
+            )
+            .into());
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut hasher = Sha256::new();
+    let body = object.body.inspect_ok(|chunk| hasher.update(chunk)).boxed();
+    let meta = target.put(key, body, PutOptions::default()).await?;
+    let sent = hex::encode(hasher.finalize());
+
+    let (stored, _) = ingest::hash_object(target, key, None).await?;
+    // Encrypted and compressed objects can only be compared with the source, their sha256 is of
+    // the contents as they were uploaded
+    let plain = blob.encoding.is_none() && ObjectKey::get(db, key).await?.is_none();
+    if stored != sent || (plain && sent != blob.sha256) {
+        target.delete(key).await?;
+        return Err(format!(
+            "`{key}` of blob {} doesn't match after copying",
+            blob.sha256
+        )
+        .into());
+    }
+
+    Ok(meta.size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::BLOB_PREFIX;
+    use crate::storage::LocalStorage;
+    use bytes::Bytes;
+    use futures::stream;
+
+    async fn local(objects: &[(&str, &[u8])]) -> StorageRef {
+        let root = std::env::temp_dir().join(format!("migrate-test-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::new(root).await.unwrap();
+        for (key, data) in objects {
+            let body = stream::once(async { Ok(Bytes::copy_from_slice(data)) }).boxed();
+            storage.put(key, body, PutOptions::default()).await.unwrap();
+        }
+        Arc::new(storage)
+    }
+
+    async fn read(storage: &dyn Storage, key: &str) -> StorageResult<Vec<u8>> {
+        let object = storage.get(key, None).await?;
+        let chunks: Vec<Bytes> = object.body.try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    #[tokio::test]
+    async fn reads_fall_back_to_the_source_until_finished() {
+        let source = local(&[("blobs/old", b"old"), ("blobs/both", b"both")]).await;
+        let target = local(&[("blobs/both", b"both")]).await;
+        let storage = MigratingStorage::new(source.clone(), target.clone());
+
+        let body = stream::once(async { Ok(Bytes::from_static(b"new")) }).boxed();
+        storage
+            .put("blobs/new", body, PutOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(read(&*target, "blobs/new").await.unwrap(), b"new");
+        assert!(matches!(
+            source.head("blobs/new").await,
+            Err(StorageError::NotFound(_))
+        ));
+
+        assert_eq!(read(&storage, "blobs/old").await.unwrap(), b"old");
+        assert_eq!(storage.head("blobs/old").await.unwrap().size, 3);
+
+        let mut keys: Vec<String> = storage
+            .list(BLOB_PREFIX)
+            .map_ok(|object| object.key)
+            .try_collect()
+            .await
+            .unwrap();
+        keys.sort();
+        assert_eq!(keys, ["blobs/both", "blobs/new", "blobs/old"]);
+
+        storage.finished.store(true, Ordering::Release);
+        assert!(matches!(
+            storage.get("blobs/old", None).await,
+            Err(StorageError::NotFound(_))
+        ));
+        let keys: Vec<ObjectMeta> = storage.list(BLOB_PREFIX).try_collect().await.unwrap();
+        assert_eq!(keys.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn deletes_from_both() {
+        let source = local(&[("blobs/a", b"a")]).await;
+        let target = local(&[("blobs/a", b"a")]).await;
+        let storage = MigratingStorage::new(source.clone(), target.clone());
+
+        storage.delete("blobs/a").await.unwrap();
+        assert!(matches!(
+            source.head("blobs/a").await,
+            Err(StorageError::NotFound(_))
+        ));
+        assert!(matches!(
+            target.head("blobs/a").await,
+            Err(StorageError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn copies_uploads_in_progress() {
+        let source = local(&[("tus/a/0", b"chunk"), ("presign/b", b"staged")]).await;
+        let target = local(&[("presign/b", b"newer")]).await;
+
+        assert!(copy_staged(&*source, &*target, "tus/a/0").await.unwrap());
+        assert_eq!(read(&*target, "tus/a/0").await.unwrap(), b"chunk");
+        // Written to the target already, that one is the current one
+        assert!(!copy_staged(&*source, &*target, "presign/b").await.unwrap());
+        assert_eq!(read(&*target, "presign/b").await.unwrap(), b"newer");
+        // Finished since it was listed
+        assert!(!copy_staged(&*source, &*target, "tus/c/0").await.unwrap());
+    }
+}
@@ -0,0 +1,215 @@
+//! Object storage backends.
+//!
+//! Everything that persists file contents goes through the [`Storage`] trait so the rest of the
+//! server never has to care whether bytes end up in Garage/S3 or on the local disk.
+mod encrypted;
+mod local;
+pub(crate) mod migrate;
+mod s3;
+
+pub(crate) use encrypted::{EncryptedStorage, Keyring};
+pub(crate) use local::LocalStorage;
+pub(crate) use s3::S3Storage;
+
+use crate::config::StorageConfig;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// A stream of body chunks. Borrowed streams are accepted for uploads so request bodies can be
+/// forwarded without copying them into an owned buffer first.
+pub type ByteStream<'a> = BoxStream<'a, io::Result<Bytes>>;
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("object `{0}` not found")]
+    NotFound(String),
+
+    #[error("invalid object key `{0}`")]
+    InvalidKey(String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("s3 error: {0}")]
+    S3(String),
+
+    #[error("{0} is not supported by this storage backend")]
+    Unsupported(&'static str),
+
+    #[error("encryption error: {0}")]
+    Encryption(String),
+}
+
+/// Metadata describing a stored object.
+#[derive(Debug, Clone)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+}
+
+/// A stored object and its body.
+pub struct GetObject {
+    pub meta: ObjectMeta,
+    /// The byte range of the object contained in `body`
+    pub range: Range<u64>,
+    pub body: ByteStream<'static>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PutOptions {
+    /// Content type stored alongside the object, when the backend supports it.
+    pub content_type: Option<String>,
+}
+
+/// A request a client can send straight to the storage backend, without going through us.
+#[derive(Debug, Clone)]
+pub struct PresignedRequest {
+    pub method: String,
+    pub url: String,
+    /// Headers the client has to send along, they are part of the signature
+    pub headers: Vec<(String, String)>,
+    pub expires: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PresignPutOptions {
+    pub content_type: Option<String>,
+    /// Exact body size the upload has to have
+    pub content_length: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PresignGetOptions {
+    /// Overrides the `Content-Type` of the response
+    pub content_type: Option<String>,
+    /// Overrides the `Content-Disposition` of the response
+    pub content_disposition: Option<String>,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Short name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Write `body` to `key`, replacing any existing object.
+    async fn put(
+        &self,
+        key: &str,
+        body: ByteStream<'_>,
+        opts: PutOptions,
+    ) -> StorageResult<ObjectMeta>;
+
+    /// Read an object. `range` is clamped to the object size.
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> StorageResult<GetObject>;
+
+    /// Fetch object metadata without the body.
+    async fn head(&self, key: &str) -> StorageResult<ObjectMeta>;
+
+    /// Remove an object. Deleting a missing object is not an error.
+    async fn delete(&self, key: &str) -> StorageResult<()>;
+
+    /// List every object whose key starts with `prefix`.
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxStream<'a, StorageResult<ObjectMeta>>;
+
+    /// Move the object at `from` to `to` within the backend, replacing any object at `to`.
+    /// The contents never pass through the server.
+    async fn rename(&self, from: &str, to: &str) -> StorageResult<()> {
+        let _ = (from, to);
+        Err(StorageError::Unsupported("moving objects"))
+    }
+
+    /// Presign a request that uploads an object to `key`.
+    async fn presign_put(
+        &self,
+        key: &str,
+        expires_in: Duration,
+        opts: PresignPutOptions,
+    ) -> StorageResult<PresignedRequest> {
+        let _ = (key, expires_in, opts);
+        Err(StorageError::Unsupported("presigning"))
+    }
+
+    /// Presign a request that downloads the object at `key`.
+    async fn presign_get(
+        &self,
+        key: &str,
+        expires_in: Duration,
+        opts: PresignGetOptions,
+    ) -> StorageResult<PresignedRequest> {
+        let _ = (key, expires_in, opts);
+        Err(StorageError::Unsupported("presigning"))
+    }
+
+    /// Remove leftovers of interrupted writes started more than `older_than` ago.
+    /// Returns how many were removed.
+    async fn cleanup_incomplete(&self, older_than: Duration) -> StorageResult<usize>;
+
+    /// Re-wrap the data keys of encrypted objects that aren't wrapped with the current master
+    /// key. Returns how many were re-wrapped.
+    async fn rewrap_keys(&self) -> StorageResult<usize> {
+        Ok(0)
+    }
+}
+
+pub type StorageRef = Arc<dyn Storage>;
+
+/// Build the storage backend described by `config`.
+pub(crate) async fn from_config(config: &StorageConfig) -> StorageResult<StorageRef> {
+    let storage: StorageRef = match config {
+        StorageConfig::Local(config) => Arc::new(LocalStorage::new(&config.path).await?),
+        StorageConfig::S3(config) => Arc::new(S3Storage::new(config).await?),
+    };
+
+    Ok(storage)
+}
+
+/// Rejects keys that could escape the storage root or that S3 would treat oddly.
+pub(crate) fn validate_key(key: &str) -> StorageResult<()> {
+    let valid = !key.is_empty()
+        && !key.starts_with('/')
+        && !key.ends_with('/')
+        && key
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..");
+
+    if valid {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidKey(key.to_string()))
+    }
+}
+
+/// Clamp a requested range to an object of `size` bytes.
+pub(crate) fn clamp_range(range: Option<Range<u64>>, size: u64) -> Range<u64> {
+    match range {
+        Some(range) => range.start.min(size)..range.end.min(size),
+        None => 0..size,
+    }
+}
+
+/// Periodic job moving data keys over to the current master key after a rotation.
+pub(crate) async fn rotate_keys(state: crate::state::AppStateRef) -> crate::prelude::Result<()> {
+    state.storage().rewrap_keys().await?;
+    Ok(())
+}
+
+/// Periodic job removing leftovers of interrupted uploads from storage.
+pub(crate) async fn sweep_incomplete(
+    state: crate::state::AppStateRef,
+) -> crate::prelude::Result<()> {
+    let older_than = Duration::from_secs(crate::config::CONFIG.upload.incomplete_expiration);
+    let removed = state.storage().cleanup_incomplete(older_than).await?;
+    if removed > 0 {
+        tracing::info!(removed, "removed incomplete uploads from storage");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,514 @@
+use super::{
+    ByteStream, GetObject, ObjectMeta, PresignGetOptions, PresignPutOptions, PresignedRequest,
+    PutOptions, Storage, StorageError, StorageResult, clamp_range, validate_key,
+};
+use crate::config::S3StorageConfig;
+use async_trait::async_trait;
+use aws_sdk_s3::Client;
+use aws_sdk_s3::config::{Credentials, Region};
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use aws_sdk_s3::presigning::{PresignedRequest as S3PresignedRequest, PresigningConfig};
+use aws_sdk_s3::primitives::{ByteStream as S3ByteStream, DateTime};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use bytes::BytesMut;
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use std::io;
+use std::ops::Range;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::task::{JoinError, JoinSet};
+use tracing::{debug, error, info, warn};
+
+/// Stores objects in an S3 compatible bucket, such as the Garage node from `docker-compose.yaml`.
+pub(crate) struct S3Storage {
+    client: Client,
+    bucket: String,
+    multipart_threshold: usize,
+    part_size: usize,
+    multipart_concurrency: usize,
+}
+
+impl S3Storage {
+    pub(crate) async fn new(config: &S3StorageConfig) -> StorageResult<Self> {
+        let credentials = Credentials::new(
+            &config.access_key_id,
+            &config.secret_access_key,
+            None,
+            None,
+            "unknown-server",
+        );
+
+        let mut builder = aws_sdk_s3::Config::builder()
+            .credentials_provider(credentials)
+            .region(Region::new(config.region.clone()))
+            .force_path_style(config.force_path_style);
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.endpoint_url(endpoint);
+        }
+
+        let storage = Self {
+            client: Client::from_conf(builder.build()),
+            bucket: config.bucket.clone(),
+            multipart_threshold: config.multipart_threshold,
+            part_size: config.part_size,
+            multipart_concurrency: config.multipart_concurrency,
+        };
+
+        // Fail early on a bad config instead of on the first upload
+        storage
+            .client
+            .head_bucket()
+            .bucket(&storage.bucket)
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        Ok(storage)
+    }
+}
+
+fn presigning_config(expires_in: Duration) -> StorageResult<PresigningConfig> {
+    PresigningConfig::expires_in(expires_in).map_err(|err| StorageError::S3(err.to_string()))
+}
+
+fn presigned(request: S3PresignedRequest, expires_in: Duration) -> PresignedRequest {
+    PresignedRequest {
+        method: request.method().to_string(),
+        url: request.uri().to_string(),
+        headers: request
+            .headers()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        expires: OffsetDateTime::now_utc() + expires_in,
+    }
+}
+
+fn s3_error<E, R>(err: SdkError<E, R>) -> StorageError
+where
+    E: std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    StorageError::S3(DisplayErrorContext(err).to_string())
+}
+
+fn to_offset_date_time(date: &DateTime) -> Option<OffsetDateTime> {
+    OffsetDateTime::from_unix_timestamp(date.secs()).ok()
+}
+
+/// What has to be escaped in the key of `x-amz-copy-source`.
+const COPY_SOURCE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.');
+
+/// Multipart uploads are limited to this many parts.
+const MAX_PARTS: i32 = 10_000;
+
+/// S3 rejects parts smaller than this, except for the last one.
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+impl S3Storage {
+    /// Upload `body` as a multipart upload. `buffer` holds data already read from `body`.
+    /// The upload is aborted if anything goes wrong so no parts are left behind.
+    async fn put_multipart(
+        &self,
+        key: &str,
+        buffer: BytesMut,
+        body: ByteStream<'_>,
+        opts: PutOptions,
+    ) -> StorageResult<()> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_content_type(opts.content_type)
+            .send()
+            .await
+            .map_err(s3_error)?;
+        let Some(upload_id) = upload.upload_id() else {
+            return Err(StorageError::S3("missing multipart upload id".to_string()));
+        };
+        debug!(key, upload_id, "started multipart upload");
+
+        let completed = match self.upload_parts(key, upload_id, buffer, body).await {
+            Ok(parts) => self
+                .client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(s3_error),
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = completed {
+            warn!(%err, key, upload_id, "aborting multipart upload");
+            if let Err(abort_err) = self.abort_multipart(key, upload_id).await {
+                // The periodic sweep will get it
+                error!(%abort_err, key, upload_id, "failed to abort multipart upload");
+            }
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        mut buffer: BytesMut,
+        mut body: ByteStream<'_>,
+    ) -> StorageResult<Vec<CompletedPart>> {
+        let concurrency = self.multipart_concurrency.max(1);
+        let mut part_size = self.part_size.max(MIN_PART_SIZE);
+
+        // Parts are spawned so they keep uploading while the next one is read from `body`.
+        // Dropping the set on error cancels whatever is still in flight.
+        let mut in_flight = JoinSet::new();
+        let mut parts = vec![];
+        let mut part_number = 1;
+        let mut ended = false;
+
+        while !ended || !buffer.is_empty() {
+            while !ended && buffer.len() < part_size {
+                match body.try_next().await? {
+                    Some(chunk) => buffer.extend_from_slice(&chunk),
+                    None => ended = true,
+                }
+            }
+            if buffer.is_empty() {
+                break;
+            }
+
+            if part_number > MAX_PARTS {
+                return Err(StorageError::S3("too many multipart parts".to_string()));
+            }
+            // Grow parts as the upload gets longer so huge bodies stay below the part limit
+            if part_number % 1000 == 0 {
+                part_size *= 2;
+            }
+
+            if in_flight.len() >= concurrency {
+                parts.push(join_part(in_flight.join_next().await)?);
+            }
+
+            let part = buffer.split_to(part_size.min(buffer.len())).freeze();
+            let request = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(S3ByteStream::from(part));
+            in_flight.spawn(async move {
+                let response = request.send().await.map_err(s3_error)?;
+                Ok(CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(response.e_tag().map(str::to_string))
+                    .build())
+            });
+            part_number += 1;
+        }
+
+        while let Some(joined) = in_flight.join_next().await {
+            parts.push(join_part(Some(joined))?);
+        }
+        parts.sort_by_key(|part| part.part_number());
+
+        Ok(parts)
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> StorageResult<()> {
+        self.client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        Ok(())
+    }
+}
+
+fn join_part(
+    joined: Option<Result<StorageResult<CompletedPart>, JoinError>>,
+) -> StorageResult<CompletedPart> {
+    match joined {
+        Some(Ok(part)) => part,
+        Some(Err(err)) => Err(StorageError::S3(format!("part upload task failed: {err}"))),
+        None => Err(StorageError::S3("no part upload in flight".to_string())),
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        mut body: ByteStream<'_>,
+        opts: PutOptions,
+    ) -> StorageResult<ObjectMeta> {
+        validate_key(key)?;
+
+        // Small bodies go up in a single PUT, anything else becomes a multipart upload
+        let mut buffer = BytesMut::new();
+        while buffer.len() <= self.multipart_threshold {
+            let Some(chunk) = body.try_next().await? else {
+                self.client
+                    .put_object()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .set_content_type(opts.content_type)
+                    .body(S3ByteStream::from(buffer.freeze()))
+                    .send()
+                    .await
+                    .map_err(s3_error)?;
+
+                return self.head(key).await;
+            };
+            buffer.extend_from_slice(&chunk);
+        }
+
+        self.put_multipart(key, buffer, body, opts).await?;
+        self.head(key).await
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> StorageResult<GetObject> {
+        validate_key(key)?;
+
+        let meta = self.head(key).await?;
+        let range = clamp_range(range, meta.size);
+
+        let mut request = self.client.get_object().bucket(&self.bucket).key(key);
+        if range.start >= range.end {
+            return Ok(GetObject {
+                meta,
+                range,
+                body: stream::empty().boxed(),
+            });
+        }
+        if range != (0..meta.size) {
+            request = request.range(format!("bytes={}-{}", range.start, range.end - 1));
+        }
+
+        let response = request.send().await.map_err(|err| {
+            match err.as_service_error().map(|e| e.is_no_such_key()) {
+                Some(true) => StorageError::NotFound(key.to_string()),
+                _ => s3_error(err),
+            }
+        })?;
+
+        let body = stream::unfold(response.body, |mut body| async move {
+            body.next()
+                .await
+                .map(|chunk| (chunk.map_err(io::Error::other), body))
+        })
+        .boxed();
+
+        Ok(GetObject { meta, range, body })
+    }
+
+    async fn head(&self, key: &str) -> StorageResult<ObjectMeta> {
+        validate_key(key)?;
+
+        let response = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(
+                |err| match err.as_service_error().map(|e| e.is_not_found()) {
+                    Some(true) => StorageError::NotFound(key.to_string()),
+                    _ => s3_error(err),
+                },
+            )?;
+
+        Ok(ObjectMeta {
+            key: key.to_string(),
+            size: response.content_length().unwrap_or_default().max(0) as u64,
+        })
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        validate_key(key)?;
+
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        Ok(())
+    }
+
+    /// A single `CopyObject`, which takes objects up to 5 GiB. That is as large as a presigned
+    /// PUT goes, the only thing moved.
+    async fn rename(&self, from: &str, to: &str) -> StorageResult<()> {
+        validate_key(from)?;
+        validate_key(to)?;
+
+        let source = format!("{}/{}", self.bucket, utf8_percent_encode(from, COPY_SOURCE));
+        self.client
+            .copy_object()
+            .bucket(&self.bucket)
+            .key(to)
+            .copy_source(source)
+            .send()
+            .await
+            .map_err(|err| {
+                // A missing source comes back as a plain `NoSuchKey` with a 404
+                match err
+                    .raw_response()
+                    .map(|response| response.status().as_u16())
+                {
+                    Some(404) => StorageError::NotFound(from.to_string()),
+                    _ => s3_error(err),
+                }
+            })?;
+        self.delete(from).await
+    }
+
+    async fn presign_put(
+        &self,
+        key: &str,
+        expires_in: Duration,
+        opts: PresignPutOptions,
+    ) -> StorageResult<PresignedRequest> {
+        validate_key(key)?;
+
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_content_type(opts.content_type)
+            .set_content_length(opts.content_length.map(|length| length as i64))
+            .presigned(presigning_config(expires_in)?)
+            .await
+            .map_err(s3_error)?;
+
+        Ok(presigned(request, expires_in))
+    }
+
+    async fn presign_get(
+        &self,
+        key: &str,
+        expires_in: Duration,
+        opts: PresignGetOptions,
+    ) -> StorageResult<PresignedRequest> {
+        validate_key(key)?;
+
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_response_content_type(opts.content_type)
+            .set_response_content_disposition(opts.content_disposition)
+            .presigned(presigning_config(expires_in)?)
+            .await
+            .map_err(s3_error)?;
+
+        Ok(presigned(request, expires_in))
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxStream<'a, StorageResult<ObjectMeta>> {
+        let pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+
+        stream::unfold(pages, |mut pages| async move {
+            let page = pages.next().await?;
+            let objects: Vec<StorageResult<ObjectMeta>> = match page {
+                Ok(page) => page
+                    .contents()
+                    .iter()
+                    .map(|object| {
+                        Ok(ObjectMeta {
+                            key: object.key().unwrap_or_default().to_string(),
+                            size: object.size().unwrap_or_default().max(0) as u64,
+                        })
+                    })
+                    .collect(),
+                Err(err) => vec![Err(s3_error(err))],
+            };
+
+            Some((stream::iter(objects), pages))
+        })
+        .flatten()
+        .boxed()
+    }
+
+    async fn cleanup_incomplete(&self, older_than: Duration) -> StorageResult<usize> {
+        let cutoff = OffsetDateTime::now_utc() - older_than;
+        let mut stale = vec![];
+
+        let mut key_marker = None;
+        let mut upload_id_marker = None;
+        loop {
+            let page = self
+                .client
+                .list_multipart_uploads()
+                .bucket(&self.bucket)
+                .set_key_marker(key_marker.take())
+                .set_upload_id_marker(upload_id_marker.take())
+                .send()
+                .await
+                .map_err(s3_error)?;
+
+            for upload in page.uploads() {
+                let initiated = upload.initiated().and_then(to_offset_date_time);
+                if let (Some(key), Some(upload_id), Some(initiated)) =
+                    (upload.key(), upload.upload_id(), initiated)
+                    && initiated < cutoff
+                {
+                    stale.push((key.to_string(), upload_id.to_string()));
+                }
+            }
+
+            if !page.is_truncated().unwrap_or_default() {
+                break;
+            }
+            key_marker = page.next_key_marker().map(str::to_string);
+            upload_id_marker = page.next_upload_id_marker().map(str::to_string);
+            if key_marker.is_none() && upload_id_marker.is_none() {
+                break;
+            }
+        }
+
+        for (key, upload_id) in &stale {
+            info!(key, upload_id, "aborting stale multipart upload");
+            self.abort_multipart(key, upload_id).await?;
+        }
+
+        Ok(stale.len())
+    }
+}
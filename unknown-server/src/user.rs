@@ -1,6 +1,10 @@
 use crate::{dto, models};
 use axum_login::{AuthnBackend, UserId};
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
 use password_auth::{generate_hash, verify_password};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
 use tokio::task;
 
@@ -27,6 +31,41 @@ impl Backend {
 
 pub type AuthSession = axum_login::AuthSession<Backend>;
 
+/// Prefix of every API token, so they are easy to spot when they leak.
+const API_TOKEN_PREFIX: &str = "uks_";
+
+impl Backend {
+    /// Check credentials sent with HTTP Basic auth. The password may be the user's password or
+    /// one of their API tokens.
+    pub(crate) async fn authenticate_basic(
+        &self,
+        username: String,
+        password: String,
+    ) -> Result<Option<models::user::User>, BackendError> {
+        if is_api_token(&password) {
+            let hash = hash_api_token(&password);
+            let Some(token) = models::api_token::ApiToken::find_by_hash(&self.0, &hash).await?
+            else {
+                return Ok(None);
+            };
+            let user = self.get_user(&token.user_id).await?;
+            // The username has to match too, a token alone isn't enough
+            let Some(user) = user.filter(|user| user.username == username) else {
+                return Ok(None);
+            };
+            models::api_token::ApiToken::touch(&self.0, token.id).await?;
+            return Ok(Some(user));
+        }
+
+        self.authenticate(dto::auth::UserLoginDto {
+            username,
+            password,
+            remember: false,
+        })
+        .await
+    }
+}
+
 impl AuthnBackend for Backend {
     type User = models::user::User;
     type Credentials = dto::auth::UserLoginDto;
@@ -64,3 +103,25 @@ pub(crate) async fn hash_password(password: String) -> Result<String, BackendErr
         .await
         .map_err(BackendError::TaskJoin)
 }
+
+/// Whether `password` is an API token rather than a password.
+pub(crate) fn is_api_token(password: &str) -> bool {
+    password.starts_with(API_TOKEN_PREFIX)
+}
+
+/// A new API token and the hash it's stored as.
+pub(crate) fn generate_api_token() -> (String, String) {
+    let mut secret = [0; 32];
+    rand::rng().fill_bytes(&mut secret);
+    let token = format!(
+        "{API_TOKEN_PREFIX}{}",
+        BASE64_URL_SAFE_NO_PAD.encode(secret)
+    );
+    let hash = hash_api_token(&token);
+    (token, hash)
+}
+
+/// Tokens are random enough that a plain hash protects them, unlike passwords.
+fn hash_api_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}